@@ -1,10 +1,7 @@
 fn main() {
-    // Ensure KVM is available on Linux
-    #[cfg(target_os = "linux")]
-    {
-        println!("cargo:rustc-cfg=kvm");
-    }
-    
+    // Whether KVM is usable is a runtime question (see
+    // vm::qemu::kvm_unavailable_reason); VMs fall back to TCG without it
+
     // Link system libraries
     if cfg!(target_os = "linux") {
         println!("cargo:rustc-link-lib=util");
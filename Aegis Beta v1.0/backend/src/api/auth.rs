@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use warp::{Filter, Rejection};
+
+use crate::error::AppError;
+
+// Ordered: a token satisfies any scope at or below its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    // GET routes, metrics, console view
+    Read,
+    // Everything
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+// How long a console token may take to be used for the VNC WebSocket
+pub const CONSOLE_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    // Separate from any regular API credentials; admin-only routes are
+    // disabled entirely while this is unset and no admin-scoped token exists
+    pub admin_token: Option<String>,
+    // token -> scope. While this is empty and no admin_token is set, routes
+    // guarded by require_scope stay open.
+    pub tokens: HashMap<String, Scope>,
+    pub console: Arc<ConsoleTokens>,
+}
+
+impl AuthConfig {
+    // The tokens file is a JSON object of token -> "read" | "admin"
+    pub fn load_tokens(path: &Path) -> Result<HashMap<String, Scope>, AppError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| AppError::Internal(format!("Failed to read {}: {}", path.display(), e)))?;
+        let tokens: HashMap<String, Scope> = serde_json::from_str(&content)
+            .map_err(|e| AppError::Internal(format!("Invalid tokens file {}: {}", path.display(), e)))?;
+
+        if tokens.keys().any(|token| token.is_empty()) {
+            return Err(AppError::Internal(format!("Empty token in {}", path.display())));
+        }
+
+        Ok(tokens)
+    }
+
+    // Highest scope `presented` carries. Every candidate is compared so the
+    // time taken doesn't depend on which one matched.
+    pub fn scope_of(&self, presented: &str) -> Option<Scope> {
+        let admin = self.admin_token.iter().map(|token| (token, Scope::Admin));
+        admin
+            .chain(self.tokens.iter().map(|(token, scope)| (token, *scope)))
+            .filter(|(token, _)| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+            .map(|(_, scope)| scope)
+            .fold(None, |best, scope| best.max(Some(scope)))
+    }
+
+    // For regular routes: open until a tokens file or an admin token is
+    // configured. An admin token alone doesn't leave the rest open, or
+    // anything admin-scoped outside require_admin would be too.
+    pub fn check_scope(&self, presented: Option<&str>, required: Scope) -> Result<(), AppError> {
+        if self.tokens.is_empty() && self.admin_token.is_none() {
+            return Ok(());
+        }
+
+        let presented = presented
+            .ok_or_else(|| AppError::Unauthorized("Missing API token".to_string()))?;
+
+        match self.scope_of(presented) {
+            Some(scope) if scope >= required => Ok(()),
+            Some(_) => Err(AppError::Forbidden(format!("Token lacks {} scope", required.as_str()))),
+            None => Err(AppError::Forbidden("Invalid API token".to_string())),
+        }
+    }
+
+    // For admin-only routes: never open, whatever else is configured.
+    // `presented` is the bare token, without any "Bearer " prefix
+    pub fn check_admin(&self, presented: Option<&str>) -> Result<(), AppError> {
+        let configured = self.admin_token.is_some()
+            || self.tokens.values().any(|scope| *scope == Scope::Admin);
+        if !configured {
+            return Err(AppError::Forbidden("Admin access is not configured".to_string()));
+        }
+
+        let presented = presented
+            .ok_or_else(|| AppError::Unauthorized("Missing admin token".to_string()))?;
+
+        if self.scope_of(presented) != Some(Scope::Admin) {
+            return Err(AppError::Forbidden("Invalid admin token".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+// Short-lived, single-use tokens binding one VNC connection to one VM:
+// "<expiry unix secs>.<blake3 keyed hash of vm_id and expiry>"
+#[derive(Debug)]
+pub struct ConsoleTokens {
+    key: [u8; 32],
+    // Tokens already spent, with their expiry, so a leaked URL can't be replayed
+    used: Mutex<HashMap<String, i64>>,
+}
+
+impl Default for ConsoleTokens {
+    // Random per process: tokens minted before a restart stop verifying
+    fn default() -> Self {
+        Self::with_key(rand::random())
+    }
+}
+
+impl ConsoleTokens {
+    // Derives the signing key from the configured secret, so every backend
+    // sharing the secret accepts the same tokens
+    pub fn from_secret(secret: &str) -> Self {
+        Self::with_key(blake3::derive_key("aegis console token v1", secret.as_bytes()))
+    }
+
+    fn with_key(key: [u8; 32]) -> Self {
+        Self { key, used: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn mint(&self, vm_id: &str) -> (String, chrono::DateTime<chrono::Utc>) {
+        let expires_at = chrono::Utc::now() + chrono::Duration::from_std(CONSOLE_TOKEN_TTL).unwrap_or_default();
+        let expiry = expires_at.timestamp();
+        (format!("{}.{}", expiry, self.sign(vm_id, expiry).to_hex()), expires_at)
+    }
+
+    // Accepts a token once, for the VM it was minted for, before it expires
+    pub fn redeem(&self, token: &str, vm_id: &str) -> Result<(), AppError> {
+        let invalid = || AppError::Forbidden("Invalid console token".to_string());
+
+        let (expiry, mac) = token.split_once('.').ok_or_else(invalid)?;
+        let expiry: i64 = expiry.parse().map_err(|_| invalid())?;
+        let mac = blake3::Hash::from_hex(mac).map_err(|_| invalid())?;
+
+        // blake3::Hash compares in constant time
+        if self.sign(vm_id, expiry) != mac {
+            return Err(invalid());
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        if expiry <= now {
+            return Err(AppError::Forbidden("Console token expired".to_string()));
+        }
+
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, expiry| *expiry > now);
+        if used.insert(token.to_string(), expiry).is_some() {
+            return Err(AppError::Forbidden("Console token already used".to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn sign(&self, vm_id: &str, expiry: i64) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        // The separator keeps (vm_id, expiry) pairs from colliding
+        hasher.update(vm_id.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(expiry.to_string().as_bytes());
+        hasher.finalize()
+    }
+}
+
+fn bearer_token(header: &Option<String>) -> Option<&str> {
+    header.as_deref().and_then(|h| h.strip_prefix("Bearer "))
+}
+
+pub fn require_scope(auth: Arc<AuthConfig>, required: Scope) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                auth.check_scope(bearer_token(&header), required).map_err(Rejection::from)
+            }
+        })
+        .untuple_one()
+}
+
+pub fn require_admin(auth: Arc<AuthConfig>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                auth.check_admin(bearer_token(&header)).map_err(Rejection::from)
+            }
+        })
+        .untuple_one()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_token_alone_closes_scoped_routes() {
+        let auth = AuthConfig { admin_token: Some("secret".to_string()), ..Default::default() };
+
+        for required in [Scope::Read, Scope::Admin] {
+            assert!(matches!(auth.check_scope(None, required), Err(AppError::Unauthorized(_))));
+            assert!(matches!(auth.check_scope(Some("guess"), required), Err(AppError::Forbidden(_))));
+            assert!(auth.check_scope(Some("secret"), required).is_ok());
+        }
+        assert!(AuthConfig::default().check_scope(None, Scope::Admin).is_ok());
+    }
+}
\ No newline at end of file
@@ -1,117 +1,972 @@
-use std::sync::Arc;
-use warp::{Rejection, Reply};
-use serde_json::json;
-
-use crate::vm::manager::VMManager;
-use crate::vm::config::{VMConfig, CreateVMRequest, UpdateVMRequest};
-use crate::security::validation::validate_vm_config;
-
-pub async fn list_vms(
-    vm_manager: Arc<VMManager>
-) -> Result<impl Reply, Rejection> {
-    let vms = vm_manager.list_vms().await;
-    Ok(warp::reply::json(&vms))
-}
-
-pub async fn get_vm(
-    vm_id: String,
-    vm_manager: Arc<VMManager>
-) -> Result<impl Reply, Rejection> {
-    match vm_manager.get_vm(&vm_id).await {
-        Some(vm) => Ok(warp::reply::json(&vm)),
-        None => Err(warp::reject::not_found()),
-    }
-}
-
-pub async fn create_vm(
-    body: CreateVMRequest,
-    vm_manager: Arc<VMManager>
-) -> Result<impl Reply, Rejection> {
-    // Validate input
-    if let Err(err) = validate_vm_config(&body) {
-        return Ok(warp::reply::json(&json!({
-            "error": err.to_string()
-        })));
-    }
-
-    match vm_manager.create_vm(body).await {
-        Ok(vm) => Ok(warp::reply::json(&vm)),
-        Err(err) => Ok(warp::reply::json(&json!({
-            "error": err.to_string()
-        }))),
-    }
-}
-
-pub async fn start_vm(
-    vm_id: String,
-    vm_manager: Arc<VMManager>
-) -> Result<impl Reply, Rejection> {
-    match vm_manager.start_vm(&vm_id).await {
-        Ok(_) => Ok(warp::reply::json(&json!({
-            "success": true,
-            "message": format!("VM {} started", vm_id)
-        }))),
-        Err(err) => Ok(warp::reply::json(&json!({
-            "error": err.to_string()
-        }))),
-    }
-}
-
-pub async fn stop_vm(
-    vm_id: String,
-    vm_manager: Arc<VMManager>
-) -> Result<impl Reply, Rejection> {
-    match vm_manager.stop_vm(&vm_id).await {
-        Ok(_) => Ok(warp::reply::json(&json!({
-            "success": true,
-            "message": format!("VM {} stopped", vm_id)
-        }))),
-        Err(err) => Ok(warp::reply::json(&json!({
-            "error": err.to_string()
-        }))),
-    }
-}
-
-pub async fn delete_vm(
-    vm_id: String,
-    vm_manager: Arc<VMManager>
-) -> Result<impl Reply, Rejection> {
-    match vm_manager.delete_vm(&vm_id).await {
-        Ok(_) => Ok(warp::reply::json(&json!({
-            "success": true,
-            "message": format!("VM {} deleted", vm_id)
-        }))),
-        Err(err) => Ok(warp::reply::json(&json!({
-            "error": err.to_string()
-        }))),
-    }
-}
-
-pub async fn get_vnc_url(
-    vm_id: String,
-    vm_manager: Arc<VMManager>
-) -> Result<impl Reply, Rejection> {
-    match vm_manager.get_vnc_url(&vm_id).await {
-        Some(url) => Ok(warp::reply::json(&json!({
-            "url": url
-        }))),
-        None => Err(warp::reject::not_found()),
-    }
-}
-
-pub async fn upload_iso(
-    vm_manager: Arc<VMManager>,
-    body: bytes::Bytes,
-) -> Result<impl Reply, Rejection> {
-    // TODO: Implement ISO upload
-    Ok(warp::reply::json(&json!({
-        "error": "Not implemented"
-    })))
-}
-
-pub async fn health_check() -> Result<impl Reply, Rejection> {
-    Ok(warp::reply::json(&json!({
-        "status": "ok",
-        "timestamp": chrono::Utc::now().to_rfc3339()
-    })))
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+use bytes::Buf;
+use futures::Stream;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use warp::hyper::Body;
+use warp::{Rejection, Reply};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::error::AppError;
+use super::auth::AuthConfig;
+use super::websocket::proxy_vnc;
+use crate::utils::logging::LogLevel;
+use crate::vm::manager::VMManager;
+use crate::vm::config::{
+    CreateVMRequest, AttachDiskRequest, UpdateVMRequest, NetworkType, NicModel, DiskFormat, BiosType, GuestArch,
+    Accelerator,
+};
+use crate::vm::qemu::{self, qemu_caps, MANAGED_FLAGS};
+use crate::storage::disks::DiskSummary;
+use crate::security::privileges::privileges;
+use crate::security::validation::{
+    validate_vm_config, MAX_NICS, MIN_MEMORY_MB, MAX_MEMORY_MB, MIN_CPU_CORES, MAX_CPU_CORES,
+    MIN_DISK_GB, MAX_DISK_GB,
+};
+
+pub async fn list_vms(
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let vms = vm_manager.list_vms().await;
+    Ok(warp::reply::json(&vms))
+}
+
+pub async fn get_vm(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let vm = vm_manager.get_vm(&vm_id).await
+        .ok_or_else(|| AppError::NotFound(format!("VM {} not found", vm_id)))?;
+    Ok(warp::reply::json(&vm))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateQuery {
+    // Return an operation id at once instead of waiting for the disk
+    #[serde(default, rename = "async")]
+    pub background: bool,
+}
+
+pub async fn create_vm(
+    query: CreateQuery,
+    body: CreateVMRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    // Validate input
+    validate_vm_config(&body).map_err(AppError::from)?;
+
+    if query.background {
+        let op_id = vm_manager.begin_create_vm(body);
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "op_id": op_id })),
+            warp::http::StatusCode::ACCEPTED,
+        ));
+    }
+
+    let vm = vm_manager.create_vm(body).await?;
+    Ok(warp::reply::with_status(warp::reply::json(&vm), warp::http::StatusCode::OK))
+}
+
+pub async fn get_operation(
+    op_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let progress = vm_manager.operations().get(&op_id)
+        .ok_or_else(|| AppError::NotFound(format!("Operation {} not found", op_id)))?;
+    Ok(warp::reply::json(&progress))
+}
+
+pub async fn start_vm(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.start_vm(&vm_id).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("VM {} started", vm_id)
+    })))
+}
+
+// Recovery for a VM stuck in Error, e.g. after a failed start
+pub async fn reset_vm_state(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let status = vm_manager.reset_state(&vm_id).await?;
+    Ok(warp::reply::json(&status))
+}
+
+pub async fn stop_vm(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.stop_vm(&vm_id).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("VM {} stopped", vm_id)
+    })))
+}
+
+pub async fn pause_vm(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.pause_vm(&vm_id).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("VM {} paused", vm_id)
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RebootQuery {
+    // system_reset instead of asking the guest
+    #[serde(default)]
+    pub hard: bool,
+}
+
+pub async fn reboot_vm(
+    vm_id: String,
+    query: RebootQuery,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.reboot_vm(&vm_id, query.hard).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("VM {} {}", vm_id, if query.hard { "reset" } else { "rebooting" })
+    })))
+}
+
+// Suspend-to-disk; start or resume restores it
+pub async fn suspend_vm(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.suspend_vm(&vm_id).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("VM {} suspended", vm_id)
+    })))
+}
+
+pub async fn resume_vm(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.resume_vm(&vm_id).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("VM {} resumed", vm_id)
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateQuery {
+    // Stage memory/cpu changes for the next start instead of refusing them
+    #[serde(default)]
+    pub defer_restart: bool,
+}
+
+pub async fn update_vm(
+    vm_id: String,
+    query: UpdateQuery,
+    body: UpdateVMRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let update = vm_manager.update_vm(&vm_id, body, query.defer_restart).await?;
+    Ok(warp::reply::json(&update))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuery {
+    #[serde(default)]
+    pub force: bool,
+}
+
+pub async fn delete_vm(
+    vm_id: String,
+    query: DeleteQuery,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.delete_vm(&vm_id, query.force).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("VM {} deleted", vm_id)
+    })))
+}
+
+// The URL is relative to the API origin and carries a single-use token
+// that only opens this VM's console, and only for CONSOLE_TOKEN_TTL
+pub async fn get_vnc_url(
+    vm_id: String,
+    vm_manager: Arc<VMManager>,
+    auth: Arc<AuthConfig>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.get_vm_status(&vm_id).await
+        .ok_or_else(|| AppError::NotFound(format!("VM {} not found", vm_id)))?;
+    let (token, expires_at) = auth.console.mint(&vm_id);
+    Ok(warp::reply::json(&json!({
+        "url": format!("/api/vms/{}/vnc/ws?token={}", vm_id, token),
+        "expires_at": expires_at
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VncQuery {
+    pub token: String,
+}
+
+// Browsers can't send an Authorization header on a WebSocket, so the
+// console token from get_vnc_url is the only credential here. Connecting
+// wakes a suspended VM.
+pub async fn vnc_websocket(
+    vm_id: String,
+    query: VncQuery,
+    ws: warp::ws::Ws,
+    vm_manager: Arc<VMManager>,
+    auth: Arc<AuthConfig>
+) -> Result<impl Reply, Rejection> {
+    auth.console.redeem(&query.token, &vm_id)?;
+    let (port, session) = vm_manager.open_console(&vm_id).await?;
+    Ok(ws.on_upgrade(move |socket| async move {
+        proxy_vnc(socket, vm_id, port).await;
+        drop(session);
+    }))
+}
+
+pub async fn metrics_history(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let history = vm_manager.metrics_history(&vm_id)?;
+    Ok(warp::reply::json(&history))
+}
+
+// For terminal clients (socat, minicom, telnet) rather than the browser
+pub async fn get_console_socket(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let socket = vm_manager.console_socket(&vm_id)?;
+    Ok(warp::reply::json(&socket))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogLevelRequest {
+    pub level: Option<LogLevel>,
+}
+
+pub async fn get_log_level(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let level = vm_manager.log_level(&vm_id)?;
+    Ok(warp::reply::json(&json!({ "level": level })))
+}
+
+pub async fn set_log_level(
+    vm_id: String,
+    body: LogLevelRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.set_log_level(&vm_id, body.level).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "level": body.level
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeIsoRequest {
+    pub iso_path: String,
+}
+
+pub async fn change_iso(
+    vm_id: String,
+    body: ChangeIsoRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let config = vm_manager.change_iso(&vm_id, &body.iso_path).await?;
+    Ok(warp::reply::json(&config))
+}
+
+pub async fn attach_disk(
+    vm_id: String,
+    body: AttachDiskRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let disk = vm_manager.attach_disk(&vm_id, body).await?;
+    Ok(warp::reply::with_status(warp::reply::json(&disk), warp::http::StatusCode::CREATED))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DetachDiskQuery {
+    // Also remove the image instead of keeping it for a later attach
+    #[serde(default)]
+    pub delete: bool,
+}
+
+pub async fn detach_disk(
+    vm_id: String,
+    name: String,
+    query: DetachDiskQuery,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.detach_disk(&vm_id, &name, query.delete).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("Disk {} detached from VM {}", name, vm_id)
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttachNicRequest {
+    pub network_type: NetworkType,
+}
+
+pub async fn attach_nic(
+    vm_id: String,
+    body: AttachNicRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let nic = vm_manager.attach_nic(&vm_id, body.network_type).await?;
+    Ok(warp::reply::json(&nic))
+}
+
+pub async fn detach_nic(
+    vm_id: String,
+    netdev_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.detach_nic(&vm_id, &netdev_id).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("NIC {} detached from VM {}", netdev_id, vm_id)
+    })))
+}
+
+pub async fn list_disk_snapshots(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let snapshots = vm_manager.list_disk_snapshots(&vm_id).await?;
+    Ok(warp::reply::json(&snapshots))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSnapshotRequest {
+    pub name: String,
+}
+
+pub async fn create_disk_snapshot(
+    vm_id: String,
+    body: CreateSnapshotRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.create_disk_snapshot(&vm_id, &body.name).await?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "success": true,
+            "message": format!("Snapshot {} of VM {} created", body.name, vm_id)
+        })),
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+pub async fn restore_disk_snapshot(
+    vm_id: String,
+    name: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.restore_disk_snapshot(&vm_id, &name).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("VM {} restored to snapshot {}", vm_id, name)
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConvertDiskRequest {
+    pub format: DiskFormat,
+    // Move the VM onto the converted disk instead of leaving a copy
+    #[serde(default)]
+    pub replace: bool,
+}
+
+pub async fn convert_disk(
+    vm_id: String,
+    req: ConvertDiskRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let info = vm_manager.convert_disk(&vm_id, req.format, req.replace).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "path": info.path,
+        "replaced": req.replace,
+        "disk": DiskSummary::from(&info),
+    })))
+}
+
+pub async fn flatten_disk(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let info = vm_manager.flatten_disk(&vm_id).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "disk": DiskSummary::from(&info),
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResizeDiskRequest {
+    pub size_gb: u32,
+}
+
+pub async fn resize_disk(
+    vm_id: String,
+    body: ResizeDiskRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.resize_disk(&vm_id, body.size_gb).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "size_gb": body.size_gb,
+    })))
+}
+
+pub async fn compact_disk(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let report = vm_manager.compact_disk(&vm_id).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "before_actual_size_gb": report.before_actual_size_gb,
+        "after_actual_size_gb": report.after_actual_size_gb,
+    })))
+}
+
+pub async fn commit_disk(
+    vm_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let base = vm_manager.commit_disk(&vm_id).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "base": base,
+    })))
+}
+
+pub async fn delete_disk_snapshot(
+    vm_id: String,
+    name: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.delete_disk_snapshot(&vm_id, &name).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("Snapshot {} of VM {} deleted", name, vm_id)
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QmpRequest {
+    pub execute: String,
+    #[serde(default)]
+    pub arguments: serde_json::Value,
+}
+
+pub async fn qmp_passthrough(
+    vm_id: String,
+    body: QmpRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let response = vm_manager.qmp_passthrough(&vm_id, &body.execute, body.arguments).await?;
+    Ok(warp::reply::json(&json!({
+        "return": response
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub compress: bool,
+}
+
+pub async fn export_vm(
+    vm_id: String,
+    query: ExportQuery,
+    range: Option<String>,
+    if_range: Option<String>,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    // A ranged request resumes an earlier download, so it has to be served
+    // from that bundle rather than a fresh one with different bytes
+    let existing = match range {
+        Some(_) => vm_manager.existing_export(&vm_id)?,
+        None => None,
+    };
+    let bundle = match existing {
+        Some(bundle) => bundle,
+        None => vm_manager.export_vm(&vm_id, query.compress).await?,
+    };
+
+    let file_name = bundle.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| format!("{}.tar", vm_id));
+    Ok(serve_file(&bundle, &file_name, "application/x-tar", range, if_range).await?)
+}
+
+// Unreadable entries don't fail the listing; they come back as `warnings`
+pub async fn list_isos(
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let listing = vm_manager.list_isos().await?;
+    Ok(warp::reply::json(&listing))
+}
+
+pub async fn delete_iso(
+    name: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.delete_iso(&name).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("ISO {} deleted", name)
+    })))
+}
+
+pub async fn list_disks(
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let listing = vm_manager.list_disks().await?;
+    Ok(warp::reply::json(&listing))
+}
+
+pub async fn download_iso(
+    name: String,
+    range: Option<String>,
+    if_range: Option<String>,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let path = vm_manager.iso_path(&name)?;
+    let content_type = match path.extension().and_then(|e| e.to_str()) {
+        Some("iso") => "application/x-iso9660-image",
+        _ => "application/octet-stream",
+    };
+    Ok(serve_file(&path, &name, content_type, range, if_range).await?)
+}
+
+enum ByteRange {
+    Full,
+    // Inclusive, like the header
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Only single ranges are honoured; anything else (several ranges, another
+// unit, bad syntax) is served as the whole file, which RFC 9110 allows
+fn parse_range(header: Option<&str>, len: u64) -> ByteRange {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+
+    let range = if start.is_empty() {
+        // "-N": the last N bytes
+        match end.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        }
+    } else {
+        let start = match start.parse::<u64>() {
+            Ok(start) => start,
+            Err(_) => return ByteRange::Full,
+        };
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) => end.min(len.saturating_sub(1)),
+                Err(_) => return ByteRange::Full,
+            }
+        };
+        (start, end)
+    };
+
+    if len == 0 || range.0 >= len || range.0 > range.1 {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range.0, range.1)
+    }
+}
+
+// Streams a file as an attachment, honouring Range so large images can be
+// resumed. The ETag changes whenever the file is rewritten, so If-Range
+// falls back to a full response instead of splicing two versions together.
+async fn serve_file(
+    path: &Path,
+    file_name: &str,
+    content_type: &str,
+    range: Option<String>,
+    if_range: Option<String>,
+) -> Result<warp::http::Response<Body>, AppError> {
+    let mut file = tokio::fs::File::open(path).await
+        .map_err(|e| AppError::Internal(format!("Failed to open {}: {}", path.display(), e)))?;
+    let metadata = file.metadata().await
+        .map_err(|e| AppError::Internal(format!("Failed to stat {}: {}", path.display(), e)))?;
+    let len = metadata.len();
+    let modified = metadata.modified().ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs());
+    let etag = format!("\"{:x}-{:x}\"", len, modified);
+
+    let range = match if_range {
+        Some(tag) if tag.trim() != etag => None,
+        _ => range,
+    };
+
+    // Keep the header well-formed whatever the file is called
+    let safe_name: String = file_name.chars()
+        .map(|c| if c == '"' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect();
+    let builder = warp::http::Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", safe_name))
+        .header("Accept-Ranges", "bytes")
+        .header("ETag", etag);
+
+    let response = match parse_range(range.as_deref(), len) {
+        ByteRange::Full => builder
+            .header("Content-Length", len)
+            .body(Body::wrap_stream(tokio_util::io::ReaderStream::new(file))),
+        ByteRange::Partial(start, end) => {
+            file.seek(SeekFrom::Start(start)).await
+                .map_err(|e| AppError::Internal(format!("Failed to seek {}: {}", path.display(), e)))?;
+            let count = end - start + 1;
+            builder
+                .status(warp::http::StatusCode::PARTIAL_CONTENT)
+                .header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+                .header("Content-Length", count)
+                .body(Body::wrap_stream(tokio_util::io::ReaderStream::new(file.take(count))))
+        }
+        ByteRange::Unsatisfiable => builder
+            .status(warp::http::StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("Content-Range", format!("bytes */{}", len))
+            .body(Body::empty()),
+    };
+
+    response.map_err(|e| AppError::Internal(e.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CloneRequest {
+    pub name: String,
+    // An overlay on the source's disk instead of a full copy
+    #[serde(default)]
+    pub linked: bool,
+}
+
+pub async fn clone_vm(
+    vm_id: String,
+    body: CloneRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let vm = vm_manager.clone_vm(&vm_id, &body.name, body.linked).await?;
+    Ok(warp::reply::json(&vm))
+}
+
+pub async fn list_templates(
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let templates = vm_manager.list_templates().await;
+    Ok(warp::reply::json(&templates))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FromTemplateRequest {
+    pub name: String,
+}
+
+pub async fn create_from_template(
+    template_id: String,
+    body: FromTemplateRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let vm = vm_manager.create_from_template(&template_id, &body.name).await?;
+    Ok(warp::reply::json(&vm))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportRequest {
+    pub bundle_path: std::path::PathBuf,
+}
+
+pub async fn import_vm(
+    body: ImportRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let vm = vm_manager.import_vm(&body.bundle_path).await?;
+    Ok(warp::reply::json(&vm))
+}
+
+pub async fn list_base_images(
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let images = vm_manager.list_base_images().await?;
+    Ok(warp::reply::json(&images))
+}
+
+pub async fn delete_base_image(
+    name: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.delete_base_image(&name).await?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("Base image {} deleted", name)
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BeginUploadRequest {
+    pub name: String,
+    // Total bytes, if known; checked against free space up front
+    pub size: Option<u64>,
+}
+
+pub async fn begin_upload(
+    body: BeginUploadRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let session = vm_manager.begin_upload(&body.name, body.size)?;
+    Ok(warp::reply::with_status(warp::reply::json(&session), warp::http::StatusCode::CREATED))
+}
+
+// Where an upload stands, so a client can resume from `received`
+pub async fn upload_status(
+    upload_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let session = vm_manager.upload_status(&upload_id)?;
+    Ok(warp::reply::json(&session))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChunkQuery {
+    pub offset: u64,
+}
+
+pub async fn upload_chunk(
+    upload_id: String,
+    query: ChunkQuery,
+    vm_manager: Arc<VMManager>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> Result<impl Reply, Rejection> {
+    let session = vm_manager.append_upload(&upload_id, query.offset, Box::pin(body)).await?;
+    Ok(warp::reply::json(&session))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompleteUploadQuery {
+    // blake3 the finished ISO has to match
+    pub hash: Option<String>,
+}
+
+pub async fn complete_upload(
+    upload_id: String,
+    query: CompleteUploadQuery,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let info = vm_manager.complete_upload(&upload_id, query.hash.as_deref()).await?;
+    Ok(warp::reply::with_status(warp::reply::json(&info), warp::http::StatusCode::CREATED))
+}
+
+pub async fn abort_upload(
+    upload_id: String,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    vm_manager.abort_upload(&upload_id)?;
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "message": format!("Upload {} aborted", upload_id)
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FetchIsoRequest {
+    pub url: String,
+    pub name: Option<String>,
+    // blake3, checked once the download completes
+    pub hash: Option<String>,
+}
+
+pub async fn fetch_iso(
+    body: FetchIsoRequest,
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let info = vm_manager.fetch_iso(&body.url, body.name.as_deref(), body.hash.as_deref()).await?;
+    Ok(warp::reply::with_status(warp::reply::json(&info), warp::http::StatusCode::CREATED))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadQuery {
+    // File name to store the ISO under; the body is the raw image
+    pub name: Option<String>,
+}
+
+pub async fn upload_iso(
+    query: UploadQuery,
+    vm_manager: Arc<VMManager>,
+    content_length: Option<u64>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> Result<impl Reply, Rejection> {
+    let name = query.name
+        .ok_or_else(|| AppError::BadRequest("Missing ?name= for the uploaded ISO".to_string()))?;
+    let info = vm_manager.upload_iso(&name, content_length, Box::pin(body)).await?;
+    Ok(warp::reply::with_status(warp::reply::json(&info), warp::http::StatusCode::CREATED))
+}
+
+pub async fn vm_schema() -> Result<impl Reply, Rejection> {
+    let disk_format = DiskFormat::default();
+    let arch = GuestArch::default();
+    
+    Ok(warp::reply::json(&json!({
+        "memory_mb": { "min": MIN_MEMORY_MB, "max": MAX_MEMORY_MB },
+        "cpu_cores": { "min": MIN_CPU_CORES, "max": MAX_CPU_CORES },
+        "disk_size_gb": { "min": MIN_DISK_GB, "max": MAX_DISK_GB },
+        "network_type": NetworkType::VARIANTS,
+        "nic_model": NicModel::VARIANTS,
+        "max_nics": MAX_NICS,
+        "disk_format": DiskFormat::VARIANTS,
+        "bios": BiosType::VARIANTS,
+        "arch": GuestArch::VARIANTS,
+        "accel": Accelerator::VARIANTS,
+        "extra_args": { "reserved": MANAGED_FLAGS },
+        "defaults": {
+            "disk_format": disk_format,
+            "discard": disk_format.discard_default(),
+            // For x86_64; other archs default to `virt` and, unless native, cpu `max`
+            "arch": arch,
+            "accel": Accelerator::default(),
+            "machine_type": arch.default_machine(),
+            "cpu_type": arch.default_cpu(Accelerator::default()),
+            "bios": arch.default_bios(),
+            "extra_args": Vec::<String>::new(),
+        }
+    })))
+}
+
+// Liveness only: answers as long as the process is serving requests
+pub async fn health_check() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&json!({
+        "status": "ok",
+        "timestamp": chrono::Utc::now().to_rfc3339()
+    })))
+}
+
+pub async fn system_capacity(
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let capacity = vm_manager.capacity().await?;
+    Ok(warp::reply::json(&capacity))
+}
+
+pub async fn network_overview(
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let overview = vm_manager.network_overview().await?;
+    Ok(warp::reply::json(&overview))
+}
+
+// What the backend can do on this host, and why anything is missing
+pub async fn system_info(
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&json!({
+        "privileged": vm_manager.privileged(),
+        "privileges": privileges(),
+        "qemu_version": qemu_caps().map(|caps| caps.version_string()),
+    })))
+}
+
+// Version and feature probe of the installed QEMU, as used to gate options
+pub async fn qemu_capabilities() -> Result<impl Reply, Rejection> {
+    let caps = qemu_caps()
+        .ok_or_else(|| AppError::Internal(format!("{} could not be probed", GuestArch::default().binary())))?;
+    Ok(warp::reply::json(caps))
+}
+
+// KVM, nested virtualization and installed guest archs, so clients can
+// offer only what will actually run
+pub async fn host_capabilities() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&qemu::host_capabilities()))
+}
+
+// Readiness: 503 with the failing checks until the host can run VMs
+pub async fn readiness_check(
+    vm_manager: Arc<VMManager>
+) -> Result<impl Reply, Rejection> {
+    let checks = vm_manager.readiness().await;
+    let ready = checks.iter().all(|check| check.ok);
+
+    let status = if ready {
+        warp::http::StatusCode::OK
+    } else {
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "checks": checks,
+            "timestamp": chrono::Utc::now().to_rfc3339()
+        })),
+        status,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::api::routes::setup_routes;
+    use crate::utils::logging::Logger;
+
+    #[tokio::test]
+    async fn iso_listing_keeps_readable_entries_and_warns_about_the_rest() {
+        let data_dir = std::env::temp_dir().join(format!("aegis-list-isos-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        let logger = Logger::new(&data_dir.join("logs").to_string_lossy(), LogLevel::Debug).unwrap();
+        let manager = Arc::new(VMManager::new(&data_dir, Arc::new(logger)).unwrap());
+        let isos = data_dir.join("isos");
+        fs::write(isos.join("good.iso"), b"installer").unwrap();
+        fs::write(isos.join("bad.iso"), b"installer").unwrap();
+        fs::write(isos.join("bad.iso.json"), b"{ not json").unwrap();
+
+        let response = warp::test::request()
+            .path("/api/isos")
+            .reply(&setup_routes(manager, AuthConfig::default()))
+            .await;
+        assert_eq!(response.status(), 200);
+        let listing: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let names: Vec<&str> = listing["isos"].as_array().unwrap().iter()
+            .map(|iso| iso["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["good.iso"]);
+        let warnings = listing["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].as_str().unwrap().contains("bad.iso"));
+
+        let _ = fs::remove_dir_all(&data_dir);
+    }
 }
\ No newline at end of file
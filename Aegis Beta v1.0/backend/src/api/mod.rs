@@ -1,7 +1,4 @@
-pub mod handlers;
-pub mod routes;
-pub mod websocket;
-
-pub use handlers::*;
-pub use routes::*;
-pub use websocket::*;
\ No newline at end of file
+pub mod auth;
+pub mod handlers;
+pub mod routes;
+pub mod websocket;
\ No newline at end of file
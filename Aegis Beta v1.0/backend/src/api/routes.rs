@@ -1,99 +1,703 @@
-use std::sync::Arc;
-use warp::Filter;
-
-use crate::vm::manager::VMManager;
-use super::handlers;
-
-pub fn setup_routes(vm_manager: Arc<VMManager>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    let vm_manager_filter = warp::any().map(move || vm_manager.clone());
-
-    // API routes
-    let api = warp::path("api");
-
-    // Health check
-    let health = api
-        .and(warp::path("health"))
-        .and(warp::get())
-        .and_then(handlers::health_check);
-
-    // VM management
-    let list_vms = api
-        .and(warp::path("vms"))
-        .and(warp::get())
-        .and(vm_manager_filter.clone())
-        .and_then(handlers::list_vms);
-
-    let get_vm = api
-        .and(warp::path("vms"))
-        .and(warp::path::param())
-        .and(warp::get())
-        .and(vm_manager_filter.clone())
-        .and_then(handlers::get_vm);
-
-    let create_vm = api
-        .and(warp::path("vms"))
-        .and(warp::post())
-        .and(warp::body::json())
-        .and(vm_manager_filter.clone())
-        .and_then(handlers::create_vm);
-
-    let start_vm = api
-        .and(warp::path("vms"))
-        .and(warp::path::param())
-        .and(warp::path("start"))
-        .and(warp::post())
-        .and(vm_manager_filter.clone())
-        .and_then(handlers::start_vm);
-
-    let stop_vm = api
-        .and(warp::path("vms"))
-        .and(warp::path::param())
-        .and(warp::path("stop"))
-        .and(warp::post())
-        .and(vm_manager_filter.clone())
-        .and_then(handlers::stop_vm);
-
-    let delete_vm = api
-        .and(warp::path("vms"))
-        .and(warp::path::param())
-        .and(warp::delete())
-        .and(vm_manager_filter.clone())
-        .and_then(handlers::delete_vm);
-
-    let get_vnc = api
-        .and(warp::path("vms"))
-        .and(warp::path::param())
-        .and(warp::path("vnc"))
-        .and(warp::get())
-        .and(vm_manager_filter.clone())
-        .and_then(handlers::get_vnc_url);
-
-    // ISO management
-    let upload_iso = api
-        .and(warp::path("isos"))
-        .and(warp::path("upload"))
-        .and(warp::post())
-        .and(vm_manager_filter.clone())
-        .and(warp::body::bytes())
-        .and_then(handlers::upload_iso);
-
-    // Static files
-    let static_files = warp::fs::dir("./frontend");
-
-    // Combine all routes
-    health
-        .or(list_vms)
-        .or(get_vm)
-        .or(create_vm)
-        .or(start_vm)
-        .or(stop_vm)
-        .or(delete_vm)
-        .or(get_vnc)
-        .or(upload_iso)
-        .or(static_files)
-        .with(warp::cors()
-            .allow_any_origin()
-            .allow_methods(vec!["GET", "POST", "DELETE"])
-            .allow_headers(vec!["Content-Type"]))
-        .with(warp::log("vm_manager"))
+use std::sync::Arc;
+use warp::Filter;
+
+use crate::error::handle_rejection;
+use crate::vm::manager::VMManager;
+use super::auth::{require_admin, require_scope, AuthConfig, Scope};
+use super::handlers;
+
+pub fn setup_routes(vm_manager: Arc<VMManager>, auth: AuthConfig) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let vm_manager_filter = warp::any().map(move || vm_manager.clone());
+    let auth = Arc::new(auth);
+    let auth_filter = {
+        let auth = auth.clone();
+        warp::any().map(move || auth.clone())
+    };
+
+    // API routes
+    let api = warp::path("api");
+
+    // Health check
+    let health = api
+        .and(warp::path("health"))
+        .and(warp::get())
+        .and_then(handlers::health_check);
+
+    let ready = api
+        .and(warp::path("ready"))
+        .and(warp::get())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::readiness_check);
+
+    let capacity = api
+        .and(warp::path("system"))
+        .and(warp::path("capacity"))
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::system_capacity);
+
+    let network_overview = api
+        .and(warp::path("network"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::network_overview);
+
+    let system_info = api
+        .and(warp::path("system"))
+        .and(warp::path("info"))
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::system_info);
+
+    let qemu_capabilities = api
+        .and(warp::path("system"))
+        .and(warp::path("qemu"))
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and_then(handlers::qemu_capabilities);
+
+    let host_capabilities = api
+        .and(warp::path("host"))
+        .and(warp::path("capabilities"))
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and_then(handlers::host_capabilities);
+
+    // Schema for building VM forms client-side
+    let vm_schema = api
+        .and(warp::path("schema"))
+        .and(warp::path("vm"))
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and_then(handlers::vm_schema);
+
+    // VM management
+    let list_vms = api
+        .and(warp::path("vms"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::list_vms);
+
+    let get_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::get_vm);
+
+    let create_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::query::<handlers::CreateQuery>())
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::create_vm);
+
+    let get_operation = api
+        .and(warp::path("operations"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::get_operation);
+
+    let import_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path("import"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::import_vm);
+
+    let clone_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("clone"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::clone_vm);
+
+    let list_templates = api
+        .and(warp::path("templates"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::list_templates);
+
+    let create_from_template = api
+        .and(warp::path("vms"))
+        .and(warp::path("from-template"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::create_from_template);
+
+    let start_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("start"))
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::start_vm);
+
+    let stop_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("stop"))
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::stop_vm);
+
+    let reset_vm_state = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("reset-state"))
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::reset_vm_state);
+
+    let pause_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("pause"))
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::pause_vm);
+
+    let reboot_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("reboot"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::query::<handlers::RebootQuery>())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::reboot_vm);
+
+    let suspend_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("suspend"))
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::suspend_vm);
+
+    let resume_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("resume"))
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::resume_vm);
+
+    let update_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::patch())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::query::<handlers::UpdateQuery>())
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::update_vm);
+
+    let delete_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::query::<handlers::DeleteQuery>())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::delete_vm);
+
+    let get_vnc = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("vnc"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and(auth_filter.clone())
+        .and_then(handlers::get_vnc_url);
+
+    // Authenticated by the console token in the query, not require_scope
+    let vnc_websocket = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("vnc"))
+        .and(warp::path("ws"))
+        .and(warp::path::end())
+        .and(warp::query::<handlers::VncQuery>())
+        .and(warp::ws())
+        .and(vm_manager_filter.clone())
+        .and(auth_filter.clone())
+        .and_then(handlers::vnc_websocket);
+
+    let metrics_history = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("metrics"))
+        .and(warp::path("history"))
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::metrics_history);
+
+    let get_console_socket = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("console"))
+        .and(warp::path("socket"))
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::get_console_socket);
+
+    let get_log_level = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("log-level"))
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::get_log_level);
+
+    let set_log_level = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("log-level"))
+        .and(warp::put())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::set_log_level);
+
+    let change_iso = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("iso"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::change_iso);
+
+    let attach_disk = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disks"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::attach_disk);
+
+    let detach_disk = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disks"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::query::<handlers::DetachDiskQuery>())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::detach_disk);
+
+    let attach_nic = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("nics"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::attach_nic);
+
+    let detach_nic = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("nics"))
+        .and(warp::path::param())
+        .and(warp::delete())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::detach_nic);
+
+    let list_disk_snapshots = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disk"))
+        .and(warp::path("snapshots"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::list_disk_snapshots);
+
+    let create_disk_snapshot = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disk"))
+        .and(warp::path("snapshots"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::create_disk_snapshot);
+
+    let restore_disk_snapshot = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disk"))
+        .and(warp::path("snapshots"))
+        .and(warp::path::param())
+        .and(warp::path("restore"))
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::restore_disk_snapshot);
+
+    let delete_disk_snapshot = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disk"))
+        .and(warp::path("snapshots"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::delete_disk_snapshot);
+
+    let convert_disk = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disk"))
+        .and(warp::path("convert"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::convert_disk);
+
+    let flatten_disk = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disk"))
+        .and(warp::path("flatten"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::flatten_disk);
+
+    let resize_disk = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disk"))
+        .and(warp::path("resize"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::resize_disk);
+
+    let compact_disk = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disk"))
+        .and(warp::path("compact"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::compact_disk);
+
+    let commit_disk = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("disk"))
+        .and(warp::path("commit"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::commit_disk);
+
+    // Raw QMP access for operators; admin token only
+    let qmp_passthrough = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("qmp"))
+        .and(warp::post())
+        .and(require_admin(auth.clone()))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::qmp_passthrough);
+
+    // GET, but hands out the whole disk image
+    let export_vm = api
+        .and(warp::path("vms"))
+        .and(warp::path::param())
+        .and(warp::path("export"))
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::query::<handlers::ExportQuery>())
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-range"))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::export_vm);
+
+    // Golden images VMs can be cloned from
+    let list_base_images = api
+        .and(warp::path("bases"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::list_base_images);
+
+    let delete_base_image = api
+        .and(warp::path("bases"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::delete_base_image);
+
+    // ISO management
+    let list_isos = api
+        .and(warp::path("isos"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::list_isos);
+
+    let delete_iso = api
+        .and(warp::path("isos"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::delete_iso);
+
+    let list_disks = api
+        .and(warp::path("disks"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::list_disks);
+
+    let download_iso = api
+        .and(warp::path("isos"))
+        .and(warp::path::param())
+        .and(warp::path("download"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Read))
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-range"))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::download_iso);
+
+    let fetch_iso = api
+        .and(warp::path("isos"))
+        .and(warp::path("fetch"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::fetch_iso);
+
+    // Chunked uploads: init, then PUT chunks at the current offset, then
+    // complete; GET reports the offset to resume from
+    let begin_upload = api
+        .and(warp::path("isos"))
+        .and(warp::path("upload"))
+        .and(warp::path("init"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::begin_upload);
+
+    let upload_status = api
+        .and(warp::path("isos"))
+        .and(warp::path("upload"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::upload_status);
+
+    let upload_chunk = api
+        .and(warp::path("isos"))
+        .and(warp::path("upload"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::query::<handlers::ChunkQuery>())
+        .and(vm_manager_filter.clone())
+        .and(warp::body::stream())
+        .and_then(handlers::upload_chunk);
+
+    let complete_upload = api
+        .and(warp::path("isos"))
+        .and(warp::path("upload"))
+        .and(warp::path::param())
+        .and(warp::path("complete"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::query::<handlers::CompleteUploadQuery>())
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::complete_upload);
+
+    let abort_upload = api
+        .and(warp::path("isos"))
+        .and(warp::path("upload"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(vm_manager_filter.clone())
+        .and_then(handlers::abort_upload);
+
+    let upload_iso = api
+        .and(warp::path("isos"))
+        .and(warp::path("upload"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(require_scope(auth.clone(), Scope::Admin))
+        .and(warp::query::<handlers::UploadQuery>())
+        .and(vm_manager_filter.clone())
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::body::stream())
+        .and_then(handlers::upload_iso);
+
+    // Static files
+    let static_files = warp::fs::dir("./frontend");
+
+    // Combine all routes. Each group is boxed: one flat chain of this many
+    // filters overflows the trait solver once it is served.
+    let system = health
+        .or(ready)
+        .or(capacity)
+        .or(system_info)
+        .or(network_overview)
+        .or(qemu_capabilities)
+        .or(host_capabilities)
+        .or(vm_schema)
+        .boxed();
+    let lifecycle = list_vms
+        .or(get_vm)
+        .or(create_vm)
+        .or(get_operation)
+        .or(import_vm)
+        .or(clone_vm)
+        .or(list_templates)
+        .or(create_from_template)
+        .or(start_vm)
+        .or(stop_vm)
+        .or(reset_vm_state)
+        .or(pause_vm)
+        .or(reboot_vm)
+        .or(suspend_vm)
+        .or(resume_vm)
+        .or(update_vm)
+        .or(delete_vm)
+        .or(get_vnc)
+        .or(vnc_websocket)
+        .or(get_console_socket)
+        .boxed();
+    let devices = metrics_history
+        .or(get_log_level)
+        .or(set_log_level)
+        .or(attach_nic)
+        .or(detach_nic)
+        .or(change_iso)
+        .or(attach_disk)
+        .or(detach_disk)
+        .or(list_disk_snapshots)
+        .or(create_disk_snapshot)
+        .or(restore_disk_snapshot)
+        .or(delete_disk_snapshot)
+        .or(convert_disk)
+        .or(flatten_disk)
+        .or(resize_disk)
+        .or(compact_disk)
+        .or(commit_disk)
+        .or(qmp_passthrough)
+        .boxed();
+    let storage = export_vm
+        .or(list_base_images)
+        .or(delete_base_image)
+        .or(list_isos)
+        .or(delete_iso)
+        .or(list_disks)
+        .or(download_iso)
+        .or(fetch_iso)
+        .or(upload_iso)
+        .or(begin_upload)
+        .or(upload_status)
+        .or(upload_chunk)
+        .or(complete_upload)
+        .or(abort_upload)
+        .boxed();
+
+    system
+        .or(lifecycle)
+        .or(devices)
+        .or(storage)
+        .or(static_files)
+        .recover(handle_rejection)
+        .with(warp::cors()
+            .allow_any_origin()
+            .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
+            .allow_headers(vec!["Content-Type", "Authorization"]))
+        .with(warp::log("vm_manager"))
 }
\ No newline at end of file
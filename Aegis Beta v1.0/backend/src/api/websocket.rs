@@ -1,83 +1,410 @@
-use futures::{StreamExt, SinkExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
-use std::net::SocketAddr;
-use std::sync::Arc;
-
-use crate::vm::manager::VMManager;
-
-pub async fn start_websocket_server(vm_manager: Arc<VMManager>, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(&addr).await?;
-    log::info!("WebSocket server listening on {}", addr);
-
-    while let Ok((stream, _)) = listener.accept().await {
-        let vm_manager_clone = vm_manager.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, vm_manager_clone).await {
-                log::error!("WebSocket error: {}", e);
-            }
-        });
-    }
-
-    Ok(())
-}
-
-async fn handle_connection(stream: TcpStream, vm_manager: Arc<VMManager>) -> Result<(), Box<dyn std::error::Error>> {
-    let ws_stream = accept_async(stream).await?;
-    let (mut write, mut read) = ws_stream.split();
-
-    // Handle incoming messages
-    while let Some(msg) = read.next().await {
-        match msg {
-            Ok(Message::Text(text)) => {
-                // Parse command
-                if let Ok(cmd) = serde_json::from_str::<WebSocketCommand>(&text) {
-                    match cmd {
-                        WebSocketCommand::Subscribe { vm_id } => {
-                            // Subscribe to VM updates
-                            let status = vm_manager.get_vm_status(&vm_id).await;
-                            if let Some(status) = status {
-                                let response = WebSocketResponse::VmStatus { status };
-                                let json = serde_json::to_string(&response).unwrap();
-                                write.send(Message::Text(json)).await?;
-                            }
-                        }
-                        WebSocketCommand::ConsoleInput { vm_id, input } => {
-                            // Send input to VM console
-                            if let Err(e) = vm_manager.send_console_input(&vm_id, &input).await {
-                                let error = WebSocketResponse::Error { message: e.to_string() };
-                                let json = serde_json::to_string(&error).unwrap();
-                                write.send(Message::Text(json)).await?;
-                            }
-                        }
-                    }
-                }
-            }
-            Ok(Message::Close(_)) => {
-                break;
-            }
-            Err(e) => {
-                log::error!("WebSocket error: {}", e);
-                break;
-            }
-            _ => {}
-        }
-    }
-
-    Ok(())
-}
-
-#[derive(serde::Deserialize)]
-#[serde(tag = "type")]
-enum WebSocketCommand {
-    Subscribe { vm_id: String },
-    ConsoleInput { vm_id: String, input: String },
-}
-
-#[derive(serde::Serialize)]
-#[serde(tag = "type")]
-enum WebSocketResponse {
-    VmStatus { status: crate::vm::config::VMStatus },
-    ConsoleOutput { output: String },
-    Error { message: String },
+use futures::{StreamExt, SinkExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::protocol::{Message, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::vm::config::VMState;
+use crate::vm::manager::{ConsoleSession, SerialIo, StatusEvent, VMManager};
+use crate::vm::operations::OperationProgress;
+use super::auth::{AuthConfig, Scope};
+
+// Hard cap enforced by tungstenite while reading frames
+const MAX_WS_MESSAGE_SIZE: usize = 64 * 1024;
+// Commands are small JSON objects; anything bigger is abuse
+const MAX_COMMAND_SIZE: usize = 4 * 1024;
+const MAX_CONSOLE_INPUT: usize = 1024;
+
+// A subscribed VM's serial console. Dropping it stops the reader and
+// closes the socket, freeing the port for the next client.
+struct ConsoleBridge {
+    input: WriteHalf<Box<dyn SerialIo>>,
+    reader: tokio::task::JoinHandle<()>,
+    _session: ConsoleSession,
+}
+
+impl Drop for ConsoleBridge {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+// Forwards everything the guest writes to its serial port as
+// ConsoleOutput frames, until the port closes or the bridge is dropped
+fn bridge_console(
+    stream: Box<dyn SerialIo>,
+    session: ConsoleSession,
+    vm_id: String,
+    tx: tokio::sync::mpsc::UnboundedSender<WebSocketResponse>,
+) -> ConsoleBridge {
+    let (mut output, input) = tokio::io::split(stream);
+    let reader = tokio::spawn(async move {
+        let mut buf = vec![0u8; 4096];
+        let mut pending = Vec::new();
+        loop {
+            match output.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    pending.extend_from_slice(&buf[..n]);
+                    let text = take_utf8(&mut pending);
+                    if !text.is_empty() && tx.send(WebSocketResponse::ConsoleOutput { vm_id: vm_id.clone(), output: text }).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    ConsoleBridge { input, reader, _session: session }
+}
+
+// Takes the longest valid UTF-8 prefix off `pending`, leaving behind a
+// character split across reads; bytes that can never be valid become U+FFFD
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+    let valid = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => return String::from_utf8_lossy(&std::mem::take(pending)).into_owned(),
+    };
+    let rest = pending.split_off(valid);
+    String::from_utf8(std::mem::replace(pending, rest)).unwrap_or_default()
+}
+
+// Pumps RFB bytes between a browser WebSocket and the VM's local VNC port
+// until either side closes
+pub async fn proxy_vnc(socket: warp::ws::WebSocket, vm_id: String, vnc_port: u16) {
+    let vnc = match TcpStream::connect(("127.0.0.1", vnc_port)).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!("VNC proxy for {} could not reach port {}: {}", vm_id, vnc_port, e);
+            return;
+        }
+    };
+    let (mut vnc_read, mut vnc_write) = vnc.into_split();
+    let (mut ws_write, mut ws_read) = socket.split();
+
+    let upstream = async {
+        while let Some(Ok(message)) = ws_read.next().await {
+            if message.is_close() {
+                break;
+            }
+            if message.is_binary() && vnc_write.write_all(message.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let downstream = async {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            match vnc_read.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if ws_write.send(warp::ws::Message::binary(&buf[..n])).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = ws_write.close().await;
+    };
+
+    tokio::select! {
+        _ = upstream => {}
+        _ = downstream => {}
+    }
+    log::debug!("VNC proxy for {} closed", vm_id);
+}
+
+pub async fn start_websocket_server(vm_manager: Arc<VMManager>, auth: Arc<AuthConfig>, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(&addr).await?;
+    log::info!("WebSocket server listening on {}", addr);
+
+    while let Ok((stream, _)) = listener.accept().await {
+        let vm_manager_clone = vm_manager.clone();
+        let auth_clone = auth.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, vm_manager_clone, auth_clone).await {
+                log::error!("WebSocket error: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+// Token from the upgrade request: an Authorization header for non-browser
+// clients, or ?token= since browsers can't set headers on a WebSocket
+fn handshake_token(request: &Request) -> Option<String> {
+    let header = request.headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = header {
+        return Some(token.to_string());
+    }
+
+    request.uri().query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .map(|token| token.to_string())
+}
+
+async fn handle_connection(stream: TcpStream, vm_manager: Arc<VMManager>, auth: Arc<AuthConfig>) -> Result<(), Box<dyn std::error::Error>> {
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_WS_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_WS_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    // Given at the handshake or later with an Authenticate command; each
+    // command is checked against it with the same rules as its REST route
+    let mut token = None;
+    // tungstenite fixes the callback's error type
+    #[allow(clippy::result_large_err)]
+    let ws_stream = accept_hdr_async_with_config(stream, |request: &Request, response: Response| {
+        token = handshake_token(request);
+        Ok(response)
+    }, Some(config)).await?;
+    let (mut write, mut read) = ws_stream.split();
+
+    // Frames from SubscribeOperation tasks and console readers, written out
+    // between commands
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel::<WebSocketResponse>();
+    // Dropped with the connection, which ends every reader task
+    let mut consoles: HashMap<String, ConsoleBridge> = HashMap::new();
+    // VMs whose status changes this connection gets pushed; it unsubscribes
+    // from all of them by closing
+    let mut subscriptions: HashSet<String> = HashSet::new();
+    let mut status_events = vm_manager.subscribe_status();
+    let mut status_open = true;
+
+    // Handle incoming messages
+    loop {
+        let msg = tokio::select! {
+            msg = read.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            Some(response) = progress_rx.recv() => {
+                let json = serde_json::to_string(&response).unwrap();
+                write.send(Message::Text(json)).await?;
+                continue;
+            }
+            event = status_events.recv(), if status_open => {
+                let response = match event {
+                    Ok(StatusEvent::Changed(status)) if subscriptions.contains(&status.id) => {
+                        WebSocketResponse::VmStatus { status: *status }
+                    }
+                    Ok(StatusEvent::Deleted(vm_id)) if subscriptions.remove(&vm_id) => {
+                        consoles.remove(&vm_id);
+                        WebSocketResponse::Error { message: format!("VM {} was deleted", vm_id) }
+                    }
+                    // Missed events are superseded by the next ones
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => {
+                        status_open = false;
+                        continue;
+                    }
+                };
+                let json = serde_json::to_string(&response).unwrap();
+                write.send(Message::Text(json)).await?;
+                continue;
+            }
+        };
+
+        match msg {
+            Ok(Message::Text(text)) if text.len() > MAX_COMMAND_SIZE => {
+                let error = WebSocketResponse::Error {
+                    message: format!("Message too large ({} bytes, max {})", text.len(), MAX_COMMAND_SIZE),
+                };
+                let json = serde_json::to_string(&error).unwrap();
+                write.send(Message::Text(json)).await?;
+                write.send(Message::Close(None)).await?;
+                break;
+            }
+            Ok(Message::Text(text)) => {
+                // Parse command
+                let cmd = match serde_json::from_str::<WebSocketCommand>(&text) {
+                    Ok(cmd) => cmd,
+                    Err(e) => {
+                        let error = WebSocketResponse::Error { message: format!("Invalid command: {}", e) };
+                        let json = serde_json::to_string(&error).unwrap();
+                        write.send(Message::Text(json)).await?;
+                        continue;
+                    }
+                };
+
+                let permitted = match &cmd {
+                    WebSocketCommand::Authenticate { .. } => Ok(()),
+                    WebSocketCommand::StartVm { .. }
+                    | WebSocketCommand::StopVm { .. }
+                    | WebSocketCommand::ConsoleInput { .. } => auth.check_scope(token.as_deref(), Scope::Admin),
+                    _ => auth.check_scope(token.as_deref(), Scope::Read),
+                };
+                if let Err(e) = permitted {
+                    let error = WebSocketResponse::Error { message: e.to_string() };
+                    let json = serde_json::to_string(&error).unwrap();
+                    write.send(Message::Text(json)).await?;
+                    continue;
+                }
+
+                match cmd {
+                    WebSocketCommand::Authenticate { token: presented } => {
+                        let response = match auth.scope_of(&presented) {
+                            Some(scope) => {
+                                token = Some(presented);
+                                WebSocketResponse::Authenticated { scope }
+                            }
+                            None => WebSocketResponse::Error { message: "Invalid API token".to_string() },
+                        };
+                        let json = serde_json::to_string(&response).unwrap();
+                        write.send(Message::Text(json)).await?;
+                    }
+                    WebSocketCommand::ListVms => {
+                        let response = WebSocketResponse::VmList { vms: vm_manager.list_vms().await };
+                        let json = serde_json::to_string(&response).unwrap();
+                        write.send(Message::Text(json)).await?;
+                    }
+                    WebSocketCommand::StartVm { vm_id } => {
+                        let response = match vm_manager.start_vm(&vm_id).await {
+                            Ok(()) => WebSocketResponse::VmStarted { vm_id },
+                            Err(e) => WebSocketResponse::Error { message: e.to_string() },
+                        };
+                        let json = serde_json::to_string(&response).unwrap();
+                        write.send(Message::Text(json)).await?;
+                    }
+                    WebSocketCommand::StopVm { vm_id } => {
+                        let response = match vm_manager.stop_vm(&vm_id).await {
+                            Ok(()) => WebSocketResponse::VmStopped { vm_id },
+                            Err(e) => WebSocketResponse::Error { message: e.to_string() },
+                        };
+                        let json = serde_json::to_string(&response).unwrap();
+                        write.send(Message::Text(json)).await?;
+                    }
+                    WebSocketCommand::Subscribe { vm_id } => {
+                        // Subscribe to VM updates
+                        let status = vm_manager.get_vm_status(&vm_id).await;
+                        let Some(status) = status else { continue };
+                        // Later changes are pushed as they happen
+                        subscriptions.insert(vm_id.clone());
+                        let running = matches!(status.state, VMState::Running | VMState::Paused);
+                        let response = WebSocketResponse::VmStatus { status };
+                        let json = serde_json::to_string(&response).unwrap();
+                        write.send(Message::Text(json)).await?;
+
+                        // And to its serial console, unless already bridged
+                        let bridged = consoles.get(&vm_id).is_some_and(|console| !console.reader.is_finished());
+                        if running && !bridged {
+                            match vm_manager.open_serial(&vm_id).await {
+                                Ok((stream, session)) => {
+                                    let console = bridge_console(stream, session, vm_id.clone(), progress_tx.clone());
+                                    consoles.insert(vm_id, console);
+                                }
+                                Err(e) => log::debug!("No serial console for {}: {}", vm_id, e),
+                            }
+                        }
+                    }
+                    WebSocketCommand::SubscribeOperation { op_id } => {
+                        match vm_manager.operations().subscribe(&op_id) {
+                            Some(mut updates) => {
+                                let tx = progress_tx.clone();
+                                // Sends the current state, then every change until it finishes
+                                tokio::spawn(async move {
+                                    loop {
+                                        let progress = updates.borrow_and_update().clone();
+                                        let finished = progress.is_finished();
+                                        if tx.send(WebSocketResponse::Progress { progress }).is_err() || finished {
+                                            break;
+                                        }
+                                        if updates.changed().await.is_err() {
+                                            break;
+                                        }
+                                    }
+                                });
+                            }
+                            None => {
+                                let error = WebSocketResponse::Error { message: format!("Operation {} not found", op_id) };
+                                let json = serde_json::to_string(&error).unwrap();
+                                write.send(Message::Text(json)).await?;
+                            }
+                        }
+                    }
+                    WebSocketCommand::ConsoleInput { input, .. } if input.len() > MAX_CONSOLE_INPUT => {
+                        let error = WebSocketResponse::Error {
+                            message: format!("Console input too long (max {} bytes)", MAX_CONSOLE_INPUT),
+                        };
+                        let json = serde_json::to_string(&error).unwrap();
+                        write.send(Message::Text(json)).await?;
+                    }
+                    WebSocketCommand::ConsoleInput { vm_id, input } => {
+                        // Goes to the serial console bridged by Subscribe
+                        let result = match consoles.get_mut(&vm_id) {
+                            Some(console) => console.input.write_all(input.as_bytes()).await
+                                .map_err(|e| format!("Console of VM {} closed: {}", vm_id, e)),
+                            None => Err(format!("Subscribe to VM {} before sending console input", vm_id)),
+                        };
+                        if let Err(message) = result {
+                            // A closed bridge is reopened by the next Subscribe
+                            consoles.remove(&vm_id);
+                            let error = WebSocketResponse::Error { message };
+                            let json = serde_json::to_string(&error).unwrap();
+                            write.send(Message::Text(json)).await?;
+                        }
+                    }
+                }
+            }
+            Ok(Message::Close(_)) => {
+                break;
+            }
+            Err(WsError::Capacity(e)) => {
+                // Frame exceeded MAX_WS_MESSAGE_SIZE; tell the client before hanging up
+                let error = WebSocketResponse::Error { message: e.to_string() };
+                let json = serde_json::to_string(&error).unwrap();
+                let _ = write.send(Message::Text(json)).await;
+                let _ = write.send(Message::Close(None)).await;
+                break;
+            }
+            Err(e) => {
+                log::error!("WebSocket error: {}", e);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "type")]
+enum WebSocketCommand {
+    Authenticate { token: String },
+    Subscribe { vm_id: String },
+    SubscribeOperation { op_id: String },
+    ConsoleInput { vm_id: String, input: String },
+    ListVms,
+    StartVm { vm_id: String },
+    StopVm { vm_id: String },
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "type")]
+enum WebSocketResponse {
+    Authenticated { scope: Scope },
+    VmStatus { status: crate::vm::config::VMStatus },
+    VmList { vms: Vec<crate::vm::config::VMStatus> },
+    VmStarted { vm_id: String },
+    VmStopped { vm_id: String },
+    ConsoleOutput { vm_id: String, output: String },
+    Progress {
+        #[serde(flatten)]
+        progress: OperationProgress,
+    },
+    Error { message: String },
 }
\ No newline at end of file
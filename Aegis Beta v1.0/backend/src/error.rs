@@ -0,0 +1,211 @@
+use std::convert::Infallible;
+
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::security::isolation::IsolationError;
+use crate::security::validation::ValidationError;
+use crate::storage::disks::DiskError;
+use crate::storage::isos::IsoError;
+use crate::utils::ports::PortError;
+use crate::vm::networking::NetworkError;
+use crate::vm::qemu::QemuError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    #[error(transparent)]
+    Qemu(#[from] QemuError),
+    #[error(transparent)]
+    Disk(#[from] DiskError),
+    #[error(transparent)]
+    Iso(#[from] IsoError),
+    #[error(transparent)]
+    Network(#[from] NetworkError),
+    #[error(transparent)]
+    Port(#[from] PortError),
+    #[error(transparent)]
+    Isolation(#[from] IsolationError),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    // Refused because the VM is marked protected
+    #[error("{0}")]
+    Protected(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Validation(ValidationError::IsoTooLarge { .. }) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Qemu(e) => match e {
+                QemuError::NotRunning => StatusCode::CONFLICT,
+                QemuError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                QemuError::Qmp(_) => StatusCode::BAD_GATEWAY,
+                QemuError::QmpCommand { .. } => StatusCode::CONFLICT,
+                QemuError::KvmPermission(_) | QemuError::KvmUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                QemuError::Unsupported { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::Disk(e) => match e {
+                DiskError::ValidationError(_) => StatusCode::BAD_REQUEST,
+                DiskError::NotFound(_) => StatusCode::NOT_FOUND,
+                DiskError::AlreadyExists(_) => StatusCode::CONFLICT,
+                DiskError::SnapshotNotFound(_) => StatusCode::NOT_FOUND,
+                DiskError::SnapshotExists(_) => StatusCode::CONFLICT,
+                DiskError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+                DiskError::InUse(_) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::Iso(e) => match e {
+                IsoError::ValidationError(ValidationError::IsoTooLarge { .. }) => StatusCode::PAYLOAD_TOO_LARGE,
+                IsoError::ValidationError(_) => StatusCode::BAD_REQUEST,
+                IsoError::NotFound(_) => StatusCode::NOT_FOUND,
+                IsoError::AlreadyExists(_) => StatusCode::CONFLICT,
+                IsoError::InsufficientSpace { .. } => StatusCode::INSUFFICIENT_STORAGE,
+                IsoError::DownloadFailed(_) => StatusCode::BAD_GATEWAY,
+                IsoError::UploadFailed(_) => StatusCode::BAD_REQUEST,
+                IsoError::UploadNotFound(_) => StatusCode::NOT_FOUND,
+                IsoError::UploadConflict(_) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::Network(e) => match e {
+                NetworkError::InvalidIp(_) | NetworkError::InvalidSubnet(_) => StatusCode::BAD_REQUEST,
+                NetworkError::InvalidDomain(_, _) => StatusCode::BAD_REQUEST,
+                NetworkError::InvalidInterfaceName(_, _) => StatusCode::BAD_REQUEST,
+                NetworkError::BridgeNotFound(_) | NetworkError::TapNotFound(_) => StatusCode::NOT_FOUND,
+                NetworkError::BridgeExists(_) | NetworkError::TapExists(_) => StatusCode::CONFLICT,
+                NetworkError::BridgeInUse(_, _) => StatusCode::CONFLICT,
+                NetworkError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::Port(e) => match e {
+                PortError::InvalidRange(_, _) => StatusCode::BAD_REQUEST,
+                PortError::PortInUse(_) => StatusCode::CONFLICT,
+                PortError::NoPortsAvailable => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::Isolation(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Protected(_) => StatusCode::CONFLICT,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Stable, machine-readable identifier for clients; never change existing values
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "validation_error",
+            AppError::Qemu(e) => match e {
+                QemuError::StartFailed(_) => "qemu_start_failed",
+                QemuError::NotRunning => "vm_not_running",
+                QemuError::IoError(_) => "io_error",
+                QemuError::Timeout => "qemu_timeout",
+                QemuError::Qmp(_) => "qmp_error",
+                QemuError::QmpCommand { .. } => "qmp_command_failed",
+                QemuError::KvmPermission(_) | QemuError::KvmUnavailable(_) => "kvm_unavailable",
+                QemuError::Unsupported { .. } => "qemu_unsupported",
+            },
+            AppError::Disk(e) => match e {
+                DiskError::IoError(_) => "io_error",
+                DiskError::ValidationError(_) => "validation_error",
+                DiskError::QemuError(_) => "qemu_img_failed",
+                DiskError::NotFound(_) => "disk_not_found",
+                DiskError::AlreadyExists(_) => "disk_exists",
+                DiskError::SnapshotNotFound(_) => "snapshot_not_found",
+                DiskError::SnapshotExists(_) => "snapshot_exists",
+                DiskError::Timeout(_) => "command_timeout",
+                DiskError::InUse(_) => "disk_in_use",
+            },
+            AppError::Iso(e) => match e {
+                IsoError::IoError(_) => "io_error",
+                IsoError::ValidationError(_) => "validation_error",
+                IsoError::NotFound(_) => "iso_not_found",
+                IsoError::AlreadyExists(_) => "iso_exists",
+                IsoError::UploadFailed(_) => "upload_failed",
+                IsoError::DownloadFailed(_) => "download_failed",
+                IsoError::UploadNotFound(_) => "upload_not_found",
+                IsoError::UploadConflict(_) => "upload_conflict",
+                IsoError::InsufficientSpace { .. } => "insufficient_space",
+                IsoError::Metadata(_) => "iso_metadata_invalid",
+            },
+            AppError::Network(e) => match e {
+                NetworkError::IoError(_) => "io_error",
+                NetworkError::CommandFailed(_) => "network_command_failed",
+                NetworkError::InvalidIp(_) => "invalid_ip",
+                NetworkError::InvalidSubnet(_) => "invalid_subnet",
+                NetworkError::InvalidDomain(_, _) => "invalid_dns_domain",
+                NetworkError::BridgeExists(_) => "bridge_exists",
+                NetworkError::BridgeNotFound(_) => "bridge_not_found",
+                NetworkError::BridgeInUse(_, _) => "bridge_in_use",
+                NetworkError::TapExists(_) => "tap_exists",
+                NetworkError::TapNotFound(_) => "tap_not_found",
+                NetworkError::InvalidInterfaceName(_, _) => "invalid_interface_name",
+                NetworkError::Timeout(_) => "command_timeout",
+            },
+            AppError::Port(e) => match e {
+                PortError::NoPortsAvailable => "no_ports_available",
+                PortError::PortInUse(_) => "port_in_use",
+                PortError::IoError(_) => "io_error",
+                PortError::InvalidRange(_, _) => "invalid_port_range",
+            },
+            AppError::Isolation(_) => "sandbox_failed",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Protected(_) => "vm_protected",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl warp::reject::Reject for AppError {}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, code, message) = if let Some(e) = err.find::<AppError>() {
+        (e.status(), e.code(), e.to_string())
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "Not found".to_string())
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", "Payload too large".to_string())
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "Method not allowed".to_string())
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", format!("Unhandled rejection: {:?}", err))
+    };
+
+    if status.is_server_error() {
+        log::error!("{}: {}", code, message);
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ErrorBody { code, message }),
+        status,
+    ))
+}
\ No newline at end of file
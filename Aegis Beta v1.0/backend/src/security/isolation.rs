@@ -1,140 +1,909 @@
-use nix::sched::{unshare, CloneFlags};
-use nix::unistd::{setgid, setuid, Gid, Uid};
-use std::fs;
-use std::io;
-use std::path::Path;
-
-#[derive(Debug, thiserror::Error)]
-pub enum IsolationError {
-    #[error("Failed to unshare namespaces: {0}")]
-    UnshareFailed(#[from] nix::Error),
-    #[error("IO error: {0}")]
-    IoError(#[from] io::Error),
-    #[error("Permission denied")]
-    PermissionDenied,
-}
-
-pub struct VMSandbox {
-    pub uid: Option<Uid>,
-    pub gid: Option<Gid>,
-    pub isolate_network: bool,
-    pub isolate_pid: bool,
-    pub isolate_mount: bool,
-    pub chroot_path: Option<String>,
-}
-
-impl VMSandbox {
-    pub fn new() -> Self {
-        Self {
-            uid: None,
-            gid: None,
-            isolate_network: true,
-            isolate_pid: true,
-            isolate_mount: true,
-            chroot_path: None,
-        }
-    }
-
-    pub fn with_user(mut self, uid: Uid, gid: Gid) -> Self {
-        self.uid = Some(uid);
-        self.gid = Some(gid);
-        self
-    }
-
-    pub fn with_chroot(mut self, path: &str) -> Self {
-        self.chroot_path = Some(path.to_string());
-        self
-    }
-
-    pub fn apply(&self) -> Result<(), IsolationError> {
-        // Drop privileges if specified
-        if let Some(gid) = self.gid {
-            setgid(gid)?;
-        }
-        if let Some(uid) = self.uid {
-            setuid(uid)?;
-        }
-
-        // Unshare namespaces
-        let mut flags = CloneFlags::empty();
-        
-        if self.isolate_pid {
-            flags.insert(CloneFlags::CLONE_NEWPID);
-        }
-        
-        if self.isolate_mount {
-            flags.insert(CloneFlags::CLONE_NEWNS);
-        }
-        
-        if self.isolate_network {
-            flags.insert(CloneFlags::CLONE_NEWNET);
-        }
-        
-        if !flags.is_empty() {
-            unshare(flags)?;
-        }
-
-        // Apply chroot if specified
-        if let Some(chroot_path) = &self.chroot_path {
-            self.apply_chroot(chroot_path)?;
-        }
-
-        Ok(())
-    }
-
-    fn apply_chroot(&self, path: &str) -> Result<(), IsolationError> {
-        let path = Path::new(path);
-        
-        // Verify path exists and is a directory
-        if !path.exists() || !path.is_dir() {
-            return Err(IsolationError::IoError(io::Error::new(
-                io::ErrorKind::NotFound,
-                "Chroot path not found",
-            )));
-        }
-
-        // Change root directory
-        nix::unistd::chroot(path)?;
-        
-        // Change to root directory inside chroot
-        nix::unistd::chdir("/")?;
-
-        Ok(())
-    }
-
-    pub fn create_vm_directory(vm_id: &str, base_path: &Path) -> Result<(), IsolationError> {
-        let vm_path = base_path.join(vm_id);
-        
-        // Create VM directory
-        fs::create_dir_all(&vm_path)?;
-        
-        // Create necessary subdirectories
-        fs::create_dir_all(vm_path.join("root"))?;
-        fs::create_dir_all(vm_path.join("tmp"))?;
-        fs::create_dir_all(vm_path.join("dev"))?;
-        fs::create_dir_all(vm_path.join("proc"))?;
-        
-        // Set permissions (read-only for others)
-        let mut perms = fs::metadata(&vm_path)?.permissions();
-        perms.set_readonly(false);
-        fs::set_permissions(&vm_path, perms)?;
-
-        Ok(())
-    }
-
-    pub fn setup_network_isolation(vm_id: &str) -> Result<(), IsolationError> {
-        // Create network namespace for VM
-        let output = std::process::Command::new("ip")
-            .args(&["netns", "add", vm_id])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(IsolationError::IoError(io::Error::new(
-                io::ErrorKind::Other,
-                String::from_utf8_lossy(&output.stderr),
-            )));
-        }
-
-        Ok(())
-    }
+use caps::{CapSet, Capability, CapsHashSet};
+use nix::sys::signal::{kill, sigprocmask, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, geteuid, ForkResult, Gid, Pid, Uid};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::utils::command::{CommandCategory, CommandTimeoutExt};
+use super::sandbox::SeccompFilter;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IsolationError {
+    #[error("Failed to unshare namespaces: {0}")]
+    UnshareFailed(#[from] nix::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Failed to drop capabilities: {0}")]
+    CapabilityDrop(String),
+    #[error("User namespace setup failed: {0}")]
+    UserNamespace(String),
+    #[error("Could not provision chroot: {}", .0.join(", "))]
+    ChrootProvision(Vec<String>),
+    #[error("Seccomp filter failed: {0}")]
+    Seccomp(String),
+    #[error("Bind mount failed: {0}")]
+    Mount(String),
+    #[error("cgroup setup failed: {0}")]
+    Cgroup(String),
+}
+
+// Firmware QEMU loads by path at runtime. The first is required; the rest
+// are used when present (OVMF for UEFI guests, Debian's seabios/ipxe).
+const FIRMWARE_DIRS: &[(&str, bool)] = &[
+    ("/usr/share/qemu", true),
+    ("/usr/share/seabios", false),
+    ("/usr/lib/ipxe/qemu", false),
+    ("/usr/share/OVMF", false),
+];
+
+// Host id range mapped onto 0..count inside the sandbox's user namespace
+#[derive(Debug, Clone)]
+pub struct IdMapping {
+    pub host_uid: u32,
+    pub host_gid: u32,
+    pub count: u32,
+}
+
+impl IdMapping {
+    // First /etc/subuid and /etc/subgid range delegated to `user`
+    pub fn from_subids(user: &str) -> Result<Self, IsolationError> {
+        let (host_uid, uid_count) = read_subid_range("/etc/subuid", user)?;
+        let (host_gid, gid_count) = read_subid_range("/etc/subgid", user)?;
+
+        Ok(Self {
+            host_uid,
+            host_gid,
+            count: uid_count.min(gid_count),
+        })
+    }
+}
+
+fn read_subid_range(path: &str, user: &str) -> Result<(u32, u32), IsolationError> {
+    let contents = fs::read_to_string(path)?;
+
+    // "name:start:count"
+    contents.lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().split(':');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(start), Some(count)) if name == user => {
+                    Some((start.parse().ok()?, count.parse().ok()?))
+                }
+                _ => None,
+            }
+        })
+        .next()
+        .ok_or_else(|| IsolationError::UserNamespace(format!("No range for {} in {}", user, path)))
+}
+
+// A host path made visible at the same path under the sandbox's root
+#[derive(Debug, Clone)]
+pub struct BindMount {
+    pub source: PathBuf,
+    pub readonly: bool,
+}
+
+pub struct VMSandbox {
+    pub uid: Option<Uid>,
+    pub gid: Option<Gid>,
+    pub isolate_network: bool,
+    pub isolate_pid: bool,
+    pub isolate_mount: bool,
+    pub chroot_path: Option<String>,
+    // With a user namespace, uid/gid above are ids inside it
+    pub user_namespace: Option<IdMapping>,
+    // Everything else is dropped from every capability set, including the
+    // bounding set, once the privileged setup in apply() is done
+    pub keep_capabilities: CapsHashSet,
+    // Loaded last, right before execve
+    pub seccomp: Option<SeccompFilter>,
+    // Mounted inside the new mount namespace, before the chroot; the
+    // mount points have to exist already
+    pub bind_mounts: Vec<BindMount>,
+    // Groups QEMU is moved into, with their limits already set
+    pub cgroups: Vec<PathBuf>,
+}
+
+impl VMSandbox {
+    pub fn new() -> Self {
+        Self {
+            uid: None,
+            gid: None,
+            isolate_network: true,
+            isolate_pid: true,
+            isolate_mount: true,
+            chroot_path: None,
+            user_namespace: None,
+            keep_capabilities: CapsHashSet::new(),
+            seccomp: None,
+            bind_mounts: Vec::new(),
+            cgroups: Vec::new(),
+        }
+    }
+
+    // For an unprivileged backend, where unshare would fail with EPERM
+    pub fn without_namespaces(mut self) -> Self {
+        self.isolate_network = false;
+        self.isolate_pid = false;
+        self.isolate_mount = false;
+        self
+    }
+
+    // QEMU's VNC and hostfwd listeners, and the taps it attaches to, all
+    // live in the host's network namespace
+    pub fn with_host_network(mut self) -> Self {
+        self.isolate_network = false;
+        self
+    }
+
+    pub fn with_user(mut self, uid: Uid, gid: Gid) -> Self {
+        self.uid = Some(uid);
+        self.gid = Some(gid);
+        self
+    }
+
+    pub fn with_chroot(mut self, path: &str) -> Self {
+        self.chroot_path = Some(path.to_string());
+        self
+    }
+
+    pub fn with_user_namespace(mut self, mapping: IdMapping) -> Self {
+        self.user_namespace = Some(mapping);
+        self
+    }
+
+    pub fn with_bind_mount<P: AsRef<Path>>(mut self, source: P, readonly: bool) -> Self {
+        self.bind_mounts.push(BindMount { source: source.as_ref().to_path_buf(), readonly });
+        self
+    }
+
+    // Where `source` shows up: under the chroot if there is one, otherwise
+    // over itself (still read-only, inside the namespace)
+    pub fn bind_target(&self, source: &Path) -> PathBuf {
+        match &self.chroot_path {
+            Some(root) => Path::new(root).join(source.strip_prefix("/").unwrap_or(source)),
+            None => source.to_path_buf(),
+        }
+    }
+
+    pub fn with_seccomp(mut self, filter: SeccompFilter) -> Self {
+        self.seccomp = Some(filter);
+        self
+    }
+
+    // e.g. CAP_NET_ADMIN when QEMU has to open its own tap
+    pub fn keep_capability(mut self, cap: Capability) -> Self {
+        self.keep_capabilities.insert(cap);
+        self
+    }
+
+    // Resolves everything apply() needs while still in the parent: paths
+    // become C strings, capability sets become masks and the mount list is
+    // fixed here. Between fork and execve only raw syscalls on memory
+    // allocated beforehand are safe, so that's all PreparedSandbox::apply does.
+    pub fn prepare(self) -> Result<PreparedSandbox, IsolationError> {
+        let cstring = |path: &Path| CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| IsolationError::IoError(io::Error::new(io::ErrorKind::InvalidInput, e)));
+
+        let cgroup_procs = self.cgroups.iter()
+            .map(|group| cstring(&group.join("cgroup.procs")).map_err(|e| {
+                IsolationError::Cgroup(format!("joining {}: {}", group.display(), e))
+            }))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let user_namespace = self.user_namespace.as_ref().map(UserNamespaceMaps::new).transpose()?;
+
+        let mut unshare_flags = 0;
+        if self.isolate_pid {
+            unshare_flags |= libc::CLONE_NEWPID;
+        }
+        if self.isolate_mount {
+            unshare_flags |= libc::CLONE_NEWNS;
+        }
+        if self.isolate_network {
+            unshare_flags |= libc::CLONE_NEWNET;
+        }
+
+        // In the host's namespace they'd outlive QEMU and pile up with
+        // every start
+        if !self.bind_mounts.is_empty() && !self.isolate_mount {
+            return Err(IsolationError::Mount("bind mounts need a mount namespace".to_string()));
+        }
+        let mut mounts = Vec::with_capacity(self.bind_mounts.len());
+        let mut mounted = Vec::with_capacity(self.bind_mounts.len());
+        for bind in &self.bind_mounts {
+            let target = self.bind_target(&bind.source);
+            mounts.push(PreparedMount {
+                source: cstring(&bind.source)?,
+                target: cstring(&target)?,
+                readonly: bind.readonly,
+            });
+            mounted.push(target);
+        }
+
+        let chroot = match &self.chroot_path {
+            Some(path) if !Path::new(path).is_dir() => {
+                return Err(IsolationError::IoError(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Chroot path {} not found", path),
+                )));
+            }
+            Some(path) => Some(cstring(Path::new(path))?),
+            None => None,
+        };
+
+        // The child inherits this process's bounding set
+        let bounding = caps::read(None, CapSet::Bounding).map_err(cap_error)?;
+        let drop_bounding = bounding.difference(&self.keep_capabilities)
+            .map(|cap| cap.index() as libc::c_ulong)
+            .collect();
+        let keep_ambient: Vec<libc::c_ulong> = self.keep_capabilities.iter()
+            .map(|cap| cap.index() as libc::c_ulong)
+            .collect();
+        let keep_mask = self.keep_capabilities.iter().fold(0u64, |mask, cap| mask | cap.bitmask());
+
+        Ok(PreparedSandbox {
+            cgroup_procs,
+            user_namespace,
+            unshare_flags,
+            isolate_pid: self.isolate_pid,
+            mounts,
+            mounted,
+            chroot,
+            drop_bounding,
+            keep_mask,
+            keep_ambient,
+            uid: self.uid.map(Uid::as_raw),
+            gid: self.gid.map(Gid::as_raw),
+            seccomp: self.seccomp,
+        })
+    }
+
+    pub fn create_vm_directory(vm_id: &str, base_path: &Path) -> Result<(), IsolationError> {
+        let vm_path = base_path.join(vm_id);
+        
+        // Create VM directory
+        fs::create_dir_all(&vm_path)?;
+        
+        // Create necessary subdirectories
+        fs::create_dir_all(vm_path.join("root"))?;
+        fs::create_dir_all(vm_path.join("tmp"))?;
+        fs::create_dir_all(vm_path.join("dev"))?;
+        fs::create_dir_all(vm_path.join("proc"))?;
+        
+        // Owner-writable, read-only for everyone else
+        fs::set_permissions(&vm_path, fs::Permissions::from_mode(0o755))?;
+
+        Ok(())
+    }
+
+    // Makes `root` able to run `qemu_binary` after chroot: the binary, every
+    // shared library ldd reports (including the loader) and the firmware
+    // directories, each at its host path. Files are hard-linked where the
+    // chroot shares a filesystem with the host copy and copied otherwise;
+    // symlinks are resolved so nothing inside points back out. Device nodes
+    // are the sandbox builder's job. Modules QEMU dlopen()s aren't listed by
+    // ldd and aren't provisioned.
+    pub fn populate_chroot(root: &Path, qemu_binary: &Path) -> Result<(), IsolationError> {
+        let mut missing = Vec::new();
+
+        let mut files = vec![qemu_binary.to_path_buf()];
+        match shared_libraries(qemu_binary) {
+            Ok((found, not_found)) => {
+                files.extend(found);
+                missing.extend(not_found);
+            }
+            Err(e) => missing.push(format!("ldd {}: {}", qemu_binary.display(), e)),
+        }
+
+        for file in &files {
+            if let Err(e) = provision_file(root, file) {
+                missing.push(format!("{}: {}", file.display(), e));
+            }
+        }
+
+        for &(dir, required) in FIRMWARE_DIRS {
+            let dir = Path::new(dir);
+            if !dir.is_dir() {
+                if required {
+                    missing.push(format!("{}: not found", dir.display()));
+                }
+                continue;
+            }
+            provision_dir(root, dir, &mut missing);
+        }
+
+        if !missing.is_empty() {
+            return Err(IsolationError::ChrootProvision(missing));
+        }
+
+        Ok(())
+    }
+}
+
+// (resolved library paths, descriptions of the ones ldd couldn't find).
+// Lines look like "libz.so.1 => /lib/.../libz.so.1 (0x...)", the loader as
+// "/lib64/ld-linux-x86-64.so.2 (0x...)"; the vDSO has no file and is skipped.
+fn shared_libraries(binary: &Path) -> Result<(Vec<PathBuf>, Vec<String>), IsolationError> {
+    let output = std::process::Command::new("ldd")
+        .arg(binary)
+        .output_within(CommandCategory::Service)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(IsolationError::IoError(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        )));
+    }
+
+    let mut found = Vec::new();
+    let mut not_found = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let line = line.trim();
+        let (name, target) = match line.split_once("=>") {
+            Some((name, target)) => (name.trim(), target.trim()),
+            None => (line, line),
+        };
+
+        if target.starts_with("not found") {
+            not_found.push(format!("{}: not found", name));
+            continue;
+        }
+
+        let path = target.split(" (").next().unwrap_or("").trim();
+        if path.starts_with('/') {
+            found.push(PathBuf::from(path));
+        }
+    }
+
+    Ok((found, not_found))
+}
+
+// Places the file behind `host_path` at the same path under `root`
+fn provision_file(root: &Path, host_path: &Path) -> io::Result<()> {
+    let source = fs::canonicalize(host_path)?;
+    let dest = root.join(host_path.strip_prefix("/").unwrap_or(host_path));
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if dest.exists() {
+        return Ok(());
+    }
+
+    // EXDEV and friends: fall back to a real copy
+    if fs::hard_link(&source, &dest).is_err() {
+        fs::copy(&source, &dest)?;
+    }
+
+    Ok(())
+}
+
+fn provision_dir(root: &Path, dir: &Path, missing: &mut Vec<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            missing.push(format!("{}: {}", dir.display(), e));
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Follows symlinks, so a link to a directory is walked like one
+        let result = if path.is_dir() {
+            provision_dir(root, &path, missing);
+            Ok(())
+        } else {
+            provision_file(root, &path)
+        };
+
+        if let Err(e) = result {
+            missing.push(format!("{}: {}", path.display(), e));
+        }
+    }
+}
+
+// Returns only in the process that goes on to exec QEMU. Above it sit two
+// processes that never return:
+//
+//   outer (the pid the manager tracks) -> init (PID 1 in the namespace) -> QEMU
+//
+// The outer process is what signals from the manager reach, so it relays
+// them to init, which relays them to QEMU. Init also reaps anything
+// reparented to it. Each exits with QEMU's status once QEMU is gone.
+fn enter_pid_namespace() -> io::Result<()> {
+    let mut forwarded = SigSet::empty();
+    for signal in FORWARDED_SIGNALS {
+        forwarded.add(*signal);
+    }
+    let mut waited = forwarded;
+    waited.add(Signal::SIGCHLD);
+
+    // Block before forking so a signal arriving in between stays pending for
+    // sigwait instead of being lost; the QEMU child restores the old mask
+    let mut old_mask = SigSet::empty();
+    sigprocmask(SigmaskHow::SIG_BLOCK, Some(&waited), Some(&mut old_mask))?;
+
+    // outer -> init
+    if let ForkResult::Parent { child } = unsafe { fork() }? {
+        supervise(child, &waited);
+    }
+
+    // If the outer supervisor is killed outright (SIGKILL can't be
+    // relayed), take the namespace, and with it QEMU, down too
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0);
+    }
+
+    // init -> QEMU
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => supervise(child, &waited),
+        ForkResult::Child => {
+            sigprocmask(SigmaskHow::SIG_SETMASK, Some(&old_mask), None)?;
+            Ok(())
+        }
+    }
+}
+
+const FORWARDED_SIGNALS: &[Signal] = &[Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP];
+
+// Relays signals to `child` and reaps every child until `child` exits, then
+// exits with its status. Drops the inherited fds first: std's spawn waits
+// for every copy of its exec-status pipe to close, and only the QEMU
+// process closes its copy by exec'ing.
+fn supervise(child: Pid, signals: &SigSet) -> ! {
+    unsafe {
+        // close_range needs Linux 5.9
+        if libc::syscall(libc::SYS_close_range, 3u32, u32::MAX, 0u32) != 0 {
+            for fd in 3..libc::sysconf(libc::_SC_OPEN_MAX).max(1024) as i32 {
+                libc::close(fd);
+            }
+        }
+    }
+
+    loop {
+        match signals.wait() {
+            Ok(Signal::SIGCHLD) => {
+                while let Ok(status) = waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+                    match status {
+                        WaitStatus::Exited(pid, code) if pid == child => unsafe { libc::_exit(code) },
+                        WaitStatus::Signaled(pid, signal, _) if pid == child => unsafe {
+                            libc::_exit(128 + signal as i32)
+                        },
+                        WaitStatus::StillAlive => break,
+                        _ => {}
+                    }
+                }
+            }
+            Ok(signal) => {
+                let _ = kill(child, signal);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+// What enter_user_namespace needs, built before the fork. The pid the maps
+// are for isn't known until then, so the helper formats it into a stack
+// buffer itself.
+struct UserNamespaceMaps {
+    // Root writes the maps directly; anyone else goes through newuidmap/newgidmap
+    privileged: bool,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    host_uid: CString,
+    host_gid: CString,
+    count: CString,
+}
+
+impl UserNamespaceMaps {
+    fn new(mapping: &IdMapping) -> Result<Self, IsolationError> {
+        let cstring = |n: u32| CString::new(n.to_string()).map_err(|e| IsolationError::UserNamespace(e.to_string()));
+        Ok(Self {
+            privileged: geteuid().is_root(),
+            uid_map: format!("0 {} {}\n", mapping.host_uid, mapping.count).into_bytes(),
+            gid_map: format!("0 {} {}\n", mapping.host_gid, mapping.count).into_bytes(),
+            host_uid: cstring(mapping.host_uid)?,
+            host_gid: cstring(mapping.host_gid)?,
+            count: cstring(mapping.count)?,
+        })
+    }
+}
+
+// A process can't map more than its own id from inside the new namespace,
+// so a helper forked beforehand (still in the original namespace) writes the
+// maps: directly when we are root, via the setuid newuidmap/newgidmap tools
+// when rootless. Runs between fork and exec, so only raw syscalls on `maps`
+// and the stack happen here.
+fn enter_user_namespace(maps: &UserNamespaceMaps) -> io::Result<()> {
+    let mut fds = [0; 2];
+    check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let (ready_r, ready_w) = (fds[0], fds[1]);
+
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            unsafe { libc::close(ready_w) };
+
+            // Wait until the parent has unshared; EOF means it gave up
+            let mut buf = [0u8; 1];
+            let ready = unsafe { libc::read(ready_r, buf.as_mut_ptr().cast(), 1) } == 1;
+
+            let mut pid = [0u8; 16];
+            write_decimal(&mut pid, unsafe { libc::getppid() } as u32);
+            let ok = ready && if maps.privileged {
+                let mut uid_map_path = [0u8; 64];
+                let mut gid_map_path = [0u8; 64];
+                write_file_raw(proc_path(&mut uid_map_path, &pid, b"uid_map"), &maps.uid_map)
+                    && write_file_raw(proc_path(&mut gid_map_path, &pid, b"gid_map"), &maps.gid_map)
+            } else {
+                let zero = c"0".as_ptr();
+                let pid = pid.as_ptr().cast();
+                run_and_wait(&[c"newuidmap".as_ptr(), pid, zero, maps.host_uid.as_ptr(), maps.count.as_ptr(), std::ptr::null()])
+                    && run_and_wait(&[c"newgidmap".as_ptr(), pid, zero, maps.host_gid.as_ptr(), maps.count.as_ptr(), std::ptr::null()])
+            };
+
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+        ForkResult::Parent { child } => {
+            unsafe { libc::close(ready_r) };
+
+            let unshared = check(unsafe { libc::unshare(libc::CLONE_NEWUSER) }).and_then(|()| {
+                // Must precede gid_map, or an unprivileged mapping is refused
+                if write_file_raw(c"/proc/self/setgroups".as_ptr(), b"deny") {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            });
+            if unshared.is_ok() {
+                unsafe { libc::write(ready_w, b"1".as_ptr().cast(), 1) };
+            }
+            unsafe { libc::close(ready_w) };
+
+            let status = waitpid(child, None)?;
+            unshared?;
+
+            match status {
+                WaitStatus::Exited(_, 0) => Ok(()),
+                // Usually a missing /etc/subuid or /etc/subgid range
+                _ => Err(io::Error::from_raw_os_error(libc::EPERM)),
+            }
+        }
+    }
+}
+
+// `n` in decimal followed by a NUL, at the start of `buf`
+fn write_decimal(buf: &mut [u8; 16], mut n: u32) {
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    for (dst, src) in buf.iter_mut().zip(digits[..len].iter().rev()) {
+        *dst = *src;
+    }
+    buf[len] = 0;
+}
+
+// "/proc/<pid>/<file>" NUL-terminated in `buf`, from a NUL-terminated pid
+fn proc_path(buf: &mut [u8; 64], pid: &[u8; 16], file: &[u8]) -> *const c_char {
+    let pid = &pid[..pid.iter().position(|b| *b == 0).unwrap_or(0)];
+    let mut len = 0;
+    for part in [b"/proc/".as_slice(), pid, b"/", file] {
+        buf[len..len + part.len()].copy_from_slice(part);
+        len += part.len();
+    }
+    buf[len] = 0;
+    buf.as_ptr().cast()
+}
+
+struct PreparedMount {
+    source: CString,
+    target: CString,
+    readonly: bool,
+}
+
+// A VMSandbox resolved for one spawn; see VMSandbox::prepare
+pub struct PreparedSandbox {
+    // <group>/cgroup.procs for each group
+    cgroup_procs: Vec<CString>,
+    user_namespace: Option<UserNamespaceMaps>,
+    unshare_flags: libc::c_int,
+    isolate_pid: bool,
+    mounts: Vec<PreparedMount>,
+    // Host paths the bind mounts land on, inside QEMU's mount namespace
+    mounted: Vec<PathBuf>,
+    chroot: Option<CString>,
+    // Capability numbers to remove from the bounding set
+    drop_bounding: Vec<libc::c_ulong>,
+    // keep_capabilities as a capset(2) mask, and as numbers for the ambient set
+    keep_mask: u64,
+    keep_ambient: Vec<libc::c_ulong>,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    seccomp: Option<SeccompFilter>,
+}
+
+impl PreparedSandbox {
+    // The bind mount targets. They live in QEMU's private mount namespace
+    // and go away with it, so nothing here has to unmount them.
+    pub fn mounted(&self) -> &[PathBuf] {
+        &self.mounted
+    }
+
+    // Runs in the child between fork and execve. Only raw syscalls on the
+    // buffers prepare() allocated: no allocation, no locks, no formatting.
+    // Errors are bare errnos, which is all std passes back from pre_exec.
+    pub fn apply(&self) -> io::Result<()> {
+        // Join the cgroups first, as the host's root and outside any
+        // namespace. "0" moves the writer itself; QEMU and anything it
+        // forks inherit the group.
+        for procs in &self.cgroup_procs {
+            if !write_file(procs, b"0") {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // The user namespace goes first, on its own, and must be mapped
+        // before anything else: the namespaces unshared below are then owned
+        // by it, and the capabilities it grants are what let an unprivileged
+        // host user unshare, mount and chroot at all
+        if let Some(maps) = &self.user_namespace {
+            enter_user_namespace(maps)?;
+        }
+
+        if self.unshare_flags != 0 {
+            check(unsafe { libc::unshare(self.unshare_flags) })?;
+        }
+
+        // unshare(CLONE_NEWPID) doesn't move the caller into the new
+        // namespace; only its next child lands there, as PID 1. Exec'ing
+        // QEMU from here would leave it outside the namespace, so fork an
+        // init for the namespace and let QEMU be that init's child.
+        if self.isolate_pid {
+            enter_pid_namespace()?;
+        }
+
+        // Paths are host paths until the chroot below
+        self.apply_bind_mounts()?;
+
+        if let Some(root) = &self.chroot {
+            check(unsafe { libc::chroot(root.as_ptr()) })?;
+            check(unsafe { libc::chdir(c"/".as_ptr()) })?;
+        }
+
+        // Everything below gives privileges up, so it runs after the setup
+        // that needs them. Dropping from the bounding set needs CAP_SETPCAP;
+        // a cap missing from it can't come back even via setuid binaries.
+        for cap in &self.drop_bounding {
+            check(unsafe { libc::prctl(libc::PR_CAPBSET_DROP, *cap, 0, 0, 0) })?;
+        }
+
+        // Keep the permitted set across setuid so the allowlist survives the
+        // switch to an unprivileged user
+        if self.uid.is_some() && self.keep_mask != 0 {
+            check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) })?;
+        }
+        if let Some(gid) = self.gid {
+            check(unsafe { libc::setgid(gid) })?;
+        }
+        if let Some(uid) = self.uid {
+            check(unsafe { libc::setuid(uid) })?;
+        }
+
+        // Inheritable and ambient carry the allowlist across execve for a
+        // non-root QEMU. Ambient caps have to be permitted and inheritable
+        // already, so they're raised after capset.
+        capset(self.keep_mask)?;
+        check(unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) })?;
+        for cap in &self.keep_ambient {
+            check(unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_RAISE, *cap, 0, 0) })?;
+        }
+        if capget_permitted()? != self.keep_mask {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        // Last: the filter may well forbid the setup calls above
+        if let Some(filter) = &self.seccomp {
+            filter.load()?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_bind_mounts(&self) -> io::Result<()> {
+        if self.mounts.is_empty() {
+            return Ok(());
+        }
+
+        // The namespace starts out sharing propagation with the host's
+        // (systemd makes / shared), so without this every bind below would
+        // show up on the host too
+        check(unsafe {
+            libc::mount(
+                std::ptr::null(),
+                c"/".as_ptr(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            )
+        })?;
+
+        for bind in &self.mounts {
+            check(unsafe {
+                libc::mount(
+                    bind.source.as_ptr(),
+                    bind.target.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND | libc::MS_REC,
+                    std::ptr::null(),
+                )
+            })?;
+
+            // MS_RDONLY is ignored on the bind itself; it takes a remount
+            if bind.readonly {
+                check(unsafe {
+                    libc::mount(
+                        std::ptr::null(),
+                        bind.target.as_ptr(),
+                        std::ptr::null(),
+                        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+                        std::ptr::null(),
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// capset(2)/capget(2) structures; libc doesn't export them
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+// Sets effective, permitted and inheritable all to `mask`
+fn capset(mask: u64) -> io::Result<()> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let data = [mask as u32, (mask >> 32) as u32].map(|half| CapData {
+        effective: half,
+        permitted: half,
+        inheritable: half,
+    });
+    check(unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapHeader, data.as_ptr()) as libc::c_int })
+}
+
+fn capget_permitted() -> io::Result<u64> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let mut data = [CapData::default(); 2];
+    check(unsafe { libc::syscall(libc::SYS_capget, &mut header as *mut CapHeader, data.as_mut_ptr()) as libc::c_int })?;
+    Ok(data[0].permitted as u64 | (data[1].permitted as u64) << 32)
+}
+
+fn write_file(path: &std::ffi::CStr, data: &[u8]) -> bool {
+    write_file_raw(path.as_ptr(), data)
+}
+
+fn write_file_raw(path: *const c_char, data: &[u8]) -> bool {
+    unsafe {
+        let fd = libc::open(path, libc::O_WRONLY);
+        if fd < 0 {
+            return false;
+        }
+        let written = libc::write(fd, data.as_ptr().cast(), data.len());
+        libc::close(fd);
+        written == data.len() as isize
+    }
+}
+
+fn run_and_wait(argv: &[*const c_char]) -> bool {
+    unsafe {
+        match libc::fork() {
+            -1 => false,
+            0 => {
+                libc::execvp(argv[0], argv.as_ptr());
+                libc::_exit(127);
+            }
+            pid => {
+                let mut status = 0;
+                libc::waitpid(pid, &mut status, 0) == pid
+                    && libc::WIFEXITED(status)
+                    && libc::WEXITSTATUS(status) == 0
+            }
+        }
+    }
+}
+
+fn cap_error(e: caps::errors::CapsError) -> IsolationError {
+    IsolationError::CapabilityDrop(e.to_string())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nul_terminated(buf: &[u8]) -> &[u8] {
+        &buf[..buf.iter().position(|b| *b == 0).unwrap()]
+    }
+
+    #[test]
+    fn write_decimal_formats_without_allocating() {
+        for (n, expected) in [(0, "0"), (7, "7"), (4096, "4096"), (u32::MAX, "4294967295")] {
+            let mut buf = [0xffu8; 16];
+            write_decimal(&mut buf, n);
+            assert_eq!(nul_terminated(&buf), expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn proc_path_joins_pid_and_file() {
+        let mut pid = [0u8; 16];
+        write_decimal(&mut pid, 1234);
+        let mut buf = [0xffu8; 64];
+        proc_path(&mut buf, &pid, b"gid_map");
+        assert_eq!(nul_terminated(&buf), b"/proc/1234/gid_map");
+    }
+
+    #[test]
+    fn prepare_refuses_bind_mounts_outside_a_mount_namespace() {
+        let sandbox = VMSandbox::new().without_namespaces().with_bind_mount("/tmp", true);
+        assert!(matches!(sandbox.prepare(), Err(IsolationError::Mount(_))));
+    }
+
+    #[test]
+    fn prepare_resolves_bind_mounts_in_the_parent() {
+        let sandbox = VMSandbox::new().with_bind_mount("/tmp", false);
+        let target = sandbox.bind_target(Path::new("/tmp"));
+        let prepared = sandbox.prepare().unwrap();
+        assert_eq!(prepared.mounted(), &[target]);
+        assert_eq!(prepared.mounts[0].source.as_bytes(), b"/tmp");
+        assert!(prepared.unshare_flags & libc::CLONE_NEWNS != 0);
+    }
+
+    #[test]
+    fn prepare_rejects_a_missing_chroot() {
+        let sandbox = VMSandbox::new().with_chroot("/nonexistent/aegis-chroot");
+        assert!(matches!(sandbox.prepare(), Err(IsolationError::IoError(_))));
+    }
+
+    #[test]
+    fn prepare_keeps_only_allowlisted_capabilities() {
+        let prepared = VMSandbox::new().keep_capability(Capability::CAP_NET_ADMIN).prepare().unwrap();
+        let kept = Capability::CAP_NET_ADMIN.index() as libc::c_ulong;
+        assert!(!prepared.drop_bounding.contains(&kept));
+        assert_eq!(prepared.keep_ambient, vec![kept]);
+        assert_eq!(prepared.keep_mask, Capability::CAP_NET_ADMIN.bitmask());
+    }
 }
\ No newline at end of file
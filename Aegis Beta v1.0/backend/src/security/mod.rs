@@ -1,7 +1,4 @@
-pub mod isolation;
-pub mod sandbox;
-pub mod validation;
-
-pub use isolation::*;
-pub use sandbox::*;
-pub use validation::*;
\ No newline at end of file
+pub mod isolation;
+pub mod privileges;
+pub mod sandbox;
+pub mod validation;
\ No newline at end of file
@@ -0,0 +1,85 @@
+use caps::{CapSet, Capability};
+use nix::unistd::{access, geteuid, AccessFlags};
+use serde::Serialize;
+use std::sync::OnceLock;
+
+// What this process may do on the host, probed once at startup. Features
+// that need something missing here are skipped rather than failing later
+// with EPERM halfway through a VM start.
+#[derive(Debug, Clone, Serialize)]
+pub struct Privileges {
+    pub root: bool,
+    // Bridges, taps and firewall rules
+    pub net_admin: bool,
+    // Namespaces, mounts and chroot
+    pub sys_admin: bool,
+    pub mknod: bool,
+    // Whether an unprivileged user namespace can stand in for sys_admin
+    pub user_namespaces: bool,
+    pub cgroups_writable: bool,
+    pub kvm: bool,
+    // Root, or at least the capabilities for host networking and namespaces
+    pub privileged: bool,
+    // Human-readable list of what is unavailable and why
+    pub degraded: Vec<String>,
+}
+
+static PRIVILEGES: OnceLock<Privileges> = OnceLock::new();
+
+pub fn privileges() -> &'static Privileges {
+    PRIVILEGES.get_or_init(probe_privileges)
+}
+
+fn probe_privileges() -> Privileges {
+    let has_cap = |cap| caps::has_cap(None, CapSet::Effective, cap).unwrap_or(false);
+
+    let root = geteuid().is_root();
+    let net_admin = has_cap(Capability::CAP_NET_ADMIN);
+    let sys_admin = has_cap(Capability::CAP_SYS_ADMIN);
+    let mknod = has_cap(Capability::CAP_MKNOD);
+    let user_namespaces = user_namespaces_allowed();
+    let cgroups_writable = access("/sys/fs/cgroup", AccessFlags::W_OK).is_ok();
+    let kvm = access("/dev/kvm", AccessFlags::R_OK | AccessFlags::W_OK).is_ok();
+
+    let mut degraded = Vec::new();
+    if !net_admin {
+        degraded.push("bridge/tap networking and firewall rules (needs CAP_NET_ADMIN); VMs use user-mode networking".to_string());
+    }
+    if !sys_admin && !user_namespaces {
+        degraded.push("PID, mount and network namespaces (needs CAP_SYS_ADMIN or user namespaces)".to_string());
+    }
+    if !mknod {
+        degraded.push("device nodes in chroots (needs CAP_MKNOD)".to_string());
+    }
+    if !cgroups_writable {
+        degraded.push("cgroup resource limits (/sys/fs/cgroup is not writable)".to_string());
+    }
+    if !kvm {
+        degraded.push("KVM acceleration (cannot open /dev/kvm read/write)".to_string());
+    }
+
+    Privileges {
+        root,
+        net_admin,
+        sys_admin,
+        mknod,
+        user_namespaces,
+        cgroups_writable,
+        kvm,
+        privileged: root || (net_admin && sys_admin),
+        degraded,
+    }
+}
+
+// Distributions turn these off in different places
+fn user_namespaces_allowed() -> bool {
+    let read = |path: &str| std::fs::read_to_string(path).ok().map(|value| value.trim().to_string());
+
+    let max_namespaces = read("/proc/sys/user/max_user_namespaces")
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(0);
+    // Debian/Ubuntu only
+    let clone_allowed = read("/proc/sys/kernel/unprivileged_userns_clone").is_none_or(|value| value == "1");
+
+    max_namespaces > 0 && clone_allowed
+}
\ No newline at end of file
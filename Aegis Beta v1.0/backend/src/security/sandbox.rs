@@ -1,550 +1,1087 @@
-use std::fs;
-use std::io;
-use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
-
-use nix::sys::stat::Mode;
-use nix::unistd::{Gid, Uid};
-
-use super::isolation::{VMSandbox, IsolationError};
-
-#[derive(Debug)]
-pub struct ResourceLimits {
-    pub memory_limit_mb: u64,
-    pub cpu_limit_percent: u32,
-    pub disk_limit_mb: u64,
-    pub network_limit_mbps: u32,
-}
-
-impl Default for ResourceLimits {
-    fn default() -> Self {
-        Self {
-            memory_limit_mb: 4096,
-            cpu_limit_percent: 100,
-            disk_limit_mb: 20480,
-            network_limit_mbps: 100,
-        }
-    }
-}
-
-pub struct VMSandboxBuilder {
-    sandbox: VMSandbox,
-    limits: ResourceLimits,
-    allowed_devices: Vec<String>,
-    allowed_syscalls: Vec<String>,
-    read_only_paths: Vec<PathBuf>,
-    writable_paths: Vec<PathBuf>,
-}
-
-impl VMSandboxBuilder {
-    pub fn new() -> Self {
-        Self {
-            sandbox: VMSandbox::new(),
-            limits: ResourceLimits::default(),
-            allowed_devices: vec![
-                "/dev/null".to_string(),
-                "/dev/zero".to_string(),
-                "/dev/random".to_string(),
-                "/dev/urandom".to_string(),
-            ],
-            allowed_syscalls: vec![
-                "read".to_string(),
-                "write".to_string(),
-                "open".to_string(),
-                "close".to_string(),
-                "stat".to_string(),
-                "fstat".to_string(),
-                "lseek".to_string(),
-                "mmap".to_string(),
-                "mprotect".to_string(),
-                "munmap".to_string(),
-                "brk".to_string(),
-                "rt_sigaction".to_string(),
-                "rt_sigprocmask".to_string(),
-                "rt_sigreturn".to_string(),
-                "ioctl".to_string(),
-                "pread64".to_string(),
-                "pwrite64".to_string(),
-                "readv".to_string(),
-                "writev".to_string(),
-                "access".to_string(),
-                "pipe".to_string(),
-                "select".to_string(),
-                "dup".to_string(),
-                "dup2".to_string(),
-                "pause".to_string(),
-                "nanosleep".to_string(),
-                "getitimer".to_string(),
-                "alarm".to_string(),
-                "setitimer".to_string(),
-                "getpid".to_string(),
-                "sendfile".to_string(),
-                "socket".to_string(),
-                "connect".to_string(),
-                "accept".to_string(),
-                "sendto".to_string(),
-                "recvfrom".to_string(),
-                "sendmsg".to_string(),
-                "recvmsg".to_string(),
-                "shutdown".to_string(),
-                "bind".to_string(),
-                "listen".to_string(),
-                "getsockname".to_string(),
-                "getpeername".to_string(),
-                "socketpair".to_string(),
-                "setsockopt".to_string(),
-                "getsockopt".to_string(),
-                "clone".to_string(),
-                "fork".to_string(),
-                "vfork".to_string(),
-                "execve".to_string(),
-                "exit".to_string(),
-                "wait4".to_string(),
-                "kill".to_string(),
-                "uname".to_string(),
-                "semget".to_string(),
-                "semop".to_string(),
-                "semctl".to_string(),
-                "shmdt".to_string(),
-                "msgrcv".to_string(),
-                "msgsnd".to_string(),
-                "msgctl".to_string(),
-                "fcntl".to_string(),
-                "flock".to_string(),
-                "fsync".to_string(),
-                "fdatasync".to_string(),
-                "truncate".to_string(),
-                "ftruncate".to_string(),
-                "getdents".to_string(),
-                "getcwd".to_string(),
-                "chdir".to_string(),
-                "fchdir".to_string(),
-                "rename".to_string(),
-                "mkdir".to_string(),
-                "rmdir".to_string(),
-                "creat".to_string(),
-                "link".to_string(),
-                "unlink".to_string(),
-                "symlink".to_string(),
-                "readlink".to_string(),
-                "chmod".to_string(),
-                "fchmod".to_string(),
-                "chown".to_string(),
-                "fchown".to_string(),
-                "lchown".to_string(),
-                "umask".to_string(),
-                "gettimeofday".to_string(),
-                "getrlimit".to_string(),
-                "getrusage".to_string(),
-                "sysinfo".to_string(),
-                "times".to_string(),
-                "ptrace".to_string(),
-                "getuid".to_string(),
-                "syslog".to_string(),
-                "getgid".to_string(),
-                "setuid".to_string(),
-                "setgid".to_string(),
-                "geteuid".to_string(),
-                "getegid".to_string(),
-                "setpgid".to_string(),
-                "getppid".to_string(),
-                "getpgrp".to_string(),
-                "setsid".to_string(),
-                "setreuid".to_string(),
-                "setregid".to_string(),
-                "getgroups".to_string(),
-                "setgroups".to_string(),
-                "setresuid".to_string(),
-                "getresuid".to_string(),
-                "setresgid".to_string(),
-                "getresgid".to_string(),
-                "getpgid".to_string(),
-                "setfsuid".to_string(),
-                "setfsgid".to_string(),
-                "getsid".to_string(),
-                "capget".to_string(),
-                "capset".to_string(),
-                "rt_sigpending".to_string(),
-                "rt_sigtimedwait".to_string(),
-                "rt_sigqueueinfo".to_string(),
-                "rt_sigsuspend".to_string(),
-                "sigaltstack".to_string(),
-                "utime".to_string(),
-                "mknod".to_string(),
-                "uselib".to_string(),
-                "personality".to_string(),
-                "ustat".to_string(),
-                "statfs".to_string(),
-                "fstatfs".to_string(),
-                "sysfs".to_string(),
-                "getpriority".to_string(),
-                "setpriority".to_string(),
-                "sched_setparam".to_string(),
-                "sched_getparam".to_string(),
-                "sched_setscheduler".to_string(),
-                "sched_getscheduler".to_string(),
-                "sched_get_priority_max".to_string(),
-                "sched_get_priority_min".to_string(),
-                "sched_rr_get_interval".to_string(),
-                "mlock".to_string(),
-                "munlock".to_string(),
-                "mlockall".to_string(),
-                "munlockall".to_string(),
-                "vhangup".to_string(),
-                "modify_ldt".to_string(),
-                "pivot_root".to_string(),
-                "_sysctl".to_string(),
-                "prctl".to_string(),
-                "arch_prctl".to_string(),
-                "adjtimex".to_string(),
-                "setrlimit".to_string(),
-                "chroot".to_string(),
-                "sync".to_string(),
-                "acct".to_string(),
-                "settimeofday".to_string(),
-                "mount".to_string(),
-                "umount2".to_string(),
-                "swapon".to_string(),
-                "swapoff".to_string(),
-                "reboot".to_string(),
-                "sethostname".to_string(),
-                "setdomainname".to_string(),
-                "iopl".to_string(),
-                "ioperm".to_string(),
-                "create_module".to_string(),
-                "init_module".to_string(),
-                "delete_module".to_string(),
-                "get_kernel_syms".to_string(),
-                "query_module".to_string(),
-                "quotactl".to_string(),
-                "nfsservctl".to_string(),
-                "getpmsg".to_string(),
-                "putpmsg".to_string(),
-                "afs_syscall".to_string(),
-                "tuxcall".to_string(),
-                "security".to_string(),
-                "gettid".to_string(),
-                "readahead".to_string(),
-                "setxattr".to_string(),
-                "lsetxattr".to_string(),
-                "fsetxattr".to_string(),
-                "getxattr".to_string(),
-                "lgetxattr".to_string(),
-                "fgetxattr".to_string(),
-                "listxattr".to_string(),
-                "llistxattr".to_string(),
-                "flistxattr".to_string(),
-                "removexattr".to_string(),
-                "lremovexattr".to_string(),
-                "fremovexattr".to_string(),
-                "tkill".to_string(),
-                "time".to_string(),
-                "futex".to_string(),
-                "sched_setaffinity".to_string(),
-                "sched_getaffinity".to_string(),
-                "set_thread_area".to_string(),
-                "io_setup".to_string(),
-                "io_destroy".to_string(),
-                "io_getevents".to_string(),
-                "io_submit".to_string(),
-                "io_cancel".to_string(),
-                "get_thread_area".to_string(),
-                "lookup_dcookie".to_string(),
-                "epoll_create".to_string(),
-                "epoll_ctl_old".to_string(),
-                "epoll_wait_old".to_string(),
-                "remap_file_pages".to_string(),
-                "getdents64".to_string(),
-                "set_tid_address".to_string(),
-                "restart_syscall".to_string(),
-                "semtimedop".to_string(),
-                "fadvise64".to_string(),
-                "timer_create".to_string(),
-                "timer_settime".to_string(),
-                "timer_gettime".to_string(),
-                "timer_getoverrun".to_string(),
-                "timer_delete".to_string(),
-                "clock_settime".to_string(),
-                "clock_gettime".to_string(),
-                "clock_getres".to_string(),
-                "clock_nanosleep".to_string(),
-                "exit_group".to_string(),
-                "epoll_wait".to_string(),
-                "epoll_ctl".to_string(),
-                "tgkill".to_string(),
-                "utimes".to_string(),
-                "vserver".to_string(),
-                "mbind".to_string(),
-                "set_mempolicy".to_string(),
-                "get_mempolicy".to_string(),
-                "mq_open".to_string(),
-                "mq_unlink".to_string(),
-                "mq_timedsend".to_string(),
-                "mq_timedreceive".to_string(),
-                "mq_notify".to_string(),
-                "mq_getsetattr".to_string(),
-                "kexec_load".to_string(),
-                "waitid".to_string(),
-                "add_key".to_string(),
-                "request_key".to_string(),
-                "keyctl".to_string(),
-                "ioprio_set".to_string(),
-                "ioprio_get".to_string(),
-                "inotify_init".to_string(),
-                "inotify_add_watch".to_string(),
-                "inotify_rm_watch".to_string(),
-                "migrate_pages".to_string(),
-                "openat".to_string(),
-                "mkdirat".to_string(),
-                "mknodat".to_string(),
-                "fchownat".to_string(),
-                "futimesat".to_string(),
-                "newfstatat".to_string(),
-                "unlinkat".to_string(),
-                "renameat".to_string(),
-                "linkat".to_string(),
-                "symlinkat".to_string(),
-                "readlinkat".to_string(),
-                "fchmodat".to_string(),
-                "faccessat".to_string(),
-                "pselect6".to_string(),
-                "ppoll".to_string(),
-                "unshare".to_string(),
-                "set_robust_list".to_string(),
-                "get_robust_list".to_string(),
-                "splice".to_string(),
-                "tee".to_string(),
-                "sync_file_range".to_string(),
-                "vmsplice".to_string(),
-                "move_pages".to_string(),
-                "utimensat".to_string(),
-                "epoll_pwait".to_string(),
-                "signalfd".to_string(),
-                "timerfd_create".to_string(),
-                "eventfd".to_string(),
-                "fallocate".to_string(),
-                "timerfd_settime".to_string(),
-                "timerfd_gettime".to_string(),
-                "accept4".to_string(),
-                "signalfd4".to_string(),
-                "eventfd2".to_string(),
-                "epoll_create1".to_string(),
-                "dup3".to_string(),
-                "pipe2".to_string(),
-                "inotify_init1".to_string(),
-                "preadv".to_string(),
-                "pwritev".to_string(),
-                "rt_tgsigqueueinfo".to_string(),
-                "perf_event_open".to_string(),
-                "recvmmsg".to_string(),
-                "fanotify_init".to_string(),
-                "fanotify_mark".to_string(),
-                "prlimit64".to_string(),
-                "name_to_handle_at".to_string(),
-                "open_by_handle_at".to_string(),
-                "clock_adjtime".to_string(),
-                "syncfs".to_string(),
-                "sendmmsg".to_string(),
-                "setns".to_string(),
-                "getcpu".to_string(),
-                "process_vm_readv".to_string(),
-                "process_vm_writev".to_string(),
-                "kcmp".to_string(),
-                "finit_module".to_string(),
-                "sched_setattr".to_string(),
-                "sched_getattr".to_string(),
-                "renameat2".to_string(),
-                "seccomp".to_string(),
-                "getrandom".to_string(),
-                "memfd_create".to_string(),
-                "kexec_file_load".to_string(),
-                "bpf".to_string(),
-                "execveat".to_string(),
-                "userfaultfd".to_string(),
-                "membarrier".to_string(),
-                "mlock2".to_string(),
-                "copy_file_range".to_string(),
-                "preadv2".to_string(),
-                "pwritev2".to_string(),
-                "pkey_mprotect".to_string(),
-                "pkey_alloc".to_string(),
-                "pkey_free".to_string(),
-                "statx".to_string(),
-                "io_pgetevents".to_string(),
-                "rseq".to_string(),
-                "pidfd_send_signal".to_string(),
-                "io_uring_setup".to_string(),
-                "io_uring_enter".to_string(),
-                "io_uring_register".to_string(),
-                "open_tree".to_string(),
-                "move_mount".to_string(),
-                "fsopen".to_string(),
-                "fsconfig".to_string(),
-                "fsmount".to_string(),
-                "fspick".to_string(),
-                "pidfd_open".to_string(),
-                "clone3".to_string(),
-                "close_range".to_string(),
-                "openat2".to_string(),
-                "pidfd_getfd".to_string(),
-                "faccessat2".to_string(),
-                "process_madvise".to_string(),
-                "epoll_pwait2".to_string(),
-                "mount_setattr".to_string(),
-                "quotactl_fd".to_string(),
-                "landlock_create_ruleset".to_string(),
-                "landlock_add_rule".to_string(),
-                "landlock_restrict_self".to_string(),
-                "memfd_secret".to_string(),
-                "process_mrelease".to_string(),
-                "futex_waitv".to_string(),
-                "set_mempolicy_home_node".to_string(),
-            ],
-            read_only_paths: Vec::new(),
-            writable_paths: Vec::new(),
-        }
-    }
-
-    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
-        self.limits = limits;
-        self
-    }
-
-    pub fn with_user(mut self, uid: u32, gid: u32) -> Self {
-        self.sandbox = self.sandbox.with_user(Uid::from_raw(uid), Gid::from_raw(gid));
-        self
-    }
-
-    pub fn with_chroot(mut self, path: &str) -> Self {
-        self.sandbox = self.sandbox.with_chroot(path);
-        self
-    }
-
-    pub fn add_allowed_device(mut self, device: &str) -> Self {
-        self.allowed_devices.push(device.to_string());
-        self
-    }
-
-    pub fn add_read_only_path<P: AsRef<Path>>(mut self, path: P) -> Self {
-        self.read_only_paths.push(path.as_ref().to_path_buf());
-        self
-    }
-
-    pub fn add_writable_path<P: AsRef<Path>>(mut self, path: P) -> Self {
-        self.writable_paths.push(path.as_ref().to_path_buf());
-        self
-    }
-
-    pub fn build(self) -> VMSandbox {
-        self.sandbox
-    }
-
-    pub fn setup_vm_environment(&self, vm_id: &str, base_path: &Path) -> Result<(), IsolationError> {
-        // Create VM directory structure
-        VMSandbox::create_vm_directory(vm_id, base_path)?;
-
-        let vm_path = base_path.join(vm_id);
-        
-        // Setup device nodes
-        self.setup_devices(&vm_path)?;
-        
-        // Setup filesystem
-        self.setup_filesystem(&vm_path)?;
-        
-        // Apply resource limits
-        self.apply_resource_limits(vm_id)?;
-        
-        // Setup seccomp filter
-        self.setup_seccomp()?;
-
-        Ok(())
-    }
-
-    fn setup_devices(&self, vm_path: &Path) -> Result<(), IsolationError> {
-        let dev_path = vm_path.join("dev");
-        
-        for device in &self.allowed_devices {
-            if Path::new(device).exists() {
-                let dest = dev_path.join(
-                    Path::new(device)
-                        .file_name()
-                        .ok_or_else(|| IsolationError::IoError(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            "Invalid device path",
-                        )))?,
-                );
-                
-                // Create device node (simplified - in reality would use mknod)
-                fs::copy(device, &dest)?;
-            }
-        }
-
-        Ok(())
-    }
-
-    fn setup_filesystem(&self, vm_path: &Path) -> Result<(), IsolationError> {
-        // Create necessary directories
-        let root = vm_path.join("root");
-        
-        // Setup read-only bind mounts
-        for path in &self.read_only_paths {
-            if path.exists() {
-                let dest = root.join(path.strip_prefix("/").unwrap_or(path));
-                fs::create_dir_all(dest.parent().unwrap())?;
-                // In reality, would use mount --bind here
-            }
-        }
-        
-        // Setup writable directories
-        for path in &self.writable_paths {
-            let dest = root.join(path.strip_prefix("/").unwrap_or(path));
-            fs::create_dir_all(&dest)?;
-            
-            // Set permissions
-            let mut perms = fs::metadata(&dest)?.permissions();
-            perms.set_mode(0o755); // rwxr-xr-x
-            fs::set_permissions(&dest, perms)?;
-        }
-
-        Ok(())
-    }
-
-    fn apply_resource_limits(&self, vm_id: &str) -> Result<(), IsolationError> {
-        // Apply cgroup limits
-        let cgroup_path = format!("/sys/fs/cgroup/vm-manager/{}", vm_id);
-        
-        // Create cgroup directory
-        fs::create_dir_all(&cgroup_path)?;
-        
-        // Set memory limit
-        if self.limits.memory_limit_mb > 0 {
-            let memory_limit = self.limits.memory_limit_mb * 1024 * 1024;
-            fs::write(
-                format!("{}/memory.limit_in_bytes", cgroup_path),
-                memory_limit.to_string(),
-            )?;
-        }
-        
-        // Set CPU limit
-        if self.limits.cpu_limit_percent < 100 {
-            let cpu_quota = (self.limits.cpu_limit_percent as u64) * 1000; // Convert to microseconds
-            fs::write(
-                format!("{}/cpu.cfs_quota_us", cgroup_path),
-                cpu_quota.to_string(),
-            )?;
-            fs::write(
-                format!("{}/cpu.cfs_period_us", cgroup_path),
-                "100000".to_string(), // 100ms period
-            )?;
-        }
-
-        Ok(())
-    }
-
-    fn setup_seccomp(&self) -> Result<(), IsolationError> {
-        // This is a simplified version
-        // In production, you would use libseccomp to create and load a filter
-        log::info!("Seccomp filter would be applied here ({} syscalls allowed)", self.allowed_syscalls.len());
-        Ok(())
-    }
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use caps::Capability;
+use libseccomp::{ScmpAction, ScmpFilterContext, ScmpSyscall};
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sys::stat::{major, minor, mknod, Mode, SFlag};
+use nix::unistd::{Gid, Uid};
+use serde::Deserialize;
+
+use super::isolation::{IdMapping, VMSandbox, IsolationError};
+use super::privileges::privileges;
+
+#[derive(Debug)]
+pub struct ResourceLimits {
+    pub memory_limit_mb: u64,
+    // Of one CPU, so 200 is two; 0 for no limit
+    pub cpu_limit_percent: u32,
+    // Read and write throughput cap in MB/s on the disk holding the VM's
+    // files; skipped where that isn't a plain block device
+    pub disk_limit_mb: u64,
+}
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// Every VM's group sits under this one
+const CGROUP_PARENT: &str = "vm-manager";
+const CPU_PERIOD_US: u64 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupVersion {
+    // One hierarchy per controller
+    V1,
+    // Unified hierarchy
+    V2,
+}
+
+pub fn cgroup_version() -> CgroupVersion {
+    if Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        CgroupVersion::V2
+    } else {
+        CgroupVersion::V1
+    }
+}
+
+// Removes the groups apply_resource_limits created for a VM. They have to
+// be empty, which they are once its QEMU has exited; missing ones are fine.
+pub fn remove_vm_cgroups(vm_id: &str) -> Result<(), IsolationError> {
+    let root = Path::new(CGROUP_ROOT);
+    let groups = match cgroup_version() {
+        CgroupVersion::V2 => vec![root.join(CGROUP_PARENT).join(vm_id)],
+        CgroupVersion::V1 => ["memory", "cpu", "blkio"].iter()
+            .map(|controller| root.join(controller).join(CGROUP_PARENT).join(vm_id))
+            .collect(),
+    };
+    for group in groups {
+        match fs::remove_dir(&group) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self {
+            memory_limit_mb: 4096,
+            cpu_limit_percent: 100,
+            disk_limit_mb: 20480,
+        }
+    }
+}
+
+// What the seccomp filter does with a syscall. Log lets operators roll a
+// profile out in audit mode and collect what QEMU actually calls before
+// switching to KillProcess.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeccompAction {
+    Allow,
+    Log,
+    Errno(i32),
+    KillProcess,
+}
+
+impl SeccompAction {
+    fn validate(self) -> Result<(), IsolationError> {
+        match self {
+            // errno is returned as -errno, and the kernel caps it at MAX_ERRNO
+            SeccompAction::Errno(errno) if !(1..=4095).contains(&errno) => {
+                Err(IsolationError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid seccomp errno {}", errno),
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn to_scmp(self) -> ScmpAction {
+        match self {
+            SeccompAction::Allow => ScmpAction::Allow,
+            SeccompAction::Log => ScmpAction::Log,
+            SeccompAction::Errno(errno) => ScmpAction::Errno(errno),
+            SeccompAction::KillProcess => ScmpAction::KillProcess,
+        }
+    }
+}
+
+// Which syscalls the filter blocks: everything not allowed, or only the
+// denied ones. DenyList is for bringing a profile up on a new QEMU release.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeccompMode {
+    #[default]
+    AllowList,
+    DenyList,
+}
+
+// A compiled seccomp-bpf program. It's built with libseccomp in the parent
+// and only handed to the kernel in the child, where nothing but raw
+// syscalls is safe between fork and execve.
+pub struct SeccompFilter {
+    program: Vec<libc::sock_filter>,
+}
+
+// seccomp(2) operation; not every libc release exports it
+const SECCOMP_SET_MODE_FILTER: libc::c_uint = 1;
+
+impl SeccompFilter {
+    fn compile(default_action: SeccompAction, rules: &[(String, SeccompAction)]) -> Result<Self, IsolationError> {
+        let mut context = ScmpFilterContext::new_filter(default_action.to_scmp()).map_err(seccomp_error)?;
+        
+        // Names from other archs' tables (e.g. `open` on aarch64) can't be
+        // called here anyway
+        let mut unknown = Vec::new();
+        for (name, action) in rules {
+            match ScmpSyscall::from_name(name) {
+                Ok(syscall) => context.add_rule(action.to_scmp(), syscall).map_err(seccomp_error)?,
+                Err(_) => unknown.push(name.as_str()),
+            }
+        }
+        if !unknown.is_empty() {
+            log::debug!("Seccomp: no such syscalls on this arch: {}", unknown.join(", "));
+        }
+        
+        // libseccomp only exports to a file descriptor
+        let mut file = tempfile::tempfile()?;
+        context.export_bpf(&mut file).map_err(seccomp_error)?;
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)?;
+        
+        // struct sock_filter { u16 code; u8 jt; u8 jf; u32 k; }, native endian
+        let program = bytes.chunks_exact(8)
+            .map(|insn| libc::sock_filter {
+                code: u16::from_ne_bytes([insn[0], insn[1]]),
+                jt: insn[2],
+                jf: insn[3],
+                k: u32::from_ne_bytes([insn[4], insn[5], insn[6], insn[7]]),
+            })
+            .collect::<Vec<_>>();
+        if program.is_empty() || program.len() > u16::MAX as usize {
+            return Err(IsolationError::Seccomp(format!("Unusable filter of {} instructions", program.len())));
+        }
+        Ok(Self { program })
+    }
+
+    // Runs in the child right before execve. no_new_privs is what lets an
+    // unprivileged process install a filter; it also keeps setuid binaries
+    // from escaping it.
+    pub fn load(&self) -> io::Result<()> {
+        let prog = libc::sock_fprog {
+            len: self.program.len() as u16,
+            filter: self.program.as_ptr() as *mut libc::sock_filter,
+        };
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER, 0, &prog as *const libc::sock_fprog) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_cgroup(group: &Path, file: &str, value: &str) -> Result<(), IsolationError> {
+    let path = group.join(file);
+    fs::write(&path, value)
+        .map_err(|e| IsolationError::Cgroup(format!("writing {:?} to {}: {}", value, path.display(), e)))
+}
+
+// The whole disk holding `path`, as throttling only takes disks, not
+// partitions. None for filesystems without one (tmpfs, overlay, btrfs).
+fn block_device(path: &Path) -> Option<(u64, u64)> {
+    let dev = fs::metadata(path).ok()?.dev();
+    if major(dev) == 0 {
+        return None;
+    }
+
+    // A partition's sysfs directory sits inside its disk's
+    let mut sys = PathBuf::from(format!("/sys/dev/block/{}:{}", major(dev), minor(dev)));
+    if sys.join("partition").exists() {
+        sys = sys.canonicalize().ok()?.parent()?.to_path_buf();
+    }
+    let numbers = fs::read_to_string(sys.join("dev")).ok()?;
+    let (disk_major, disk_minor) = numbers.trim().split_once(':')?;
+    Some((disk_major.parse().ok()?, disk_minor.parse().ok()?))
+}
+
+// Same major/minor and permission bits as the host node. Without CAP_MKNOD
+// (e.g. inside a user namespace) the host node is bind-mounted instead.
+fn create_device_node(source: &Path, dest: &Path, metadata: &fs::Metadata) -> Result<(), IsolationError> {
+    let permissions = metadata.permissions().mode() & 0o7777;
+    match mknod(dest, SFlag::S_IFCHR, Mode::from_bits_truncate(permissions), metadata.rdev()) {
+        Ok(()) => {
+            // mknod applies the umask
+            fs::set_permissions(dest, fs::Permissions::from_mode(permissions))?;
+            Ok(())
+        }
+        Err(Errno::EPERM) => {
+            fs::File::create(dest)?;
+            mount(Some(source), dest, None::<&str>, MsFlags::MS_BIND, None::<&str>)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn seccomp_error(e: libseccomp::error::SeccompError) -> IsolationError {
+    IsolationError::Seccomp(e.to_string())
+}
+
+pub struct VMSandboxBuilder {
+    sandbox: VMSandbox,
+    limits: ResourceLimits,
+    allowed_devices: Vec<String>,
+    allowed_syscalls: Vec<String>,
+    // Blocked even when allowed; what no QEMU has any business calling
+    denied_syscalls: Vec<String>,
+    seccomp_mode: SeccompMode,
+    // What blocked syscalls get: denied ones, and in AllowList mode
+    // anything not in allowed_syscalls. seccomp_overrides beat all of it.
+    seccomp_default_action: SeccompAction,
+    seccomp_overrides: HashMap<String, SeccompAction>,
+    read_only_paths: Vec<PathBuf>,
+    writable_paths: Vec<PathBuf>,
+}
+
+impl VMSandboxBuilder {
+    pub fn new() -> Self {
+        Self {
+            sandbox: VMSandbox::new(),
+            limits: ResourceLimits::default(),
+            allowed_devices: vec![
+                "/dev/null".to_string(),
+                "/dev/zero".to_string(),
+                "/dev/random".to_string(),
+                "/dev/urandom".to_string(),
+            ],
+            allowed_syscalls: vec![
+                "read".to_string(),
+                "write".to_string(),
+                "open".to_string(),
+                "close".to_string(),
+                "stat".to_string(),
+                "fstat".to_string(),
+                "lseek".to_string(),
+                "mmap".to_string(),
+                "mprotect".to_string(),
+                "mremap".to_string(),
+                "madvise".to_string(),
+                "msync".to_string(),
+                "mincore".to_string(),
+                "poll".to_string(),
+                "lstat".to_string(),
+                "sched_yield".to_string(),
+                "shmget".to_string(),
+                "shmat".to_string(),
+                "shmctl".to_string(),
+                "munmap".to_string(),
+                "brk".to_string(),
+                "rt_sigaction".to_string(),
+                "rt_sigprocmask".to_string(),
+                "rt_sigreturn".to_string(),
+                "ioctl".to_string(),
+                "pread64".to_string(),
+                "pwrite64".to_string(),
+                "readv".to_string(),
+                "writev".to_string(),
+                "access".to_string(),
+                "pipe".to_string(),
+                "select".to_string(),
+                "dup".to_string(),
+                "dup2".to_string(),
+                "pause".to_string(),
+                "nanosleep".to_string(),
+                "getitimer".to_string(),
+                "alarm".to_string(),
+                "setitimer".to_string(),
+                "getpid".to_string(),
+                "sendfile".to_string(),
+                "socket".to_string(),
+                "connect".to_string(),
+                "accept".to_string(),
+                "sendto".to_string(),
+                "recvfrom".to_string(),
+                "sendmsg".to_string(),
+                "recvmsg".to_string(),
+                "shutdown".to_string(),
+                "bind".to_string(),
+                "listen".to_string(),
+                "getsockname".to_string(),
+                "getpeername".to_string(),
+                "socketpair".to_string(),
+                "setsockopt".to_string(),
+                "getsockopt".to_string(),
+                "clone".to_string(),
+                "fork".to_string(),
+                "vfork".to_string(),
+                "execve".to_string(),
+                "exit".to_string(),
+                "wait4".to_string(),
+                "kill".to_string(),
+                "uname".to_string(),
+                "semget".to_string(),
+                "semop".to_string(),
+                "semctl".to_string(),
+                "shmdt".to_string(),
+                "msgrcv".to_string(),
+                "msgsnd".to_string(),
+                "msgctl".to_string(),
+                "fcntl".to_string(),
+                "flock".to_string(),
+                "fsync".to_string(),
+                "fdatasync".to_string(),
+                "truncate".to_string(),
+                "ftruncate".to_string(),
+                "getdents".to_string(),
+                "getcwd".to_string(),
+                "chdir".to_string(),
+                "fchdir".to_string(),
+                "rename".to_string(),
+                "mkdir".to_string(),
+                "rmdir".to_string(),
+                "creat".to_string(),
+                "link".to_string(),
+                "unlink".to_string(),
+                "symlink".to_string(),
+                "readlink".to_string(),
+                "chmod".to_string(),
+                "fchmod".to_string(),
+                "chown".to_string(),
+                "fchown".to_string(),
+                "lchown".to_string(),
+                "umask".to_string(),
+                "gettimeofday".to_string(),
+                "getrlimit".to_string(),
+                "getrusage".to_string(),
+                "sysinfo".to_string(),
+                "times".to_string(),
+                "ptrace".to_string(),
+                "getuid".to_string(),
+                "syslog".to_string(),
+                "getgid".to_string(),
+                "setuid".to_string(),
+                "setgid".to_string(),
+                "geteuid".to_string(),
+                "getegid".to_string(),
+                "setpgid".to_string(),
+                "getppid".to_string(),
+                "getpgrp".to_string(),
+                "setsid".to_string(),
+                "setreuid".to_string(),
+                "setregid".to_string(),
+                "getgroups".to_string(),
+                "setgroups".to_string(),
+                "setresuid".to_string(),
+                "getresuid".to_string(),
+                "setresgid".to_string(),
+                "getresgid".to_string(),
+                "getpgid".to_string(),
+                "setfsuid".to_string(),
+                "setfsgid".to_string(),
+                "getsid".to_string(),
+                "capget".to_string(),
+                "capset".to_string(),
+                "rt_sigpending".to_string(),
+                "rt_sigtimedwait".to_string(),
+                "rt_sigqueueinfo".to_string(),
+                "rt_sigsuspend".to_string(),
+                "sigaltstack".to_string(),
+                "utime".to_string(),
+                "mknod".to_string(),
+                "uselib".to_string(),
+                "personality".to_string(),
+                "ustat".to_string(),
+                "statfs".to_string(),
+                "fstatfs".to_string(),
+                "sysfs".to_string(),
+                "getpriority".to_string(),
+                "setpriority".to_string(),
+                "sched_setparam".to_string(),
+                "sched_getparam".to_string(),
+                "sched_setscheduler".to_string(),
+                "sched_getscheduler".to_string(),
+                "sched_get_priority_max".to_string(),
+                "sched_get_priority_min".to_string(),
+                "sched_rr_get_interval".to_string(),
+                "mlock".to_string(),
+                "munlock".to_string(),
+                "mlockall".to_string(),
+                "munlockall".to_string(),
+                "vhangup".to_string(),
+                "modify_ldt".to_string(),
+                "pivot_root".to_string(),
+                "_sysctl".to_string(),
+                "prctl".to_string(),
+                "arch_prctl".to_string(),
+                "adjtimex".to_string(),
+                "setrlimit".to_string(),
+                "chroot".to_string(),
+                "sync".to_string(),
+                "acct".to_string(),
+                "settimeofday".to_string(),
+                "mount".to_string(),
+                "umount2".to_string(),
+                "swapon".to_string(),
+                "swapoff".to_string(),
+                "reboot".to_string(),
+                "sethostname".to_string(),
+                "setdomainname".to_string(),
+                "iopl".to_string(),
+                "ioperm".to_string(),
+                "create_module".to_string(),
+                "init_module".to_string(),
+                "delete_module".to_string(),
+                "get_kernel_syms".to_string(),
+                "query_module".to_string(),
+                "quotactl".to_string(),
+                "nfsservctl".to_string(),
+                "getpmsg".to_string(),
+                "putpmsg".to_string(),
+                "afs_syscall".to_string(),
+                "tuxcall".to_string(),
+                "security".to_string(),
+                "gettid".to_string(),
+                "readahead".to_string(),
+                "setxattr".to_string(),
+                "lsetxattr".to_string(),
+                "fsetxattr".to_string(),
+                "getxattr".to_string(),
+                "lgetxattr".to_string(),
+                "fgetxattr".to_string(),
+                "listxattr".to_string(),
+                "llistxattr".to_string(),
+                "flistxattr".to_string(),
+                "removexattr".to_string(),
+                "lremovexattr".to_string(),
+                "fremovexattr".to_string(),
+                "tkill".to_string(),
+                "time".to_string(),
+                "futex".to_string(),
+                "sched_setaffinity".to_string(),
+                "sched_getaffinity".to_string(),
+                "set_thread_area".to_string(),
+                "io_setup".to_string(),
+                "io_destroy".to_string(),
+                "io_getevents".to_string(),
+                "io_submit".to_string(),
+                "io_cancel".to_string(),
+                "get_thread_area".to_string(),
+                "lookup_dcookie".to_string(),
+                "epoll_create".to_string(),
+                "epoll_ctl_old".to_string(),
+                "epoll_wait_old".to_string(),
+                "remap_file_pages".to_string(),
+                "getdents64".to_string(),
+                "set_tid_address".to_string(),
+                "restart_syscall".to_string(),
+                "semtimedop".to_string(),
+                "fadvise64".to_string(),
+                "timer_create".to_string(),
+                "timer_settime".to_string(),
+                "timer_gettime".to_string(),
+                "timer_getoverrun".to_string(),
+                "timer_delete".to_string(),
+                "clock_settime".to_string(),
+                "clock_gettime".to_string(),
+                "clock_getres".to_string(),
+                "clock_nanosleep".to_string(),
+                "exit_group".to_string(),
+                "epoll_wait".to_string(),
+                "epoll_ctl".to_string(),
+                "tgkill".to_string(),
+                "utimes".to_string(),
+                "vserver".to_string(),
+                "mbind".to_string(),
+                "set_mempolicy".to_string(),
+                "get_mempolicy".to_string(),
+                "mq_open".to_string(),
+                "mq_unlink".to_string(),
+                "mq_timedsend".to_string(),
+                "mq_timedreceive".to_string(),
+                "mq_notify".to_string(),
+                "mq_getsetattr".to_string(),
+                "kexec_load".to_string(),
+                "waitid".to_string(),
+                "add_key".to_string(),
+                "request_key".to_string(),
+                "keyctl".to_string(),
+                "ioprio_set".to_string(),
+                "ioprio_get".to_string(),
+                "inotify_init".to_string(),
+                "inotify_add_watch".to_string(),
+                "inotify_rm_watch".to_string(),
+                "migrate_pages".to_string(),
+                "openat".to_string(),
+                "mkdirat".to_string(),
+                "mknodat".to_string(),
+                "fchownat".to_string(),
+                "futimesat".to_string(),
+                "newfstatat".to_string(),
+                "unlinkat".to_string(),
+                "renameat".to_string(),
+                "linkat".to_string(),
+                "symlinkat".to_string(),
+                "readlinkat".to_string(),
+                "fchmodat".to_string(),
+                "faccessat".to_string(),
+                "pselect6".to_string(),
+                "ppoll".to_string(),
+                "unshare".to_string(),
+                "set_robust_list".to_string(),
+                "get_robust_list".to_string(),
+                "splice".to_string(),
+                "tee".to_string(),
+                "sync_file_range".to_string(),
+                "vmsplice".to_string(),
+                "move_pages".to_string(),
+                "utimensat".to_string(),
+                "epoll_pwait".to_string(),
+                "signalfd".to_string(),
+                "timerfd_create".to_string(),
+                "eventfd".to_string(),
+                "fallocate".to_string(),
+                "timerfd_settime".to_string(),
+                "timerfd_gettime".to_string(),
+                "accept4".to_string(),
+                "signalfd4".to_string(),
+                "eventfd2".to_string(),
+                "epoll_create1".to_string(),
+                "dup3".to_string(),
+                "pipe2".to_string(),
+                "inotify_init1".to_string(),
+                "preadv".to_string(),
+                "pwritev".to_string(),
+                "rt_tgsigqueueinfo".to_string(),
+                "perf_event_open".to_string(),
+                "recvmmsg".to_string(),
+                "fanotify_init".to_string(),
+                "fanotify_mark".to_string(),
+                "prlimit64".to_string(),
+                "name_to_handle_at".to_string(),
+                "open_by_handle_at".to_string(),
+                "clock_adjtime".to_string(),
+                "syncfs".to_string(),
+                "sendmmsg".to_string(),
+                "setns".to_string(),
+                "getcpu".to_string(),
+                "process_vm_readv".to_string(),
+                "process_vm_writev".to_string(),
+                "kcmp".to_string(),
+                "finit_module".to_string(),
+                "sched_setattr".to_string(),
+                "sched_getattr".to_string(),
+                "renameat2".to_string(),
+                "seccomp".to_string(),
+                "getrandom".to_string(),
+                "memfd_create".to_string(),
+                "kexec_file_load".to_string(),
+                "bpf".to_string(),
+                "execveat".to_string(),
+                "userfaultfd".to_string(),
+                "membarrier".to_string(),
+                "mlock2".to_string(),
+                "copy_file_range".to_string(),
+                "preadv2".to_string(),
+                "pwritev2".to_string(),
+                "pkey_mprotect".to_string(),
+                "pkey_alloc".to_string(),
+                "pkey_free".to_string(),
+                "statx".to_string(),
+                "io_pgetevents".to_string(),
+                "rseq".to_string(),
+                "pidfd_send_signal".to_string(),
+                "io_uring_setup".to_string(),
+                "io_uring_enter".to_string(),
+                "io_uring_register".to_string(),
+                "open_tree".to_string(),
+                "move_mount".to_string(),
+                "fsopen".to_string(),
+                "fsconfig".to_string(),
+                "fsmount".to_string(),
+                "fspick".to_string(),
+                "pidfd_open".to_string(),
+                "clone3".to_string(),
+                "close_range".to_string(),
+                "openat2".to_string(),
+                "pidfd_getfd".to_string(),
+                "faccessat2".to_string(),
+                "process_madvise".to_string(),
+                "epoll_pwait2".to_string(),
+                "mount_setattr".to_string(),
+                "quotactl_fd".to_string(),
+                "landlock_create_ruleset".to_string(),
+                "landlock_add_rule".to_string(),
+                "landlock_restrict_self".to_string(),
+                "memfd_secret".to_string(),
+                "process_mrelease".to_string(),
+                "futex_waitv".to_string(),
+                "set_mempolicy_home_node".to_string(),
+            ],
+            denied_syscalls: [
+                "reboot", "kexec_load", "kexec_file_load",
+                "init_module", "finit_module", "delete_module", "create_module",
+                "swapon", "swapoff", "mount", "umount2", "pivot_root", "mount_setattr",
+                "open_tree", "move_mount", "fsopen", "fsconfig", "fsmount", "fspick",
+                "settimeofday", "clock_settime", "adjtimex", "clock_adjtime",
+                "sethostname", "setdomainname", "acct", "syslog", "quotactl", "quotactl_fd",
+                "iopl", "ioperm", "bpf", "perf_event_open", "open_by_handle_at",
+                "ptrace", "process_vm_readv", "process_vm_writev", "kcmp",
+                "add_key", "request_key", "keyctl",
+                "uselib", "_sysctl", "vhangup", "lookup_dcookie", "nfsservctl",
+            ].iter().map(|name| name.to_string()).collect(),
+            seccomp_mode: SeccompMode::AllowList,
+            seccomp_default_action: SeccompAction::KillProcess,
+            seccomp_overrides: HashMap::new(),
+            read_only_paths: Vec::new(),
+            writable_paths: Vec::new(),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_user(mut self, uid: u32, gid: u32) -> Self {
+        self.sandbox = self.sandbox.with_user(Uid::from_raw(uid), Gid::from_raw(gid));
+        self
+    }
+
+    pub fn with_user_namespace(mut self, mapping: IdMapping) -> Self {
+        self.sandbox = self.sandbox.with_user_namespace(mapping);
+        self
+    }
+
+    pub fn with_host_network(mut self) -> Self {
+        self.sandbox = self.sandbox.with_host_network();
+        self
+    }
+
+    // Seccomp and limits only, for a backend that can't unshare
+    pub fn without_namespaces(mut self) -> Self {
+        self.sandbox = self.sandbox.without_namespaces();
+        self
+    }
+
+    pub fn with_chroot(mut self, path: &str) -> Self {
+        self.sandbox = self.sandbox.with_chroot(path);
+        self
+    }
+
+    // Capabilities QEMU keeps after setup; everything else is dropped
+    pub fn with_capabilities(mut self, caps: &[Capability]) -> Self {
+        for cap in caps {
+            self.sandbox = self.sandbox.keep_capability(*cap);
+        }
+        self
+    }
+
+    pub fn with_seccomp_action(mut self, action: SeccompAction) -> Self {
+        self.seccomp_default_action = action;
+        self
+    }
+
+    pub fn with_seccomp_mode(mut self, mode: SeccompMode) -> Self {
+        self.seccomp_mode = mode;
+        self
+    }
+
+    // Takes precedence over both allowed_syscalls and the default action
+    pub fn with_syscall_action(mut self, syscall: &str, action: SeccompAction) -> Self {
+        self.seccomp_overrides.insert(syscall.to_string(), action);
+        self
+    }
+
+    pub fn add_denied_syscall(mut self, syscall: &str) -> Self {
+        self.denied_syscalls.push(syscall.to_string());
+        self
+    }
+
+    // Action the filter should take for `syscall`
+    pub fn seccomp_action_for(&self, syscall: &str) -> SeccompAction {
+        if let Some(action) = self.seccomp_overrides.get(syscall) {
+            *action
+        } else if self.denied_syscalls.iter().any(|s| s == syscall) {
+            self.seccomp_default_action
+        } else if self.seccomp_mode == SeccompMode::DenyList || self.allowed_syscalls.iter().any(|s| s == syscall) {
+            SeccompAction::Allow
+        } else {
+            self.seccomp_default_action
+        }
+    }
+
+    // Compiles the rules into the filter VMSandbox::apply loads
+    pub fn seccomp_filter(&self) -> Result<SeccompFilter, IsolationError> {
+        self.seccomp_default_action.validate()?;
+        for action in self.seccomp_overrides.values() {
+            action.validate()?;
+        }
+
+        let filter_default = match self.seccomp_mode {
+            SeccompMode::AllowList => self.seccomp_default_action,
+            SeccompMode::DenyList => SeccompAction::Allow,
+        };
+        // libseccomp rejects rules that repeat the filter's default
+        let names: BTreeSet<&String> = self.allowed_syscalls.iter()
+            .chain(&self.denied_syscalls)
+            .chain(self.seccomp_overrides.keys())
+            .collect();
+        let rules: Vec<(String, SeccompAction)> = names.into_iter()
+            .map(|name| (name.clone(), self.seccomp_action_for(name)))
+            .filter(|(_, action)| *action != filter_default)
+            .collect();
+
+        let filter = SeccompFilter::compile(filter_default, &rules)?;
+        log::info!(
+            "Seccomp filter: {:?} mode, {} rules, default {:?}",
+            self.seccomp_mode, rules.len(), filter_default,
+        );
+        Ok(filter)
+    }
+
+    pub fn add_allowed_device(mut self, device: &str) -> Self {
+        self.allowed_devices.push(device.to_string());
+        self
+    }
+
+    pub fn add_read_only_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.read_only_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn add_writable_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.writable_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    // Writable paths missing on the host stay plain directories in the
+    // sandbox's root instead of binds
+    pub fn build(self) -> Result<VMSandbox, IsolationError> {
+        let filter = self.seccomp_filter()?;
+        let mut sandbox = self.sandbox.with_seccomp(filter);
+        for path in &self.read_only_paths {
+            if path.exists() {
+                sandbox = sandbox.with_bind_mount(path, true);
+            }
+        }
+        for path in &self.writable_paths {
+            if path.exists() {
+                sandbox = sandbox.with_bind_mount(path, false);
+            }
+        }
+        Ok(sandbox)
+    }
+
+    pub fn setup_vm_environment(&mut self, vm_id: &str, base_path: &Path) -> Result<(), IsolationError> {
+        // Create VM directory structure
+        VMSandbox::create_vm_directory(vm_id, base_path)?;
+
+        let vm_path = base_path.join(vm_id);
+        
+        // Setup device nodes
+        self.setup_devices(&vm_path)?;
+        
+        // Setup filesystem
+        self.setup_filesystem(&vm_path)?;
+
+        Ok(())
+    }
+
+    // Each node goes at its host path under the root the sandbox chroots
+    // into, so /dev/net/tun stays /dev/net/tun
+    fn setup_devices(&self, vm_path: &Path) -> Result<(), IsolationError> {
+        let root = vm_path.join("root");
+        
+        for device in &self.allowed_devices {
+            let source = Path::new(device);
+            let metadata = match fs::metadata(source) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if !metadata.file_type().is_char_device() {
+                return Err(IsolationError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a character device", device),
+                )));
+            }
+            
+            let dest = root.join(source.strip_prefix("/").unwrap_or(source));
+            fs::create_dir_all(dest.parent().unwrap())?;
+            
+            // Left over from an earlier setup, possibly still bind-mounted
+            // by the fallback below
+            let _ = umount2(&dest, MntFlags::MNT_DETACH);
+            match fs::remove_file(&dest) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            create_device_node(source, &dest, &metadata)?;
+        }
+
+        Ok(())
+    }
+
+    fn setup_filesystem(&self, vm_path: &Path) -> Result<(), IsolationError> {
+        // Create necessary directories
+        let root = vm_path.join("root");
+        
+        // Mount points for the read-only binds VMSandbox::apply makes;
+        // a file can only be bound onto a file
+        for path in &self.read_only_paths {
+            if path.exists() {
+                let dest = root.join(path.strip_prefix("/").unwrap_or(path));
+                if path.is_dir() {
+                    fs::create_dir_all(&dest)?;
+                } else {
+                    fs::create_dir_all(dest.parent().unwrap())?;
+                    if !dest.exists() {
+                        fs::File::create(&dest)?;
+                    }
+                }
+            }
+        }
+        
+        // Setup writable directories, which double as mount points for
+        // the ones that exist on the host
+        for path in &self.writable_paths {
+            let dest = root.join(path.strip_prefix("/").unwrap_or(path));
+            fs::create_dir_all(&dest)?;
+            
+            // Set permissions
+            let mut perms = fs::metadata(&dest)?.permissions();
+            perms.set_mode(0o755); // rwxr-xr-x
+            fs::set_permissions(&dest, perms)?;
+        }
+
+        Ok(())
+    }
+
+    // Creates the VM's cgroups and sets their limits (QEMU joins them in
+    // VMSandbox::apply). The disk limit goes on the disk holding
+    // `data_path`. Call before build(), which
+    // carries the groups over. Limits that can't be set are an error, not a
+    // no-op.
+    pub fn apply_resource_limits(&mut self, vm_id: &str, data_path: &Path) -> Result<(), IsolationError> {
+        if !privileges().cgroups_writable {
+            return Err(IsolationError::Cgroup(format!("{} is not writable", CGROUP_ROOT)));
+        }
+
+        let disk = match self.limits.disk_limit_mb {
+            0 => None,
+            _ => {
+                let disk = block_device(data_path);
+                if disk.is_none() {
+                    log::warn!("No block device behind {}; disk throughput isn't limited", data_path.display());
+                }
+                disk
+            }
+        };
+
+        let groups = match cgroup_version() {
+            CgroupVersion::V2 => vec![self.cgroup_v2(vm_id, disk)?],
+            CgroupVersion::V1 => self.cgroup_v1(vm_id, disk)?,
+        };
+        self.sandbox.cgroups.extend(groups);
+
+        Ok(())
+    }
+
+    fn cgroup_v2(&self, vm_id: &str, disk: Option<(u64, u64)>) -> Result<PathBuf, IsolationError> {
+        let parent = Path::new(CGROUP_ROOT).join(CGROUP_PARENT);
+        fs::create_dir_all(&parent)?;
+
+        // Only controllers the parent was given can be passed down to VMs
+        let mut controllers = vec!["memory", "cpu"];
+        if disk.is_some() {
+            controllers.push("io");
+        }
+        let available = fs::read_to_string(parent.join("cgroup.controllers"))?;
+        if let Some(missing) = controllers.iter().find(|c| !available.split_whitespace().any(|a| a == **c)) {
+            return Err(IsolationError::Cgroup(format!(
+                "the {} controller isn't delegated to {}", missing, parent.display()
+            )));
+        }
+        let enable = controllers.iter().map(|c| format!("+{}", c)).collect::<Vec<_>>().join(" ");
+        write_cgroup(&parent, "cgroup.subtree_control", &enable)?;
+
+        let group = parent.join(vm_id);
+        fs::create_dir_all(&group)?;
+
+        // Written even when unlimited, to clear what an earlier setup set
+        let memory = match self.limits.memory_limit_mb {
+            0 => "max".to_string(),
+            mb => (mb * 1024 * 1024).to_string(),
+        };
+        write_cgroup(&group, "memory.max", &memory)?;
+
+        let cpu = match self.limits.cpu_limit_percent {
+            0 => format!("max {}", CPU_PERIOD_US),
+            percent => format!("{} {}", percent as u64 * CPU_PERIOD_US / 100, CPU_PERIOD_US),
+        };
+        write_cgroup(&group, "cpu.max", &cpu)?;
+
+        if let Some((major, minor)) = disk {
+            let bps = self.limits.disk_limit_mb * 1024 * 1024;
+            write_cgroup(&group, "io.max", &format!("{}:{} rbps={} wbps={}", major, minor, bps, bps))?;
+        }
+
+        Ok(group)
+    }
+
+    fn cgroup_v1(&self, vm_id: &str, disk: Option<(u64, u64)>) -> Result<Vec<PathBuf>, IsolationError> {
+        let group = |controller: &str| -> Result<PathBuf, IsolationError> {
+            let hierarchy = Path::new(CGROUP_ROOT).join(controller);
+            if !hierarchy.is_dir() {
+                return Err(IsolationError::Cgroup(format!(
+                    "the {} controller isn't mounted at {}", controller, hierarchy.display()
+                )));
+            }
+            let path = hierarchy.join(CGROUP_PARENT).join(vm_id);
+            fs::create_dir_all(&path)?;
+            Ok(path)
+        };
+
+        let memory = group("memory")?;
+        let memory_limit = match self.limits.memory_limit_mb {
+            0 => "-1".to_string(),
+            mb => (mb * 1024 * 1024).to_string(),
+        };
+        write_cgroup(&memory, "memory.limit_in_bytes", &memory_limit)?;
+
+        let cpu = group("cpu")?;
+        write_cgroup(&cpu, "cpu.cfs_period_us", &CPU_PERIOD_US.to_string())?;
+        let quota = match self.limits.cpu_limit_percent {
+            0 => "-1".to_string(),
+            percent => (percent as u64 * CPU_PERIOD_US / 100).to_string(),
+        };
+        write_cgroup(&cpu, "cpu.cfs_quota_us", &quota)?;
+
+        let mut groups = vec![memory, cpu];
+        if let Some((major, minor)) = disk {
+            let blkio = group("blkio")?;
+            let limit = format!("{}:{} {}", major, minor, self.limits.disk_limit_mb * 1024 * 1024);
+            write_cgroup(&blkio, "blkio.throttle.read_bps_device", &limit)?;
+            write_cgroup(&blkio, "blkio.throttle.write_bps_device", &limit)?;
+            groups.push(blkio);
+        }
+
+        Ok(groups)
+    }
+
+}
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    // Loads `filter` in a forked child, runs `call` there and exits with
+    // its result; returns the child's wait status
+    fn run_filtered(filter: &SeccompFilter, call: fn() -> libc::c_int) -> libc::c_int {
+        unsafe {
+            match libc::fork() {
+                -1 => panic!("fork failed: {}", io::Error::last_os_error()),
+                0 => {
+                    if filter.load().is_err() {
+                        libc::_exit(100);
+                    }
+                    libc::_exit(call());
+                }
+                pid => {
+                    let mut status = 0;
+                    assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                    status
+                }
+            }
+        }
+    }
+
+    // An invalid magic, so reboot(2) fails even unfiltered; 0 if it got
+    // that far
+    fn call_reboot() -> libc::c_int {
+        unsafe { libc::syscall(libc::SYS_reboot, 0, 0, 0, std::ptr::null::<libc::c_void>()) };
+        0
+    }
+
+    fn call_getpid() -> libc::c_int {
+        if unsafe { libc::getpid() } > 0 { 0 } else { 1 }
+    }
+
+    #[test]
+    fn denied_syscall_kills_the_process() {
+        let filter = VMSandboxBuilder::new()
+            .with_seccomp_mode(SeccompMode::DenyList)
+            .add_denied_syscall("reboot")
+            .seccomp_filter()
+            .unwrap();
+
+        let status = run_filtered(&filter, call_reboot);
+        assert!(libc::WIFSIGNALED(status), "reboot wasn't blocked (status {:#x})", status);
+        assert_eq!(libc::WTERMSIG(status), libc::SIGSYS);
+
+        let status = run_filtered(&filter, call_getpid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+
+    #[test]
+    fn allow_list_blocks_what_it_doesnt_list() {
+        let filter = VMSandboxBuilder::new().seccomp_filter().unwrap();
+        assert_eq!(VMSandboxBuilder::new().seccomp_action_for("reboot"), SeccompAction::KillProcess);
+
+        let status = run_filtered(&filter, call_reboot);
+        assert!(libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSYS);
+    }
+
+    #[test]
+    fn errno_action_fails_the_call_instead() {
+        fn call_reboot_errno() -> libc::c_int {
+            let result = unsafe { libc::syscall(libc::SYS_reboot, 0, 0, 0, std::ptr::null::<libc::c_void>()) };
+            if result == -1 && io::Error::last_os_error().raw_os_error() == Some(libc::EDOM) { 0 } else { 1 }
+        }
+        let filter = VMSandboxBuilder::new()
+            .with_seccomp_mode(SeccompMode::DenyList)
+            .with_syscall_action("reboot", SeccompAction::Errno(libc::EDOM))
+            .seccomp_filter()
+            .unwrap();
+
+        let status = run_filtered(&filter, call_reboot_errno);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "status {:#x}", status);
+    }
+
+    #[test]
+    fn device_node_matches_the_host_device() {
+        let dir = std::env::temp_dir().join(format!("aegis-devnode-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("null");
+        let source = fs::metadata("/dev/null").unwrap();
+
+        match create_device_node(Path::new("/dev/null"), &dest, &source) {
+            Ok(()) => {
+                let node = fs::metadata(&dest).unwrap();
+                assert!(node.file_type().is_char_device());
+                assert_eq!(node.rdev(), source.rdev());
+                assert_eq!(node.permissions().mode() & 0o7777, source.permissions().mode() & 0o7777);
+                // Reads end right away instead of copying /dev/null's "contents"
+                assert_eq!(fs::read(&dest).unwrap().len(), 0);
+                let _ = umount2(&dest, MntFlags::MNT_DETACH);
+            }
+            // Neither CAP_MKNOD nor CAP_SYS_ADMIN here
+            Err(e) => eprintln!("skipping: {}", e),
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn out_of_range_errno_is_refused() {
+        let builder = VMSandboxBuilder::new().with_syscall_action("reboot", SeccompAction::Errno(0));
+        assert!(builder.seccomp_filter().is_err());
+    }
 }
\ No newline at end of file
@@ -1,209 +1,851 @@
-use std::path::{Path, PathBuf};
-use std::ffi::OsStr;
-use regex::Regex;
-use blake3::Hasher;
-
-use crate::vm::config::{CreateVMRequest, UpdateVMRequest};
-
-#[derive(Debug, thiserror::Error)]
-pub enum ValidationError {
-    #[error("Invalid VM name: {0}")]
-    InvalidName(String),
-    #[error("Invalid ISO path: {0}")]
-    InvalidIsoPath(String),
-    #[error("Invalid memory size: {0} MB (must be between 256 and 32768)")]
-    InvalidMemory(u32),
-    #[error("Invalid CPU cores: {0} (must be between 1 and 16)")]
-    InvalidCpu(u32),
-    #[error("Invalid disk size: {0} GB (must be between 10 and 1000)")]
-    InvalidDisk(u32),
-    #[error("Invalid VNC port: {0} (must be between 5900 and 5999)")]
-    InvalidVncPort(u16),
-    #[error("Path contains invalid characters or traversal attempts: {0}")]
-    InvalidPath(String),
-    #[error("ISO file hash mismatch")]
-    IsoHashMismatch,
-    #[error("ISO file too large (max 10GB)")]
-    IsoTooLarge,
-    #[error("Command injection attempt detected")]
-    CommandInjection,
-}
-
-pub fn validate_vm_config(config: &CreateVMRequest) -> Result<(), ValidationError> {
-    // Validate VM name
-    validate_vm_name(&config.name)?;
-    
-    // Validate ISO path
-    validate_iso_path(&config.iso_path)?;
-    
-    // Validate resource limits
-    validate_memory(config.memory_mb)?;
-    validate_cpu(config.cpu_cores)?;
-    validate_disk(config.disk_size_gb)?;
-    
-    Ok(())
-}
-
-pub fn validate_vm_name(name: &str) -> Result<(), ValidationError> {
-    let name_regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_-]{1,31}$").unwrap();
-    
-    if !name_regex.is_match(name) {
-        return Err(ValidationError::InvalidName(
-            "Name must be 2-32 characters, start with alphanumeric, and contain only a-z, A-Z, 0-9, _, -".to_string()
-        ));
-    }
-    
-    // Check for reserved names
-    let reserved = vec!["none", "null", "all", "default", "system"];
-    if reserved.contains(&name.to_lowercase().as_str()) {
-        return Err(ValidationError::InvalidName(
-            "Name is reserved".to_string()
-        ));
-    }
-    
-    Ok(())
-}
-
-pub fn validate_iso_path(path: &str) -> Result<(), ValidationError> {
-    let path = Path::new(path);
-    
-    // Check for path traversal attempts
-    if path.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
-        return Err(ValidationError::InvalidPath(
-            "Path contains parent directory traversal".to_string()
-        ));
-    }
-    
-    // Check file extension
-    let extension = path.extension()
-        .and_then(OsStr::to_str)
-        .unwrap_or("")
-        .to_lowercase();
-    
-    let valid_extensions = vec!["iso", "img", "qcow2", "raw"];
-    if !valid_extensions.contains(&extension.as_str()) {
-        return Err(ValidationError::InvalidIsoPath(
-            format!("Invalid file extension: .{} (must be .iso, .img, .qcow2, or .raw)", extension)
-        ));
-    }
-    
-    // Check if file exists (for local paths)
-    if !path.exists() && !path.starts_with("http://") && !path.starts_with("https://") {
-        return Err(ValidationError::InvalidIsoPath(
-            "File does not exist".to_string()
-        ));
-    }
-    
-    // Check file size if it exists
-    if path.exists() {
-        if let Ok(metadata) = std::fs::metadata(path) {
-            let size_gb = metadata.len() as f64 / (1024.0 * 1024.0 * 1024.0);
-            if size_gb > 10.0 {
-                return Err(ValidationError::IsoTooLarge);
-            }
-        }
-    }
-    
-    Ok(())
-}
-
-pub fn validate_memory(memory_mb: u32) -> Result<(), ValidationError> {
-    if memory_mb < 256 || memory_mb > 32768 {
-        Err(ValidationError::InvalidMemory(memory_mb))
-    } else {
-        Ok(())
-    }
-}
-
-pub fn validate_cpu(cpu_cores: u32) -> Result<(), ValidationError> {
-    if cpu_cores < 1 || cpu_cores > 16 {
-        Err(ValidationError::InvalidCpu(cpu_cores))
-    } else {
-        Ok(())
-    }
-}
-
-pub fn validate_disk(disk_gb: u32) -> Result<(), ValidationError> {
-    if disk_gb < 10 || disk_gb > 1000 {
-        Err(ValidationError::InvalidDisk(disk_gb))
-    } else {
-        Ok(())
-    }
-}
-
-pub fn validate_vnc_port(port: u16) -> Result<(), ValidationError> {
-    if port < 5900 || port > 5999 {
-        Err(ValidationError::InvalidVncPort(port))
-    } else {
-        Ok(())
-    }
-}
-
-pub fn sanitize_command(input: &str) -> Result<String, ValidationError> {
-    // Check for command injection attempts
-    let dangerous_patterns = vec![
-        (";", "semicolon"),
-        ("&&", "double ampersand"),
-        ("||", "double pipe"),
-        ("`", "backtick"),
-        ("$(", "command substitution"),
-        ("|", "pipe"),
-        (">", "output redirect"),
-        ("<", "input redirect"),
-        ("&", "background process"),
-    ];
-    
-    for (pattern, name) in dangerous_patterns {
-        if input.contains(pattern) {
-            return Err(ValidationError::CommandInjection);
-        }
-    }
-    
-    // Remove any non-printable characters
-    let sanitized: String = input.chars()
-        .filter(|c| c.is_ascii_graphic() || c.is_whitespace())
-        .collect();
-    
-    Ok(sanitized.trim().to_string())
-}
-
-pub fn calculate_file_hash(path: &Path) -> Result<String, ValidationError> {
-    let mut hasher = Hasher::new();
-    let mut file = std::fs::File::open(path)
-        .map_err(|_| ValidationError::InvalidIsoPath("Cannot open file".to_string()))?;
-    
-    std::io::copy(&mut file, &mut hasher)
-        .map_err(|_| ValidationError::InvalidIsoPath("Cannot read file".to_string()))?;
-    
-    Ok(hasher.finalize().to_hex().to_string())
-}
-
-pub fn validate_iso_hash(path: &Path, expected_hash: &str) -> Result<(), ValidationError> {
-    let actual_hash = calculate_file_hash(path)?;
-    
-    if actual_hash != expected_hash {
-        Err(ValidationError::IsoHashMismatch)
-    } else {
-        Ok(())
-    }
-}
-
-pub fn validate_network_config(bridge: &str, subnet: &str) -> Result<(), ValidationError> {
-    // Validate bridge name
-    let bridge_regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_-]{0,15}$").unwrap();
-    if !bridge_regex.is_match(bridge) {
-        return Err(ValidationError::InvalidPath(
-            "Invalid bridge name".to_string()
-        ));
-    }
-    
-    // Validate subnet
-    let subnet_regex = Regex::new(r"^\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}/\d{1,2}$").unwrap();
-    if !subnet_regex.is_match(subnet) {
-        return Err(ValidationError::InvalidPath(
-            "Invalid subnet format (expected CIDR notation)".to_string()
-        ));
-    }
-    
-    Ok(())
+use std::path::{Path, PathBuf};
+use std::ffi::OsStr;
+use std::sync::{OnceLock, RwLock};
+use regex::Regex;
+use blake3::Hasher;
+
+use crate::storage::disks::Preallocation;
+use crate::utils::ports::port_ranges;
+use crate::vm::config::{
+    Accelerator, BiosType, CreateVMRequest, DiskFormat, GuestArch, IdleSuspendPolicy, NetworkType, PortForward, SnapshotPolicy,
+    UpdateVMRequest,
+};
+use crate::vm::networking::{parse_cidr, NetworkError};
+use crate::vm::qemu::{find_in_path, managed_flag, qemu_caps_for, MachineLayout, MANAGED_FLAGS};
+
+pub const MIN_MEMORY_MB: u32 = 256;
+pub const MAX_MEMORY_MB: u32 = 32768;
+pub const MIN_CPU_CORES: u32 = 1;
+pub const MAX_CPU_CORES: u32 = 16;
+pub const MIN_DISK_GB: u32 = 10;
+pub const MAX_DISK_GB: u32 = 1000;
+pub const MIN_SNAPSHOT_INTERVAL_MINUTES: u32 = 5;
+pub const MIN_IDLE_SUSPEND_MINUTES: u32 = 5;
+pub const MAX_NICS: usize = 8;
+pub const ISO_EXTENSIONS: &[&str] = &["iso", "img", "qcow2", "raw"];
+
+#[derive(Debug, Clone)]
+pub struct ValidationConfig {
+    pub iso_dir: PathBuf,
+    // Extra directories (e.g. a shared NFS library) ISOs may be used from
+    pub allowed_iso_roots: Vec<PathBuf>,
+    // Golden images new VMs may be thin-cloned from
+    pub base_image_dir: PathBuf,
+    // Where existing disk images may be imported from
+    pub import_dir: PathBuf,
+    pub allowed_import_roots: Vec<PathBuf>,
+    // When set, machine/CPU models missing from QEMU's help output are rejected
+    // outright instead of only when they look like a typo of a known model
+    pub strict_qemu_validation: bool,
+    // Largest ISO accepted from disk, upload or download, in bytes
+    pub max_iso_size: u64,
+}
+
+pub const DEFAULT_MAX_ISO_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            iso_dir: PathBuf::from("/var/lib/vm-manager/isos"),
+            allowed_iso_roots: Vec::new(),
+            base_image_dir: PathBuf::from("/var/lib/vm-manager/bases"),
+            import_dir: PathBuf::from("/var/lib/vm-manager/imports"),
+            allowed_import_roots: Vec::new(),
+            strict_qemu_validation: false,
+            max_iso_size: DEFAULT_MAX_ISO_SIZE,
+        }
+    }
+}
+
+static VALIDATION_CONFIG: OnceLock<RwLock<ValidationConfig>> = OnceLock::new();
+
+fn config_lock() -> &'static RwLock<ValidationConfig> {
+    VALIDATION_CONFIG.get_or_init(|| RwLock::new(ValidationConfig::default()))
+}
+
+pub fn set_validation_config(config: ValidationConfig) {
+    *config_lock().write().unwrap() = config;
+}
+
+pub fn validation_config() -> ValidationConfig {
+    config_lock().read().unwrap().clone()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ValidationError {
+    #[error("Invalid VM name: {0}")]
+    InvalidName(String),
+    #[error("Invalid ISO path: {0}")]
+    InvalidIsoPath(String),
+    #[error("Invalid memory size: {0} MB (must be between {} and {})", MIN_MEMORY_MB, MAX_MEMORY_MB)]
+    InvalidMemory(u32),
+    #[error("Invalid CPU cores: {0} (must be between {} and {})", MIN_CPU_CORES, MAX_CPU_CORES)]
+    InvalidCpu(u32),
+    #[error("Invalid disk size: {0} GB (must be between {} and {})", MIN_DISK_GB, MAX_DISK_GB)]
+    InvalidDisk(u32),
+    #[error("Invalid disk option: {0}")]
+    InvalidDiskOption(String),
+    #[error("Invalid base image: {0}")]
+    InvalidBaseImage(String),
+    #[error("Invalid import disk: {0}")]
+    InvalidImportDisk(String),
+    #[error("Invalid architecture: {0}")]
+    InvalidArch(String),
+    #[error("Invalid accelerator: {0}")]
+    InvalidAccel(String),
+    #[error("Invalid machine type: {0}")]
+    InvalidMachineType(String),
+    #[error("Invalid CPU type: {0}")]
+    InvalidCpuType(String),
+    #[error("Invalid subnet: {0}")]
+    InvalidSubnet(String),
+    #[error("Invalid snapshot schedule: {0}")]
+    InvalidSnapshotPolicy(String),
+    #[error("Invalid snapshot name: {0}")]
+    InvalidSnapshotName(String),
+    #[error("Invalid volume: {0}")]
+    InvalidVolume(String),
+    #[error("Invalid idle suspend policy: {0}")]
+    InvalidIdleSuspendPolicy(String),
+    #[error("Invalid network configuration: {0}")]
+    InvalidNetwork(String),
+    #[error("Invalid MAC address: {0}")]
+    InvalidMacAddress(String),
+    #[error("Invalid port forward: {0}")]
+    InvalidPortForward(String),
+    #[error("Invalid VNC password: {0}")]
+    InvalidVncPassword(String),
+    #[error("Path contains invalid characters or traversal attempts: {0}")]
+    InvalidPath(String),
+    #[error("ISO file hash mismatch")]
+    IsoHashMismatch,
+    #[error("ISO file too large ({size} bytes, max {max} bytes)")]
+    IsoTooLarge { size: u64, max: u64 },
+    #[error("extra_args can't set {flag}; these are managed: {reserved}")]
+    ReservedQemuFlag { flag: String, reserved: String },
+}
+
+pub fn validate_vm_config(config: &CreateVMRequest) -> Result<(), ValidationError> {
+    check_vm_config(config, config.import_disk.is_some() || config.base_image.is_some())
+}
+
+// A VM coming out of an export bundle, as the request that would recreate
+// it; its disk comes with it, so the ISO is optional
+pub fn validate_bundle_config(config: &CreateVMRequest) -> Result<(), ValidationError> {
+    check_vm_config(config, true)
+}
+
+fn check_vm_config(config: &CreateVMRequest, has_os_disk: bool) -> Result<(), ValidationError> {
+    // Validate VM name
+    validate_vm_name(&config.name)?;
+    
+    // Validate ISO path; optional when the disk already holds an OS
+    if !(config.iso_path.is_empty() && has_os_disk) {
+        validate_iso_path(&config.iso_path)?;
+    }
+    
+    // Validate resource limits
+    validate_memory(config.memory_mb)?;
+    validate_cpu(config.cpu_cores)?;
+    validate_disk(config.disk_size_gb)?;
+    
+    if let Some(password) = &config.vnc_password {
+        validate_vnc_password(password)?;
+    }
+    
+    // Validate disk options
+    if config.discard == Some(true) {
+        let format = config.disk_format.clone().unwrap_or_default();
+        validate_discard(&format)?;
+    }
+    
+    if let Some(base) = &config.base_image {
+        validate_base_image(base)?;
+        if !matches!(config.disk_format.clone().unwrap_or_default(), DiskFormat::Qcow2) {
+            return Err(ValidationError::InvalidDiskOption("base_image requires a qcow2 disk".to_string()));
+        }
+        // qemu-img refuses to preallocate an image with a backing file
+        let preallocated = config.disk_options.as_ref()
+            .and_then(|options| options.preallocation)
+            .is_some_and(|mode| mode != Preallocation::Off);
+        if preallocated {
+            return Err(ValidationError::InvalidDiskOption(
+                "preallocation can't be combined with base_image".to_string()
+            ));
+        }
+    }
+    
+    if let Some(source) = &config.import_disk {
+        if config.base_image.is_some() {
+            return Err(ValidationError::InvalidDiskOption(
+                "import_disk can't be combined with base_image".to_string()
+            ));
+        }
+        validate_import_disk(source)?;
+    }
+    
+    let encrypted = config.disk_options.as_ref().is_some_and(|options| options.encrypted);
+    if encrypted {
+        if !matches!(config.disk_format.clone().unwrap_or_default(), DiskFormat::Qcow2) {
+            return Err(ValidationError::InvalidDiskOption("encryption requires a qcow2 disk".to_string()));
+        }
+        if config.import_disk.is_some() {
+            return Err(ValidationError::InvalidDiskOption(
+                "import_disk can't be combined with encryption".to_string()
+            ));
+        }
+    }
+    if let Some(passphrase) = &config.disk_passphrase {
+        if !encrypted {
+            return Err(ValidationError::InvalidDiskOption(
+                "disk_passphrase requires disk_options.encrypted".to_string()
+            ));
+        }
+        validate_disk_passphrase(passphrase)?;
+    }
+    
+    if let Some(policy) = &config.snapshot_schedule {
+        validate_snapshot_policy(policy, &config.disk_format.clone().unwrap_or_default())?;
+    }
+    
+    if let Some(policy) = &config.idle_suspend {
+        validate_idle_suspend_policy(policy)?;
+    }
+    
+    if let Some(extra_args) = &config.extra_args {
+        validate_extra_args(extra_args)?;
+    }
+    
+    validate_networks(config)?;
+    
+    let arch = config.arch.unwrap_or_default();
+    validate_arch(arch, config.bios.as_ref())?;
+    validate_accel(arch, config.accel.unwrap_or_default(), config.cpu_type.as_deref())?;
+    
+    // Validate machine and CPU models against what the installed QEMU offers
+    if let Some(caps) = qemu_caps_for(arch) {
+        let strict = validation_config().strict_qemu_validation;
+        if let Some(machine) = &config.machine_type {
+            validate_machine_type(machine, &caps.machines, strict)?;
+            // The q35 and virt layouts hang every device off a pcie-root-port
+            if MachineLayout::for_machine(machine).is_pcie() && !caps.has_device("pcie-root-port") {
+                return Err(ValidationError::InvalidMachineType(format!(
+                    "{} needs pcie-root-port, which requires QEMU >= 2.9 (installed: {})",
+                    machine, caps.version_string()
+                )));
+            }
+        }
+        if let Some(cpu) = &config.cpu_type {
+            validate_cpu_type(cpu, &caps.cpus, strict)?;
+        }
+    }
+    
+    Ok(())
+}
+
+pub fn validate_vm_update(update: &UpdateVMRequest) -> Result<(), ValidationError> {
+    if let Some(name) = &update.name {
+        validate_vm_name(name)?;
+    }
+    if let Some(memory_mb) = update.memory_mb {
+        validate_memory(memory_mb)?;
+    }
+    if let Some(cpu_cores) = update.cpu_cores {
+        validate_cpu(cpu_cores)?;
+    }
+    if let Some(password) = &update.vnc_password {
+        validate_vnc_password(password)?;
+    }
+    if let Some(extra_args) = &update.extra_args {
+        validate_extra_args(extra_args)?;
+    }
+    if let Some(Some(policy)) = &update.snapshot_schedule {
+        // The disk format isn't known here; the manager checks it against the VM
+        validate_snapshot_policy(policy, &DiskFormat::Qcow2)?;
+    }
+    if let Some(Some(policy)) = &update.idle_suspend {
+        validate_idle_suspend_policy(policy)?;
+    }
+    
+    Ok(())
+}
+
+pub fn validate_snapshot_policy(policy: &SnapshotPolicy, format: &DiskFormat) -> Result<(), ValidationError> {
+    if !matches!(format, DiskFormat::Qcow2) {
+        return Err(ValidationError::InvalidSnapshotPolicy(
+            format!("scheduled snapshots need a qcow2 disk, not .{}", format.extension())
+        ));
+    }
+    if policy.interval_minutes < MIN_SNAPSHOT_INTERVAL_MINUTES {
+        return Err(ValidationError::InvalidSnapshotPolicy(
+            format!("interval_minutes must be at least {}", MIN_SNAPSHOT_INTERVAL_MINUTES)
+        ));
+    }
+    if policy.keep == 0 {
+        return Err(ValidationError::InvalidSnapshotPolicy("keep must be at least 1".to_string()));
+    }
+    
+    Ok(())
+}
+
+// The manager builds these options from the config; passing them again
+// would put them on the command line twice
+pub fn validate_extra_args(extra_args: &[String]) -> Result<(), ValidationError> {
+    match managed_flag(extra_args) {
+        Some(flag) => Err(ValidationError::ReservedQemuFlag {
+            flag: flag.to_string(),
+            reserved: MANAGED_FLAGS.join(", "),
+        }),
+        None => Ok(()),
+    }
+}
+
+pub fn validate_idle_suspend_policy(policy: &IdleSuspendPolicy) -> Result<(), ValidationError> {
+    if policy.idle_minutes < MIN_IDLE_SUSPEND_MINUTES {
+        return Err(ValidationError::InvalidIdleSuspendPolicy(
+            format!("idle_minutes must be at least {}", MIN_IDLE_SUSPEND_MINUTES)
+        ));
+    }
+    if !(policy.cpu_percent > 0.0 && policy.cpu_percent <= 100.0) {
+        return Err(ValidationError::InvalidIdleSuspendPolicy(
+            "cpu_percent must be above 0 and at most 100".to_string()
+        ));
+    }
+    
+    Ok(())
+}
+
+pub fn validate_vm_name(name: &str) -> Result<(), ValidationError> {
+    let name_regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_-]{1,31}$").unwrap();
+    
+    if !name_regex.is_match(name) {
+        return Err(ValidationError::InvalidName(
+            "Name must be 2-32 characters, start with alphanumeric, and contain only a-z, A-Z, 0-9, _, -".to_string()
+        ));
+    }
+    
+    // Check for reserved names
+    let reserved = ["none", "null", "all", "default", "system"];
+    if reserved.contains(&name.to_lowercase().as_str()) {
+        return Err(ValidationError::InvalidName(
+            "Name is reserved".to_string()
+        ));
+    }
+    
+    Ok(())
+}
+
+// Names end up in qemu-img arguments, HMP command lines and the
+// whitespace-separated snapshot table
+pub fn validate_snapshot_name(name: &str) -> Result<(), ValidationError> {
+    let name_regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_.-]{0,63}$").unwrap();
+    
+    if !name_regex.is_match(name) {
+        return Err(ValidationError::InvalidSnapshotName(
+            "Name must be 1-64 characters, start with alphanumeric, and contain only a-z, A-Z, 0-9, _, ., -".to_string()
+        ));
+    }
+    
+    // QEMU looks snapshots up by ID as well as by name
+    if name.chars().all(|c| c.is_ascii_digit()) {
+        return Err(ValidationError::InvalidSnapshotName(
+            "Name can't be all digits; those are snapshot IDs".to_string()
+        ));
+    }
+    
+    Ok(())
+}
+
+// Volume names end up in file names and QEMU drive ids
+pub fn validate_volume_name(name: &str) -> Result<(), ValidationError> {
+    let name_regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_-]{0,31}$").unwrap();
+    
+    if !name_regex.is_match(name) {
+        return Err(ValidationError::InvalidVolume(
+            "Name must be 1-32 characters, start with alphanumeric, and contain only a-z, A-Z, 0-9, _, -".to_string()
+        ));
+    }
+    
+    Ok(())
+}
+
+pub fn validate_networks(config: &CreateVMRequest) -> Result<(), ValidationError> {
+    if config.network_type.is_some() && !config.networks.is_empty() {
+        return Err(ValidationError::InvalidNetwork("give either network_type or networks, not both".to_string()));
+    }
+    
+    let networks = config.interfaces();
+    if networks.len() > MAX_NICS {
+        return Err(ValidationError::InvalidNetwork(format!("at most {} NICs are supported", MAX_NICS)));
+    }
+    
+    let mut macs: Vec<String> = Vec::new();
+    for nic in &networks {
+        if matches!(nic.network_type, NetworkType::None) {
+            return Err(ValidationError::InvalidNetwork(
+                "None isn't a NIC; leave it out of networks instead".to_string()
+            ));
+        }
+        if let Some(mac) = &nic.mac_address {
+            validate_mac_address(mac)?;
+            let mac = mac.to_ascii_lowercase();
+            if macs.contains(&mac) {
+                return Err(ValidationError::InvalidMacAddress(format!("{} is used by more than one NIC", mac)));
+            }
+            macs.push(mac);
+        }
+    }
+    
+    // Duplicates across NICs collide on the host just the same
+    let forwards: Vec<PortForward> = networks.iter()
+        .flat_map(|nic| nic.network_type.port_forwards().iter().cloned())
+        .collect();
+    validate_port_forwards(&forwards)
+}
+
+pub fn validate_mac_address(mac: &str) -> Result<(), ValidationError> {
+    let mac_regex = Regex::new(r"^[0-9a-fA-F]{2}(:[0-9a-fA-F]{2}){5}$").unwrap();
+    
+    if !mac_regex.is_match(mac) {
+        return Err(ValidationError::InvalidMacAddress(
+            "Must be six colon-separated hex octets, e.g. 02:00:00:12:34:56".to_string()
+        ));
+    }
+    
+    // Low bit of the first octet set means multicast, which a NIC can't use
+    let first = u8::from_str_radix(&mac[..2], 16).unwrap();
+    if first & 0x01 != 0 {
+        return Err(ValidationError::InvalidMacAddress(format!("{} is a multicast address", mac)));
+    }
+    
+    Ok(())
+}
+
+pub fn validate_iso_path(path: &str) -> Result<(), ValidationError> {
+    let path = Path::new(path);
+    
+    // Check for path traversal attempts
+    if path.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+        return Err(ValidationError::InvalidPath(
+            "Path contains parent directory traversal".to_string()
+        ));
+    }
+    
+    // Check file extension
+    let extension = path.extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("")
+        .to_lowercase();
+    
+    if !ISO_EXTENSIONS.contains(&extension.as_str()) {
+        return Err(ValidationError::InvalidIsoPath(
+            format!("Invalid file extension: .{} (must be .iso, .img, .qcow2, or .raw)", extension)
+        ));
+    }
+    
+    // Check if file exists (for local paths)
+    if !path.exists() && !path.starts_with("http://") && !path.starts_with("https://") {
+        return Err(ValidationError::InvalidIsoPath(
+            "File does not exist".to_string()
+        ));
+    }
+    
+    // Local files must live under the ISO directory or an allowlisted root
+    if path.exists() {
+        validate_iso_root(path)?;
+    }
+    
+    // Check file size if it exists
+    if path.exists() {
+        if let Ok(metadata) = std::fs::metadata(path) {
+            validate_iso_size(metadata.len())?;
+        }
+    }
+    
+    Ok(())
+}
+
+// Name for an ISO that doesn't exist yet (a download or upload target): a
+// bare file name with one of the ISO extensions
+pub fn validate_iso_file_name(name: &str) -> Result<(), ValidationError> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(ValidationError::InvalidPath(name.to_string()));
+    }
+    
+    let extension = Path::new(name).extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("")
+        .to_lowercase();
+    if !ISO_EXTENSIONS.contains(&extension.as_str()) {
+        return Err(ValidationError::InvalidIsoPath(
+            format!("Invalid file extension: .{} (must be .iso, .img, .qcow2, or .raw)", extension)
+        ));
+    }
+    
+    Ok(())
+}
+
+// The one size check for ISOs, whichever way they arrive
+pub fn validate_iso_size(size: u64) -> Result<(), ValidationError> {
+    let max = config_lock().read().unwrap().max_iso_size;
+    if size > max {
+        return Err(ValidationError::IsoTooLarge { size, max });
+    }
+    Ok(())
+}
+
+// Base images must be qcow2 files inside the configured base image directory
+pub fn validate_base_image(path: &str) -> Result<(), ValidationError> {
+    let path = Path::new(path);
+    
+    if path.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+        return Err(ValidationError::InvalidPath(
+            "Path contains parent directory traversal".to_string()
+        ));
+    }
+    
+    let canonical = path.canonicalize()
+        .map_err(|_| ValidationError::InvalidBaseImage(format!("{} not found", path.display())))?;
+    let root = validation_config().base_image_dir;
+    let allowed = root.canonicalize().is_ok_and(|root| canonical.starts_with(root));
+    if !allowed {
+        return Err(ValidationError::InvalidPath(
+            format!("Base images must be in {}", root.display())
+        ));
+    }
+    
+    if !canonical.is_file() {
+        return Err(ValidationError::InvalidBaseImage(format!("{} is not a file", path.display())));
+    }
+    
+    // qcow2 headers start with "QFI\xfb"
+    let mut magic = [0u8; 4];
+    let is_qcow2 = std::fs::File::open(&canonical)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .is_ok_and(|()| magic == *b"QFI\xfb");
+    if !is_qcow2 {
+        return Err(ValidationError::InvalidBaseImage(format!("{} is not a qcow2 image", path.display())));
+    }
+    
+    Ok(())
+}
+
+// Path checks only; the image itself is inspected with qemu-img on import
+pub fn validate_import_disk(path: &str) -> Result<(), ValidationError> {
+    let path = Path::new(path);
+    
+    if path.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+        return Err(ValidationError::InvalidPath(
+            "Path contains parent directory traversal".to_string()
+        ));
+    }
+    
+    let config = validation_config();
+    let canonical = path.canonicalize()
+        .map_err(|_| ValidationError::InvalidImportDisk(format!("{} not found", path.display())))?;
+    let allowed = std::iter::once(&config.import_dir)
+        .chain(config.allowed_import_roots.iter())
+        .filter_map(|root| root.canonicalize().ok())
+        .any(|root| canonical.starts_with(root));
+    if !allowed {
+        return Err(ValidationError::InvalidPath(
+            format!("{} is outside the import directory and allowed import roots", canonical.display())
+        ));
+    }
+    
+    if !canonical.is_file() {
+        return Err(ValidationError::InvalidImportDisk(format!("{} is not a file", path.display())));
+    }
+    
+    Ok(())
+}
+
+fn validate_iso_root(path: &Path) -> Result<(), ValidationError> {
+    let config = validation_config();
+    
+    // Canonicalize both sides so symlinks can't escape the allowed roots
+    let canonical = path.canonicalize()
+        .map_err(|_| ValidationError::InvalidIsoPath("Cannot resolve path".to_string()))?;
+    
+    let allowed = std::iter::once(&config.iso_dir)
+        .chain(config.allowed_iso_roots.iter())
+        .filter_map(|root| root.canonicalize().ok())
+        .any(|root| canonical.starts_with(root));
+    
+    if allowed {
+        Ok(())
+    } else {
+        Err(ValidationError::InvalidPath(
+            format!("{} is outside the ISO directory and allowed ISO roots", canonical.display())
+        ))
+    }
+}
+
+pub fn validate_memory(memory_mb: u32) -> Result<(), ValidationError> {
+    if !(MIN_MEMORY_MB..=MAX_MEMORY_MB).contains(&memory_mb) {
+        Err(ValidationError::InvalidMemory(memory_mb))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn validate_cpu(cpu_cores: u32) -> Result<(), ValidationError> {
+    if !(MIN_CPU_CORES..=MAX_CPU_CORES).contains(&cpu_cores) {
+        Err(ValidationError::InvalidCpu(cpu_cores))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn validate_disk(disk_gb: u32) -> Result<(), ValidationError> {
+    if !(MIN_DISK_GB..=MAX_DISK_GB).contains(&disk_gb) {
+        Err(ValidationError::InvalidDisk(disk_gb))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn validate_discard(format: &DiskFormat) -> Result<(), ValidationError> {
+    if format.supports_discard() {
+        Ok(())
+    } else {
+        Err(ValidationError::InvalidDiskOption(
+            format!("discard is not supported for .{} disks (use qcow2 or raw)", format.extension())
+        ))
+    }
+}
+
+// The arch's QEMU has to be installed, and the firmware has to make sense
+// for it; `bios` is the explicitly requested one, if any
+pub fn validate_arch(arch: GuestArch, bios: Option<&BiosType>) -> Result<(), ValidationError> {
+    if find_in_path(&arch.binary()).is_none() {
+        return Err(ValidationError::InvalidArch(format!(
+            "{} guests need {}, which is not in PATH", arch.as_str(), arch.binary()
+        )));
+    }
+    
+    match (arch, bios.cloned().unwrap_or_else(|| arch.default_bios())) {
+        (GuestArch::Aarch64, BiosType::SeaBios) => Err(ValidationError::InvalidArch(
+            "aarch64 guests boot UEFI firmware; use Ovmf or a Custom BIOS".to_string()
+        )),
+        (arch, BiosType::Ovmf) if arch.uefi_firmware().is_none() => Err(ValidationError::InvalidArch(format!(
+            "no packaged UEFI firmware for {} guests; use a Custom BIOS", arch.as_str()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Only combinations that can never work; whether this host has KVM right
+// now is for start() to find out
+pub fn validate_accel(arch: GuestArch, accel: Accelerator, cpu: Option<&str>) -> Result<(), ValidationError> {
+    if accel == Accelerator::Kvm && !arch.is_native() {
+        return Err(ValidationError::InvalidAccel(format!(
+            "KVM can't run {} guests on this {} host", arch.as_str(), std::env::consts::ARCH
+        )));
+    }
+    
+    // `host` passes the host CPU through, which only KVM can do
+    if cpu.and_then(|cpu| cpu.split(',').next()) == Some("host") && (!arch.is_native() || accel == Accelerator::Tcg) {
+        return Err(ValidationError::InvalidCpuType(
+            "'host' needs KVM; use e.g. 'max' under TCG".to_string()
+        ));
+    }
+    
+    Ok(())
+}
+
+pub fn validate_machine_type(machine: &str, known: &[String], strict: bool) -> Result<(), ValidationError> {
+    check_qemu_model("machine type", machine, known, strict)
+        .map_err(ValidationError::InvalidMachineType)
+}
+
+pub fn validate_cpu_type(cpu: &str, known: &[String], strict: bool) -> Result<(), ValidationError> {
+    check_qemu_model("CPU model", cpu, known, strict)
+        .map_err(ValidationError::InvalidCpuType)
+}
+
+fn check_qemu_model(kind: &str, value: &str, known: &[String], strict: bool) -> Result<(), String> {
+    // Only the model name is checked; ",accel=kvm" / ",+vmx" style options follow it
+    let name = value.split(',').next().unwrap_or("");
+    if known.iter().any(|k| k == name) {
+        return Ok(());
+    }
+    
+    let suggestions = close_matches(name, known);
+    if suggestions.is_empty() && !strict {
+        log::warn!("{} '{}' is not listed by QEMU; passing it through", kind, name);
+        return Ok(());
+    }
+    
+    if suggestions.is_empty() {
+        Err(format!("'{}' is not a {} supported by QEMU", name, kind))
+    } else {
+        Err(format!("'{}' is not a {} supported by QEMU (did you mean: {}?)", name, kind, suggestions.join(", ")))
+    }
+}
+
+fn close_matches(name: &str, known: &[String]) -> Vec<String> {
+    let name = name.to_lowercase();
+    let mut matches: Vec<(usize, &String)> = known.iter()
+        .filter_map(|k| {
+            let candidate = k.to_lowercase();
+            let distance = edit_distance(&name, &candidate);
+            if distance <= 2 || (name.len() >= 3 && (candidate.contains(&name) || name.contains(&candidate))) {
+                Some((distance, k))
+            } else {
+                None
+            }
+        })
+        .collect();
+    
+    matches.sort();
+    matches.into_iter().take(5).map(|(_, k)| k.clone()).collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(row[j + 1])
+            };
+            prev = current;
+        }
+    }
+    
+    row[b.len()]
+}
+
+// Host ports in these ranges belong to the VM consoles
+const CONSOLE_PORT_RANGES: [(&str, (u16, u16)); 3] = [
+    ("VNC", port_ranges::VNC),
+    ("websocket", port_ranges::WEBSOCKET),
+    ("serial", port_ranges::SERIAL),
+];
+
+pub fn validate_port_forwards(forwards: &[PortForward]) -> Result<(), ValidationError> {
+    for (i, forward) in forwards.iter().enumerate() {
+        if forward.guest_port == 0 {
+            return Err(ValidationError::InvalidPortForward("guest_port can't be 0".to_string()));
+        }
+        // 0 is filled in from the SSH range on create
+        if forward.host_port == 0 {
+            continue;
+        }
+        if forward.host_port < 1024 {
+            return Err(ValidationError::InvalidPortForward(format!(
+                "host port {} is privileged; use 1024 or above", forward.host_port
+            )));
+        }
+        for (name, (min, max)) in CONSOLE_PORT_RANGES {
+            if (min..=max).contains(&forward.host_port) {
+                return Err(ValidationError::InvalidPortForward(format!(
+                    "host port {} is in the {} range ({}-{})", forward.host_port, name, min, max
+                )));
+            }
+        }
+        let duplicate = forwards[..i].iter()
+            .any(|f| f.host_port == forward.host_port && f.protocol == forward.protocol);
+        if duplicate {
+            return Err(ValidationError::InvalidPortForward(format!(
+                "host port {}/{} is forwarded twice", forward.host_port, forward.protocol.as_str()
+            )));
+        }
+    }
+    Ok(())
+}
+
+// VNC authentication only uses the first 8 bytes, so a longer password
+// would silently be truncated
+pub fn validate_vnc_password(password: &str) -> Result<(), ValidationError> {
+    if password.is_empty() || password.len() > 8 {
+        return Err(ValidationError::InvalidVncPassword("must be 1-8 characters".to_string()));
+    }
+    if !password.chars().all(|c| c.is_ascii_graphic()) {
+        return Err(ValidationError::InvalidVncPassword(
+            "only printable ASCII characters without spaces are allowed".to_string()
+        ));
+    }
+    Ok(())
+}
+
+// Never echoed back in the error
+pub fn validate_disk_passphrase(passphrase: &str) -> Result<(), ValidationError> {
+    if passphrase.len() < 8 || passphrase.len() > 512 {
+        return Err(ValidationError::InvalidDiskOption(
+            "disk_passphrase must be 8-512 bytes".to_string()
+        ));
+    }
+    Ok(())
+}
+
+pub fn calculate_file_hash(path: &Path) -> Result<String, ValidationError> {
+    let mut hasher = Hasher::new();
+    let mut file = std::fs::File::open(path)
+        .map_err(|_| ValidationError::InvalidIsoPath("Cannot open file".to_string()))?;
+    
+    std::io::copy(&mut file, &mut hasher)
+        .map_err(|_| ValidationError::InvalidIsoPath("Cannot read file".to_string()))?;
+    
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+pub fn validate_network_config(bridge: &str, subnet: &str) -> Result<(), ValidationError> {
+    // Validate bridge name
+    let bridge_regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_-]{0,14}$").unwrap();
+    if !bridge_regex.is_match(bridge) {
+        return Err(ValidationError::InvalidPath(
+            "Invalid bridge name".to_string()
+        ));
+    }
+    
+    // Validate subnet
+    parse_cidr(subnet).map_err(|e| match e {
+        NetworkError::InvalidSubnet(msg) => ValidationError::InvalidSubnet(msg),
+        other => ValidationError::InvalidSubnet(other.to_string()),
+    })?;
+    
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn extra_args_repeating_memory_are_rejected() {
+        let err = validate_extra_args(&args(&["-usb", "-m", "4096"])).unwrap_err();
+        assert!(matches!(&err, ValidationError::ReservedQemuFlag { flag, .. } if flag == "-m"));
+        assert!(err.to_string().contains("-vnc"), "message should list the managed options: {}", err);
+    }
+
+    #[test]
+    fn extra_args_repeating_vnc_are_rejected() {
+        for vnc in ["-vnc", "--vnc"] {
+            let err = validate_extra_args(&args(&[vnc, ":5"])).unwrap_err();
+            assert!(matches!(err, ValidationError::ReservedQemuFlag { flag, .. } if flag == vnc));
+        }
+    }
+
+    #[test]
+    fn extra_args_without_managed_options_pass() {
+        assert!(validate_extra_args(&args(&["-usb", "-device", "usb-tablet", "-name", "guest"])).is_ok());
+    }
+
+    #[test]
+    fn network_config_uses_strict_cidr_parsing() {
+        assert!(validate_network_config("virbr0", "192.168.122.1/24").is_ok());
+        assert!(matches!(validate_network_config("virbr0", "999.0.0.0/99"), Err(ValidationError::InvalidSubnet(_))));
+        assert!(matches!(validate_network_config("virbr0", "10.0.0.0/33"), Err(ValidationError::InvalidSubnet(_))));
+    }
 }
\ No newline at end of file
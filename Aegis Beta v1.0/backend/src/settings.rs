@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::security::sandbox::{SeccompAction, SeccompMode};
+use crate::security::validation::DEFAULT_MAX_ISO_SIZE;
+use crate::utils::ports::port_ranges;
+use crate::vm::manager::StopPolicy;
+use crate::vm::networking::FirewallBackend;
+
+// config/default.toml, overridable per key from the environment as
+// AEGIS_<SECTION>__<KEY> (e.g. AEGIS_SERVER__PORT=8080). Every key has a
+// default, so a missing file or section just means stock behaviour.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub qemu: QemuSettings,
+    pub timeouts: TimeoutSettings,
+    pub storage: StorageSettings,
+    pub network: NetworkSettings,
+    pub vnc: VncSettings,
+    pub metrics: MetricsSettings,
+    pub security: SecuritySettings,
+    pub limits: LimitsSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    pub host: IpAddr,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    pub shutdown_policy: StopPolicy,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3030,
+            data_dir: PathBuf::from("/var/lib/vm-manager"),
+            shutdown_policy: StopPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QemuSettings {
+    pub strict_validation: bool,
+    pub startup_timeout_secs: u64,
+}
+
+impl Default for QemuSettings {
+    fn default() -> Self {
+        Self { strict_validation: false, startup_timeout_secs: 30 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TimeoutSettings {
+    pub disk_secs: u64,
+    pub disk_copy_secs: u64,
+    pub network_secs: u64,
+    pub service_secs: u64,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        Self { disk_secs: 120, disk_copy_secs: 3600, network_secs: 10, service_secs: 30 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StorageSettings {
+    pub auto_snapshot_before_mutation: bool,
+    pub auto_snapshot_keep: usize,
+    pub allowed_import_roots: Vec<PathBuf>,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self { auto_snapshot_before_mutation: false, auto_snapshot_keep: 3, allowed_import_roots: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NetworkSettings {
+    pub default_bridge: String,
+    pub nat_network: String,
+    pub uplink: Option<String>,
+    pub dns_servers: Option<Vec<Ipv4Addr>>,
+    pub dns_upstream: Option<Vec<IpAddr>>,
+    pub dns_resolve_locally: bool,
+    pub dns_domain: Option<String>,
+    // None picks nftables when the nft binary is installed
+    pub firewall_backend: Option<FirewallBackend>,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            default_bridge: "virbr0".to_string(),
+            nat_network: "192.168.122.0/24".to_string(),
+            uplink: None,
+            dns_servers: None,
+            dns_upstream: None,
+            dns_resolve_locally: false,
+            dns_domain: None,
+            firewall_backend: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VncSettings {
+    pub deterministic_ports: bool,
+    // Where the status/console WebSocket server listens
+    pub websockify_port: u16,
+    pub serial_tcp_bind: Option<IpAddr>,
+}
+
+impl Default for VncSettings {
+    fn default() -> Self {
+        Self { deterministic_ports: false, websockify_port: port_ranges::WEBSOCKET.0, serial_tcp_bind: None }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsSettings {
+    pub history_samples: usize,
+    pub sample_interval_secs: u64,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self { history_samples: 300, sample_interval_secs: 5 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SecuritySettings {
+    pub allowed_iso_roots: Vec<PathBuf>,
+    pub max_iso_size: u64,
+    pub admin_token: Option<String>,
+    pub tokens_file: Option<PathBuf>,
+    pub console_token_secret: Option<String>,
+    pub privileged: bool,
+    pub qemu_user: Option<String>,
+    // Host user whose /etc/subuid and /etc/subgid ranges are mapped into a
+    // user namespace around QEMU
+    pub subid_user: Option<String>,
+    pub sandbox_vms: bool,
+    pub seccomp_mode: SeccompMode,
+    pub seccomp_action: SeccompAction,
+    pub denied_syscalls: Vec<String>,
+    pub syscall_actions: HashMap<String, SeccompAction>,
+    pub chroot_dir: Option<PathBuf>,
+}
+
+impl Default for SecuritySettings {
+    fn default() -> Self {
+        Self {
+            allowed_iso_roots: Vec::new(),
+            max_iso_size: DEFAULT_MAX_ISO_SIZE,
+            admin_token: None,
+            tokens_file: None,
+            console_token_secret: None,
+            privileged: true,
+            qemu_user: None,
+            subid_user: None,
+            sandbox_vms: true,
+            seccomp_mode: SeccompMode::AllowList,
+            seccomp_action: SeccompAction::KillProcess,
+            denied_syscalls: Vec::new(),
+            syscall_actions: HashMap::new(),
+            chroot_dir: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitsSettings {
+    pub cgroups: bool,
+    pub disk_mb_per_sec: u64,
+    pub network_mbps: u32,
+}
+
+impl Settings {
+    pub fn load(path: &Path) -> Result<Self, config::ConfigError> {
+        config::Config::builder()
+            .add_source(config::File::from(path).required(false))
+            .add_source(config::Environment::with_prefix("AEGIS").prefix_separator("_").separator("__").try_parsing(true))
+            .build()?
+            .try_deserialize()
+    }
+}
+
+// Usable DHCP pool of a NAT network: everything but the network and
+// broadcast addresses and the gateway (the address the CIDR was written with)
+pub fn dhcp_range(cidr: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    let (gateway, prefix) = crate::vm::networking::parse_cidr(cidr).ok()?;
+    if prefix == 0 || prefix > 30 {
+        return None;
+    }
+    let mask = u32::MAX << (32 - prefix);
+    let network = u32::from(gateway) & mask;
+    let broadcast = network | !mask;
+    let gateway = u32::from(gateway);
+    let start = if gateway == network + 1 || gateway == network { network + 2 } else { network + 1 };
+    let end = if gateway == broadcast - 1 { broadcast - 2 } else { broadcast - 1 };
+    // A gateway in the middle of the pool would have to be carved out
+    if (start..=end).contains(&gateway) || start > end {
+        return None;
+    }
+    Some((Ipv4Addr::from(start), Ipv4Addr::from(end)))
+}
\ No newline at end of file
@@ -1,294 +1,1310 @@
-use std::fs;
-use std::io;
-use std::path::{Path, PathBuf};
-use std::process::Command;
-
-use crate::security::validation::{validate_disk, ValidationError};
-
-#[derive(Debug, thiserror::Error)]
-pub enum DiskError {
-    #[error("IO error: {0}")]
-    IoError(#[from] io::Error),
-    #[error("Validation error: {0}")]
-    ValidationError(#[from] ValidationError),
-    #[error("QEMU-img error: {0}")]
-    QemuError(String),
-    #[error("Disk not found: {0}")]
-    NotFound(String),
-    #[error("Disk already exists: {0}")]
-    AlreadyExists(String),
-}
-
-pub struct DiskManager {
-    disk_dir: PathBuf,
-}
-
-impl DiskManager {
-    pub fn new(disk_dir: &Path) -> Self {
-        Self {
-            disk_dir: disk_dir.to_path_buf(),
-        }
-    }
-
-    pub fn create_disk(&self, vm_id: &str, size_gb: u32, format: DiskFormat) -> Result<PathBuf, DiskError> {
-        // Validate disk size
-        validate_disk(size_gb)?;
-        
-        let disk_path = self.disk_dir.join(format!("{}.{}", vm_id, format.extension()));
-        
-        // Check if disk already exists
-        if disk_path.exists() {
-            return Err(DiskError::AlreadyExists(vm_id.to_string()));
-        }
-        
-        // Create disk image
-        let format_str = match format {
-            DiskFormat::Qcow2 => "qcow2",
-            DiskFormat::Raw => "raw",
-            DiskFormat::Vdi => "vdi",
-            DiskFormat::Vmdk => "vmdk",
-        };
-        
-        let output = Command::new("qemu-img")
-            .arg("create")
-            .arg("-f")
-            .arg(format_str)
-            .arg(&disk_path)
-            .arg(format!("{}G", size_gb))
-            .output()
-            .map_err(|e| DiskError::IoError(e))?;
-        
-        if !output.status.success() {
-            return Err(DiskError::QemuError(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        // Set permissions (owner read/write, group read, others none)
-        let mut perms = fs::metadata(&disk_path)?.permissions();
-        perms.set_readonly(false);
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            perms.set_mode(0o640); // rw-r-----
-        }
-        fs::set_permissions(&disk_path, perms)?;
-        
-        Ok(disk_path)
-    }
-
-    pub fn delete_disk(&self, vm_id: &str) -> Result<(), DiskError> {
-        // Try different formats
-        let formats = vec!["qcow2", "raw", "vdi", "vmdk"];
-        
-        for format in formats {
-            let disk_path = self.disk_dir.join(format!("{}.{}", vm_id, format));
-            if disk_path.exists() {
-                fs::remove_file(&disk_path)?;
-                return Ok(());
-            }
-        }
-        
-        Err(DiskError::NotFound(vm_id.to_string()))
-    }
-
-    pub fn resize_disk(&self, vm_id: &str, new_size_gb: u32) -> Result<(), DiskError> {
-        validate_disk(new_size_gb)?;
-        
-        // Find existing disk
-        let formats = vec!["qcow2", "raw", "vdi", "vmdk"];
-        let mut disk_path = None;
-        
-        for format in &formats {
-            let path = self.disk_dir.join(format!("{}.{}", vm_id, format));
-            if path.exists() {
-                disk_path = Some((path, format));
-                break;
-            }
-        }
-        
-        let (disk_path, format) = disk_path.ok_or_else(|| DiskError::NotFound(vm_id.to_string()))?;
-        
-        // Create backup
-        let backup_path = disk_path.with_extension(format!("{}.backup", format));
-        fs::copy(&disk_path, &backup_path)?;
-        
-        // Resize disk
-        let output = Command::new("qemu-img")
-            .arg("resize")
-            .arg(&disk_path)
-            .arg(format!("{}G", new_size_gb))
-            .output()
-            .map_err(|e| DiskError::IoError(e))?;
-        
-        if !output.status.success() {
-            // Restore from backup
-            fs::copy(&backup_path, &disk_path)?;
-            fs::remove_file(&backup_path)?;
-            
-            return Err(DiskError::QemuError(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        // Remove backup
-        fs::remove_file(&backup_path)?;
-        
-        Ok(())
-    }
-
-    pub fn get_disk_info(&self, vm_id: &str) -> Result<DiskInfo, DiskError> {
-        let formats = vec!["qcow2", "raw", "vdi", "vmdk"];
-        
-        for format in &formats {
-            let disk_path = self.disk_dir.join(format!("{}.{}", vm_id, format));
-            if disk_path.exists() {
-                let output = Command::new("qemu-img")
-                    .arg("info")
-                    .arg(&disk_path)
-                    .output()
-                    .map_err(|e| DiskError::IoError(e))?;
-                
-                if !output.status.success() {
-                    return Err(DiskError::QemuError(
-                        String::from_utf8_lossy(&output.stderr).to_string()
-                    ));
-                }
-                
-                let output_str = String::from_utf8_lossy(&output.stdout);
-                return Ok(DiskInfo::from_qemu_output(&output_str, &disk_path));
-            }
-        }
-        
-        Err(DiskError::NotFound(vm_id.to_string()))
-    }
-
-    pub fn list_disks(&self) -> Result<Vec<DiskInfo>, DiskError> {
-        let mut disks = Vec::new();
-        
-        for entry in fs::read_dir(&self.disk_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if path.is_file() {
-                if let Some(extension) = path.extension() {
-                    let ext = extension.to_string_lossy();
-                    if matches!(ext.as_ref(), "qcow2" | "raw" | "vdi" | "vmdk") {
-                        if let Ok(info) = self.get_disk_info(
-                            path.file_stem()
-                                .and_then(|s| s.to_str())
-                                .unwrap_or("unknown")
-                        ) {
-                            disks.push(info);
-                        }
-                    }
-                }
-            }
-        }
-        
-        Ok(disks)
-    }
-}
-
-#[derive(Debug, Clone)]
-pub enum DiskFormat {
-    Qcow2,
-    Raw,
-    Vdi,
-    Vmdk,
-}
-
-impl DiskFormat {
-    pub fn extension(&self) -> &'static str {
-        match self {
-            DiskFormat::Qcow2 => "qcow2",
-            DiskFormat::Raw => "raw",
-            DiskFormat::Vdi => "vdi",
-            DiskFormat::Vmdk => "vmdk",
-        }
-    }
-    
-    pub fn from_extension(ext: &str) -> Option<Self> {
-        match ext.to_lowercase().as_str() {
-            "qcow2" => Some(DiskFormat::Qcow2),
-            "raw" => Some(DiskFormat::Raw),
-            "vdi" => Some(DiskFormat::Vdi),
-            "vmdk" => Some(DiskFormat::Vmdk),
-            _ => None,
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct DiskInfo {
-    pub path: PathBuf,
-    pub format: DiskFormat,
-    pub virtual_size_gb: f64,
-    pub actual_size_gb: f64,
-    pub backing_file: Option<PathBuf>,
-    pub encrypted: bool,
-    pub snapshot_count: usize,
-}
-
-impl DiskInfo {
-    fn from_qemu_output(output: &str, path: &Path) -> Self {
-        let mut info = DiskInfo {
-            path: path.to_path_buf(),
-            format: DiskFormat::Raw,
-            virtual_size_gb: 0.0,
-            actual_size_gb: 0.0,
-            backing_file: None,
-            encrypted: false,
-            snapshot_count: 0,
-        };
-        
-        for line in output.lines() {
-            let line = line.trim();
-            
-            if line.starts_with("file format:") {
-                let format_str = line.split(':').nth(1).unwrap_or("").trim();
-                info.format = DiskFormat::from_extension(format_str).unwrap_or(DiskFormat::Raw);
-            } else if line.starts_with("virtual size:") {
-                if let Some(size_part) = line.split('(').nth(1) {
-                    if let Some(size_str) = size_part.split(' ').next() {
-                        if let Ok(size_bytes) = size_str.parse::<f64>() {
-                            info.virtual_size_gb = size_bytes / (1024.0 * 1024.0 * 1024.0);
-                        }
-                    }
-                }
-            } else if line.starts_with("disk size:") {
-                if let Some(size_str) = line.split(':').nth(1) {
-                    let parts: Vec<&str> = size_str.trim().split(' ').collect();
-                    if parts.len() >= 2 {
-                        if let Ok(size) = parts[0].parse::<f64>() {
-                            let unit = parts[1].to_lowercase();
-                            info.actual_size_gb = match unit.as_str() {
-                                "k" => size / (1024.0 * 1024.0),
-                                "m" => size / 1024.0,
-                                "g" => size,
-                                "t" => size * 1024.0,
-                                _ => size / (1024.0 * 1024.0 * 1024.0),
-                            };
-                        }
-                    }
-                }
-            } else if line.starts_with("backing file:") {
-                if let Some(backing_path) = line.split(':').nth(1) {
-                    let path_str = backing_path.trim();
-                    if !path_str.is_empty() && path_str != "(null)" {
-                        info.backing_file = Some(PathBuf::from(path_str));
-                    }
-                }
-            } else if line.contains("encrypted: yes") {
-                info.encrypted = true;
-            } else if line.contains("Snapshot list:") {
-                // Count snapshot lines
-                info.snapshot_count = output.lines()
-                    .filter(|l| l.contains("snapshot"))
-                    .count();
-            }
-        }
-        
-        info
-    }
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::security::validation::{validate_disk, validate_snapshot_name, validate_volume_name, ValidationError};
+use crate::utils::command::{CommandCategory, CommandError, CommandTimeoutExt};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DiskError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Validation error: {0}")]
+    ValidationError(#[from] ValidationError),
+    #[error("QEMU-img error: {0}")]
+    QemuError(String),
+    #[error("Disk not found: {0}")]
+    NotFound(String),
+    #[error("Disk already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Snapshot not found: {0}")]
+    SnapshotNotFound(String),
+    #[error("Snapshot already exists: {0}")]
+    SnapshotExists(String),
+    #[error("Command timed out: {0}")]
+    Timeout(String),
+    #[error("Disk is in use: {0}")]
+    InUse(String),
+}
+
+impl From<CommandError> for DiskError {
+    fn from(err: CommandError) -> Self {
+        match err {
+            CommandError::IoError(e) => DiskError::IoError(e),
+            timeout => DiskError::Timeout(timeout.to_string()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DiskManager {
+    disk_dir: PathBuf,
+    auto_snapshot_before_mutation: bool,
+    auto_snapshot_keep: usize,
+}
+
+const AUTO_SNAPSHOT_PREFIX: &str = "auto-pre-";
+// The secret object qemu-img and QEMU read a disk's LUKS key from
+pub const KEY_SECRET_ID: &str = "disk0-key";
+
+impl DiskManager {
+    pub fn new(disk_dir: &Path) -> Self {
+        Self {
+            disk_dir: disk_dir.to_path_buf(),
+            auto_snapshot_before_mutation: false,
+            auto_snapshot_keep: 3,
+        }
+    }
+    
+    // (total, available) bytes on the filesystem holding the disk images
+    pub fn filesystem_space(&self) -> Result<(u64, u64), DiskError> {
+        let stat = nix::sys::statvfs::statvfs(&self.disk_dir)
+            .map_err(|e| DiskError::IoError(io::Error::from(e)))?;
+        let fragment = stat.fragment_size() as u64;
+        Ok((
+            stat.blocks() as u64 * fragment,
+            stat.blocks_available() as u64 * fragment,
+        ))
+    }
+    
+    // Take an internal qcow2 snapshot before destructive operations, keeping
+    // only the newest `keep` of them
+    pub fn set_auto_snapshots(&mut self, enabled: bool, keep: usize) {
+        self.auto_snapshot_before_mutation = enabled;
+        self.auto_snapshot_keep = keep;
+    }
+    
+    // How many auto snapshots to keep, if they're taken at all
+    pub fn auto_snapshot_keep(&self) -> Option<usize> {
+        self.auto_snapshot_before_mutation.then_some(self.auto_snapshot_keep)
+    }
+
+    // LUKS keys of encrypted disks, as keys/{vm_id}.key readable by the
+    // owner only. qemu-img needs the key for everything but `info`: resize,
+    // snapshots and compaction pass it along as a secret object, the same
+    // way QEMU gets it at boot, and conversion is refused since the result
+    // would be written out unencrypted.
+    pub fn key_path(&self, vm_id: &str) -> PathBuf {
+        self.disk_dir.join("keys").join(format!("{}.key", vm_id))
+    }
+    
+    pub fn write_key(&self, vm_id: &str, secret: &[u8]) -> Result<PathBuf, DiskError> {
+        let path = self.key_path(vm_id);
+        let dir = self.disk_dir.join("keys");
+        fs::create_dir_all(&dir)?;
+        
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+            options.mode(0o600);
+        }
+        options.open(&path)?.write_all(secret)?;
+        Ok(path)
+    }
+    
+    pub fn delete_key(&self, vm_id: &str) -> Result<(), DiskError> {
+        match fs::remove_file(self.key_path(vm_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+    
+    // How qemu-img is pointed at the image: by path, or as an option string
+    // carrying its key when it's encrypted
+    fn image_args(&self, vm_id: &str, path: &Path) -> Vec<String> {
+        let key = self.key_path(vm_id);
+        if !key.exists() {
+            return vec![path.display().to_string()];
+        }
+        vec![
+            "--object".to_string(),
+            secret_object(&key),
+            "--image-opts".to_string(),
+            format!("driver=qcow2,file.filename={},encrypt.key-secret={}", path.display(), KEY_SECRET_ID),
+        ]
+    }
+
+    // An encrypted disk takes its passphrase from key_path(vm_id), which
+    // write_key has to have filled in first
+    pub fn create_disk(
+        &self,
+        vm_id: &str,
+        size_gb: u32,
+        format: DiskFormat,
+        options: &DiskOptions,
+        backing: Option<&Path>,
+    ) -> Result<PathBuf, DiskError> {
+        // Validate disk size
+        validate_disk(size_gb)?;
+        options.validate(&format)?;
+        
+        let disk_path = self.disk_dir.join(format!("{}.{}", vm_id, format.extension()));
+        
+        // Check if disk already exists
+        if disk_path.exists() {
+            return Err(DiskError::AlreadyExists(vm_id.to_string()));
+        }
+        
+        // Create disk image
+        let format_str = match format {
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Raw => "raw",
+            DiskFormat::Vdi => "vdi",
+            DiskFormat::Vmdk => "vmdk",
+        };
+        
+        let mut cmd = Command::new("qemu-img");
+        cmd.arg("create").arg("-f").arg(format_str);
+        
+        if options.encrypted {
+            let key = self.key_path(vm_id);
+            if !key.is_file() {
+                return Err(ValidationError::InvalidDiskOption(
+                    format!("encrypted disk has no key at {}", key.display())
+                ).into());
+            }
+            cmd.arg("--object").arg(secret_object(&key));
+        }
+        
+        // A thin clone: reads fall through to the base, writes stay in ours
+        if let Some(base) = backing {
+            cmd.arg("-b").arg(base).arg("-F").arg("qcow2");
+        }
+        
+        let qemu_options = options.qemu_img_options();
+        if !qemu_options.is_empty() {
+            cmd.arg("-o").arg(qemu_options.join(","));
+        }
+        
+        // Full preallocation writes every byte, which takes as long as a copy
+        let category = match options.preallocation {
+            Some(Preallocation::Full) => CommandCategory::DiskCopy,
+            _ => CommandCategory::Disk,
+        };
+        
+        cmd.arg(&disk_path);
+        // Without a size a clone takes on the base's
+        if backing.is_none() {
+            cmd.arg(format!("{}G", size_gb));
+        }
+        let output = cmd.output_within(category).map_err(|e| match e {
+            CommandError::IoError(e) => DiskError::IoError(io::Error::new(
+                e.kind(),
+                format!("Failed to run qemu-img for {}: {}", disk_path.display(), e),
+            )),
+            timeout => timeout.into(),
+        })?;
+        
+        if !output.status.success() {
+            return Err(DiskError::QemuError(format!(
+                "qemu-img create {} failed: {}",
+                disk_path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        
+        // Set permissions (owner read/write, group read, others none)
+        let mut perms = fs::metadata(&disk_path)?.permissions();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            perms.set_mode(0o640); // rw-r-----
+        }
+        fs::set_permissions(&disk_path, perms)?;
+        
+        Ok(disk_path)
+    }
+
+    // Copies an existing image in as the VM's disk. qemu-img has to detect
+    // `format`, and images with a backing file are refused since their
+    // chain would point outside the disk directory.
+    pub fn import_disk(&self, vm_id: &str, source: &Path, format: DiskFormat) -> Result<(PathBuf, DiskInfo), DiskError> {
+        let output = Command::new("qemu-img")
+            .arg("info")
+            .arg(source)
+            .output_within(CommandCategory::Disk)?;
+        if !output.status.success() {
+            return Err(DiskError::QemuError(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let info = DiskInfo::from_qemu_output(&stdout, source);
+        
+        let invalid = |reason: String| DiskError::ValidationError(ValidationError::InvalidImportDisk(reason));
+        // Compared on the raw name: DiskInfo maps formats it doesn't know to raw
+        let detected = stdout.lines()
+            .find_map(|line| line.trim().strip_prefix("file format:"))
+            .map(|name| name.trim().to_string())
+            .unwrap_or_default();
+        if detected != format.extension() {
+            return Err(invalid(format!(
+                "{} is {}, not {}", source.display(), detected, format.extension()
+            )));
+        }
+        if let Some(backing) = &info.backing_file {
+            return Err(invalid(format!("{} has a backing file ({})", source.display(), backing.display())));
+        }
+        validate_disk(info.virtual_size_gb.ceil() as u32)?;
+        
+        let disk_path = self.disk_dir.join(format!("{}.{}", vm_id, format.extension()));
+        if disk_path.exists() {
+            return Err(DiskError::AlreadyExists(vm_id.to_string()));
+        }
+        
+        let needed = fs::metadata(source)?.len();
+        let (_, available) = self.filesystem_space()?;
+        if needed > available {
+            return Err(invalid(format!("needs {} bytes, {} available", needed, available)));
+        }
+        
+        // cp keeps holes, so a sparse source stays sparse
+        let output = Command::new("cp")
+            .arg("--sparse=always")
+            .arg("--reflink=auto")
+            .arg(source)
+            .arg(&disk_path)
+            .output_within(CommandCategory::DiskCopy)?;
+        if !output.status.success() {
+            let _ = fs::remove_file(&disk_path);
+            return Err(DiskError::IoError(io::Error::other(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            )));
+        }
+        
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&disk_path, fs::Permissions::from_mode(0o640))?;
+        }
+        
+        Ok((disk_path.clone(), DiskInfo { path: disk_path, ..info }))
+    }
+
+    pub fn delete_disk(&self, vm_id: &str) -> Result<(), DiskError> {
+        // Try different formats
+        let formats = vec!["qcow2", "raw", "vdi", "vmdk"];
+        
+        for format in formats {
+            let disk_path = self.disk_dir.join(format!("{}.{}", vm_id, format));
+            if disk_path.exists() {
+                fs::remove_file(&disk_path)?;
+                return Ok(());
+            }
+        }
+        
+        Err(DiskError::NotFound(vm_id.to_string()))
+    }
+
+    // A qcow2 overlay on another VM's disk: reads fall through to the base,
+    // writes stay in the clone. The base must not change from here on, so
+    // it can't be an image QEMU has open.
+    pub fn create_linked_clone(&self, base_vm_id: &str, new_vm_id: &str) -> Result<PathBuf, DiskError> {
+        let base = self.get_disk_info(base_vm_id)?;
+        if !matches!(base.format, DiskFormat::Qcow2) {
+            return Err(ValidationError::InvalidDiskOption(
+                format!("linked clones need a qcow2 base, not .{}", base.format.extension())
+            ).into());
+        }
+        // The clone would need the base's key just to read through to it
+        if base.encrypted {
+            return Err(ValidationError::InvalidDiskOption(
+                "encrypted disks can't back a linked clone".to_string()
+            ).into());
+        }
+        self.ensure_unlocked(&base.path)?;
+        
+        let options = DiskOptions::default().resolved(&DiskFormat::Qcow2);
+        self.create_disk(
+            new_vm_id,
+            base.virtual_size_gb.ceil() as u32,
+            DiskFormat::Qcow2,
+            &options,
+            Some(&base.path),
+        )
+    }
+    
+    // An independent copy of the disk as `new_vm_id`'s, snapshots, sparseness
+    // and any backing file reference included; an encrypted disk's key is
+    // copied along with it
+    pub fn copy_disk(&self, vm_id: &str, new_vm_id: &str) -> Result<PathBuf, DiskError> {
+        let source = self.get_disk_info(vm_id)?;
+        self.ensure_unlocked(&source.path)?;
+        
+        let ext = source.format.extension();
+        let dest = self.disk_dir.join(format!("{}.{}", new_vm_id, ext));
+        if dest.exists() {
+            return Err(DiskError::AlreadyExists(new_vm_id.to_string()));
+        }
+        
+        let needed = (source.actual_size_gb * 1024.0 * 1024.0 * 1024.0) as u64;
+        let (_, available) = self.filesystem_space()?;
+        if needed > available {
+            return Err(DiskError::IoError(io::Error::other(
+                format!("copy needs about {} bytes, {} available", needed, available),
+            )));
+        }
+        
+        // cp keeps holes (and shares extents where the filesystem can),
+        // where fs::copy would write out every zero of a sparse image
+        let tmp_path = dest.with_extension(format!("{}.copy", ext));
+        let output = Command::new("cp")
+            .arg("--sparse=always")
+            .arg("--reflink=auto")
+            .arg(&source.path)
+            .arg(&tmp_path)
+            .output_within(CommandCategory::DiskCopy)?;
+        
+        if !output.status.success() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(DiskError::IoError(io::Error::other(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            )));
+        }
+        
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o640))?;
+        }
+        
+        if source.encrypted {
+            let key = fs::read(self.key_path(vm_id))?;
+            if let Err(e) = self.write_key(new_vm_id, &key) {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        }
+        
+        fs::rename(&tmp_path, &dest)?;
+        Ok(dest)
+    }
+    
+    // Pulls everything the disk still reads from its backing file into it,
+    // so it stands on its own again. The base is left as it was.
+    pub fn flatten_disk(&self, vm_id: &str) -> Result<DiskInfo, DiskError> {
+        let info = self.get_disk_info(vm_id)?;
+        if info.backing_file.is_none() {
+            return Err(ValidationError::InvalidDiskOption("disk has no backing file".to_string()).into());
+        }
+        
+        let output = Command::new("qemu-img")
+            .arg("rebase")
+            .arg("-b")
+            .arg("")
+            .args(self.image_args(vm_id, &info.path))
+            .output_within(CommandCategory::DiskCopy)?;
+        
+        if !output.status.success() {
+            return Err(DiskError::QemuError(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        self.get_disk_info(vm_id)
+    }
+    
+    // Writes the disk's changes down into its backing file, leaving the disk
+    // an empty overlay on the updated base. Every other image on the same
+    // base sees it change underneath, so callers have to rule those out.
+    pub fn commit_disk(&self, vm_id: &str) -> Result<PathBuf, DiskError> {
+        let info = self.get_disk_info(vm_id)?;
+        let base = info.backing_file
+            .ok_or_else(|| ValidationError::InvalidDiskOption("disk has no backing file".to_string()))?;
+        self.ensure_unlocked(&base)?;
+        
+        let output = Command::new("qemu-img")
+            .arg("commit")
+            .args(self.image_args(vm_id, &info.path))
+            .output_within(CommandCategory::DiskCopy)?;
+        
+        if !output.status.success() {
+            return Err(DiskError::QemuError(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(base)
+    }
+    
+    // Plain `qemu-img info` (no -U) takes the image lock, which QEMU holds
+    // for as long as it has the image open
+    fn ensure_unlocked(&self, path: &Path) -> Result<(), DiskError> {
+        let output = Command::new("qemu-img")
+            .arg("info")
+            .arg(path)
+            .output_within(CommandCategory::Disk)?;
+        
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        if stderr.contains("lock") {
+            Err(DiskError::InUse(path.display().to_string()))
+        } else {
+            Err(DiskError::QemuError(stderr))
+        }
+    }
+
+    // Extra volumes sit next to the primary disk as {vm_id}-{name}.{ext}, so
+    // list_disks shows them too. One kept by an earlier detach is picked up
+    // again as it is, whatever size_gb says.
+    pub fn attach_disk(&self, vm_id: &str, name: &str, size_gb: u32, format: DiskFormat) -> Result<PathBuf, DiskError> {
+        validate_volume_name(name)?;
+        
+        let volume = volume_id(vm_id, name);
+        let path = self.disk_dir.join(format!("{}.{}", volume, format.extension()));
+        if path.exists() {
+            return Ok(path);
+        }
+        
+        let options = DiskOptions::default().resolved(&format);
+        self.create_disk(&volume, size_gb, format, &options, None)
+    }
+    
+    // The image is only removed with `delete`; otherwise it waits for the
+    // same name to be attached again
+    pub fn detach_disk(&self, vm_id: &str, name: &str, delete: bool) -> Result<(), DiskError> {
+        validate_volume_name(name)?;
+        
+        if delete {
+            self.delete_disk(&volume_id(vm_id, name))?;
+        }
+        Ok(())
+    }
+
+    pub fn resize_disk(&self, vm_id: &str, new_size_gb: u32) -> Result<(), DiskError> {
+        validate_disk(new_size_gb)?;
+        
+        // Find existing disk
+        let formats = vec!["qcow2", "raw", "vdi", "vmdk"];
+        let mut disk_path = None;
+        
+        for format in &formats {
+            let path = self.disk_dir.join(format!("{}.{}", vm_id, format));
+            if path.exists() {
+                disk_path = Some((path, format));
+                break;
+            }
+        }
+        
+        let (disk_path, format) = disk_path.ok_or_else(|| DiskError::NotFound(vm_id.to_string()))?;
+        
+        self.snapshot_before(vm_id, "resize", None)?;
+        
+        // Create backup
+        let backup_path = disk_path.with_extension(format!("{}.backup", format));
+        fs::copy(&disk_path, &backup_path)?;
+        
+        // Resize disk
+        let output = Command::new("qemu-img")
+            .arg("resize")
+            .args(self.image_args(vm_id, &disk_path))
+            .arg(format!("{}G", new_size_gb))
+            .output_within(CommandCategory::Disk)?;
+        
+        if !output.status.success() {
+            // Restore from backup
+            fs::copy(&backup_path, &disk_path)?;
+            fs::remove_file(&backup_path)?;
+            
+            return Err(DiskError::QemuError(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        // Remove backup
+        fs::remove_file(&backup_path)?;
+        
+        Ok(())
+    }
+
+    // Rewrites the image through qemu-img convert so blocks the guest has
+    // freed (but which were never discarded) are dropped. The VM must be
+    // stopped; qemu-img refuses to open an image QEMU still holds locked.
+    // A linked clone stays on its base; internal snapshots are dropped, as
+    // with convert_disk.
+    pub fn compact_disk(&self, vm_id: &str) -> Result<CompactReport, DiskError> {
+        let before = self.get_disk_info(vm_id)?;
+        let format = before.format.extension();
+        let tmp_path = before.path.with_extension(format!("{}.compact", format));
+        
+        let mut cmd = Command::new("qemu-img");
+        cmd.arg("convert")
+            .arg("-O")
+            .arg(format)
+            .args(self.image_args(vm_id, &before.path));
+        // Only what differs from the base is written back
+        if let Some(backing) = &before.backing_file {
+            cmd.arg("-B").arg(backing).arg("-F").arg("qcow2");
+        }
+        // Re-encrypted under the same key
+        if before.encrypted {
+            cmd.arg("-o").arg(format!("encrypt.format=luks,encrypt.key-secret={}", KEY_SECRET_ID));
+        }
+        let output = cmd.arg(&tmp_path).output_within(CommandCategory::DiskCopy)?;
+        
+        if !output.status.success() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(DiskError::QemuError(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        // Keep the same permissions as a freshly created disk
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o640))?;
+        }
+        
+        fs::rename(&tmp_path, &before.path)?;
+        
+        let after = self.get_disk_info(vm_id)?;
+        
+        Ok(CompactReport {
+            before_actual_size_gb: before.actual_size_gb,
+            after_actual_size_gb: after.actual_size_gb,
+        })
+    }
+
+    // Rewrites the disk in `target` format with qemu-img convert. With
+    // `replace` the result takes the original's place as {vm_id}.{ext} and
+    // the original is deleted; otherwise it's left in converted/ for the
+    // caller to take elsewhere. Backing files are flattened into the result
+    // and internal snapshots don't survive. The VM must be stopped.
+    //
+    // With auto snapshots on, a replaced original is kept as
+    // converted/{vm_id}.pre-convert.{ext}, its auto snapshot included,
+    // rather than deleted.
+    pub fn convert_disk(&self, vm_id: &str, target: DiskFormat, replace: bool) -> Result<DiskInfo, DiskError> {
+        let source = self.get_disk_info(vm_id)?;
+        if source.encrypted {
+            return Err(DiskError::ValidationError(ValidationError::InvalidDiskOption(
+                "encrypted disks can't be converted; the result would be unencrypted".to_string()
+            )));
+        }
+        let ext = target.extension();
+        if source.format.extension() == ext {
+            return Err(DiskError::ValidationError(ValidationError::InvalidDiskOption(
+                format!("disk is already {}", ext)
+            )));
+        }
+        
+        let dest = if replace {
+            self.disk_dir.join(format!("{}.{}", vm_id, ext))
+        } else {
+            let dir = self.disk_dir.join("converted");
+            fs::create_dir_all(&dir)?;
+            dir.join(format!("{}.{}", vm_id, ext))
+        };
+        
+        // The result is about as large as the data in use, backing file included
+        let needed = (source.actual_size_gb * 1024.0 * 1024.0 * 1024.0) as u64;
+        let (_, available) = self.filesystem_space()?;
+        if needed > available {
+            return Err(DiskError::IoError(io::Error::other(
+                format!("conversion needs about {} bytes, {} available", needed, available),
+            )));
+        }
+        
+        if replace {
+            self.snapshot_before(vm_id, "convert", None)?;
+        }
+        
+        let tmp_path = dest.with_extension(format!("{}.convert", ext));
+        let output = Command::new("qemu-img")
+            .arg("convert")
+            .arg("-O")
+            .arg(ext)
+            .arg(&source.path)
+            .arg(&tmp_path)
+            .output_within(CommandCategory::DiskCopy)?;
+        
+        if !output.status.success() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(DiskError::QemuError(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o640))?;
+        }
+        
+        fs::rename(&tmp_path, &dest)?;
+        if replace && self.auto_snapshot_before_mutation {
+            let dir = self.disk_dir.join("converted");
+            fs::create_dir_all(&dir)?;
+            fs::rename(&source.path, dir.join(format!("{}.pre-convert.{}", vm_id, source.format.extension())))?;
+        } else if replace {
+            fs::remove_file(&source.path)?;
+        }
+        
+        self.disk_info_at(&dest)
+    }
+
+    pub fn list_snapshots(&self, vm_id: &str) -> Result<Vec<SnapshotInfo>, DiskError> {
+        let info = self.get_disk_info(vm_id)?;
+        
+        let output = Command::new("qemu-img")
+            .arg("snapshot")
+            .arg("-l")
+            .args(self.image_args(vm_id, &info.path))
+            .output_within(CommandCategory::Disk)?;
+        
+        if !output.status.success() {
+            return Err(DiskError::QemuError(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(SnapshotInfo::from_qemu_output(&String::from_utf8_lossy(&output.stdout)))
+    }
+    
+    // The snapshot methods below work on the image directly, so the VM must
+    // not be running; QEMU holds the image locked while it is
+    pub fn create_snapshot(&self, vm_id: &str, name: &str) -> Result<(), DiskError> {
+        validate_snapshot_name(name)?;
+        let info = self.snapshot_disk(vm_id)?;
+        
+        if self.list_snapshots(vm_id)?.iter().any(|s| s.name == name) {
+            return Err(DiskError::SnapshotExists(format!("{} of {}", name, vm_id)));
+        }
+        
+        self.run_snapshot_cmd(vm_id, "-c", name, &info.path)
+    }
+    
+    // Reverts the disk to the snapshot; everything written since is lost
+    pub fn restore_snapshot(&self, vm_id: &str, name: &str) -> Result<(), DiskError> {
+        validate_snapshot_name(name)?;
+        let info = self.snapshot_disk(vm_id)?;
+        self.require_snapshot(vm_id, name)?;
+        
+        // Pruning must not take the snapshot being restored with it
+        self.snapshot_before(vm_id, "restore", Some(name))?;
+        self.run_snapshot_cmd(vm_id, "-a", name, &info.path)
+    }
+    
+    pub fn delete_snapshot(&self, vm_id: &str, name: &str) -> Result<(), DiskError> {
+        validate_snapshot_name(name)?;
+        let info = self.snapshot_disk(vm_id)?;
+        self.require_snapshot(vm_id, name)?;
+        
+        self.run_snapshot_cmd(vm_id, "-d", name, &info.path)
+    }
+    
+    // Only qcow2 carries internal snapshots
+    fn snapshot_disk(&self, vm_id: &str) -> Result<DiskInfo, DiskError> {
+        let info = self.get_disk_info(vm_id)?;
+        if !matches!(info.format, DiskFormat::Qcow2) {
+            return Err(ValidationError::InvalidDiskOption(
+                format!(".{} disks have no internal snapshots", info.format.extension())
+            ).into());
+        }
+        Ok(info)
+    }
+    
+    fn require_snapshot(&self, vm_id: &str, name: &str) -> Result<(), DiskError> {
+        if self.list_snapshots(vm_id)?.iter().any(|s| s.name == name) {
+            Ok(())
+        } else {
+            Err(DiskError::SnapshotNotFound(format!("{} of {}", name, vm_id)))
+        }
+    }
+    
+    // The image must not be in use; a running VM's auto snapshots go
+    // through the monitor instead (see VMManager::snapshot_before)
+    pub fn snapshot_before(&self, vm_id: &str, op: &str, spare: Option<&str>) -> Result<(), DiskError> {
+        if !self.auto_snapshot_before_mutation {
+            return Ok(());
+        }
+        
+        // Only qcow2 carries internal snapshots
+        let info = self.get_disk_info(vm_id)?;
+        if !matches!(info.format, DiskFormat::Qcow2) {
+            return Ok(());
+        }
+        
+        self.run_snapshot_cmd(vm_id, "-c", &auto_snapshot_name(op), &info.path)?;
+        
+        let snapshots = self.list_snapshots(vm_id)?;
+        for name in excess_auto_snapshots(snapshots, self.auto_snapshot_keep, spare) {
+            if let Err(e) = self.run_snapshot_cmd(vm_id, "-d", &name, &info.path) {
+                log::warn!("Failed to prune snapshot {} of {}: {}", name, vm_id, e);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn run_snapshot_cmd(&self, vm_id: &str, flag: &str, name: &str, path: &Path) -> Result<(), DiskError> {
+        let output = Command::new("qemu-img")
+            .arg("snapshot")
+            .arg(flag)
+            .arg(name)
+            .args(self.image_args(vm_id, path))
+            .output_within(CommandCategory::Disk)?;
+        
+        if !output.status.success() {
+            return Err(DiskError::QemuError(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(())
+    }
+
+    pub fn get_disk_info(&self, vm_id: &str) -> Result<DiskInfo, DiskError> {
+        let formats = vec!["qcow2", "raw", "vdi", "vmdk"];
+        
+        for format in &formats {
+            let disk_path = self.disk_dir.join(format!("{}.{}", vm_id, format));
+            if disk_path.exists() {
+                return self.disk_info_at(&disk_path);
+            }
+        }
+        
+        Err(DiskError::NotFound(vm_id.to_string()))
+    }
+    
+    fn disk_info_at(&self, disk_path: &Path) -> Result<DiskInfo, DiskError> {
+        // -U: a running VM holds the image lock, and info only reads
+        let output = Command::new("qemu-img")
+            .arg("info")
+            .arg("-U")
+            .arg(disk_path)
+            .output_within(CommandCategory::Disk)?;
+        
+        if !output.status.success() {
+            return Err(DiskError::QemuError(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        let output_str = String::from_utf8_lossy(&output.stdout);
+        Ok(DiskInfo::from_qemu_output(&output_str, disk_path))
+    }
+
+    pub fn list_disks(&self) -> Result<DiskListing, DiskError> {
+        let mut listing = DiskListing::default();
+        
+        for entry in fs::read_dir(&self.disk_dir)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    listing.skip(format!("Failed to read entry in {}: {}", self.disk_dir.display(), e));
+                    continue;
+                }
+            };
+            let path = entry.path();
+            
+            if path.is_file() {
+                if let Some(extension) = path.extension() {
+                    let ext = extension.to_string_lossy();
+                    if matches!(ext.as_ref(), "qcow2" | "raw" | "vdi" | "vmdk") {
+                        let vm_id = path.file_stem()
+                            .and_then(|s| s.to_str())
+                            .unwrap_or("unknown");
+                        
+                        match self.get_disk_info(vm_id) {
+                            Ok(info) => listing.disks.push(info),
+                            Err(e) => listing.skip(format!("Skipping disk {}: {}", path.display(), e)),
+                        }
+                    }
+                }
+            }
+        }
+        
+        Ok(listing)
+    }
+}
+
+// --object definition for a key file; the key never shows up in argv
+pub fn secret_object(key: &Path) -> String {
+    format!("secret,id={},file={}", KEY_SECRET_ID, key.display())
+}
+
+// auto-pre-<op>-<timestamp>-<n>: milliseconds plus a per-process counter,
+// so operations in quick succession still get a snapshot each
+pub fn auto_snapshot_name(op: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}{}-{}-{}",
+        AUTO_SNAPSHOT_PREFIX, op, chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f"), COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// Auto snapshots beyond the newest `keep`, oldest first; IDs increase in
+// creation order. `spare` is never one of them.
+pub fn excess_auto_snapshots(snapshots: Vec<SnapshotInfo>, keep: usize, spare: Option<&str>) -> Vec<String> {
+    let mut auto: Vec<SnapshotInfo> = snapshots.into_iter()
+        .filter(|s| s.name.starts_with(AUTO_SNAPSHOT_PREFIX) && Some(s.name.as_str()) != spare)
+        .collect();
+    auto.sort_by_key(|s| s.id.parse::<u64>().unwrap_or(0));
+    
+    let excess = auto.len().saturating_sub(keep);
+    auto.into_iter().take(excess).map(|s| s.name).collect()
+}
+
+// qcow2 v3 incompatible feature bit for "guest data lives in another file"
+const QCOW2_EXTERNAL_DATA_FILE: u64 = 1 << 2;
+// QEMU's own limit on a qcow2 backing file name
+const QCOW2_MAX_BACKING_NAME: usize = 1023;
+
+// The file an image would have QEMU open besides itself: a qcow2 backing
+// file. Read from the header, so an untrusted image never reaches qemu-img.
+// qcow2 with an external data file and vmdk descriptors, whose extents can
+// name any file, are refused outright.
+pub fn image_backing_file(path: &Path, format: &DiskFormat) -> Result<Option<PathBuf>, DiskError> {
+    let invalid = |reason: &str| DiskError::ValidationError(
+        ValidationError::InvalidImportDisk(format!("{}: {}", path.display(), reason))
+    );
+    let mut file = fs::File::open(path)?;
+    let mut header = [0u8; 80];
+
+    match format {
+        DiskFormat::Qcow2 => {}
+        // Monolithic sparse images start with the binary extent header
+        DiskFormat::Vmdk => {
+            file.read_exact(&mut header[..4]).map_err(|_| invalid("truncated vmdk header"))?;
+            if header[..4] != *b"KDMV" {
+                return Err(invalid("only monolithic sparse vmdk images can be imported"));
+            }
+            return Ok(None);
+        }
+        DiskFormat::Raw | DiskFormat::Vdi => return Ok(None),
+    }
+
+    file.read_exact(&mut header[..72]).map_err(|_| invalid("truncated qcow2 header"))?;
+    if header[..4] != *b"QFI\xfb" {
+        return Err(invalid("not a qcow2 image"));
+    }
+    let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    if version >= 3 {
+        file.read_exact(&mut header[72..80]).map_err(|_| invalid("truncated qcow2 header"))?;
+        let incompatible = u64::from_be_bytes(header[72..80].try_into().unwrap());
+        if incompatible & QCOW2_EXTERNAL_DATA_FILE != 0 {
+            return Err(invalid("keeps its data in an external file"));
+        }
+    }
+
+    let offset = u64::from_be_bytes(header[8..16].try_into().unwrap());
+    let size = u32::from_be_bytes(header[16..20].try_into().unwrap()) as usize;
+    if offset == 0 || size == 0 {
+        return Ok(None);
+    }
+    if size > QCOW2_MAX_BACKING_NAME {
+        return Err(invalid("backing file name is too long"));
+    }
+    let mut name = vec![0u8; size];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut name).map_err(|_| invalid("truncated backing file name"))?;
+    Ok(Some(PathBuf::from(std::ffi::OsString::from_vec(name))))
+}
+
+fn volume_id(vm_id: &str, name: &str) -> String {
+    format!("{}-{}", vm_id, name)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Preallocation {
+    #[default]
+    Off,
+    // qcow2 only: allocate L1/L2 tables and refcounts up front
+    Metadata,
+    Falloc,
+    Full,
+}
+
+impl Preallocation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Preallocation::Off => "off",
+            Preallocation::Metadata => "metadata",
+            Preallocation::Falloc => "falloc",
+            Preallocation::Full => "full",
+        }
+    }
+}
+
+// qcow2's accepted cluster sizes (512 B - 2 MiB)
+pub const MIN_CLUSTER_SIZE: u32 = 512;
+pub const MAX_CLUSTER_SIZE: u32 = 2 * 1024 * 1024;
+
+// `qemu-img create -o` tuning. Unset fields fall back to qemu-img's own
+// defaults; `resolved` fills them in so the config records what was used.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskOptions {
+    pub preallocation: Option<Preallocation>,
+    // Bytes
+    pub cluster_size: Option<u32>,
+    pub lazy_refcounts: Option<bool>,
+    // LUKS inside qcow2, keyed by DiskManager::key_path
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl DiskOptions {
+    pub fn resolved(&self, format: &DiskFormat) -> Self {
+        match format {
+            DiskFormat::Qcow2 => Self {
+                preallocation: Some(self.preallocation.unwrap_or_default()),
+                cluster_size: Some(self.cluster_size.unwrap_or(65536)),
+                lazy_refcounts: Some(self.lazy_refcounts.unwrap_or(false)),
+                encrypted: self.encrypted,
+            },
+            DiskFormat::Raw => Self {
+                preallocation: Some(self.preallocation.unwrap_or_default()),
+                ..self.clone()
+            },
+            DiskFormat::Vdi | DiskFormat::Vmdk => self.clone(),
+        }
+    }
+    
+    pub fn validate(&self, format: &DiskFormat) -> Result<(), DiskError> {
+        let invalid = |msg: String| Err(DiskError::ValidationError(ValidationError::InvalidDiskOption(msg)));
+        
+        if let Some(preallocation) = self.preallocation {
+            match (format, preallocation) {
+                (DiskFormat::Qcow2, _) | (_, Preallocation::Off) => {}
+                (DiskFormat::Raw, Preallocation::Metadata) => {
+                    return invalid("metadata preallocation is only supported for qcow2".to_string());
+                }
+                (DiskFormat::Raw, _) => {}
+                _ => {
+                    return invalid(format!("preallocation is not supported for .{} disks", format.extension()));
+                }
+            }
+        }
+        
+        if let Some(size) = self.cluster_size {
+            if !matches!(format, DiskFormat::Qcow2) {
+                return invalid("cluster_size is only supported for qcow2".to_string());
+            }
+            if !size.is_power_of_two() || !(MIN_CLUSTER_SIZE..=MAX_CLUSTER_SIZE).contains(&size) {
+                return invalid(format!(
+                    "cluster_size must be a power of two between {} and {} bytes",
+                    MIN_CLUSTER_SIZE, MAX_CLUSTER_SIZE
+                ));
+            }
+        }
+        
+        if self.lazy_refcounts == Some(true) && !matches!(format, DiskFormat::Qcow2) {
+            return invalid("lazy_refcounts is only supported for qcow2".to_string());
+        }
+        
+        if self.encrypted && !matches!(format, DiskFormat::Qcow2) {
+            return invalid("encryption is only supported for qcow2".to_string());
+        }
+        
+        Ok(())
+    }
+    
+    fn qemu_img_options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if let Some(preallocation) = self.preallocation {
+            options.push(format!("preallocation={}", preallocation.as_str()));
+        }
+        if let Some(size) = self.cluster_size {
+            options.push(format!("cluster_size={}", size));
+        }
+        if let Some(lazy) = self.lazy_refcounts {
+            options.push(format!("lazy_refcounts={}", if lazy { "on" } else { "off" }));
+        }
+        if self.encrypted {
+            options.push("encrypt.format=luks".to_string());
+            options.push(format!("encrypt.key-secret={}", KEY_SECRET_ID));
+        }
+        options
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskFormat {
+    Qcow2,
+    Raw,
+    Vdi,
+    Vmdk,
+}
+
+impl DiskFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Raw => "raw",
+            DiskFormat::Vdi => "vdi",
+            DiskFormat::Vmdk => "vmdk",
+        }
+    }
+    
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "qcow2" => Some(DiskFormat::Qcow2),
+            "raw" => Some(DiskFormat::Raw),
+            "vdi" => Some(DiskFormat::Vdi),
+            "vmdk" => Some(DiskFormat::Vmdk),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskInfo {
+    pub path: PathBuf,
+    pub format: DiskFormat,
+    pub virtual_size_gb: f64,
+    pub actual_size_gb: f64,
+    pub backing_file: Option<PathBuf>,
+    pub encrypted: bool,
+    pub snapshot_count: usize,
+}
+
+// The parts of DiskInfo worth showing alongside a VM's status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskSummary {
+    pub virtual_size_gb: f64,
+    pub actual_size_gb: f64,
+    pub backing_file: Option<PathBuf>,
+    pub snapshot_count: usize,
+    pub refreshed_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<&DiskInfo> for DiskSummary {
+    fn from(info: &DiskInfo) -> Self {
+        Self {
+            virtual_size_gb: info.virtual_size_gb,
+            actual_size_gb: info.actual_size_gb,
+            backing_file: info.backing_file.clone(),
+            snapshot_count: info.snapshot_count,
+            refreshed_at: chrono::Utc::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub name: String,
+    pub vm_size: String,
+    pub date: String,
+}
+
+impl SnapshotInfo {
+    // Parses the table printed by `qemu-img snapshot -l`:
+    // ID  TAG  VM SIZE  DATE  VM CLOCK  [ICOUNT]
+    // Also understands HMP `info snapshots`, which uses the same columns
+    pub fn from_qemu_output(output: &str) -> Vec<Self> {
+        output.lines()
+            .skip_while(|line| !line.trim_start().starts_with("ID"))
+            .skip(1)
+            .filter_map(|line| {
+                let tokens: Vec<&str> = line.split_whitespace().collect();
+                // VM SIZE is "0 B" on newer qemu-img and "0" on older, so
+                // locate the date column instead of counting
+                let date_idx = tokens.iter().position(|t| {
+                    t.len() == 10 && t.as_bytes()[4] == b'-' && t.as_bytes()[7] == b'-'
+                })?;
+                if date_idx < 3 || tokens.len() < date_idx + 2 {
+                    return None;
+                }
+                
+                Some(SnapshotInfo {
+                    id: tokens[0].to_string(),
+                    name: tokens[1].to_string(),
+                    vm_size: tokens[2..date_idx].join(" "),
+                    date: format!("{} {}", tokens[date_idx], tokens[date_idx + 1]),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactReport {
+    pub before_actual_size_gb: f64,
+    pub after_actual_size_gb: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DiskListing {
+    pub disks: Vec<DiskInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl DiskListing {
+    fn skip(&mut self, warning: String) {
+        log::warn!("{}", warning);
+        self.warnings.push(warning);
+    }
+}
+
+impl DiskInfo {
+    fn from_qemu_output(output: &str, path: &Path) -> Self {
+        let mut info = DiskInfo {
+            path: path.to_path_buf(),
+            format: DiskFormat::Raw,
+            virtual_size_gb: 0.0,
+            actual_size_gb: 0.0,
+            backing_file: None,
+            encrypted: false,
+            snapshot_count: 0,
+        };
+        
+        for line in output.lines() {
+            let line = line.trim();
+            
+            if line.starts_with("file format:") {
+                let format_str = line.split(':').nth(1).unwrap_or("").trim();
+                info.format = DiskFormat::from_extension(format_str).unwrap_or(DiskFormat::Raw);
+            } else if line.starts_with("virtual size:") {
+                if let Some(size_part) = line.split('(').nth(1) {
+                    if let Some(size_str) = size_part.split(' ').next() {
+                        if let Ok(size_bytes) = size_str.parse::<f64>() {
+                            info.virtual_size_gb = size_bytes / (1024.0 * 1024.0 * 1024.0);
+                        }
+                    }
+                }
+            } else if line.starts_with("disk size:") {
+                if let Some(size_str) = line.split(':').nth(1) {
+                    let parts: Vec<&str> = size_str.trim().split(' ').collect();
+                    if parts.len() >= 2 {
+                        if let Ok(size) = parts[0].parse::<f64>() {
+                            // "K"/"M"/"G" on older qemu-img, "KiB"/"MiB"/"GiB" on newer
+                            let unit = parts[1].to_lowercase();
+                            info.actual_size_gb = match unit.chars().next() {
+                                Some('k') => size / (1024.0 * 1024.0),
+                                Some('m') => size / 1024.0,
+                                Some('g') => size,
+                                Some('t') => size * 1024.0,
+                                _ => size / (1024.0 * 1024.0 * 1024.0),
+                            };
+                        }
+                    }
+                }
+            } else if line.starts_with("backing file:") {
+                if let Some(backing_path) = line.split(':').nth(1) {
+                    let path_str = backing_path.trim();
+                    if !path_str.is_empty() && path_str != "(null)" {
+                        info.backing_file = Some(PathBuf::from(path_str));
+                    }
+                }
+            } else if line.contains("encrypted: yes") {
+                info.encrypted = true;
+            } else if line.contains("Snapshot list:") {
+                info.snapshot_count = SnapshotInfo::from_qemu_output(output).len();
+            }
+        }
+        
+        info
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_round_trips_qcow2_through_raw() {
+        if crate::vm::qemu::find_in_path("qemu-img").is_none() {
+            eprintln!("skipping: qemu-img not installed");
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("aegis-convert-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let disks = DiskManager::new(&dir);
+        let options = DiskOptions::default().resolved(&DiskFormat::Qcow2);
+        let original = disks.create_disk("convert-vm", 1, DiskFormat::Qcow2, &options, None).unwrap();
+
+        let raw = disks.convert_disk("convert-vm", DiskFormat::Raw, true).unwrap();
+        assert_eq!(raw.format.extension(), "raw");
+        assert_eq!(raw.path, dir.join("convert-vm.raw"));
+        assert!(!original.exists());
+
+        let qcow2 = disks.convert_disk("convert-vm", DiskFormat::Qcow2, true).unwrap();
+        assert_eq!(qcow2.format.extension(), "qcow2");
+        assert_eq!(qcow2.path, original);
+        assert_eq!(qcow2.virtual_size_gb, 1.0);
+        assert!(!dir.join("convert-vm.raw").exists());
+
+        // Without replace the original stays and the copy goes aside
+        let copy = disks.convert_disk("convert-vm", DiskFormat::Raw, false).unwrap();
+        assert_eq!(copy.path, dir.join("converted").join("convert-vm.raw"));
+        assert!(original.exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // A v3 header with `backing` stored right after it
+    fn qcow2_header(backing: &[u8], incompatible: u64) -> Vec<u8> {
+        let mut image = vec![0u8; 104];
+        image[..4].copy_from_slice(b"QFI\xfb");
+        image[4..8].copy_from_slice(&3u32.to_be_bytes());
+        if !backing.is_empty() {
+            image[8..16].copy_from_slice(&104u64.to_be_bytes());
+            image[16..20].copy_from_slice(&(backing.len() as u32).to_be_bytes());
+        }
+        image[72..80].copy_from_slice(&incompatible.to_be_bytes());
+        image.extend_from_slice(backing);
+        image
+    }
+
+    #[test]
+    fn backing_files_are_read_from_the_qcow2_header() {
+        let dir = std::env::temp_dir().join(format!("aegis-backing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("disk.qcow2");
+
+        fs::write(&image, qcow2_header(b"", 0)).unwrap();
+        assert_eq!(image_backing_file(&image, &DiskFormat::Qcow2).unwrap(), None);
+
+        fs::write(&image, qcow2_header(b"/etc/shadow", 0)).unwrap();
+        assert_eq!(image_backing_file(&image, &DiskFormat::Qcow2).unwrap(), Some(PathBuf::from("/etc/shadow")));
+
+        fs::write(&image, qcow2_header(b"", QCOW2_EXTERNAL_DATA_FILE)).unwrap();
+        assert!(image_backing_file(&image, &DiskFormat::Qcow2).is_err());
+
+        fs::write(&image, b"not an image").unwrap();
+        assert!(image_backing_file(&image, &DiskFormat::Qcow2).is_err());
+
+        // A vmdk descriptor lists its extents as paths
+        let vmdk = dir.join("disk.vmdk");
+        fs::write(&vmdk, "# Disk DescriptorFile\nRW 2048 FLAT \"/dev/sda\" 0\n").unwrap();
+        assert!(image_backing_file(&vmdk, &DiskFormat::Vmdk).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn auto_snapshots_are_pruned_oldest_first_sparing_the_restored_one() {
+        let first = auto_snapshot_name("resize");
+        let second = auto_snapshot_name("resize");
+        assert_ne!(first, second);
+        assert!(validate_snapshot_name(&first).is_ok());
+
+        let snapshots = ["auto-pre-resize-1", "manual", "auto-pre-restore-2", "auto-pre-convert-3"].iter()
+            .enumerate()
+            .map(|(id, name)| SnapshotInfo {
+                id: (id + 1).to_string(),
+                name: name.to_string(),
+                vm_size: "0 B".to_string(),
+                date: String::new(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(excess_auto_snapshots(snapshots.clone(), 1, None), ["auto-pre-resize-1", "auto-pre-restore-2"]);
+        assert_eq!(excess_auto_snapshots(snapshots, 1, Some("auto-pre-resize-1")), ["auto-pre-restore-2"]);
+    }
 }
\ No newline at end of file
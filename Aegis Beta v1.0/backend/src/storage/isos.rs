@@ -1,231 +1,362 @@
-use std::fs;
-use std::io;
-use std::path::{Path, PathBuf};
-use std::io::Write;
-
-use crate::security::validation::{validate_iso_path, calculate_file_hash, ValidationError};
-
-#[derive(Debug, thiserror::Error)]
-pub enum IsoError {
-    #[error("IO error: {0}")]
-    IoError(#[from] io::Error),
-    #[error("Validation error: {0}")]
-    ValidationError(#[from] ValidationError),
-    #[error("ISO not found: {0}")]
-    NotFound(String),
-    #[error("ISO already exists: {0}")]
-    AlreadyExists(String),
-    #[error("Upload failed: {0}")]
-    UploadFailed(String),
-}
-
-pub struct IsoManager {
-    iso_dir: PathBuf,
-}
-
-impl IsoManager {
-    pub fn new(iso_dir: &Path) -> Self {
-        Self {
-            iso_dir: iso_dir.to_path_buf(),
-        }
-    }
-
-    pub fn add_iso(&self, source_path: &Path, name: Option<&str>) -> Result<IsoInfo, IsoError> {
-        // Validate source path
-        validate_iso_path(source_path.to_str().unwrap_or(""))?;
-        
-        let file_name = name.map(|n| n.to_string())
-            .unwrap_or_else(|| {
-                source_path.file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("unknown")
-                    .to_string()
-            });
-        
-        let dest_path = self.iso_dir.join(&file_name);
-        
-        // Check if ISO already exists
-        if dest_path.exists() {
-            return Err(IsoError::AlreadyExists(file_name));
-        }
-        
-        // Copy ISO file
-        fs::copy(source_path, &dest_path)?;
-        
-        // Calculate hash
-        let hash = calculate_file_hash(&dest_path)?;
-        
-        // Get file size
-        let metadata = fs::metadata(&dest_path)?;
-        let size_gb = metadata.len() as f64 / (1024.0 * 1024.0 * 1024.0);
-        
-        // Create info file
-        let info = IsoInfo {
-            name: file_name.clone(),
-            path: dest_path,
-            size_gb,
-            hash,
-            uploaded_at: chrono::Utc::now(),
-        };
-        
-        // Save info as JSON
-        let info_json = serde_json::to_string_pretty(&info)?;
-        let info_path = self.iso_dir.join(format!("{}.json", file_name));
-        fs::write(info_path, info_json)?;
-        
-        Ok(info)
-    }
-
-    pub fn upload_iso(&self, data: &[u8], filename: &str) -> Result<IsoInfo, IsoError> {
-        // Validate filename
-        validate_iso_path(filename)?;
-        
-        let dest_path = self.iso_dir.join(filename);
-        
-        // Check if ISO already exists
-        if dest_path.exists() {
-            return Err(IsoError::AlreadyExists(filename.to_string()));
-        }
-        
-        // Write uploaded data
-        let mut file = fs::File::create(&dest_path)?;
-        file.write_all(data)?;
-        
-        // Calculate hash
-        let hash = calculate_file_hash(&dest_path)?;
-        
-        // Get file size
-        let size_gb = data.len() as f64 / (1024.0 * 1024.0 * 1024.0);
-        
-        // Create info
-        let info = IsoInfo {
-            name: filename.to_string(),
-            path: dest_path,
-            size_gb,
-            hash,
-            uploaded_at: chrono::Utc::now(),
-        };
-        
-        // Save info
-        let info_json = serde_json::to_string_pretty(&info)?;
-        let info_path = self.iso_dir.join(format!("{}.json", filename));
-        fs::write(info_path, info_json)?;
-        
-        Ok(info)
-    }
-
-    pub fn delete_iso(&self, name: &str) -> Result<(), IsoError> {
-        let iso_path = self.iso_dir.join(name);
-        let info_path = self.iso_dir.join(format!("{}.json", name));
-        
-        if !iso_path.exists() {
-            return Err(IsoError::NotFound(name.to_string()));
-        }
-        
-        // Delete ISO file
-        fs::remove_file(&iso_path)?;
-        
-        // Delete info file if exists
-        if info_path.exists() {
-            fs::remove_file(&info_path)?;
-        }
-        
-        Ok(())
-    }
-
-    pub fn get_iso(&self, name: &str) -> Result<IsoInfo, IsoError> {
-        let info_path = self.iso_dir.join(format!("{}.json", name));
-        
-        if info_path.exists() {
-            let data = fs::read_to_string(&info_path)?;
-            let info: IsoInfo = serde_json::from_str(&data)?;
-            Ok(info)
-        } else {
-            // Try to create info from ISO file
-            let iso_path = self.iso_dir.join(name);
-            if iso_path.exists() {
-                let hash = calculate_file_hash(&iso_path)?;
-                let metadata = fs::metadata(&iso_path)?;
-                let size_gb = metadata.len() as f64 / (1024.0 * 1024.0 * 1024.0);
-                
-                let info = IsoInfo {
-                    name: name.to_string(),
-                    path: iso_path,
-                    size_gb,
-                    hash,
-                    uploaded_at: chrono::Utc::now(),
-                };
-                
-                // Save info for next time
-                let info_json = serde_json::to_string_pretty(&info)?;
-                fs::write(info_path, info_json)?;
-                
-                Ok(info)
-            } else {
-                Err(IsoError::NotFound(name.to_string()))
-            }
-        }
-    }
-
-    pub fn list_isos(&self) -> Result<Vec<IsoInfo>, IsoError> {
-        let mut isos = Vec::new();
-        
-        for entry in fs::read_dir(&self.iso_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if path.is_file() {
-                let extension = path.extension()
-                    .and_then(|e| e.to_str())
-                    .unwrap_or("");
-                
-                // Skip JSON files
-                if extension == "json" {
-                    continue;
-                }
-                
-                // Check if it's an ISO file by extension
-                let valid_extensions = vec!["iso", "img", "qcow2", "raw"];
-                if valid_extensions.contains(&extension) {
-                    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                        if let Ok(info) = self.get_iso(name) {
-                            isos.push(info);
-                        }
-                    }
-                }
-            }
-        }
-        
-        Ok(isos)
-    }
-
-    pub fn verify_iso(&self, name: &str, expected_hash: &str) -> Result<bool, IsoError> {
-        let info = self.get_iso(name)?;
-        Ok(info.hash == expected_hash)
-    }
-
-    pub fn get_iso_path(&self, name: &str) -> Result<PathBuf, IsoError> {
-        let iso_path = self.iso_dir.join(name);
-        
-        if iso_path.exists() {
-            Ok(iso_path)
-        } else {
-            Err(IsoError::NotFound(name.to_string()))
-        }
-    }
-}
-
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct IsoInfo {
-    pub name: String,
-    pub path: PathBuf,
-    pub size_gb: f64,
-    pub hash: String,
-    pub uploaded_at: chrono::DateTime<chrono::Utc>,
-}
-
-impl IsoInfo {
-    pub fn to_json(&self) -> Result<String, serde_json::Error> {
-        serde_json::to_string_pretty(self)
-    }
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use crate::security::validation::{
+    validate_iso_file_name, validate_iso_size, calculate_file_hash, ValidationError,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum IsoError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Validation error: {0}")]
+    ValidationError(#[from] ValidationError),
+    #[error("ISO not found: {0}")]
+    NotFound(String),
+    #[error("ISO already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Upload failed: {0}")]
+    UploadFailed(String),
+    #[error("Download failed: {0}")]
+    DownloadFailed(String),
+    #[error("Upload not found: {0}")]
+    UploadNotFound(String),
+    #[error("Upload conflict: {0}")]
+    UploadConflict(String),
+    #[error("Not enough space for ISO: {needed} bytes needed, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    // The .json sidecar kept next to each ISO
+    #[error("ISO metadata error: {0}")]
+    Metadata(#[from] serde_json::Error),
+}
+
+#[derive(Clone)]
+pub struct IsoManager {
+    iso_dir: PathBuf,
+}
+
+impl IsoManager {
+    pub fn new(iso_dir: &Path) -> Self {
+        Self {
+            iso_dir: iso_dir.to_path_buf(),
+        }
+    }
+
+    // Size and free-space checks for an incoming ISO of `size` bytes; run
+    // before any of it is written so a rejected upload leaves nothing behind
+    pub fn check_incoming(&self, size: u64) -> Result<(), IsoError> {
+        validate_iso_size(size)?;
+        
+        let stat = nix::sys::statvfs::statvfs(&self.iso_dir)
+            .map_err(|e| IsoError::IoError(io::Error::from(e)))?;
+        let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+        if size > available {
+            return Err(IsoError::InsufficientSpace { needed: size, available });
+        }
+        
+        Ok(())
+    }
+
+    // Streams the ISO through curl into a hidden partial file, which only
+    // becomes visible once complete and verified. The size cap is enforced
+    // as bytes arrive, since servers can omit or misreport Content-Length.
+    // `progress` gets the running byte count after each chunk.
+    pub fn download_iso<F: FnMut(u64)>(
+        &self,
+        url: &str,
+        name: Option<&str>,
+        expected_hash: Option<&str>,
+        mut progress: F,
+    ) -> Result<IsoInfo, IsoError> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(IsoError::ValidationError(ValidationError::InvalidIsoPath(
+                format!("Not an http(s) URL: {}", url)
+            )));
+        }
+        
+        // Default to the last path segment, without query or fragment
+        let file_name = name.map(|n| n.to_string())
+            .unwrap_or_else(|| {
+                url.split(['?', '#']).next()
+                    .and_then(|path| path.rsplit('/').next())
+                    .unwrap_or("")
+                    .to_string()
+            });
+        self.check_new_iso(&file_name, None)?;
+        
+        let partial_path = self.iso_dir.join(format!(".{}.part", file_name));
+        if let Err(e) = self.fetch_to(url, &partial_path, &mut progress) {
+            let _ = fs::remove_file(&partial_path);
+            return Err(e);
+        }
+        
+        self.complete_partial(&partial_path, &file_name, expected_hash)
+    }
+
+    // Up-front checks for a new ISO named `file_name`, of `size` bytes when
+    // that is known in advance
+    pub fn check_new_iso(&self, file_name: &str, size: Option<u64>) -> Result<(), IsoError> {
+        validate_iso_file_name(file_name)?;
+        
+        if self.iso_dir.join(file_name).exists() {
+            return Err(IsoError::AlreadyExists(file_name.to_string()));
+        }
+        
+        if let Some(size) = size {
+            self.check_incoming(size)?;
+        }
+        
+        Ok(())
+    }
+
+    // Where an in-progress chunked upload accumulates. Hidden, and without
+    // an ISO extension, so list_isos never picks it up.
+    pub fn partial_upload_path(&self, upload_id: &str) -> PathBuf {
+        self.iso_dir.join(format!(".upload-{}.part", upload_id))
+    }
+
+    // Partial uploads don't survive a restart; their sessions were in memory
+    pub fn remove_partial_uploads(&self) -> Result<(), IsoError> {
+        for entry in fs::read_dir(&self.iso_dir)? {
+            let path = entry?.path();
+            let stale = path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(".upload-") && n.ends_with(".part"));
+            if stale {
+                log::info!("Removing interrupted upload {}", path.display());
+                let _ = fs::remove_file(&path);
+            }
+        }
+        
+        Ok(())
+    }
+
+    // Turns a fully received partial file into the ISO `file_name`: hashes
+    // it, checks `expected_hash`, moves it into place and writes its info
+    // file. The partial file is gone afterwards either way.
+    pub fn complete_partial(&self, partial_path: &Path, file_name: &str, expected_hash: Option<&str>) -> Result<IsoInfo, IsoError> {
+        let result = self.check_new_iso(file_name, None)
+            .and_then(|_| Ok(calculate_file_hash(partial_path)?))
+            .and_then(|hash| match expected_hash {
+                Some(expected) if !hash.eq_ignore_ascii_case(expected.trim()) => {
+                    Err(IsoError::ValidationError(ValidationError::IsoHashMismatch))
+                }
+                _ => Ok(hash),
+            });
+        let hash = match result {
+            Ok(hash) => hash,
+            Err(e) => {
+                let _ = fs::remove_file(partial_path);
+                return Err(e);
+            }
+        };
+        
+        let dest_path = self.iso_dir.join(file_name);
+        fs::rename(partial_path, &dest_path)?;
+        
+        // Get file size
+        let metadata = fs::metadata(&dest_path)?;
+        let size_gb = metadata.len() as f64 / (1024.0 * 1024.0 * 1024.0);
+        
+        // Create info
+        let info = IsoInfo {
+            name: file_name.to_string(),
+            path: dest_path,
+            size_gb,
+            hash,
+            uploaded_at: chrono::Utc::now(),
+        };
+        
+        // Save info
+        let info_json = serde_json::to_string_pretty(&info)?;
+        let info_path = self.iso_dir.join(format!("{}.json", file_name));
+        fs::write(info_path, info_json)?;
+        
+        Ok(info)
+    }
+
+    fn fetch_to(&self, url: &str, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), IsoError> {
+        let mut child = Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--location", "--max-redirs", "5"])
+            .args(["--proto", "=http,https", "--proto-redir", "=http,https"])
+            // Give up on stalled transfers rather than on slow ones
+            .args(["--connect-timeout", "30", "--speed-limit", "1024", "--speed-time", "120"])
+            .arg("--output").arg("-")
+            .arg(url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        
+        let mut stdout = child.stdout.take()
+            .ok_or_else(|| IsoError::DownloadFailed("curl has no stdout".to_string()))?;
+        let mut file = fs::File::create(path)?;
+        let mut buf = vec![0u8; 1024 * 1024];
+        let mut written = 0u64;
+        
+        let copied: Result<(), IsoError> = loop {
+            let n = match stdout.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e.into()),
+            };
+            written += n as u64;
+            if let Err(e) = validate_iso_size(written) {
+                break Err(e.into());
+            }
+            if let Err(e) = file.write_all(&buf[..n]) {
+                break Err(e.into());
+            }
+            progress(written);
+        };
+        
+        if let Err(e) = copied {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        
+        file.sync_all()?;
+        
+        let status = child.wait()?;
+        if !status.success() {
+            let mut stderr = String::new();
+            if let Some(mut pipe) = child.stderr.take() {
+                let _ = pipe.read_to_string(&mut stderr);
+            }
+            return Err(IsoError::DownloadFailed(format!("{}: {}", url, stderr.trim())));
+        }
+        
+        Ok(())
+    }
+
+    pub fn delete_iso(&self, name: &str) -> Result<(), IsoError> {
+        let iso_path = self.get_iso_path(name)?;
+        let info_path = self.iso_dir.join(format!("{}.json", name));
+        
+        // Delete ISO file
+        fs::remove_file(&iso_path)?;
+        
+        // Delete info file if exists
+        if info_path.exists() {
+            fs::remove_file(&info_path)?;
+        }
+        
+        Ok(())
+    }
+
+    pub fn get_iso(&self, name: &str) -> Result<IsoInfo, IsoError> {
+        let info_path = self.iso_dir.join(format!("{}.json", name));
+        
+        if info_path.exists() {
+            let data = fs::read_to_string(&info_path)?;
+            let info: IsoInfo = serde_json::from_str(&data)?;
+            Ok(info)
+        } else {
+            // Try to create info from ISO file
+            let iso_path = self.iso_dir.join(name);
+            if iso_path.exists() {
+                let hash = calculate_file_hash(&iso_path)?;
+                let metadata = fs::metadata(&iso_path)?;
+                let size_gb = metadata.len() as f64 / (1024.0 * 1024.0 * 1024.0);
+                
+                let info = IsoInfo {
+                    name: name.to_string(),
+                    path: iso_path,
+                    size_gb,
+                    hash,
+                    uploaded_at: chrono::Utc::now(),
+                };
+                
+                // Save info for next time
+                let info_json = serde_json::to_string_pretty(&info)?;
+                fs::write(info_path, info_json)?;
+                
+                Ok(info)
+            } else {
+                Err(IsoError::NotFound(name.to_string()))
+            }
+        }
+    }
+
+    pub fn list_isos(&self) -> Result<IsoListing, IsoError> {
+        let mut listing = IsoListing::default();
+        
+        for entry in fs::read_dir(&self.iso_dir)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    listing.skip(format!("Failed to read entry in {}: {}", self.iso_dir.display(), e));
+                    continue;
+                }
+            };
+            let path = entry.path();
+            
+            if path.is_file() {
+                let extension = path.extension()
+                    .and_then(|e| e.to_str())
+                    .unwrap_or("");
+                
+                // Skip JSON files
+                if extension == "json" {
+                    continue;
+                }
+                
+                // Check if it's an ISO file by extension
+                let valid_extensions = ["iso", "img", "qcow2", "raw"];
+                if valid_extensions.contains(&extension) {
+                    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                        match self.get_iso(name) {
+                            Ok(info) => listing.isos.push(info),
+                            Err(e) => listing.skip(format!("Skipping ISO {}: {}", name, e)),
+                        }
+                    }
+                }
+            }
+        }
+        
+        Ok(listing)
+    }
+
+    pub fn get_iso_path(&self, name: &str) -> Result<PathBuf, IsoError> {
+        // A bare file name, so the result can't leave iso_dir
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(IsoError::ValidationError(ValidationError::InvalidPath(name.to_string())));
+        }
+        
+        let iso_path = self.iso_dir.join(name);
+        
+        if iso_path.exists() {
+            Ok(iso_path)
+        } else {
+            Err(IsoError::NotFound(name.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct IsoInfo {
+    pub name: String,
+    pub path: PathBuf,
+    pub size_gb: f64,
+    pub hash: String,
+    pub uploaded_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct IsoListing {
+    pub isos: Vec<IsoInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl IsoListing {
+    fn skip(&mut self, warning: String) {
+        log::warn!("{}", warning);
+        self.warnings.push(warning);
+    }
 }
\ No newline at end of file
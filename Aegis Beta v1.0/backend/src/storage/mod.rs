@@ -1,5 +1,3 @@
-pub mod disks;
-pub mod isos;
-
-pub use disks::*;
-pub use isos::*;
\ No newline at end of file
+pub mod disks;
+pub mod isos;
+pub mod uploads;
\ No newline at end of file
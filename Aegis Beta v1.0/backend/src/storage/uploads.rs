@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Mutex;
+
+use bytes::Buf;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::security::validation::validate_iso_size;
+use super::isos::{IsoError, IsoInfo, IsoManager};
+
+// An ISO arriving in chunks. `received` is the length of the partial file,
+// which only ever holds a prefix of the image, so after a dropped
+// connection it is where to resume.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub name: String,
+    // Total size, if the client declared it up front
+    pub size: Option<u64>,
+    pub received: u64,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    // A chunk is being written right now
+    #[serde(skip)]
+    writing: bool,
+}
+
+// Chunked ISO uploads, so multi-GB images stream to disk instead of being
+// buffered in memory. Sessions live in memory only; partial files left by
+// a previous run are removed on startup.
+pub struct UploadManager {
+    isos: IsoManager,
+    sessions: Mutex<HashMap<String, UploadSession>>,
+}
+
+impl UploadManager {
+    pub fn new(iso_dir: &Path) -> Self {
+        let isos = IsoManager::new(iso_dir);
+        if let Err(e) = isos.remove_partial_uploads() {
+            log::warn!("Failed to clean up partial uploads in {}: {}", iso_dir.display(), e);
+        }
+
+        Self {
+            isos,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn begin(&self, name: &str, size: Option<u64>) -> Result<UploadSession, IsoError> {
+        self.isos.check_new_iso(name, size)?;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.values().any(|session| session.name == name) {
+            return Err(IsoError::UploadConflict(format!("{} is already being uploaded", name)));
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        fs::File::create(self.isos.partial_upload_path(&id))?;
+
+        let now = chrono::Utc::now();
+        let session = UploadSession {
+            id: id.clone(),
+            name: name.to_string(),
+            size,
+            received: 0,
+            started_at: now,
+            updated_at: now,
+            writing: false,
+        };
+        sessions.insert(id, session.clone());
+
+        log::info!("Started upload {} of {}", session.id, name);
+        Ok(session)
+    }
+
+    pub fn status(&self, id: &str) -> Result<UploadSession, IsoError> {
+        self.sessions.lock().unwrap().get(id)
+            .cloned()
+            .ok_or_else(|| IsoError::UploadNotFound(id.to_string()))
+    }
+
+    // Writes `body` at `offset`, which has to be where the upload currently
+    // stands. Chunks of one upload are taken one at a time.
+    pub async fn append<S, B, E>(&self, id: &str, offset: u64, mut body: S) -> Result<UploadSession, IsoError>
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: Buf,
+        E: std::fmt::Display,
+    {
+        let limit = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions.get_mut(id).ok_or_else(|| IsoError::UploadNotFound(id.to_string()))?;
+            if session.writing {
+                return Err(IsoError::UploadConflict(format!("Upload {} is already receiving a chunk", id)));
+            }
+            if offset != session.received {
+                return Err(IsoError::UploadConflict(format!(
+                    "Upload {} is at offset {}, not {}", id, session.received, offset
+                )));
+            }
+            session.writing = true;
+            session.size
+        };
+
+        let path = self.isos.partial_upload_path(id);
+        let result = {
+            let _guard = ChunkGuard { uploads: self, id, path: &path };
+            write_chunk(&path, offset, limit, &mut body).await
+        };
+
+        // Not found if it was aborted while the chunk was arriving
+        let session = self.status(id)?;
+        result.map(|_| session)
+    }
+
+    // Hashes the upload and adds it as an ISO. The session ends either way;
+    // an upload that fails here (e.g. on `expected_hash`) is discarded.
+    pub fn complete(&self, id: &str, expected_hash: Option<&str>) -> Result<IsoInfo, IsoError> {
+        let session = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions.get(id).ok_or_else(|| IsoError::UploadNotFound(id.to_string()))?;
+            if session.writing {
+                return Err(IsoError::UploadConflict(format!("Upload {} is still receiving a chunk", id)));
+            }
+            if let Some(size) = session.size {
+                if session.received != size {
+                    return Err(IsoError::UploadConflict(format!(
+                        "Upload {} has {} of {} bytes", id, session.received, size
+                    )));
+                }
+            }
+            sessions.remove(id).unwrap()
+        };
+
+        let info = self.isos.complete_partial(&self.isos.partial_upload_path(id), &session.name, expected_hash)?;
+        log::info!("Completed upload {} of {} ({})", id, info.name, info.hash);
+        Ok(info)
+    }
+
+    pub fn abort(&self, id: &str) -> Result<(), IsoError> {
+        let session = self.sessions.lock().unwrap().remove(id)
+            .ok_or_else(|| IsoError::UploadNotFound(id.to_string()))?;
+
+        let _ = fs::remove_file(self.isos.partial_upload_path(id));
+        log::info!("Aborted upload {} of {}", id, session.name);
+        Ok(())
+    }
+}
+
+// Ends a chunk however the write finishes, including the handler being
+// dropped when the client disconnects
+struct ChunkGuard<'a> {
+    uploads: &'a UploadManager,
+    id: &'a str,
+    path: &'a Path,
+}
+
+impl Drop for ChunkGuard<'_> {
+    fn drop(&mut self) {
+        let mut sessions = self.uploads.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(self.id) {
+            session.writing = false;
+            if let Ok(metadata) = fs::metadata(self.path) {
+                session.received = metadata.len();
+            }
+            session.updated_at = chrono::Utc::now();
+        }
+    }
+}
+
+async fn write_chunk<S, B, E>(path: &Path, offset: u64, limit: Option<u64>, body: &mut S) -> Result<(), IsoError>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: Buf,
+    E: std::fmt::Display,
+{
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    // Drop whatever an interrupted chunk left beyond the offset
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut written = 0u64;
+    let result = loop {
+        let mut chunk = match body.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => break Err(IsoError::UploadFailed(format!("Chunk interrupted: {}", e))),
+            None => break Ok(()),
+        };
+
+        let total = offset + written + chunk.remaining() as u64;
+        if let Err(e) = validate_iso_size(total) {
+            break Err(e.into());
+        }
+        if let Some(size) = limit {
+            if total > size {
+                break Err(IsoError::UploadFailed(format!("More than the declared {} bytes", size)));
+            }
+        }
+
+        let mut failed = None;
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            let len = bytes.len();
+            if let Err(e) = file.write_all(bytes).await {
+                failed = Some(e);
+                break;
+            }
+            chunk.advance(len);
+            written += len as u64;
+        }
+        if let Some(e) = failed {
+            break Err(e.into());
+        }
+    };
+
+    // tokio may still be holding the last write; land it before the guard
+    // reads the file length
+    file.flush().await?;
+    result
+}
\ No newline at end of file
@@ -0,0 +1,127 @@
+use std::io::{self, Read};
+use std::process::{Command, Output, Stdio};
+use std::sync::{OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("{0} timed out after {1:?}")]
+    Timeout(String, Duration),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CommandCategory {
+    // qemu-img create/info/resize/snapshot
+    Disk,
+    // Whole-image copies: convert, compaction, export
+    DiskCopy,
+    // ip, iptables, tc
+    Network,
+    // systemctl and tool probes
+    Service,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandTimeouts {
+    pub disk: Duration,
+    pub disk_copy: Duration,
+    pub network: Duration,
+    pub service: Duration,
+}
+
+impl Default for CommandTimeouts {
+    fn default() -> Self {
+        Self {
+            disk: Duration::from_secs(120),
+            disk_copy: Duration::from_secs(3600),
+            network: Duration::from_secs(10),
+            service: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CommandTimeouts {
+    pub fn get(&self, category: CommandCategory) -> Duration {
+        match category {
+            CommandCategory::Disk => self.disk,
+            CommandCategory::DiskCopy => self.disk_copy,
+            CommandCategory::Network => self.network,
+            CommandCategory::Service => self.service,
+        }
+    }
+}
+
+static COMMAND_TIMEOUTS: OnceLock<RwLock<CommandTimeouts>> = OnceLock::new();
+
+fn timeouts_lock() -> &'static RwLock<CommandTimeouts> {
+    COMMAND_TIMEOUTS.get_or_init(|| RwLock::new(CommandTimeouts::default()))
+}
+
+pub fn set_command_timeouts(timeouts: CommandTimeouts) {
+    *timeouts_lock().write().unwrap() = timeouts;
+}
+
+pub fn command_timeout(category: CommandCategory) -> Duration {
+    timeouts_lock().read().unwrap().get(category)
+}
+
+// Like Command::output, but kills the child and returns CommandError::Timeout
+// if it hasn't exited within `timeout`
+pub fn run_with_timeout(cmd: &mut Command, timeout: Duration) -> Result<Output, CommandError> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain pipes on their own threads so a chatty child can't block on a full pipe
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            log::warn!("{} timed out after {:?}, killed", program, timeout);
+            return Err(CommandError::Timeout(program, timeout));
+        }
+
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+pub trait CommandTimeoutExt {
+    fn output_within(&mut self, category: CommandCategory) -> Result<Output, CommandError>;
+}
+
+impl CommandTimeoutExt for Command {
+    fn output_within(&mut self, category: CommandCategory) -> Result<Output, CommandError> {
+        run_with_timeout(self, command_timeout(category))
+    }
+}
\ No newline at end of file
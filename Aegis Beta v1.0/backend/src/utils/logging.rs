@@ -1,111 +1,149 @@
-use std::fs::{self, OpenOptions};
-use std::io::{self, Write};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use chrono::Local;
-
-pub struct Logger {
-    log_file: Option<Arc<Mutex<fs::File>>>,
-    log_level: LogLevel,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub enum LogLevel {
-    Error = 0,
-    Warn = 1,
-    Info = 2,
-    Debug = 3,
-    Trace = 4,
-}
-
-impl LogLevel {
-    pub fn from_str(level: &str) -> Self {
-        match level.to_lowercase().as_str() {
-            "error" => LogLevel::Error,
-            "warn" => LogLevel::Warn,
-            "info" => LogLevel::Info,
-            "debug" => LogLevel::Debug,
-            "trace" => LogLevel::Trace,
-            _ => LogLevel::Info,
-        }
-    }
-}
-
-impl Logger {
-    pub fn new(log_dir: &str, level: LogLevel) -> io::Result<Self> {
-        // Create log directory if it doesn't exist
-        fs::create_dir_all(log_dir)?;
-        
-        // Create log file with date suffix
-        let date = Local::now().format("%Y-%m-%d");
-        let log_path = PathBuf::from(log_dir).join(format!("vm-manager-{}.log", date));
-        
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(log_path)?;
-        
-        Ok(Self {
-            log_file: Some(Arc::new(Mutex::new(file))),
-            log_level,
-        })
-    }
-    
-    pub fn console_only(level: LogLevel) -> Self {
-        Self {
-            log_file: None,
-            log_level,
-        }
-    }
-    
-    pub fn log(&self, level: LogLevel, module: &str, message: &str) {
-        if level > self.log_level {
-            return;
-        }
-        
-        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
-        let level_str = match level {
-            LogLevel::Error => "ERROR",
-            LogLevel::Warn => "WARN",
-            LogLevel::Info => "INFO",
-            LogLevel::Debug => "DEBUG",
-            LogLevel::Trace => "TRACE",
-        };
-        
-        let log_line = format!("{} [{}] {}: {}\n", timestamp, level_str, module, message);
-        
-        // Print to console (with color)
-        match level {
-            LogLevel::Error => eprint!("\x1b[31m{}\x1b[0m", log_line),
-            LogLevel::Warn => eprint!("\x1b[33m{}\x1b[0m", log_line),
-            LogLevel::Info => print!("{}", log_line),
-            LogLevel::Debug => print!("\x1b[36m{}\x1b[0m", log_line),
-            LogLevel::Trace => print!("\x1b[90m{}\x1b[0m", log_line),
-        }
-        
-        // Write to file if configured
-        if let Some(log_file) = &self.log_file {
-            if let Ok(mut file) = log_file.lock() {
-                let _ = file.write_all(log_line.as_bytes());
-            }
-        }
-    }
-    
-    pub fn error(&self, module: &str, message: &str) {
-        self.log(LogLevel::Error, module, message);
-    }
-    
-    pub fn warn(&self, module: &str, message: &str) {
-        self.log(LogLevel::Warn, module, message);
-    }
-    
-    pub fn info(&self, module: &str, message: &str) {
-        self.log(LogLevel::Info, module, message);
-    }
-    
-    pub fn debug(&self, module: &str, message: &str) {
-        self.log(LogLevel::Debug, module, message);
-    }
-    
-    pub fn trace(&self, module: &str, message: &str) {
-       
\ No newline at end of file
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+pub struct Logger {
+    log_file: Option<Arc<Mutex<fs::File>>>,
+    log_level: LogLevel,
+    log_dir: Option<PathBuf>,
+    vm_logs: Mutex<HashMap<String, VmLog>>,
+}
+
+struct VmLog {
+    level: LogLevel,
+    file: fs::File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Logger {
+    pub fn new(log_dir: &str, level: LogLevel) -> io::Result<Self> {
+        // Create log directory if it doesn't exist
+        fs::create_dir_all(log_dir)?;
+        
+        // Create log file with date suffix
+        let date = Local::now().format("%Y-%m-%d");
+        let log_path = PathBuf::from(log_dir).join(format!("vm-manager-{}.log", date));
+        
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)?;
+        
+        Ok(Self {
+            log_file: Some(Arc::new(Mutex::new(file))),
+            log_level: level,
+            log_dir: Some(PathBuf::from(log_dir)),
+            vm_logs: Mutex::new(HashMap::new()),
+        })
+    }
+    
+    // Pushes every open log file to disk, e.g. before the process exits
+    pub fn flush(&self) -> io::Result<()> {
+        if let Some(file) = &self.log_file {
+            let mut file = file.lock().unwrap();
+            file.flush()?;
+            file.sync_data()?;
+        }
+        for vm_log in self.vm_logs.lock().unwrap().values_mut() {
+            vm_log.file.flush()?;
+            vm_log.file.sync_data()?;
+        }
+        Ok(())
+    }
+    
+    // Sends a copy of this VM's entries at or above `level` to logs/vm-<id>.log,
+    // independent of the global level
+    pub fn set_vm_log_level(&self, vm_id: &str, level: LogLevel) -> io::Result<()> {
+        let log_dir = self.log_dir.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "Logger has no log directory")
+        })?;
+        
+        let mut vm_logs = self.vm_logs.lock().unwrap();
+        if let Some(vm_log) = vm_logs.get_mut(vm_id) {
+            vm_log.level = level;
+            return Ok(());
+        }
+        
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_dir.join(format!("vm-{}.log", vm_id)))?;
+        
+        vm_logs.insert(vm_id.to_string(), VmLog { level, file });
+        Ok(())
+    }
+    
+    pub fn clear_vm_log_level(&self, vm_id: &str) {
+        self.vm_logs.lock().unwrap().remove(vm_id);
+    }
+    
+    pub fn vm_log_level(&self, vm_id: &str) -> Option<LogLevel> {
+        self.vm_logs.lock().unwrap().get(vm_id).map(|l| l.level)
+    }
+    
+    pub fn log_vm(&self, level: LogLevel, module: &str, vm_id: &str, message: &str) {
+        let tagged = format!("[vm {}] {}", vm_id, message);
+        self.log(level, module, &tagged);
+        
+        let mut vm_logs = self.vm_logs.lock().unwrap();
+        if let Some(vm_log) = vm_logs.get_mut(vm_id) {
+            if level <= vm_log.level {
+                let _ = vm_log.file.write_all(format_line(level, module, &tagged).as_bytes());
+            }
+        }
+    }
+    
+    pub fn log(&self, level: LogLevel, module: &str, message: &str) {
+        if level > self.log_level {
+            return;
+        }
+        
+        let log_line = format_line(level, module, message);
+        
+        // Print to console (with color)
+        match level {
+            LogLevel::Error => eprint!("\x1b[31m{}\x1b[0m", log_line),
+            LogLevel::Warn => eprint!("\x1b[33m{}\x1b[0m", log_line),
+            LogLevel::Info => print!("{}", log_line),
+            LogLevel::Debug => print!("\x1b[36m{}\x1b[0m", log_line),
+            LogLevel::Trace => print!("\x1b[90m{}\x1b[0m", log_line),
+        }
+        
+        // Write to file if configured
+        if let Some(log_file) = &self.log_file {
+            if let Ok(mut file) = log_file.lock() {
+                let _ = file.write_all(log_line.as_bytes());
+            }
+        }
+    }
+    
+    pub fn info(&self, module: &str, message: &str) {
+        self.log(LogLevel::Info, module, message);
+    }
+}
+
+fn format_line(level: LogLevel, module: &str, message: &str) -> String {
+    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+    let level_str = match level {
+        LogLevel::Error => "ERROR",
+        LogLevel::Warn => "WARN",
+        LogLevel::Info => "INFO",
+        LogLevel::Debug => "DEBUG",
+        LogLevel::Trace => "TRACE",
+    };
+    
+    format!("{} [{}] {}: {}\n", timestamp, level_str, module, message)
+}
\ No newline at end of file
@@ -1,5 +1,3 @@
-pub mod logging;
-pub mod ports;
-
-pub use logging::*;
-pub use ports::*;
\ No newline at end of file
+pub mod command;
+pub mod logging;
+pub mod ports;
\ No newline at end of file
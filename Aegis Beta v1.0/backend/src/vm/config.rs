@@ -1,178 +1,844 @@
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use uuid::Uuid;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct VMConfig {
-    pub id: String,
-    pub name: String,
-    pub iso_path: String,
-    pub memory_mb: u32,
-    pub cpu_cores: u32,
-    pub disk_size_gb: u32,
-    pub vnc_port: u16,
-    pub vnc_password: Option<String>,
-    pub network_type: NetworkType,
-    pub disk_format: DiskFormat,
-    pub machine_type: String,
-    pub cpu_type: String,
-    pub bios: BiosType,
-    pub extra_args: Vec<String>,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-    pub updated_at: chrono::DateTime<chrono::Utc>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CreateVMRequest {
-    pub name: String,
-    pub iso_path: String,
-    pub memory_mb: u32,
-    pub cpu_cores: u32,
-    pub disk_size_gb: u32,
-    pub vnc_password: Option<String>,
-    pub network_type: NetworkType,
-    pub disk_format: Option<DiskFormat>,
-    pub machine_type: Option<String>,
-    pub cpu_type: Option<String>,
-    pub bios: Option<BiosType>,
-    pub extra_args: Option<Vec<String>>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UpdateVMRequest {
-    pub name: Option<String>,
-    pub memory_mb: Option<u32>,
-    pub cpu_cores: Option<u32>,
-    pub vnc_password: Option<String>,
-    pub extra_args: Option<Vec<String>>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct VMStatus {
-    pub id: String,
-    pub name: String,
-    pub state: VMState,
-    pub pid: Option<u32>,
-    pub cpu_usage: f32,
-    pub memory_mb: u64,
-    pub vnc_port: u16,
-    pub uptime_seconds: u64,
-    pub disk_usage_gb: f64,
-    pub network_rx_bytes: u64,
-    pub network_tx_bytes: u64,
-    pub last_updated: chrono::DateTime<chrono::Utc>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub enum VMState {
-    Stopped,
-    Starting,
-    Running,
-    Stopping,
-    Paused,
-    Suspended,
-    Error(String),
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum NetworkType {
-    User,
-    Tap(String),
-    Bridge(String),
-    None,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum DiskFormat {
-    Qcow2,
-    Raw,
-    Vdi,
-    Vmdk,
-}
-
-impl DiskFormat {
-    pub fn extension(&self) -> &'static str {
-        match self {
-            DiskFormat::Qcow2 => "qcow2",
-            DiskFormat::Raw => "raw",
-            DiskFormat::Vdi => "vdi",
-            DiskFormat::Vmdk => "vmdk",
-        }
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum BiosType {
-    SeaBios,
-    Ovmf,
-    Custom(String),
-}
-
-impl VMConfig {
-    pub fn new(req: CreateVMRequest, vnc_port: u16) -> Self {
-        let now = chrono::Utc::now();
-        
-        Self {
-            id: Uuid::new_v4().to_string(),
-            name: req.name,
-            iso_path: req.iso_path,
-            memory_mb: req.memory_mb,
-            cpu_cores: req.cpu_cores,
-            disk_size_gb: req.disk_size_gb,
-            vnc_port,
-            vnc_password: req.vnc_password,
-            network_type: req.network_type,
-            disk_format: req.disk_format.unwrap_or(DiskFormat::Qcow2),
-            machine_type: req.machine_type.unwrap_or_else(|| "pc".to_string()),
-            cpu_type: req.cpu_type.unwrap_or_else(|| "host".to_string()),
-            bios: req.bios.unwrap_or(BiosType::SeaBios),
-            extra_args: req.extra_args.unwrap_or_default(),
-            created_at: now,
-            updated_at: now,
-        }
-    }
-    
-    pub fn update(&mut self, req: UpdateVMRequest) {
-        if let Some(name) = req.name {
-            self.name = name;
-        }
-        
-        if let Some(memory_mb) = req.memory_mb {
-            self.memory_mb = memory_mb;
-        }
-        
-        if let Some(cpu_cores) = req.cpu_cores {
-            self.cpu_cores = cpu_cores;
-        }
-        
-        if let Some(vnc_password) = req.vnc_password {
-            self.vnc_password = Some(vnc_password);
-        }
-        
-        if let Some(extra_args) = req.extra_args {
-            self.extra_args = extra_args;
-        }
-        
-        self.updated_at = chrono::Utc::now();
-    }
-    
-    pub fn to_json(&self) -> Result<String, serde_json::Error> {
-        serde_json::to_string_pretty(self)
-    }
-    
-    pub fn save_to_file(&self, path: &PathBuf) -> Result<(), std::io::Error> {
-        let json = self.to_json().map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, e)
-        })?;
-        
-        std::fs::write(path, json)
-    }
-    
-    pub fn load_from_file(path: &PathBuf) -> Result<Self, std::io::Error> {
-        let data = std::fs::read_to_string(path)?;
-        let config: Self = serde_json::from_str(&data)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        
-        Ok(config)
-    }
+use serde::{Deserialize, Deserializer, Serialize};
+use std::path::PathBuf;
+
+use crate::storage::disks::{DiskOptions, DiskSummary};
+
+pub const DEFAULT_MACHINE_TYPE: &str = "pc";
+pub const DEFAULT_CPU_TYPE: &str = "host";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VMConfig {
+    pub id: String,
+    pub name: String,
+    pub iso_path: String,
+    pub memory_mb: u32,
+    pub cpu_cores: u32,
+    pub disk_size_gb: u32,
+    pub vnc_port: u16,
+    pub vnc_password: Option<String>,
+    // NICs from the command line, net0 first; hot-plugged ones are separate
+    #[serde(default)]
+    pub networks: Vec<NetworkInterface>,
+    // The single NIC of configs from before `networks`; folded into it on load
+    #[serde(default, rename = "network_type", skip_serializing)]
+    legacy_network_type: Option<NetworkType>,
+    #[serde(default, rename = "mac_address", skip_serializing)]
+    legacy_mac_address: Option<String>,
+    pub disk_format: DiskFormat,
+    #[serde(default)]
+    pub discard: bool,
+    // What the disk was provisioned with
+    #[serde(default)]
+    pub disk_options: DiskOptions,
+    // Key file of an encrypted disk; the passphrase itself is never kept here
+    #[serde(default)]
+    pub disk_key: Option<PathBuf>,
+    // Volumes besides the primary disk; configs from before this field
+    // simply have none
+    #[serde(default)]
+    pub disks: Vec<DiskAttachment>,
+    #[serde(default)]
+    pub snapshot_schedule: Option<SnapshotPolicy>,
+    #[serde(default)]
+    pub idle_suspend: Option<IdleSuspendPolicy>,
+    // qcow2 backing file the disk was cloned from
+    #[serde(default)]
+    pub base_image: Option<String>,
+    // Configs from before this field are all x86_64
+    #[serde(default)]
+    pub arch: GuestArch,
+    #[serde(default)]
+    pub accel: Accelerator,
+    pub machine_type: String,
+    pub cpu_type: String,
+    pub bios: BiosType,
+    pub extra_args: Vec<String>,
+    #[serde(default)]
+    pub hotplug_nics: Vec<HotplugNic>,
+    // Blocks delete and destructive disk operations until cleared
+    #[serde(default)]
+    pub protected: bool,
+    // Never started itself; new VMs are stamped out of it as linked clones
+    #[serde(default)]
+    pub is_template: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub network_type: NetworkType,
+    // Generated from the VM id and the NIC's position when absent
+    #[serde(default)]
+    pub mac_address: Option<String>,
+    #[serde(default)]
+    pub model: NicModel,
+}
+
+impl NetworkInterface {
+    pub fn netdev_id(index: usize) -> String {
+        format!("net{}", index)
+    }
+    
+    // None for NetworkType::None, which has no backend to give the NIC
+    pub fn netdev_arg(&self, index: usize) -> Option<String> {
+        let id = Self::netdev_id(index);
+        match &self.network_type {
+            NetworkType::User(forwards) => {
+                let mut arg = format!("user,id={}", id);
+                for forward in forwards {
+                    arg.push_str(&format!(",hostfwd={}", forward.hostfwd()));
+                }
+                Some(arg)
+            }
+            NetworkType::Tap(tap) => Some(format!("tap,id={},ifname={}", id, tap)),
+            NetworkType::Bridge(bridge) => Some(format!("bridge,id={},br={}", id, bridge)),
+            NetworkType::None => None,
+        }
+    }
+    
+    // `bus` is the PCIe root port the NIC sits behind on q35 and virt
+    pub fn device_arg(&self, index: usize, mac: &str, bus: Option<&str>) -> String {
+        let mut arg = format!("{},netdev={},mac={}", self.model.as_str(), Self::netdev_id(index), mac);
+        if let Some(bus) = bus {
+            arg.push_str(&format!(",bus={}", bus));
+        }
+        arg
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NicModel {
+    #[default]
+    Virtio,
+    // For guests without virtio drivers
+    E1000,
+    Rtl8139,
+}
+
+impl NicModel {
+    pub const VARIANTS: &'static [&'static str] = &["virtio", "e1000", "rtl8139"];
+    
+    // The QEMU device
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NicModel::Virtio => "virtio-net-pci",
+            NicModel::E1000 => "e1000",
+            NicModel::Rtl8139 => "rtl8139",
+        }
+    }
+}
+
+// A NIC added to a running VM; kept in the config so it comes back on the next boot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HotplugNic {
+    pub netdev_id: String,
+    pub network_type: NetworkType,
+    // Tap created for this NIC, removed again on detach
+    pub tap: Option<String>,
+    // PCIe root port the NIC sits behind on q35; None on pc
+    #[serde(default)]
+    pub bus: Option<String>,
+}
+
+impl HotplugNic {
+    pub fn device_id(&self) -> String {
+        format!("{}-dev", self.netdev_id)
+    }
+    
+    // The host tap the NIC's traffic goes through, when it's known by name
+    pub fn tap_name(&self) -> Option<&str> {
+        match (&self.network_type, &self.tap) {
+            (_, Some(tap)) | (NetworkType::Tap(tap), None) => Some(tap),
+            _ => None,
+        }
+    }
+    
+    pub fn netdev_arg(&self) -> String {
+        match (&self.network_type, &self.tap) {
+            (_, Some(tap)) | (NetworkType::Tap(tap), None) => {
+                format!("tap,id={},ifname={},script=no,downscript=no", self.netdev_id, tap)
+            }
+            (NetworkType::Bridge(bridge), None) => format!("bridge,id={},br={}", self.netdev_id, bridge),
+            _ => format!("user,id={}", self.netdev_id),
+        }
+    }
+    
+    pub fn device_arg(&self) -> String {
+        match &self.bus {
+            Some(bus) => format!("virtio-net-pci,netdev={},id={},bus={}", self.netdev_id, self.device_id(), bus),
+            None => format!("virtio-net-pci,netdev={},id={}", self.netdev_id, self.device_id()),
+        }
+    }
+}
+
+// An extra volume, e.g. a separate data disk. The primary disk isn't
+// listed; it's always the VM's own image in the disks directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskAttachment {
+    // Unique per VM; names the volume file and the QEMU drive
+    pub name: String,
+    pub path: PathBuf,
+    pub format: DiskFormat,
+    #[serde(default)]
+    pub bus: DiskBus,
+    #[serde(default)]
+    pub readonly: bool,
+    // Position in the boot order; 0 and 1 are the CD-ROM and primary disk
+    #[serde(default)]
+    pub boot_index: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiskBus {
+    #[default]
+    Virtio,
+    // For guests without virtio drivers
+    Ide,
+    // Behind one virtio-scsi controller shared by all SCSI volumes
+    Scsi,
+}
+
+impl DiskAttachment {
+    pub fn drive_id(&self) -> String {
+        format!("vol-{}", self.name)
+    }
+    
+    pub fn drive_arg(&self, discard: bool) -> String {
+        let mut arg = format!(
+            "file={},format={},if=none,id={}",
+            self.path.display(), self.format.extension(), self.drive_id()
+        );
+        if self.readonly {
+            arg.push_str(",readonly=on");
+        } else if discard && self.format.supports_discard() {
+            arg.push_str(",discard=unmap");
+        }
+        arg
+    }
+    
+    // `port` is the PCIe root port a virtio volume sits behind on q35
+    pub fn device_arg(&self, port: Option<&str>) -> String {
+        let mut arg = match self.bus {
+            DiskBus::Virtio => format!("virtio-blk-pci,drive={}", self.drive_id()),
+            DiskBus::Ide => format!("ide-hd,drive={}", self.drive_id()),
+            DiskBus::Scsi => format!("scsi-hd,drive={},bus=scsi0.0", self.drive_id()),
+        };
+        if let (DiskBus::Virtio, Some(port)) = (self.bus, port) {
+            arg.push_str(&format!(",bus={}", port));
+        }
+        if let Some(index) = self.boot_index {
+            arg.push_str(&format!(",bootindex={}", index));
+        }
+        arg
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachDiskRequest {
+    pub name: String,
+    // Ignored when a volume of this name was kept by an earlier detach
+    pub size_gb: u32,
+    #[serde(default)]
+    pub format: DiskFormat,
+    #[serde(default)]
+    pub bus: DiskBus,
+    #[serde(default)]
+    pub readonly: bool,
+    #[serde(default)]
+    pub boot_index: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateVMRequest {
+    pub name: String,
+    pub iso_path: String,
+    pub memory_mb: u32,
+    pub cpu_cores: u32,
+    pub disk_size_gb: u32,
+    pub vnc_password: Option<String>,
+    // Shorthand for a VM with one NIC; can't be combined with networks
+    #[serde(default)]
+    pub network_type: Option<NetworkType>,
+    #[serde(default)]
+    pub mac_address: Option<String>,
+    #[serde(default)]
+    pub networks: Vec<NetworkInterface>,
+    pub disk_format: Option<DiskFormat>,
+    pub discard: Option<bool>,
+    pub disk_options: Option<DiskOptions>,
+    // Passphrase for a disk_options.encrypted disk, which otherwise gets a
+    // random key. Goes straight to the key file.
+    #[serde(default, skip_serializing)]
+    pub disk_passphrase: Option<String>,
+    pub snapshot_schedule: Option<SnapshotPolicy>,
+    #[serde(default)]
+    pub idle_suspend: Option<IdleSuspendPolicy>,
+    // Thin-clone the disk off this image; the disk then has the base's size
+    // and disk_size_gb is only validated
+    pub base_image: Option<String>,
+    // Copy this existing image in as the disk instead of creating a blank
+    // one; disk_size_gb is then taken from the image
+    #[serde(default)]
+    pub import_disk: Option<String>,
+    #[serde(default)]
+    pub arch: Option<GuestArch>,
+    #[serde(default)]
+    pub accel: Option<Accelerator>,
+    // Defaults depend on the arch
+    pub machine_type: Option<String>,
+    pub cpu_type: Option<String>,
+    pub bios: Option<BiosType>,
+    pub extra_args: Option<Vec<String>>,
+    pub protected: Option<bool>,
+}
+
+impl CreateVMRequest {
+    // networks, or the one NIC network_type and mac_address describe
+    pub fn interfaces(&self) -> Vec<NetworkInterface> {
+        if !self.networks.is_empty() {
+            return self.networks.clone();
+        }
+        match &self.network_type {
+            None | Some(NetworkType::None) => Vec::new(),
+            Some(network_type) => vec![NetworkInterface {
+                network_type: network_type.clone(),
+                mac_address: self.mac_address.clone(),
+                model: NicModel::default(),
+            }],
+        }
+    }
+}
+
+// Periodic internal snapshots of a running qcow2 VM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotPolicy {
+    pub interval_minutes: u32,
+    // Scheduled snapshots beyond this many are pruned, oldest first
+    pub keep: usize,
+}
+
+pub const DEFAULT_IDLE_CPU_PERCENT: f32 = 2.0;
+
+// Suspend-to-disk once a running VM has been idle for idle_minutes: CPU
+// below cpu_percent, no tap traffic and no console attached
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdleSuspendPolicy {
+    pub idle_minutes: u32,
+    // Percent of one host core, summed over the VM's vCPUs
+    #[serde(default = "default_idle_cpu_percent")]
+    pub cpu_percent: f32,
+}
+
+fn default_idle_cpu_percent() -> f32 {
+    DEFAULT_IDLE_CPU_PERCENT
+}
+
+// Lets an update tell "absent" (leave alone) from `null` (clear)
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl UpdateVMRequest {
+    pub fn field_names(&self) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.name.is_some() { fields.push("name"); }
+        if self.memory_mb.is_some() { fields.push("memory_mb"); }
+        if self.cpu_cores.is_some() { fields.push("cpu_cores"); }
+        if self.vnc_password.is_some() { fields.push("vnc_password"); }
+        if self.extra_args.is_some() { fields.push("extra_args"); }
+        if self.snapshot_schedule.is_some() { fields.push("snapshot_schedule"); }
+        if self.idle_suspend.is_some() { fields.push("idle_suspend"); }
+        if self.protected.is_some() { fields.push("protected"); }
+        if self.is_template.is_some() { fields.push("is_template"); }
+        fields
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateVMRequest {
+    pub name: Option<String>,
+    pub memory_mb: Option<u32>,
+    pub cpu_cores: Option<u32>,
+    pub vnc_password: Option<String>,
+    pub extra_args: Option<Vec<String>>,
+    // null clears the schedule
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub snapshot_schedule: Option<Option<SnapshotPolicy>>,
+    // null turns idle suspend off
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub idle_suspend: Option<Option<IdleSuspendPolicy>>,
+    #[serde(default)]
+    pub accel: Option<Accelerator>,
+    pub protected: Option<bool>,
+    // Only while stopped
+    #[serde(default)]
+    pub is_template: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VMStatus {
+    pub id: String,
+    pub name: String,
+    pub state: VMState,
+    pub pid: Option<u32>,
+    pub cpu_usage: f32,
+    pub memory_mb: u64,
+    pub vnc_port: u16,
+    pub uptime_seconds: u64,
+    // Wall-clock boot time; uptime is derived from it so it survives backend restarts
+    #[serde(default)]
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub disk_usage_gb: f64,
+    // Cached qemu-img info; refreshed far less often than cpu/memory
+    #[serde(default)]
+    pub disk: Option<DiskSummary>,
+    pub network_rx_bytes: u64,
+    pub network_tx_bytes: u64,
+    // Stored config has changes the running QEMU won't pick up until restart
+    #[serde(default)]
+    pub config_drift: bool,
+    // Mirrors the config flag so lists can show it
+    #[serde(default)]
+    pub protected: bool,
+    #[serde(default)]
+    pub is_template: bool,
+    // Message of the most recent Error state; kept after reset-state so
+    // operators can still see what went wrong, cleared by the next good start
+    #[serde(default)]
+    pub last_error: Option<String>,
+    pub last_updated: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum VMState {
+    Stopped,
+    Starting,
+    Running,
+    Stopping,
+    Paused,
+    Suspended,
+    Error(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum NetworkType {
+    // Host ports forwarded into the guest through QEMU's slirp stack
+    User(Vec<PortForward>),
+    Tap(String),
+    Bridge(String),
+    None,
+}
+
+impl NetworkType {
+    pub const VARIANTS: &'static [&'static str] = &["User", "Tap", "Bridge", "None"];
+    
+    pub fn port_forwards(&self) -> &[PortForward] {
+        match self {
+            NetworkType::User(forwards) => forwards,
+            _ => &[],
+        }
+    }
+}
+
+// Configs and clients from before port forwarding send a bare "User"
+impl<'de> Deserialize<'de> for NetworkType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        enum Tagged {
+            User(Vec<PortForward>),
+            Tap(String),
+            Bridge(String),
+            None,
+        }
+        
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Tagged(Tagged),
+            Bare(String),
+        }
+        
+        match Repr::deserialize(deserializer)? {
+            Repr::Tagged(Tagged::User(forwards)) => Ok(NetworkType::User(forwards)),
+            Repr::Tagged(Tagged::Tap(tap)) => Ok(NetworkType::Tap(tap)),
+            Repr::Tagged(Tagged::Bridge(bridge)) => Ok(NetworkType::Bridge(bridge)),
+            Repr::Tagged(Tagged::None) => Ok(NetworkType::None),
+            Repr::Bare(name) if name == "User" => Ok(NetworkType::User(Vec::new())),
+            Repr::Bare(name) => Err(serde::de::Error::unknown_variant(&name, NetworkType::VARIANTS)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortForward {
+    // 0 picks a free port from the SSH range when the VM is created
+    pub host_port: u16,
+    pub guest_port: u16,
+    #[serde(default)]
+    pub protocol: ForwardProtocol,
+}
+
+impl PortForward {
+    // e.g. tcp::2222-:22
+    pub fn hostfwd(&self) -> String {
+        format!("{}::{}-:{}", self.protocol.as_str(), self.host_port, self.guest_port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl ForwardProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ForwardProtocol::Tcp => "tcp",
+            ForwardProtocol::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum DiskFormat {
+    #[default]
+    Qcow2,
+    Raw,
+    Vdi,
+    Vmdk,
+}
+
+impl DiskFormat {
+    pub const VARIANTS: &'static [&'static str] = &["Qcow2", "Raw", "Vdi", "Vmdk"];
+    
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Raw => "raw",
+            DiskFormat::Vdi => "vdi",
+            DiskFormat::Vmdk => "vmdk",
+        }
+    }
+    
+    // Only qcow2 and raw images can punch holes for guest TRIM requests
+    pub fn supports_discard(&self) -> bool {
+        matches!(self, DiskFormat::Qcow2 | DiskFormat::Raw)
+    }
+    
+    pub fn discard_default(&self) -> bool {
+        matches!(self, DiskFormat::Qcow2)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum BiosType {
+    #[default]
+    SeaBios,
+    Ovmf,
+    Custom(String),
+}
+
+impl BiosType {
+    pub const VARIANTS: &'static [&'static str] = &["SeaBios", "Ovmf", "Custom"];
+}
+
+// How the guest CPU is run. Auto uses KVM where the host can and falls back
+// to (much slower) TCG emulation; Kvm refuses to start without it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Accelerator {
+    #[default]
+    Auto,
+    Kvm,
+    Tcg,
+}
+
+impl Accelerator {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "kvm", "tcg"];
+}
+
+// Guest CPU architecture; picks the qemu-system-* binary
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuestArch {
+    #[default]
+    X86_64,
+    Aarch64,
+    Riscv64,
+}
+
+impl GuestArch {
+    pub const VARIANTS: &'static [&'static str] = &["x86_64", "aarch64", "riscv64"];
+    pub const ALL: [GuestArch; 3] = [GuestArch::X86_64, GuestArch::Aarch64, GuestArch::Riscv64];
+    
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GuestArch::X86_64 => "x86_64",
+            GuestArch::Aarch64 => "aarch64",
+            GuestArch::Riscv64 => "riscv64",
+        }
+    }
+    
+    pub fn binary(&self) -> String {
+        format!("qemu-system-{}", self.as_str())
+    }
+    
+    // The arch this host runs, if it's one we can emulate
+    pub fn host() -> Option<GuestArch> {
+        match std::env::consts::ARCH {
+            "x86_64" => Some(GuestArch::X86_64),
+            "aarch64" => Some(GuestArch::Aarch64),
+            "riscv64" => Some(GuestArch::Riscv64),
+            _ => None,
+        }
+    }
+    
+    // KVM, and with it `-cpu host`, only works for the host's own arch;
+    // anything else runs under TCG emulation
+    pub fn is_native(&self) -> bool {
+        GuestArch::host() == Some(*self)
+    }
+    
+    pub fn default_machine(&self) -> &'static str {
+        match self {
+            GuestArch::X86_64 => DEFAULT_MACHINE_TYPE,
+            GuestArch::Aarch64 | GuestArch::Riscv64 => "virt",
+        }
+    }
+    
+    // Auto keeps `host` too; start() swaps it out if KVM turns out missing
+    pub fn default_cpu(&self, accel: Accelerator) -> &'static str {
+        if self.is_native() && accel != Accelerator::Tcg {
+            DEFAULT_CPU_TYPE
+        } else {
+            "max"
+        }
+    }
+    
+    // aarch64 `virt` has no legacy BIOS to fall back on
+    pub fn default_bios(&self) -> BiosType {
+        match self {
+            GuestArch::Aarch64 => BiosType::Ovmf,
+            GuestArch::X86_64 | GuestArch::Riscv64 => BiosType::SeaBios,
+        }
+    }
+    
+    // What BiosType::Ovmf loads; riscv64 has no packaged -bios image, so
+    // it needs a Custom one
+    pub fn uefi_firmware(&self) -> Option<&'static str> {
+        match self {
+            GuestArch::X86_64 => Some("/usr/share/OVMF/OVMF_CODE.fd"),
+            GuestArch::Aarch64 => Some("/usr/share/qemu-efi-aarch64/QEMU_EFI.fd"),
+            GuestArch::Riscv64 => None,
+        }
+    }
+}
+
+// Locally administered unicast (02:...), derived from the VM id and the
+// NIC's position so the guest keeps its DHCP leases across boots
+pub fn generated_mac(id: &str, index: usize) -> String {
+    // net0 keeps the MAC VMs had before they could have more than one NIC
+    let hash = match index {
+        0 => blake3::hash(id.as_bytes()),
+        _ => blake3::hash(format!("{}/{}", id, NetworkInterface::netdev_id(index)).as_bytes()),
+    };
+    let octets = &hash.as_bytes()[..5];
+    format!(
+        "02:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        octets[0], octets[1], octets[2], octets[3], octets[4]
+    )
+}
+
+impl VMConfig {
+    #[cfg(test)]
+    pub fn new(req: CreateVMRequest, vnc_port: u16) -> Self {
+        Self::with_id(uuid::Uuid::new_v4().to_string(), req, vnc_port)
+    }
+    
+    pub fn with_id(id: String, req: CreateVMRequest, vnc_port: u16) -> Self {
+        let now = chrono::Utc::now();
+        let mut networks = req.interfaces();
+        for (index, nic) in networks.iter_mut().enumerate() {
+            nic.mac_address.get_or_insert_with(|| generated_mac(&id, index));
+        }
+        let disk_format = req.disk_format.unwrap_or_default();
+        let discard = req.discard.unwrap_or(disk_format.discard_default());
+        let arch = req.arch.unwrap_or_default();
+        let accel = req.accel.unwrap_or_default();
+        
+        Self {
+            id,
+            name: req.name,
+            iso_path: req.iso_path,
+            memory_mb: req.memory_mb,
+            cpu_cores: req.cpu_cores,
+            disk_size_gb: req.disk_size_gb,
+            vnc_port,
+            vnc_password: req.vnc_password,
+            networks,
+            legacy_network_type: None,
+            legacy_mac_address: None,
+            disk_format,
+            discard,
+            disk_options: req.disk_options.unwrap_or_default(),
+            disk_key: None,
+            disks: Vec::new(),
+            snapshot_schedule: req.snapshot_schedule,
+            idle_suspend: req.idle_suspend,
+            base_image: req.base_image,
+            arch,
+            accel,
+            machine_type: req.machine_type.unwrap_or_else(|| arch.default_machine().to_string()),
+            cpu_type: req.cpu_type.unwrap_or_else(|| arch.default_cpu(accel).to_string()),
+            bios: req.bios.unwrap_or_else(|| arch.default_bios()),
+            extra_args: req.extra_args.unwrap_or_default(),
+            hotplug_nics: Vec::new(),
+            protected: req.protected.unwrap_or(false),
+            is_template: false,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+    
+    // The request with_id would turn back into this config, so configs that
+    // didn't come in through the API can go through the same validation
+    pub fn to_create_request(&self) -> CreateVMRequest {
+        CreateVMRequest {
+            name: self.name.clone(),
+            iso_path: self.iso_path.clone(),
+            memory_mb: self.memory_mb,
+            cpu_cores: self.cpu_cores,
+            disk_size_gb: self.disk_size_gb,
+            vnc_password: self.vnc_password.clone(),
+            network_type: None,
+            mac_address: None,
+            networks: self.networks.clone(),
+            disk_format: Some(self.disk_format.clone()),
+            discard: Some(self.discard),
+            disk_options: Some(self.disk_options.clone()),
+            disk_passphrase: None,
+            snapshot_schedule: self.snapshot_schedule.clone(),
+            idle_suspend: self.idle_suspend.clone(),
+            base_image: self.base_image.clone(),
+            import_disk: None,
+            arch: Some(self.arch),
+            accel: Some(self.accel),
+            machine_type: Some(self.machine_type.clone()),
+            cpu_type: Some(self.cpu_type.clone()),
+            bios: Some(self.bios.clone()),
+            extra_args: Some(self.extra_args.clone()),
+            protected: Some(self.protected),
+        }
+    }
+    
+    pub fn nic_mac(&self, index: usize) -> String {
+        self.networks.get(index)
+            .and_then(|nic| nic.mac_address.clone())
+            .unwrap_or_else(|| generated_mac(&self.id, index))
+    }
+    
+    pub fn port_forwards(&self) -> impl Iterator<Item = &PortForward> {
+        self.networks.iter().flat_map(|nic| nic.network_type.port_forwards())
+    }
+    
+    // A config from before `networks` becomes one with a single NIC
+    fn migrate_networks(&mut self) {
+        let mac_address = self.legacy_mac_address.take();
+        match self.legacy_network_type.take() {
+            Some(NetworkType::None) | None => {}
+            Some(network_type) => {
+                if self.networks.is_empty() {
+                    self.networks.push(NetworkInterface { network_type, mac_address, model: NicModel::default() });
+                }
+            }
+        }
+    }
+    
+    pub fn update(&mut self, req: UpdateVMRequest) {
+        if let Some(name) = req.name {
+            self.name = name;
+        }
+        
+        if let Some(memory_mb) = req.memory_mb {
+            self.memory_mb = memory_mb;
+        }
+        
+        if let Some(cpu_cores) = req.cpu_cores {
+            self.cpu_cores = cpu_cores;
+        }
+        
+        if let Some(vnc_password) = req.vnc_password {
+            self.vnc_password = Some(vnc_password);
+        }
+        
+        if let Some(extra_args) = req.extra_args {
+            self.extra_args = extra_args;
+        }
+        
+        if let Some(snapshot_schedule) = req.snapshot_schedule {
+            self.snapshot_schedule = snapshot_schedule;
+        }
+        
+        if let Some(idle_suspend) = req.idle_suspend {
+            self.idle_suspend = idle_suspend;
+        }
+        
+        if let Some(accel) = req.accel {
+            self.accel = accel;
+        }
+        
+        if let Some(protected) = req.protected {
+            self.protected = protected;
+        }
+        
+        if let Some(is_template) = req.is_template {
+            self.is_template = is_template;
+        }
+        
+        self.updated_at = chrono::Utc::now();
+    }
+    
+    // Fields baked into the QEMU command line that differ from what `running`
+    // was started with
+    pub fn restart_required_fields(&self, running: &VMConfig) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.memory_mb != running.memory_mb { fields.push("memory_mb"); }
+        if self.cpu_cores != running.cpu_cores { fields.push("cpu_cores"); }
+        if self.vnc_password != running.vnc_password { fields.push("vnc_password"); }
+        if self.extra_args != running.extra_args { fields.push("extra_args"); }
+        fields
+    }
+    
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+    
+    pub fn save_to_file(&self, path: &PathBuf) -> Result<(), std::io::Error> {
+        let json = self.to_json().map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+        })?;
+        
+        // Write then rename so readers never see a half-written config
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, json)?;
+        std::fs::rename(&tmp_path, path)
+    }
+    
+    pub fn load_from_file(path: &PathBuf) -> Result<Self, std::io::Error> {
+        let data = std::fs::read_to_string(path)?;
+        let mut config: Self = serde_json::from_str(&data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        config.migrate_networks();
+        
+        Ok(config)
+    }
 }
\ No newline at end of file
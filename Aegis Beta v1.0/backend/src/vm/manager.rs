@@ -0,0 +1,3813 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use caps::Capability;
+use nix::unistd::{Gid, Uid};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::AppError;
+use crate::security::isolation::{IdMapping, VMSandbox};
+use crate::security::privileges::privileges;
+use crate::security::sandbox::{remove_vm_cgroups, ResourceLimits, SeccompAction, SeccompMode, VMSandboxBuilder};
+use crate::security::validation::{
+    set_validation_config, validate_base_image, validate_bundle_config, validate_iso_path, validate_snapshot_name, validate_snapshot_policy, validate_vm_name,
+    validate_vm_update, validate_volume_name, validation_config, ValidationError,
+};
+use crate::storage::disks::{
+    auto_snapshot_name, excess_auto_snapshots, image_backing_file, CompactReport, DiskFormat as StorageFormat, DiskInfo,
+    DiskListing, DiskManager, DiskSummary, SnapshotInfo,
+};
+use crate::storage::isos::{IsoInfo, IsoListing, IsoManager};
+use crate::storage::uploads::{UploadManager, UploadSession};
+use crate::utils::command::{CommandCategory, CommandTimeoutExt};
+use crate::utils::logging::{LogLevel, Logger};
+use crate::utils::ports::{port_ranges, PortManager};
+use super::config::{
+    Accelerator, AttachDiskRequest, CreateVMRequest, DiskAttachment, DiskBus, DiskFormat, GuestArch, SnapshotPolicy, HotplugNic, IdleSuspendPolicy, NetworkType, UpdateVMRequest, VMConfig,
+    VMState, VMStatus, NetworkInterface, generated_mac,
+};
+use super::networking::{interface_traffic, NetworkError, NetworkManager, NetworkStatus};
+use super::operations::Operations;
+use super::qemu::{
+    clock_ticks_per_second, kvm_unavailable_reason, process_cpu_ticks, process_rss_mb, process_start_time, qemu_caps,
+    qemu_log_tail,
+    qmp_command_at, qmp_socket_path, serial_socket_path, suspend_uri, uptime_since, MachineLayout, QemuError,
+    QemuProcess, QemuSpawner, SerialConsole, SystemQemu, DEFAULT_STARTUP_TIMEOUT, Q35_HOTPLUG_PORTS,
+};
+
+struct VMInstance {
+    config: VMConfig,
+    status: VMStatus,
+    disk_path: PathBuf,
+    // Config the current QEMU process was launched with
+    running_config: Option<VMConfig>,
+}
+
+impl VMInstance {
+    // Status fields that mirror the config
+    fn sync_status(&mut self) {
+        self.status.name = self.config.name.clone();
+        self.status.vnc_port = self.config.vnc_port;
+        self.status.config_drift = !self.pending_restart().is_empty();
+        self.status.protected = self.config.protected;
+        self.status.is_template = self.config.is_template;
+        self.status.last_updated = chrono::Utc::now();
+    }
+
+    // Snapshot for callers, with uptime computed as of now
+    fn current_status(&self) -> VMStatus {
+        let mut status = self.status.clone();
+        if let Some(started_at) = status.started_at {
+            status.uptime_seconds = uptime_since(started_at);
+        }
+        status
+    }
+
+    fn pending_restart(&self) -> Vec<&'static str> {
+        self.running_config.as_ref()
+            .map(|running| self.config.restart_required_fields(running))
+            .unwrap_or_default()
+    }
+
+    fn stopped(config: VMConfig, disk_path: PathBuf) -> Self {
+        Self {
+            status: VMStatus {
+                id: config.id.clone(),
+                name: config.name.clone(),
+                state: VMState::Stopped,
+                pid: None,
+                cpu_usage: 0.0,
+                memory_mb: 0,
+                vnc_port: config.vnc_port,
+                uptime_seconds: 0,
+                started_at: None,
+                disk_usage_gb: 0.0,
+                disk: None,
+                network_rx_bytes: 0,
+                network_tx_bytes: 0,
+                config_drift: false,
+                protected: config.protected,
+                is_template: config.is_template,
+                last_error: None,
+                last_updated: chrono::Utc::now(),
+            },
+            config,
+            disk_path,
+            running_config: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VMDetails {
+    pub config: VMConfig,
+    pub status: VMStatus,
+    // End of the QEMU log, usually where a failed start explains itself
+    pub qemu_log_tail: Vec<String>,
+}
+
+const QEMU_LOG_TAIL_LINES: usize = 50;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessCheck {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl ReadinessCheck {
+    fn from_result(name: &'static str, result: Result<String, String>) -> Self {
+        match result {
+            Ok(detail) => Self { name, ok: true, detail },
+            Err(detail) => Self { name, ok: false, detail },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResourceTotals {
+    pub vms: usize,
+    pub cpu_cores: u32,
+    pub memory_mb: u64,
+}
+
+impl ResourceTotals {
+    fn add(&mut self, config: &VMConfig) {
+        self.vms += 1;
+        self.cpu_cores += config.cpu_cores;
+        self.memory_mb += config.memory_mb as u64;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkOverview {
+    // None when the backend runs without a managed network
+    pub managed: Option<NetworkStatus>,
+    // Every bridge on the host, for attaching NICs to
+    pub bridges: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostCapacity {
+    pub cpu_cores: u32,
+    // Host cores not claimed by running VMs
+    pub cpu_cores_available: u32,
+    pub memory_total_mb: u64,
+    // What the kernel reports as available, not total minus allocations
+    pub memory_available_mb: u64,
+    // Every defined VM, as if all were started
+    pub allocated: ResourceTotals,
+    pub running: ResourceTotals,
+    pub vnc_ports_free: usize,
+    pub websocket_ports_free: usize,
+    pub disk_total_gb: f64,
+    pub disk_available_gb: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BaseImage {
+    pub name: String,
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    // Ids of VMs cloned from this image
+    pub dependents: Vec<String>,
+}
+
+// Where a terminal client can attach to the VM's serial console. Exactly
+// one of path and tcp is set, depending on the manager's serial mode.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsoleSocket {
+    pub path: Option<PathBuf>,
+    pub tcp: Option<SocketAddr>,
+    // The endpoint only accepts connections while this is true
+    pub running: bool,
+}
+
+// Keeps a VM counted as in use by a console client until dropped, so the
+// idle monitor leaves it alone while someone is attached
+pub struct ConsoleSession {
+    manager: Weak<VMManager>,
+    vm_id: String,
+}
+
+impl Drop for ConsoleSession {
+    fn drop(&mut self) {
+        if let Some(manager) = self.manager.upgrade() {
+            let mut sessions = manager.console_sessions.lock().unwrap();
+            if let Some(count) = sessions.get_mut(&self.vm_id) {
+                *count -= 1;
+                if *count == 0 {
+                    sessions.remove(&self.vm_id);
+                }
+            }
+        }
+    }
+}
+
+// A connection to a VM's serial port, whichever way it's exposed
+pub trait SerialIo: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send> SerialIo for T {}
+
+// The idle monitor's previous reading of a VM
+struct IdleSample {
+    pid: u32,
+    cpu_ticks: u64,
+    // rx + tx over the VM's taps
+    traffic: u64,
+    at: Instant,
+    idle_since: Instant,
+}
+
+// One reading of a running VM. rx/tx are the kernel's running totals over
+// the VM's taps; the difference between samples is the traffic in between.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSample {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    // Percent of one host core, summed over vCPUs
+    pub cpu_usage: f32,
+    pub memory_mb: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsHistory {
+    pub interval_seconds: u64,
+    // Oldest first
+    pub samples: Vec<MetricsSample>,
+}
+
+#[derive(Default)]
+struct MetricsBuffer {
+    samples: VecDeque<MetricsSample>,
+    // (pid, cpu ticks, when) from the previous sample, for the CPU delta
+    last_cpu: Option<(u32, u64, Instant)>,
+}
+
+// Per-boot facts that outlive the backend process; removed on stop
+#[derive(Debug, Serialize, Deserialize)]
+struct RuntimeState {
+    pid: u32,
+    started_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VMUpdate {
+    pub config: VMConfig,
+    // Fields the VM already reflects (all of them while it's stopped)
+    pub applied: Vec<String>,
+    // Fields that only take effect on the next start
+    pub pending_restart: Vec<String>,
+}
+
+// QMP commands that can read or write arbitrary host files or hand the
+// guest to another host; not available through the passthrough endpoint
+const DENIED_QMP_COMMANDS: &[&str] = &[
+    "migrate",
+    "migrate-incoming",
+    "human-monitor-command",
+    "dump-guest-memory",
+    "screendump",
+    "memsave",
+    "pmemsave",
+    "drive-backup",
+    "drive-mirror",
+    "blockdev-snapshot-sync",
+    "getfd",
+    "add-fd",
+    "qmp_capabilities",
+];
+
+pub struct VMManager {
+    vms: Arc<Mutex<HashMap<String, VMInstance>>>,
+    // Held across awaits while talking to QEMU, so kept apart from the
+    // metadata map which is only ever locked briefly
+    processes: tokio::sync::Mutex<HashMap<String, QemuProcess>>,
+    spawner: Arc<dyn QemuSpawner>,
+    disk_manager: DiskManager,
+    vnc_ports: PortManager,
+    // Host ports forwarded into user-mode guests
+    forward_ports: PortManager,
+    logger: Arc<Logger>,
+    startup_timeout: Duration,
+    deterministic_vnc_ports: bool,
+    // Bind address for serial consoles over TCP; None keeps them on unix sockets
+    serial_tcp: Option<IpAddr>,
+    data_dir: PathBuf,
+    // When the snapshot scheduler last acted on each VM
+    last_scheduled_snapshot: Mutex<HashMap<String, chrono::DateTime<chrono::Utc>>>,
+    idle_samples: Mutex<HashMap<String, IdleSample>>,
+    // Open console connections per VM
+    console_sessions: Mutex<HashMap<String, usize>>,
+    metrics: Mutex<HashMap<String, MetricsBuffer>>,
+    metrics_capacity: usize,
+    metrics_interval: Duration,
+    operations: Operations,
+    uploads: Arc<UploadManager>,
+    // The NAT bridge and generated taps, when this manager owns networking
+    network: Option<Arc<NetworkManager>>,
+    // Without it VMs get user-mode networking and no namespace isolation
+    privileged: bool,
+    // Host user QEMU switches to inside its sandbox; None leaves it root
+    qemu_user: Option<(Uid, Gid)>,
+    // Host id range mapped into a user namespace around QEMU
+    user_namespace: Option<IdMapping>,
+    // Seccomp (and whatever else the sandbox builder sets up) around QEMU;
+    // without it QEMU only gets the namespaces
+    sandbox_vms: bool,
+    seccomp_mode: SeccompMode,
+    // What syscalls the filter blocks get
+    seccomp_action: SeccompAction,
+    // Blocked on top of the mode's list, and per-syscall overrides of both
+    denied_syscalls: Vec<String>,
+    syscall_actions: HashMap<String, SeccompAction>,
+    // Per-VM cgroups when set, with this disk throughput cap in MB/s (0
+    // for none); memory and CPU follow each VM's config
+    cgroup_disk_limit: Option<u64>,
+    // Each QEMU is chrooted into <chroot_dir>/<id>/root when set
+    chroot_dir: Option<PathBuf>,
+    // Flipped by shutdown to end the background tasks
+    stop_tasks: tokio::sync::watch::Sender<bool>,
+    status_events: tokio::sync::broadcast::Sender<StatusEvent>,
+}
+
+// Pushed to WebSocket subscribers. Sent on state transitions and on large
+// enough swings in the sampled figures, not on every poll.
+#[derive(Debug, Clone)]
+pub enum StatusEvent {
+    // Boxed, as every subscriber's queue holds one per event
+    Changed(Box<VMStatus>),
+    Deleted(String),
+}
+
+// A client that falls this far behind skips to the newest events
+const STATUS_EVENT_BUFFER: usize = 256;
+// CPU in percentage points; memory relative to the previous reading
+const STATUS_CPU_DELTA: f32 = 5.0;
+const STATUS_MEMORY_DELTA: f64 = 0.05;
+
+// What shutdown does with VMs that are still running
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StopPolicy {
+    // Leave guests running for the next backend to reattach to, so an
+    // upgrade doesn't take workloads down
+    Detach,
+    #[default]
+    Stop,
+}
+
+// qemu-img info forks a process per VM, so its results are reused this long
+const DISK_SUMMARY_TTL: Duration = Duration::from_secs(300);
+
+const SCHEDULED_SNAPSHOT_PREFIX: &str = "sched-";
+const SNAPSHOT_SCHEDULER_TICK: Duration = Duration::from_secs(60);
+// savevm/delvm write or drop the full RAM image; suspend and restore
+// move the same amount of data
+const SAVEVM_TIMEOUT: Duration = Duration::from_secs(600);
+const IDLE_MONITOR_TICK: Duration = Duration::from_secs(60);
+// What a VM's cgroup allows QEMU on top of guest RAM: its own heap, device
+// emulation and the VNC framebuffer
+const QEMU_MEMORY_OVERHEAD_MB: u64 = 256;
+// Migration's default bandwidth cap is meant for live migration over a
+// network, not a local file
+const SUSPEND_BANDWIDTH: u64 = 10 << 30;
+const QMP_TIMEOUT: Duration = Duration::from_secs(10);
+// Well past stop()'s own SIGTERM-then-SIGKILL bound, so it only fires when
+// the stop can't even get at the process
+const DELETE_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+// 25 minutes of history at the default interval
+const DEFAULT_METRICS_SAMPLES: usize = 300;
+const DEFAULT_METRICS_INTERVAL: Duration = Duration::from_secs(5);
+
+impl VMManager {
+    pub fn new(data_dir: &Path, logger: Arc<Logger>) -> Result<Self, AppError> {
+        for dir in ["isos", "disks", "bases", "imports", "configs", "logs", "exports", "run"] {
+            fs::create_dir_all(data_dir.join(dir)).map_err(|e| {
+                AppError::Internal(format!("Failed to create {}: {}", data_dir.join(dir).display(), e))
+            })?;
+        }
+
+        // ISOs are validated against this manager's ISO directory
+        let mut validation = validation_config();
+        validation.iso_dir = data_dir.join("isos");
+        validation.base_image_dir = data_dir.join("bases");
+        validation.import_dir = data_dir.join("imports");
+        set_validation_config(validation);
+
+        // Probe QEMU's machine/CPU lists now rather than on the first create
+        if qemu_caps().is_none() {
+            log::warn!("QEMU model lists unavailable; machine/cpu types won't be validated");
+        }
+
+        let host = privileges();
+        if !host.privileged {
+            log::warn!("Running unprivileged; VMs get user-mode networking and no namespace isolation");
+        }
+        for feature in &host.degraded {
+            log::warn!("Unavailable: {}", feature);
+        }
+
+        Ok(Self {
+            vms: Arc::new(Mutex::new(HashMap::new())),
+            processes: tokio::sync::Mutex::new(HashMap::new()),
+            spawner: Arc::new(SystemQemu),
+            disk_manager: DiskManager::new(&data_dir.join("disks")),
+            vnc_ports: PortManager::new(port_ranges::VNC.0, port_ranges::VNC.1)?,
+            forward_ports: PortManager::new(1024, u16::MAX)?,
+            logger,
+            startup_timeout: DEFAULT_STARTUP_TIMEOUT,
+            deterministic_vnc_ports: false,
+            serial_tcp: None,
+            data_dir: data_dir.to_path_buf(),
+            last_scheduled_snapshot: Mutex::new(HashMap::new()),
+            idle_samples: Mutex::new(HashMap::new()),
+            console_sessions: Mutex::new(HashMap::new()),
+            metrics: Mutex::new(HashMap::new()),
+            metrics_capacity: DEFAULT_METRICS_SAMPLES,
+            metrics_interval: DEFAULT_METRICS_INTERVAL,
+            operations: Operations::new(),
+            uploads: Arc::new(UploadManager::new(&data_dir.join("isos"))),
+            network: None,
+            privileged: host.privileged,
+            qemu_user: None,
+            user_namespace: None,
+            sandbox_vms: true,
+            seccomp_mode: SeccompMode::AllowList,
+            seccomp_action: SeccompAction::KillProcess,
+            denied_syscalls: Vec::new(),
+            syscall_actions: HashMap::new(),
+            cgroup_disk_limit: None,
+            chroot_dir: None,
+            stop_tasks: tokio::sync::watch::channel(false).0,
+            status_events: tokio::sync::broadcast::channel(STATUS_EVENT_BUFFER).0,
+        })
+    }
+
+    pub fn with_auto_snapshots(mut self, enabled: bool, keep: usize) -> Self {
+        self.disk_manager.set_auto_snapshots(enabled, keep);
+        self
+    }
+
+    // Derive each VM's VNC display from its id so console URLs stay stable
+    pub fn with_deterministic_vnc_ports(mut self, enabled: bool) -> Self {
+        self.deterministic_vnc_ports = enabled;
+        self
+    }
+
+    // Expose serial consoles as TCP listeners on `bind` instead of unix
+    // sockets. Applies to VMs started afterwards.
+    pub fn with_serial_tcp(mut self, bind: Option<IpAddr>) -> Self {
+        self.serial_tcp = bind;
+        self
+    }
+
+    // How many samples the metrics sampler keeps per VM, and how often it
+    // takes one
+    pub fn with_metrics_history(mut self, samples: usize, interval: Duration) -> Self {
+        self.metrics_capacity = samples.max(1);
+        self.metrics_interval = interval.max(Duration::from_secs(1));
+        self
+    }
+
+    // Only ever lowers the mode: false forces unprivileged behaviour even as
+    // root, true still depends on what the probe found
+    #[cfg(test)]
+    fn with_spawner(mut self, spawner: Arc<dyn QemuSpawner>) -> Self {
+        self.spawner = spawner;
+        self
+    }
+
+    pub fn with_privileged(mut self, enabled: bool) -> Self {
+        if enabled && !privileges().privileged {
+            log::warn!("Privileged mode requested but unavailable; staying unprivileged");
+        }
+        self.privileged = enabled && privileges().privileged;
+        self
+    }
+
+    // The bridge needs CAP_NET_ADMIN, so an unprivileged manager runs without it
+    pub fn with_network(mut self, network: Arc<NetworkManager>) -> Self {
+        if self.privileged {
+            self.network = Some(network);
+        } else {
+            log::warn!("Running unprivileged; NAT bridge networking disabled");
+        }
+        self
+    }
+
+    pub fn privileged(&self) -> bool {
+        self.privileged
+    }
+
+    // Run QEMU as this user once its sandbox is set up. Only a privileged
+    // manager can switch users; each start hands the VM's files over first.
+    pub fn with_qemu_user(mut self, user: Option<(Uid, Gid)>) -> Self {
+        self.qemu_user = user;
+        self
+    }
+
+    // Applies whenever the sandbox does, privileged or not; it's what lets a
+    // rootless backend isolate QEMU at all
+    pub fn with_user_namespace(mut self, mapping: Option<IdMapping>) -> Self {
+        self.user_namespace = mapping;
+        self
+    }
+
+    pub fn with_sandboxing(mut self, enabled: bool) -> Self {
+        self.sandbox_vms = enabled;
+        self
+    }
+
+    // Log lets a new profile run in audit mode before it's enforced
+    pub fn with_seccomp(mut self, mode: SeccompMode, action: SeccompAction) -> Self {
+        self.seccomp_mode = mode;
+        self.seccomp_action = action;
+        self
+    }
+
+    pub fn with_syscall_rules(mut self, denied: Vec<String>, actions: HashMap<String, SeccompAction>) -> Self {
+        self.denied_syscalls = denied;
+        self.syscall_actions = actions;
+        self
+    }
+
+    pub fn with_cgroup_limits(mut self, enabled: bool, disk_limit_mb: u64) -> Self {
+        self.cgroup_disk_limit = enabled.then_some(disk_limit_mb);
+        self
+    }
+
+    // Only applies to privileged sandboxes, as it needs a mount namespace
+    pub fn with_chroot(mut self, chroot_dir: Option<PathBuf>) -> Self {
+        self.chroot_dir = chroot_dir;
+        self
+    }
+
+    pub fn with_startup_timeout(mut self, timeout: Duration) -> Self {
+        self.startup_timeout = timeout;
+        self
+    }
+
+    pub fn operations(&self) -> &Operations {
+        &self.operations
+    }
+
+    // Picks up the VMs saved under configs/ by an earlier run. They come
+    // back stopped. A config that can't be read, or whose ports are already
+    // taken, is skipped rather than failing startup.
+    pub async fn load_saved_vms(&self) -> usize {
+        let configs_dir = self.data_dir.join("configs");
+        let entries = match fs::read_dir(&configs_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Failed to read {}: {}", configs_dir.display(), e);
+                return 0;
+            }
+        };
+
+        let mut loaded = 0;
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let mut config = match VMConfig::load_from_file(&path) {
+                Ok(config) => config,
+                Err(e) => {
+                    log::warn!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if self.vms.lock().unwrap().contains_key(&config.id) {
+                continue;
+            }
+            if let Err(e) = self.vnc_ports.reserve_port(config.vnc_port) {
+                log::warn!("Skipping {}: VNC port {}: {}", path.display(), config.vnc_port, e);
+                continue;
+            }
+            if let Err(e) = self.reserve_forwards(&mut config.networks) {
+                self.vnc_ports.release_port(config.vnc_port);
+                log::warn!("Skipping {}: {}", path.display(), e);
+                continue;
+            }
+
+            let disk_path = self.data_dir.join("disks").join(format!("{}.{}", config.id, config.disk_format.extension()));
+            let mut instance = VMInstance::stopped(config, disk_path);
+            let vm_id = instance.config.id.clone();
+            match self.reattach(&instance.config) {
+                Some(process) => {
+                    instance.status.state = VMState::Running;
+                    instance.status.pid = Some(process.pid());
+                    instance.status.started_at = Some(process.started_at());
+                    // What it was started with isn't kept; the saved config stands in
+                    instance.running_config = Some(instance.config.clone());
+                    self.log(LogLevel::Info, &vm_id, &format!("Reattached to QEMU (PID {})", process.pid()));
+                    self.processes.lock().await.insert(vm_id.clone(), process);
+                }
+                None => {
+                    let _ = fs::remove_file(self.runtime_state_path(&vm_id));
+                    if self.suspend_state_path(&vm_id).exists() {
+                        instance.status.state = VMState::Suspended;
+                    }
+                }
+            }
+            self.vms.lock().unwrap().insert(vm_id, instance);
+            loaded += 1;
+        }
+        loaded
+    }
+
+    // Status changes of every VM from now on; receivers filter by id
+    pub fn subscribe_status(&self) -> tokio::sync::broadcast::Receiver<StatusEvent> {
+        self.status_events.subscribe()
+    }
+
+    // Runs create_vm as a tracked operation and returns its id right away;
+    // the new config is the operation's result
+    pub fn begin_create_vm(self: &Arc<Self>, req: CreateVMRequest) -> String {
+        let op = self.operations.begin("create_vm", &format!("Creating VM '{}'", req.name));
+        let op_id = op.id().to_string();
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            // qemu-img create reports no progress, so all there is to send is a heartbeat
+            let ticker = op.spawn_ticker();
+            match manager.create_vm(req).await {
+                Ok(config) => op.complete(serde_json::to_value(&config).unwrap_or(Value::Null)),
+                Err(e) => op.fail(&e.to_string()),
+            }
+            ticker.abort();
+        });
+
+        op_id
+    }
+
+    pub async fn create_vm(&self, mut req: CreateVMRequest) -> Result<VMConfig, AppError> {
+        let mut networks = req.interfaces();
+        let mut requested_networks = Vec::new();
+        for nic in &mut networks {
+            if let NetworkType::Tap(name) | NetworkType::Bridge(name) = &nic.network_type {
+                NetworkManager::validate_interface_name(name)?;
+                if !self.privileged {
+                    requested_networks.push(std::mem::replace(&mut nic.network_type, NetworkType::User(Vec::new())));
+                }
+            }
+        }
+        req.networks = networks;
+        req.network_type = None;
+        req.mac_address = None;
+        let passphrase = req.disk_passphrase.take();
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let vnc_port = if self.deterministic_vnc_ports {
+            self.vnc_ports.allocate_stable_port(&id)?
+        } else {
+            self.vnc_ports.allocate_port()?
+        };
+        let import_disk = req.import_disk.clone();
+        let mut config = VMConfig::with_id(id, req, vnc_port);
+        config.disk_options = config.disk_options.resolved(&storage_format(&config.disk_format));
+        if let Err(e) = self.reserve_forwards(&mut config.networks) {
+            self.vnc_ports.release_port(vnc_port);
+            return Err(e);
+        }
+
+        // The key has to be in place before the disk it encrypts
+        if config.disk_options.encrypted {
+            let secret = passphrase.map_or_else(random_disk_key, String::into_bytes);
+            let (disks, id) = (self.disk_manager.clone(), config.id.clone());
+            match blocking(move || disks.write_key(&id, &secret)).await {
+                Ok(key) => config.disk_key = Some(key),
+                Err(e) => {
+                    self.vnc_ports.release_port(vnc_port);
+                    self.release_forwards(&config);
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let disks = self.disk_manager.clone();
+        let (id, format) = (config.id.clone(), storage_format(&config.disk_format));
+        let disk = match import_disk {
+            Some(source) => blocking(move || disks.import_disk(&id, Path::new(&source), format)).await
+                .map(|(path, info)| (path, Some(info.virtual_size_gb.ceil() as u32))),
+            None => {
+                let (size_gb, options, base) = (config.disk_size_gb, config.disk_options.clone(), config.base_image.clone());
+                blocking(move || disks.create_disk(&id, size_gb, format, &options, base.as_deref().map(Path::new))).await
+                    .map(|path| (path, None))
+            }
+        };
+        let disk_path = match disk {
+            Ok((path, imported_size_gb)) => {
+                if let Some(size_gb) = imported_size_gb {
+                    config.disk_size_gb = size_gb;
+                }
+                path
+            }
+            Err(e) => {
+                let _ = self.disk_manager.delete_key(&config.id);
+                self.vnc_ports.release_port(vnc_port);
+                self.release_forwards(&config);
+                return Err(e.into());
+            }
+        };
+
+        if let Err(e) = config.save_to_file(&self.config_path(&config.id)) {
+            let _ = self.disk_manager.delete_disk(&config.id);
+            let _ = self.disk_manager.delete_key(&config.id);
+            self.vnc_ports.release_port(vnc_port);
+            self.release_forwards(&config);
+            return Err(AppError::Internal(format!("Failed to save config: {}", e)));
+        }
+
+        let instance = VMInstance::stopped(config.clone(), disk_path);
+        self.vms.lock().unwrap().insert(config.id.clone(), instance);
+        self.log(LogLevel::Info, &config.id, &format!("Created VM '{}'", config.name));
+        if !requested_networks.is_empty() {
+            self.log(LogLevel::Warn, &config.id, &format!(
+                "Running unprivileged; using user-mode networking instead of {:?}", requested_networks
+            ));
+        }
+        self.refresh_disk_summary(&config.id, true).await;
+
+        Ok(config)
+    }
+
+    pub async fn start_vm(&self, vm_id: &str) -> Result<(), AppError> {
+        self.ensure_no_linked_clones(vm_id, "start it")?;
+
+        // Flipping to Starting under the lock is the claim on this VM: a
+        // concurrent start sees it and backs off instead of launching a
+        // second QEMU against the same disk
+        let (config, disk_path, incoming) = {
+            let mut vms = self.vms.lock().unwrap();
+            let instance = vms.get_mut(vm_id).ok_or_else(|| not_found(vm_id))?;
+
+            if instance.config.is_template {
+                return Err(AppError::Conflict(format!(
+                    "VM {} is a template; create VMs from it instead", vm_id
+                )));
+            }
+
+            let resuming = match &instance.status.state {
+                VMState::Stopped | VMState::Error(_) => false,
+                VMState::Suspended => true,
+                VMState::Running => return Err(AppError::Conflict("VM already running".to_string())),
+                VMState::Starting => return Err(AppError::Conflict("VM is starting".to_string())),
+                state => {
+                    return Err(AppError::Conflict(format!("VM cannot be started while {:?}", state)));
+                }
+            };
+
+            instance.status.state = VMState::Starting;
+            instance.status.last_updated = chrono::Utc::now();
+            self.publish_status(instance.current_status());
+            // Saved state only loads into the devices it was saved from, so
+            // a suspended VM comes back with its old config
+            let config = match (&instance.running_config, resuming) {
+                (Some(running), true) => running.clone(),
+                _ => instance.config.clone(),
+            };
+            (config, instance.disk_path.clone(), resuming.then(|| self.suspend_state_path(vm_id)))
+        };
+
+        self.log(LogLevel::Debug, vm_id, &format!("Starting QEMU with disk {}", disk_path.display()));
+
+        if let Err(e) = self.grant_qemu_access(&config, &disk_path) {
+            self.log(LogLevel::Error, vm_id, &format!("Failed to start: {}", e));
+            self.update_status(vm_id, |status| status.state = VMState::Error(e.to_string()));
+            return Err(e);
+        }
+
+        let sandbox = match self.sandbox(&config, &disk_path) {
+            Ok(sandbox) => sandbox,
+            Err(e) => {
+                self.log(LogLevel::Error, vm_id, &format!("Failed to start: {}", e));
+                self.update_status(vm_id, |status| status.state = VMState::Error(e.to_string()));
+                return Err(e);
+            }
+        };
+
+        let serial = self.serial_console(&config);
+        let started = self.spawner.spawn(
+            &config, &disk_path, sandbox, serial, incoming.as_deref(), self.startup_timeout,
+        ).await;
+        match started {
+            Ok(process) => {
+                let pid = process.pid();
+                let started_at = process.started_at();
+                if process.accel() == Accelerator::Tcg && config.accel == Accelerator::Auto && config.arch.is_native() {
+                    self.log(LogLevel::Warn, vm_id, "KVM is unavailable; running under TCG emulation");
+                }
+                self.shape_taps(vm_id, &self.vm_taps(&config));
+                self.processes.lock().await.insert(vm_id.to_string(), process);
+                self.set_running_config(vm_id, Some(config));
+
+                if let Some(state_path) = &incoming {
+                    let restored = self.wait_for_restore(vm_id).await;
+                    // Either it's loaded or it never will; a fresh boot
+                    // can't use it
+                    let _ = fs::remove_file(state_path);
+                    if let Err(e) = restored {
+                        self.log(LogLevel::Error, vm_id, &format!("Failed to restore suspended state: {}", e));
+                        if let Some(mut process) = self.processes.lock().await.remove(vm_id) {
+                            let _ = process.stop().await;
+                        }
+                        self.set_running_config(vm_id, None);
+                        self.update_status(vm_id, |status| {
+                            status.state = VMState::Error(format!("Restore from suspend failed: {}", e));
+                        });
+                        return Err(e);
+                    }
+                    self.log(LogLevel::Info, vm_id, "Restored from suspend");
+                }
+
+                self.save_runtime_state(vm_id, &RuntimeState { pid, started_at });
+                self.update_status(vm_id, |status| {
+                    status.state = VMState::Running;
+                    status.pid = Some(pid);
+                    status.started_at = Some(started_at);
+                    status.last_error = None;
+                });
+                self.log(LogLevel::Info, vm_id, &format!("Started with PID {}", pid));
+                Ok(())
+            }
+            Err(e) => {
+                self.log(LogLevel::Error, vm_id, &format!("Failed to start: {}", e));
+                self.update_status(vm_id, |status| status.state = VMState::Error(e.to_string()));
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn stop_vm(&self, vm_id: &str) -> Result<(), AppError> {
+        {
+            let mut vms = self.vms.lock().unwrap();
+            let instance = vms.get_mut(vm_id).ok_or_else(|| not_found(vm_id))?;
+
+            match &instance.status.state {
+                VMState::Running | VMState::Paused => {}
+                VMState::Stopped => return Ok(()),
+                // Nothing is running; stopping just discards the saved state
+                VMState::Suspended => {
+                    instance.status.state = VMState::Stopped;
+                    instance.running_config = None;
+                    instance.sync_status();
+                    self.publish_status(instance.current_status());
+                    drop(vms);
+                    let _ = fs::remove_file(self.suspend_state_path(vm_id));
+                    self.log(LogLevel::Info, vm_id, "Stopped; suspended state discarded");
+                    return Ok(());
+                }
+                state => {
+                    return Err(AppError::Conflict(format!("VM cannot be stopped while {:?}", state)));
+                }
+            }
+
+            instance.status.state = VMState::Stopping;
+            self.publish_status(instance.current_status());
+        }
+
+        self.log(LogLevel::Debug, vm_id, "Stopping");
+
+        let process = self.processes.lock().await.remove(vm_id);
+        let result = match process {
+            Some(mut process) => process.stop().await,
+            None => Ok(()),
+        };
+
+        if matches!(result, Err(QemuError::Timeout)) {
+            self.log(LogLevel::Warn, vm_id, "QEMU did not exit in time and was killed");
+        }
+
+        match result {
+            // stop() escalates to SIGKILL on timeout, so the process is gone either way
+            Ok(()) | Err(QemuError::Timeout) => {
+                self.log(LogLevel::Info, vm_id, "Stopped");
+                self.set_running_config(vm_id, None);
+                let _ = fs::remove_file(self.runtime_state_path(vm_id));
+                self.update_status(vm_id, |status| {
+                    status.state = VMState::Stopped;
+                    status.pid = None;
+                    status.cpu_usage = 0.0;
+                    status.memory_mb = 0;
+                    status.uptime_seconds = 0;
+                    status.started_at = None;
+                });
+                Ok(())
+            }
+            Err(e) => {
+                self.log(LogLevel::Error, vm_id, &format!("Failed to stop: {}", e));
+                self.update_status(vm_id, |status| status.state = VMState::Error(e.to_string()));
+                Err(e.into())
+            }
+        }
+    }
+
+    // Clears an Error back to Stopped once it's certain no QEMU is left
+    // running for the VM; last_error is kept for reference
+    pub async fn reset_state(&self, vm_id: &str) -> Result<VMStatus, AppError> {
+        let pid = {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+            if !matches!(instance.status.state, VMState::Error(_)) {
+                return Err(AppError::Conflict(format!(
+                    "VM is {:?}, only an Error state can be reset", instance.status.state
+                )));
+            }
+            instance.status.pid
+        };
+
+        {
+            let mut processes = self.processes.lock().await;
+            if let Some(process) = processes.get_mut(vm_id) {
+                if process.is_running().await {
+                    return Err(AppError::Conflict("QEMU is still running; stop the VM instead".to_string()));
+                }
+                processes.remove(vm_id);
+            }
+        }
+        // A QEMU this backend didn't launch (e.g. from before a restart)
+        if let Some(pid) = pid {
+            if Path::new(&format!("/proc/{}", pid)).exists() {
+                return Err(AppError::Conflict(format!("QEMU process {} is still running", pid)));
+            }
+        }
+
+        self.set_running_config(vm_id, None);
+        let _ = fs::remove_file(self.runtime_state_path(vm_id));
+        let _ = fs::remove_file(serial_socket_path(vm_id));
+        let _ = fs::remove_file(self.suspend_state_path(vm_id));
+
+        // Scoped so the std guard is released before the await below
+        {
+            let mut vms = self.vms.lock().unwrap();
+            let instance = vms.get_mut(vm_id).ok_or_else(|| not_found(vm_id))?;
+            // Re-checked: a start may have claimed the VM while we looked
+            if !matches!(instance.status.state, VMState::Error(_)) {
+                return Err(AppError::Conflict(format!("VM became {:?} during reset", instance.status.state)));
+            }
+            let status = &mut instance.status;
+            status.state = VMState::Stopped;
+            status.pid = None;
+            status.cpu_usage = 0.0;
+            status.memory_mb = 0;
+            status.uptime_seconds = 0;
+            status.started_at = None;
+            status.last_updated = chrono::Utc::now();
+            self.publish_status(instance.current_status());
+        }
+
+        self.log(LogLevel::Info, vm_id, "Error state cleared");
+        self.get_vm_status(vm_id).await.ok_or_else(|| not_found(vm_id))
+    }
+
+    // Saves the guest's RAM and device state to disk and stops QEMU; the
+    // next start or resume picks up where it left off
+    pub async fn suspend_vm(&self, vm_id: &str) -> Result<(), AppError> {
+        {
+            let mut vms = self.vms.lock().unwrap();
+            let instance = vms.get_mut(vm_id).ok_or_else(|| not_found(vm_id))?;
+            match &instance.status.state {
+                VMState::Running => {}
+                state => return Err(AppError::Conflict(format!("VM cannot be suspended while {:?}", state))),
+            }
+            instance.status.state = VMState::Stopping;
+            instance.status.last_updated = chrono::Utc::now();
+            self.publish_status(instance.current_status());
+        }
+
+        self.log(LogLevel::Info, vm_id, "Suspending to disk");
+
+        let state_path = self.suspend_state_path(vm_id);
+        if let Err(e) = self.save_state(vm_id, &state_path).await {
+            let _ = fs::remove_file(&state_path);
+            // save_state paused the guest first; let it carry on
+            if let Err(cont) = qmp_command_at(&qmp_socket_path(vm_id), "cont", Value::Null, QMP_TIMEOUT).await {
+                self.log(LogLevel::Warn, vm_id, &format!("Failed to resume after suspend error: {}", cont));
+            }
+            self.update_status(vm_id, |status| status.state = VMState::Running);
+            self.log(LogLevel::Warn, vm_id, &format!("Suspend failed: {}", e));
+            return Err(e);
+        }
+
+        // The guest is paused and saved, so how QEMU goes away doesn't matter
+        if let Some(mut process) = self.processes.lock().await.remove(vm_id) {
+            let _ = process.stop().await;
+        }
+        let _ = fs::remove_file(self.runtime_state_path(vm_id));
+        self.idle_samples.lock().unwrap().remove(vm_id);
+        // running_config is kept: it's what the saved state restores into
+        self.update_status(vm_id, |status| {
+            status.state = VMState::Suspended;
+            status.pid = None;
+            status.cpu_usage = 0.0;
+            status.memory_mb = 0;
+            status.uptime_seconds = 0;
+            status.started_at = None;
+        });
+        self.log(LogLevel::Info, vm_id, "Suspended");
+        Ok(())
+    }
+
+    // Same as starting a suspended VM, but refuses any other state
+    // Freezes the guest's vCPUs in place; QEMU and its memory stay up
+    pub async fn pause_vm(&self, vm_id: &str) -> Result<(), AppError> {
+        match self.get_vm_status(vm_id).await.ok_or_else(|| not_found(vm_id))?.state {
+            VMState::Running => {}
+            state => return Err(AppError::Conflict(format!("VM cannot be paused while {:?}", state))),
+        }
+
+        {
+            let processes = self.processes.lock().await;
+            let process = processes.get(vm_id)
+                .ok_or_else(|| AppError::Conflict("VM is not running".to_string()))?;
+            process.qmp_command("stop", Value::Null).await?;
+        }
+
+        self.update_status(vm_id, |status| {
+            status.state = VMState::Paused;
+            status.cpu_usage = 0.0;
+        });
+        self.log(LogLevel::Info, vm_id, "Paused");
+        Ok(())
+    }
+
+    // A soft reboot sends Ctrl+Alt+Del and leaves it to the guest OS, which
+    // may ignore it; a hard one resets the machine like the reset button.
+    // Either way QEMU keeps running, so the VM only passes through Starting.
+    pub async fn reboot_vm(&self, vm_id: &str, hard: bool) -> Result<(), AppError> {
+        {
+            let mut vms = self.vms.lock().unwrap();
+            let instance = vms.get_mut(vm_id).ok_or_else(|| not_found(vm_id))?;
+            match &instance.status.state {
+                VMState::Running => {}
+                state => return Err(AppError::Conflict(format!("VM cannot be rebooted while {:?}", state))),
+            }
+            instance.status.state = VMState::Starting;
+            instance.status.last_updated = chrono::Utc::now();
+            self.publish_status(instance.current_status());
+        }
+
+        let result = {
+            let processes = self.processes.lock().await;
+            match processes.get(vm_id) {
+                Some(process) if hard => process.qmp_command("system_reset", Value::Null).await,
+                Some(process) => process.qmp_command("send-key", json!({
+                    "keys": [
+                        { "type": "qcode", "data": "ctrl" },
+                        { "type": "qcode", "data": "alt" },
+                        { "type": "qcode", "data": "delete" },
+                    ]
+                })).await,
+                None => Err(QemuError::NotRunning),
+            }
+        };
+
+        // Back to Running even on failure: QEMU is still up either way
+        self.update_status(vm_id, |status| {
+            if status.state == VMState::Starting {
+                status.state = VMState::Running;
+            }
+            if result.is_ok() && hard {
+                status.started_at = Some(chrono::Utc::now());
+            }
+        });
+        result?;
+
+        self.log(LogLevel::Info, vm_id, if hard { "Hard reset" } else { "Reboot requested (Ctrl+Alt+Del)" });
+        Ok(())
+    }
+
+    // Continues a paused guest, or restores a suspended one from disk
+    pub async fn resume_vm(&self, vm_id: &str) -> Result<(), AppError> {
+        match self.get_vm_status(vm_id).await.ok_or_else(|| not_found(vm_id))?.state {
+            VMState::Suspended => self.start_vm(vm_id).await,
+            VMState::Paused => {
+                {
+                    let processes = self.processes.lock().await;
+                    let process = processes.get(vm_id)
+                        .ok_or_else(|| AppError::Conflict("VM is not running".to_string()))?;
+                    process.qmp_command("cont", Value::Null).await?;
+                }
+                self.update_status(vm_id, |status| status.state = VMState::Running);
+                self.log(LogLevel::Info, vm_id, "Resumed");
+                Ok(())
+            }
+            state => Err(AppError::Conflict(format!("VM is {:?}, not paused or suspended", state))),
+        }
+    }
+
+    // For the console proxy: wakes a suspended VM, then returns its VNC
+    // port and a session that holds off idle suspend until dropped
+    pub async fn open_console(self: &Arc<Self>, vm_id: &str) -> Result<(u16, ConsoleSession), AppError> {
+        let state = self.get_vm_status(vm_id).await.ok_or_else(|| not_found(vm_id))?.state;
+        if state == VMState::Suspended {
+            self.log(LogLevel::Info, vm_id, "Console connected; resuming");
+            self.resume_vm(vm_id).await?;
+        }
+
+        let port = self.vnc_port(vm_id)?;
+        *self.console_sessions.lock().unwrap().entry(vm_id.to_string()).or_insert(0) += 1;
+        Ok((port, ConsoleSession { manager: Arc::downgrade(self), vm_id: vm_id.to_string() }))
+    }
+
+    // For the WebSocket console bridge: connects to the serial port of a
+    // running VM. QEMU serves one client at a time, so while a terminal
+    // client is attached nothing arrives until it leaves.
+    pub async fn open_serial(self: &Arc<Self>, vm_id: &str) -> Result<(Box<dyn SerialIo>, ConsoleSession), AppError> {
+        let socket = self.console_socket(vm_id)?;
+        if !socket.running {
+            return Err(AppError::Conflict(format!("VM {} is not running", vm_id)));
+        }
+
+        let unavailable = |e: std::io::Error| AppError::Conflict(format!("Serial console of VM {} unavailable: {}", vm_id, e));
+        let stream: Box<dyn SerialIo> = match (socket.path, socket.tcp) {
+            (Some(path), _) => Box::new(tokio::net::UnixStream::connect(path).await.map_err(unavailable)?),
+            (None, Some(mut addr)) => {
+                // Listening on every address; loopback is one of them
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(IpAddr::from([127, 0, 0, 1]));
+                }
+                Box::new(tokio::net::TcpStream::connect(addr).await.map_err(unavailable)?)
+            }
+            (None, None) => return Err(AppError::Internal("No serial console endpoint".to_string())),
+        };
+
+        *self.console_sessions.lock().unwrap().entry(vm_id.to_string()).or_insert(0) += 1;
+        Ok((stream, ConsoleSession { manager: Arc::downgrade(self), vm_id: vm_id.to_string() }))
+    }
+
+    // Golden images in data_dir/bases with the VMs cloned from each
+    pub async fn list_base_images(&self) -> Result<Vec<BaseImage>, AppError> {
+        let bases_dir = self.data_dir.join("bases");
+        let mut images = Vec::new();
+
+        for entry in fs::read_dir(&bases_dir).map_err(internal)? {
+            let path = entry.map_err(internal)?.path();
+            if !path.is_file() {
+                continue;
+            }
+            images.push(BaseImage {
+                name: path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+                size_bytes: fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+                dependents: self.base_image_dependents(&path),
+                path,
+            });
+        }
+
+        images.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(images)
+    }
+
+    // Refused while any VM's disk still reads through to the image
+    pub async fn delete_base_image(&self, name: &str) -> Result<(), AppError> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(AppError::BadRequest(format!("Invalid base image name: {}", name)));
+        }
+
+        let path = self.data_dir.join("bases").join(name);
+        if !path.is_file() {
+            return Err(AppError::NotFound(format!("Base image {} not found", name)));
+        }
+
+        let dependents = self.base_image_dependents(&path);
+        if !dependents.is_empty() {
+            return Err(AppError::Conflict(format!(
+                "Base image {} is in use by {}", name, dependents.join(", ")
+            )));
+        }
+
+        fs::remove_file(&path).map_err(internal)?;
+        log::info!("Deleted base image {}", path.display());
+        Ok(())
+    }
+
+    fn base_image_dependents(&self, base: &Path) -> Vec<String> {
+        let base = match base.canonicalize() {
+            Ok(base) => base,
+            Err(_) => return Vec::new(),
+        };
+
+        let vms = self.vms.lock().unwrap();
+        let mut dependents: Vec<String> = vms.values()
+            .filter(|instance| {
+                instance.config.base_image.as_deref()
+                    .and_then(|path| Path::new(path).canonicalize().ok())
+                    .is_some_and(|path| path == base)
+            })
+            .map(|instance| instance.config.id.clone())
+            .collect();
+        dependents.sort();
+        dependents
+    }
+
+    // Guard for delete and destructive disk operations. Clearing the flag is
+    // a separate update, so nothing can unprotect and destroy in one call.
+    fn ensure_unprotected(&self, vm_id: &str, action: &str) -> Result<(), AppError> {
+        let vms = self.vms.lock().unwrap();
+        let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+        if instance.config.protected {
+            return Err(AppError::Protected(format!(
+                "VM {} is protected; clear the protected flag before you {}", vm_id, action
+            )));
+        }
+        Ok(())
+    }
+
+    // Refuses to touch a live VM's disk unless `force` is set, in which case
+    // the VM is stopped first; stop_vm only returns once QEMU has exited
+    pub async fn delete_vm(&self, vm_id: &str, force: bool) -> Result<(), AppError> {
+        self.ensure_unprotected(vm_id, "delete it")?;
+        self.ensure_no_linked_clones(vm_id, "delete it")?;
+
+        let state = self.get_vm_status(vm_id).await
+            .ok_or_else(|| not_found(vm_id))?
+            .state;
+
+        match state {
+            VMState::Stopped | VMState::Error(_) | VMState::Suspended => {}
+            VMState::Running | VMState::Paused if force => {
+                self.log(LogLevel::Info, vm_id, "Force delete: stopping first");
+                match tokio::time::timeout(DELETE_STOP_TIMEOUT, self.stop_vm(vm_id)).await {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        // Still waiting for the process table, so QEMU wasn't
+                        // touched; don't leave the VM looking half stopped
+                        self.update_status(vm_id, |status| {
+                            if status.state == VMState::Stopping {
+                                status.state = state.clone();
+                            }
+                        });
+                        return Err(QemuError::Timeout.into());
+                    }
+                }
+            }
+            VMState::Running | VMState::Paused => {
+                return Err(AppError::Conflict(
+                    "VM is running; stop it first or delete with force=true".to_string()
+                ));
+            }
+            VMState::Starting | VMState::Stopping => {
+                return Err(AppError::Conflict(format!("VM cannot be deleted while {:?}", state)));
+            }
+        }
+
+        // Re-check under the lock: a concurrent start (or protect) could have
+        // claimed the VM since the stop above
+        let instance = {
+            let mut vms = self.vms.lock().unwrap();
+            if vms.get(vm_id).is_some_and(|instance| instance.config.protected) {
+                return Err(AppError::Protected(format!("VM {} was protected while being deleted", vm_id)));
+            }
+            match vms.get(vm_id).map(|instance| &instance.status.state) {
+                None => return Err(not_found(vm_id)),
+                Some(VMState::Stopped) | Some(VMState::Error(_)) | Some(VMState::Suspended) => {}
+                Some(state) => {
+                    return Err(AppError::Conflict(format!("VM cannot be deleted while {:?}", state)));
+                }
+            }
+            vms.remove(vm_id).unwrap()
+        };
+
+        let _ = fs::remove_file(&instance.disk_path);
+        for disk in &instance.config.disks {
+            let _ = fs::remove_file(&disk.path);
+        }
+        let _ = self.disk_manager.delete_key(vm_id);
+        let _ = fs::remove_file(self.config_path(vm_id));
+        let _ = fs::remove_file(self.suspend_state_path(vm_id));
+        if let Some(chroot_dir) = &self.chroot_dir {
+            let _ = fs::remove_dir_all(chroot_dir.join(vm_id));
+        }
+        if self.cgroup_disk_limit.is_some() {
+            if let Err(e) = remove_vm_cgroups(vm_id) {
+                self.log(LogLevel::Warn, vm_id, &format!("Failed to remove cgroups: {}", e));
+            }
+        }
+        self.vnc_ports.release_port(instance.config.vnc_port);
+        self.release_forwards(&instance.config);
+
+        self.log(LogLevel::Info, vm_id, "Deleted");
+        self.logger.clear_vm_log_level(vm_id);
+        self.last_scheduled_snapshot.lock().unwrap().remove(vm_id);
+        self.idle_samples.lock().unwrap().remove(vm_id);
+        self.metrics.lock().unwrap().remove(vm_id);
+        let _ = self.status_events.send(StatusEvent::Deleted(vm_id.to_string()));
+
+        Ok(())
+    }
+
+    // Memory and CPU count can't change under a running QEMU, so changing
+    // them is refused while one runs unless `defer_restart` asks for them
+    // to be staged for the next start instead
+    pub async fn update_vm(&self, vm_id: &str, req: UpdateVMRequest, defer_restart: bool) -> Result<VMUpdate, AppError> {
+        validate_vm_update(&req)?;
+
+        let (format, running) = self.vms.lock().unwrap()
+            .get(vm_id)
+            .map(|instance| (instance.config.disk_format.clone(), instance.running_config.clone()))
+            .ok_or_else(|| not_found(vm_id))?;
+        if let Some(Some(policy)) = &req.snapshot_schedule {
+            validate_snapshot_policy(policy, &format)?;
+        }
+        if req.is_template == Some(true) {
+            self.ensure_stopped(vm_id, "make it a template")?;
+        }
+        if let (Some(running), false) = (&running, defer_restart) {
+            let mut changed = Vec::new();
+            if req.memory_mb.is_some_and(|memory_mb| memory_mb != running.memory_mb) {
+                changed.push("memory_mb");
+            }
+            if req.cpu_cores.is_some_and(|cpu_cores| cpu_cores != running.cpu_cores) {
+                changed.push("cpu_cores");
+            }
+            if !changed.is_empty() {
+                return Err(AppError::Conflict(format!(
+                    "{} can't change while the VM is running; stop it first, or pass defer_restart=true to apply on the next start",
+                    changed.join(" and ")
+                )));
+            }
+        }
+
+        let fields = req.field_names();
+        let config = self.update_config(vm_id, |config| config.update(req))?;
+
+        let pending = self.vms.lock().unwrap()
+            .get(vm_id)
+            .map(|instance| instance.pending_restart())
+            .unwrap_or_default();
+        let (pending_restart, applied): (Vec<&str>, Vec<&str>) = fields.into_iter()
+            .partition(|field| pending.contains(field));
+
+        if pending_restart.is_empty() {
+            self.log(LogLevel::Info, vm_id, "Config updated");
+        } else {
+            self.log(LogLevel::Info, vm_id, &format!(
+                "Config updated; {} take effect after restart", pending_restart.join(", ")
+            ));
+        }
+
+        Ok(VMUpdate {
+            config,
+            applied: applied.into_iter().map(String::from).collect(),
+            pending_restart: pending_restart.into_iter().map(String::from).collect(),
+        })
+    }
+
+    // Swaps the installer media. A running VM gets it through QMP without a
+    // reboot; a stopped one just boots with it next time.
+    pub async fn change_iso(&self, vm_id: &str, new_iso: &str) -> Result<VMConfig, AppError> {
+        validate_iso_path(new_iso)?;
+
+        let live = {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+            match &instance.status.state {
+                VMState::Running | VMState::Paused => {
+                    let running = instance.running_config.as_ref().unwrap_or(&instance.config);
+                    // No ISO at start means no CD-ROM drive to put one in
+                    if running.iso_path.is_empty() {
+                        return Err(AppError::Conflict(
+                            "VM was started without a CD-ROM drive; stop it to set an ISO".to_string()
+                        ));
+                    }
+                    Some(MachineLayout::for_machine(&running.machine_type))
+                }
+                VMState::Stopped | VMState::Error(_) => None,
+                state => return Err(AppError::Conflict(format!("The ISO can't be changed while {:?}", state))),
+            }
+        };
+
+        self.snapshot_before(vm_id, "change-iso").await?;
+
+        if let Some(layout) = live {
+            let format = match Path::new(new_iso).extension().and_then(|ext| ext.to_str()) {
+                Some(ext) if ext.eq_ignore_ascii_case("qcow2") => "qcow2",
+                _ => "raw",
+            };
+            {
+                let processes = self.processes.lock().await;
+                let process = processes.get(vm_id)
+                    .ok_or_else(|| AppError::Conflict("VM is not running".to_string()))?;
+                // Forces the tray open even if the guest has locked it
+                process.qmp_command("blockdev-change-medium", json!({
+                    "device": layout.cdrom_drive(),
+                    "filename": new_iso,
+                    "format": format,
+                    "force": true,
+                })).await?;
+            }
+
+            // The running VM has the new media too, so it's no pending change
+            if let Some(running) = self.vms.lock().unwrap().get_mut(vm_id).and_then(|i| i.running_config.as_mut()) {
+                running.iso_path = new_iso.to_string();
+            }
+        }
+
+        let config = self.update_config(vm_id, |config| config.iso_path = new_iso.to_string())?;
+        self.log(LogLevel::Info, vm_id, &format!(
+            "ISO changed to {}{}", new_iso, if live.is_some() { " (live)" } else { "" }
+        ));
+
+        Ok(config)
+    }
+
+    // Adds a volume besides the primary disk, creating its image unless one
+    // kept by an earlier detach is there. QEMU picks it up on the next
+    // start; a suspended VM's saved state wouldn't match the new devices.
+    pub async fn attach_disk(&self, vm_id: &str, req: AttachDiskRequest) -> Result<DiskAttachment, AppError> {
+        validate_volume_name(&req.name)?;
+        if req.boot_index.is_some_and(|index| index < 2) {
+            return Err(AppError::BadRequest("Boot indexes 0 and 1 belong to the CD-ROM and primary disk".to_string()));
+        }
+        if req.readonly && req.bus == DiskBus::Ide {
+            return Err(AppError::BadRequest("IDE disks can't be read-only".to_string()));
+        }
+
+        {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+            if !matches!(instance.status.state, VMState::Stopped | VMState::Error(_)) {
+                return Err(AppError::Conflict("Disks can only be attached while the VM is stopped".to_string()));
+            }
+            if instance.config.disks.iter().any(|disk| disk.name == req.name) {
+                return Err(AppError::Conflict(format!("VM {} already has a disk named {}", vm_id, req.name)));
+            }
+            if let Some(index) = req.boot_index {
+                if instance.config.disks.iter().any(|disk| disk.boot_index == Some(index)) {
+                    return Err(AppError::Conflict(format!("Boot index {} is already taken", index)));
+                }
+            }
+        }
+
+        let (disks, id, name, size_gb, format) =
+            (self.disk_manager.clone(), vm_id.to_string(), req.name.clone(), req.size_gb, storage_format(&req.format));
+        let path = blocking(move || disks.attach_disk(&id, &name, size_gb, format)).await?;
+        let disk = DiskAttachment {
+            name: req.name,
+            path,
+            format: req.format,
+            bus: req.bus,
+            readonly: req.readonly,
+            boot_index: req.boot_index,
+        };
+
+        self.update_config(vm_id, |config| config.disks.push(disk.clone()))?;
+        self.log(LogLevel::Info, vm_id, &format!("Attached disk {} ({})", disk.name, disk.path.display()));
+
+        Ok(disk)
+    }
+
+    // Drops a volume from the config; its image is kept for a later attach
+    // unless `delete` is set
+    pub async fn detach_disk(&self, vm_id: &str, name: &str, delete: bool) -> Result<(), AppError> {
+        if delete {
+            self.ensure_unprotected(vm_id, "delete its disks")?;
+        }
+        {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+            if !matches!(instance.status.state, VMState::Stopped | VMState::Error(_)) {
+                return Err(AppError::Conflict("Disks can only be detached while the VM is stopped".to_string()));
+            }
+            if !instance.config.disks.iter().any(|disk| disk.name == name) {
+                return Err(AppError::NotFound(format!("Disk {} not found on VM {}", name, vm_id)));
+            }
+        }
+
+        self.update_config(vm_id, |config| config.disks.retain(|disk| disk.name != name))?;
+        let (disks, id, disk_name) = (self.disk_manager.clone(), vm_id.to_string(), name.to_string());
+        blocking(move || disks.detach_disk(&id, &disk_name, delete)).await?;
+        self.log(LogLevel::Info, vm_id, &format!(
+            "Detached disk {}{}", name, if delete { " and deleted its image" } else { "" }
+        ));
+
+        Ok(())
+    }
+
+    pub async fn attach_nic(&self, vm_id: &str, network_type: NetworkType) -> Result<HotplugNic, AppError> {
+        if !self.privileged && matches!(network_type, NetworkType::Tap(_) | NetworkType::Bridge(_)) {
+            return Err(AppError::Forbidden(
+                "Tap and bridge NICs need CAP_NET_ADMIN; this backend runs unprivileged".to_string()
+            ));
+        }
+        let (netdev_id, bus) = {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+            if instance.status.state != VMState::Running {
+                return Err(AppError::Conflict("NICs can only be attached to a running VM".to_string()));
+            }
+            // net0.. are the NICs from the command line; older configs
+            // always kept net0 for it, hence at least 1
+            let used: Vec<&str> = instance.config.hotplug_nics.iter().map(|n| n.netdev_id.as_str()).collect();
+            let first = instance.config.networks.len().max(1);
+            let netdev_id = (first..).map(NetworkInterface::netdev_id).find(|id| !used.contains(&id.as_str())).unwrap();
+
+            // On q35 and virt the NIC needs one of the spare root ports QEMU was started with
+            let running = instance.running_config.as_ref().unwrap_or(&instance.config);
+            let bus = match MachineLayout::for_machine(&running.machine_type) {
+                MachineLayout::Pc => None,
+                MachineLayout::Q35 | MachineLayout::Virt => {
+                    let taken: Vec<&str> = instance.config.hotplug_nics.iter().filter_map(|n| n.bus.as_deref()).collect();
+                    let port = (0..Q35_HOTPLUG_PORTS).map(MachineLayout::hotplug_port)
+                        .find(|port| !taken.contains(&port.as_str()))
+                        .ok_or_else(|| AppError::Conflict(format!(
+                            "All {} hot-plug slots are in use", Q35_HOTPLUG_PORTS
+                        )))?;
+                    Some(port)
+                }
+            };
+            (netdev_id, bus)
+        };
+
+        let tap = match &network_type {
+            NetworkType::User(forwards) if !forwards.is_empty() => {
+                return Err(AppError::BadRequest("Port forwards are only supported on the VM's primary NIC".to_string()));
+            }
+            NetworkType::Bridge(bridge) => {
+                let tap = NetworkManager::unused_tap_name(vm_id)?;
+                match self.managed_bridge(bridge) {
+                    Some(network) => {
+                        network.create_bridge()?;
+                        network.create_tap(&tap)?;
+                    }
+                    None => NetworkManager::create_tap_on_bridge(bridge, &tap)?,
+                }
+                Some(tap)
+            }
+            NetworkType::None => return Err(AppError::BadRequest("Network type None has no NIC to attach".to_string())),
+            _ => None,
+        };
+
+        let nic = HotplugNic { netdev_id, network_type, tap, bus };
+
+        if let Err(e) = self.hotplug_nic(vm_id, &nic).await {
+            let _ = self.remove_nic_tap(&nic);
+            self.log(LogLevel::Error, vm_id, &format!("Failed to attach NIC {}: {}", nic.netdev_id, e));
+            return Err(e);
+        }
+
+        self.update_config(vm_id, |config| config.hotplug_nics.push(nic.clone()))?;
+        self.shape_taps(vm_id, &nic.tap_name().map(String::from).into_iter().collect::<Vec<_>>());
+        self.log(LogLevel::Info, vm_id, &format!("Attached NIC {}", nic.netdev_id));
+
+        Ok(nic)
+    }
+
+    pub async fn detach_nic(&self, vm_id: &str, netdev_id: &str) -> Result<(), AppError> {
+        let nic = {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+            if instance.status.state != VMState::Running {
+                return Err(AppError::Conflict("NICs can only be detached from a running VM".to_string()));
+            }
+            instance.config.hotplug_nics.iter()
+                .find(|n| n.netdev_id == netdev_id)
+                .cloned()
+                .ok_or_else(|| AppError::NotFound(format!("NIC {} not found on VM {}", netdev_id, vm_id)))?
+        };
+
+        {
+            let processes = self.processes.lock().await;
+            let process = processes.get(vm_id)
+                .ok_or_else(|| AppError::Conflict("VM is not running".to_string()))?;
+            process.qmp_command("device_del", json!({ "id": nic.device_id() })).await?;
+            process.qmp_command("netdev_del", json!({ "id": nic.netdev_id })).await?;
+        }
+
+        if let Err(e) = self.remove_nic_tap(&nic) {
+            self.log(LogLevel::Warn, vm_id, &format!("Failed to remove tap of {}: {}", nic.netdev_id, e));
+        }
+
+        self.update_config(vm_id, |config| config.hotplug_nics.retain(|n| n.netdev_id != netdev_id))?;
+        self.log(LogLevel::Info, vm_id, &format!("Detached NIC {}", netdev_id));
+
+        Ok(())
+    }
+
+    // The NAT bridge when `bridge` is it. Taps there go through the network
+    // manager, which brings the bridge up, shapes and tracks them, and takes
+    // the bridge down again with the last one.
+    fn managed_bridge(&self, bridge: &str) -> Option<&Arc<NetworkManager>> {
+        self.network.as_ref().filter(|network| network.bridge_name() == bridge)
+    }
+
+    fn remove_nic_tap(&self, nic: &HotplugNic) -> Result<(), NetworkError> {
+        let Some(tap) = &nic.tap else { return Ok(()) };
+        match &nic.network_type {
+            NetworkType::Bridge(bridge) => match self.managed_bridge(bridge) {
+                Some(network) => network.release_tap(tap).map(|_| ()),
+                None => NetworkManager::remove_tap(tap),
+            },
+            _ => NetworkManager::remove_tap(tap),
+        }
+    }
+
+    async fn hotplug_nic(&self, vm_id: &str, nic: &HotplugNic) -> Result<(), AppError> {
+        let processes = self.processes.lock().await;
+        let process = processes.get(vm_id)
+            .ok_or_else(|| AppError::Conflict("VM is not running".to_string()))?;
+
+        let mut netdev = json!({ "id": nic.netdev_id });
+        match (&nic.network_type, &nic.tap) {
+            (_, Some(tap)) | (NetworkType::Tap(tap), None) => {
+                netdev["type"] = json!("tap");
+                netdev["ifname"] = json!(tap);
+                netdev["script"] = json!("no");
+                netdev["downscript"] = json!("no");
+            }
+            _ => netdev["type"] = json!("user"),
+        }
+        process.qmp_command("netdev_add", netdev).await?;
+
+        let mut device = json!({
+            "driver": "virtio-net-pci",
+            "id": nic.device_id(),
+            "netdev": nic.netdev_id,
+        });
+        if let Some(bus) = &nic.bus {
+            device["bus"] = json!(bus);
+        }
+        if let Err(e) = process.qmp_command("device_add", device).await {
+            let _ = process.qmp_command("netdev_del", json!({ "id": nic.netdev_id })).await;
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    // A running QEMU holds its image locked, so while it's up snapshots go
+    // through the monitor (savevm/delvm, which also capture RAM) rather
+    // than qemu-img
+    pub async fn list_disk_snapshots(&self, vm_id: &str) -> Result<Vec<SnapshotInfo>, AppError> {
+        let state = self.get_vm_status(vm_id).await.ok_or_else(|| not_found(vm_id))?.state;
+        if matches!(state, VMState::Running | VMState::Paused) {
+            let listing = self.hmp(vm_id, "info snapshots", Duration::from_secs(10)).await?;
+            return Ok(SnapshotInfo::from_qemu_output(&listing));
+        }
+
+        let (disks, id) = (self.disk_manager.clone(), vm_id.to_string());
+        Ok(blocking(move || disks.list_snapshots(&id)).await?)
+    }
+
+    pub async fn create_disk_snapshot(&self, vm_id: &str, name: &str) -> Result<(), AppError> {
+        validate_snapshot_name(name)?;
+
+        if self.snapshot_live(vm_id)? {
+            if self.list_disk_snapshots(vm_id).await?.iter().any(|s| s.name == name) {
+                return Err(AppError::Conflict(format!("Snapshot {} already exists", name)));
+            }
+            self.hmp(vm_id, &format!("savevm {}", name), SAVEVM_TIMEOUT).await?;
+        } else {
+            let (disks, id, name) = (self.disk_manager.clone(), vm_id.to_string(), name.to_string());
+            blocking(move || disks.create_snapshot(&id, &name)).await?;
+        }
+
+        self.log(LogLevel::Info, vm_id, &format!("Created snapshot {}", name));
+        Ok(())
+    }
+
+    // Only while stopped: reverting the image under a running guest (or
+    // under a suspended one's saved RAM) would corrupt its filesystems
+    pub async fn restore_disk_snapshot(&self, vm_id: &str, name: &str) -> Result<(), AppError> {
+        validate_snapshot_name(name)?;
+
+        {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+            match &instance.status.state {
+                VMState::Stopped | VMState::Error(_) => {}
+                state => {
+                    return Err(AppError::Conflict(format!("VM must be stopped to restore a snapshot ({:?})", state)));
+                }
+            }
+        }
+
+        self.ensure_no_linked_clones(vm_id, "restore a snapshot")?;
+        let (disks, id, snapshot) = (self.disk_manager.clone(), vm_id.to_string(), name.to_string());
+        blocking(move || disks.restore_snapshot(&id, &snapshot)).await?;
+        self.log(LogLevel::Info, vm_id, &format!("Restored snapshot {}", name));
+        Ok(())
+    }
+
+    // Converts the primary disk to `target`. Without `replace` the VM is
+    // untouched and the copy is left beside the disks; with it the VM moves
+    // onto the converted disk, flattened off any base image.
+    pub async fn convert_disk(&self, vm_id: &str, target: DiskFormat, replace: bool) -> Result<DiskInfo, AppError> {
+        if replace {
+            self.ensure_unprotected(vm_id, "replace its disk")?;
+            self.ensure_no_linked_clones(vm_id, "replace its disk")?;
+        }
+
+        {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+            match &instance.status.state {
+                VMState::Stopped | VMState::Error(_) => {}
+                state => {
+                    return Err(AppError::Conflict(format!("VM must be stopped to convert its disk ({:?})", state)));
+                }
+            }
+            if replace && instance.config.snapshot_schedule.is_some() && !matches!(target, DiskFormat::Qcow2) {
+                return Err(ValidationError::InvalidDiskOption(format!(
+                    "snapshot_schedule needs a qcow2 disk; clear it before converting to {}", target.extension()
+                )).into());
+            }
+        }
+
+        let (disks, id, format) = (self.disk_manager.clone(), vm_id.to_string(), storage_format(&target));
+        let info = blocking(move || disks.convert_disk(&id, format, replace)).await?;
+
+        if replace {
+            self.update_config(vm_id, |config| {
+                config.discard = config.discard && target.supports_discard();
+                config.disk_format = target.clone();
+                config.base_image = None;
+            })?;
+            if let Some(instance) = self.vms.lock().unwrap().get_mut(vm_id) {
+                instance.disk_path = info.path.clone();
+            }
+            self.refresh_disk_summary(vm_id, true).await;
+        }
+
+        self.log(LogLevel::Info, vm_id, &format!(
+            "Converted disk to {} at {}", target.extension(), info.path.display()
+        ));
+        Ok(info)
+    }
+
+    // A new VM with its own id, MACs, VNC port and forwarded ports, on a copy
+    // of the source's disk or, with `linked`, an overlay on it. A linked
+    // source can't be started again until its clones are flattened.
+    // Volumes aren't cloned.
+    pub async fn clone_vm(&self, src_id: &str, new_name: &str, linked: bool) -> Result<VMConfig, AppError> {
+        validate_vm_name(new_name)?;
+        self.ensure_stopped(src_id, "clone it")?;
+
+        let (source, source_disk) = {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(src_id).ok_or_else(|| not_found(src_id))?;
+            (instance.config.clone(), instance.disk_path.clone())
+        };
+        // A named tap belongs to one VM; two QEMUs can't both open it
+        let tap = source.networks.iter().find_map(|nic| match &nic.network_type {
+            NetworkType::Tap(tap) => Some(tap.clone()),
+            _ => None,
+        });
+        if let Some(tap) = tap {
+            return Err(AppError::BadRequest(format!("VM {} uses tap {}, which a clone can't share", src_id, tap)));
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut config = source.clone();
+        config.id = id.clone();
+        config.name = new_name.to_string();
+        for (index, nic) in config.networks.iter_mut().enumerate() {
+            nic.mac_address = Some(generated_mac(&id, index));
+            if let NetworkType::User(forwards) = &mut nic.network_type {
+                for forward in forwards {
+                    forward.host_port = 0;
+                }
+            }
+        }
+        config.hotplug_nics.clear();
+        config.disks.clear();
+        config.is_template = false;
+        let now = chrono::Utc::now();
+        config.created_at = now;
+        config.updated_at = now;
+
+        config.vnc_port = if self.deterministic_vnc_ports {
+            self.vnc_ports.allocate_stable_port(&id)?
+        } else {
+            self.vnc_ports.allocate_port()?
+        };
+        if let Err(e) = self.reserve_forwards(&mut config.networks) {
+            self.vnc_ports.release_port(config.vnc_port);
+            return Err(e);
+        }
+
+        let (disks, from, to) = (self.disk_manager.clone(), src_id.to_string(), id.clone());
+        let disk = if linked {
+            blocking(move || disks.create_linked_clone(&from, &to)).await
+        } else {
+            blocking(move || disks.copy_disk(&from, &to)).await
+        };
+        let disk_path = match disk {
+            Ok(path) => path,
+            Err(e) => {
+                self.vnc_ports.release_port(config.vnc_port);
+                self.release_forwards(&config);
+                return Err(e.into());
+            }
+        };
+        if linked {
+            config.disk_options = Default::default();
+            config.disk_options = config.disk_options.resolved(&StorageFormat::Qcow2);
+            config.base_image = Some(source_disk.to_string_lossy().into_owned());
+        } else if config.disk_key.is_some() {
+            config.disk_key = Some(self.disk_manager.key_path(&id));
+        }
+
+        if let Err(e) = config.save_to_file(&self.config_path(&id)) {
+            let _ = self.disk_manager.delete_disk(&id);
+            let _ = self.disk_manager.delete_key(&id);
+            self.vnc_ports.release_port(config.vnc_port);
+            self.release_forwards(&config);
+            return Err(AppError::Internal(format!("Failed to save config: {}", e)));
+        }
+
+        let instance = VMInstance::stopped(config.clone(), disk_path);
+        self.vms.lock().unwrap().insert(id.clone(), instance);
+        self.log(LogLevel::Info, &id, &format!(
+            "Cloned from '{}' ({}){}", source.name, src_id, if linked { " as a linked clone" } else { "" }
+        ));
+        self.refresh_disk_summary(&id, true).await;
+
+        Ok(config)
+    }
+
+    pub async fn list_templates(&self) -> Vec<VMConfig> {
+        let vms = self.vms.lock().unwrap();
+        let mut templates: Vec<VMConfig> = vms.values()
+            .filter(|instance| instance.config.is_template)
+            .map(|instance| instance.config.clone())
+            .collect();
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+        templates
+    }
+
+    // A linked clone of the template, which its own config carries over
+    // from; only the name is new
+    pub async fn create_from_template(&self, template_id: &str, name: &str) -> Result<VMConfig, AppError> {
+        let is_template = self.vms.lock().unwrap().get(template_id)
+            .map(|instance| instance.config.is_template)
+            .ok_or_else(|| not_found(template_id))?;
+        if !is_template {
+            return Err(AppError::BadRequest(format!("VM {} is not a template", template_id)));
+        }
+
+        self.clone_vm(template_id, name, true).await
+    }
+
+    // Detaches a linked clone from its base by copying in what it still
+    // reads from there
+    pub async fn flatten_disk(&self, vm_id: &str) -> Result<DiskInfo, AppError> {
+        self.ensure_stopped(vm_id, "flatten its disk")?;
+
+        let (disks, id) = (self.disk_manager.clone(), vm_id.to_string());
+        let info = blocking(move || disks.flatten_disk(&id)).await?;
+        self.update_config(vm_id, |config| config.base_image = None)?;
+        self.refresh_disk_summary(vm_id, true).await;
+
+        self.log(LogLevel::Info, vm_id, "Flattened disk off its base");
+        Ok(info)
+    }
+
+    // Offline only: qemu-img can't open an image QEMU holds locked. Growing
+    // is all qemu-img allows without --shrink, so a smaller size fails.
+    pub async fn resize_disk(&self, vm_id: &str, size_gb: u32) -> Result<(), AppError> {
+        self.ensure_stopped(vm_id, "resize its disk")?;
+
+        let (disks, id) = (self.disk_manager.clone(), vm_id.to_string());
+        blocking(move || disks.resize_disk(&id, size_gb)).await?;
+        self.update_config(vm_id, |config| config.disk_size_gb = size_gb)?;
+        self.refresh_disk_summary(vm_id, true).await;
+
+        self.log(LogLevel::Info, vm_id, &format!("Resized disk to {} GB", size_gb));
+        Ok(())
+    }
+
+    // Refused while running, like convert_disk, and while linked clones
+    // read through to the disk, since its file is replaced
+    pub async fn compact_disk(&self, vm_id: &str) -> Result<CompactReport, AppError> {
+        self.ensure_stopped(vm_id, "compact its disk")?;
+        self.ensure_no_linked_clones(vm_id, "compact its disk")?;
+
+        let (disks, id) = (self.disk_manager.clone(), vm_id.to_string());
+        let report = blocking(move || disks.compact_disk(&id)).await?;
+        self.refresh_disk_summary(vm_id, true).await;
+
+        self.log(LogLevel::Info, vm_id, &format!(
+            "Compacted disk from {:.2} GB to {:.2} GB", report.before_actual_size_gb, report.after_actual_size_gb
+        ));
+        Ok(report)
+    }
+
+    // Merges a linked clone's changes into its base. Refused while anything
+    // else reads through to that base, since it would change under them.
+    pub async fn commit_disk(&self, vm_id: &str) -> Result<PathBuf, AppError> {
+        self.ensure_stopped(vm_id, "commit its disk")?;
+
+        let base = self.vms.lock().unwrap().get(vm_id)
+            .and_then(|instance| instance.config.base_image.clone())
+            .ok_or_else(|| AppError::BadRequest(format!("VM {} has no base image", vm_id)))?;
+        let others: Vec<String> = self.base_image_dependents(Path::new(&base))
+            .into_iter()
+            .filter(|id| id != vm_id)
+            .collect();
+        if !others.is_empty() {
+            return Err(AppError::Conflict(format!(
+                "Base {} is shared with {}; committing would change their disks", base, others.join(", ")
+            )));
+        }
+
+        let (disks, id) = (self.disk_manager.clone(), vm_id.to_string());
+        let base = blocking(move || disks.commit_disk(&id)).await?;
+        self.refresh_disk_summary(vm_id, true).await;
+
+        self.log(LogLevel::Info, vm_id, &format!("Committed disk into {}", base.display()));
+        Ok(base)
+    }
+
+    fn ensure_stopped(&self, vm_id: &str, action: &str) -> Result<(), AppError> {
+        let vms = self.vms.lock().unwrap();
+        let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+        match &instance.status.state {
+            VMState::Stopped | VMState::Error(_) => Ok(()),
+            state => Err(AppError::Conflict(format!("VM must be stopped to {} ({:?})", action, state))),
+        }
+    }
+
+    // A disk that backs linked clones has to stay as it is; the clones
+    // would see its blocks change under them
+    fn ensure_no_linked_clones(&self, vm_id: &str, action: &str) -> Result<(), AppError> {
+        let disk_path = self.vms.lock().unwrap().get(vm_id)
+            .map(|instance| instance.disk_path.clone())
+            .ok_or_else(|| not_found(vm_id))?;
+        let clones = self.base_image_dependents(&disk_path);
+        if !clones.is_empty() {
+            return Err(AppError::Conflict(format!(
+                "VM {} backs the linked clones {}; flatten them before you {}", vm_id, clones.join(", "), action
+            )));
+        }
+        Ok(())
+    }
+
+    pub async fn delete_disk_snapshot(&self, vm_id: &str, name: &str) -> Result<(), AppError> {
+        validate_snapshot_name(name)?;
+
+        if self.snapshot_live(vm_id)? {
+            if !self.list_disk_snapshots(vm_id).await?.iter().any(|s| s.name == name) {
+                return Err(AppError::NotFound(format!("Snapshot {} not found", name)));
+            }
+            self.hmp(vm_id, &format!("delvm {}", name), SAVEVM_TIMEOUT).await?;
+        } else {
+            let (disks, id, name) = (self.disk_manager.clone(), vm_id.to_string(), name.to_string());
+            blocking(move || disks.delete_snapshot(&id, &name)).await?;
+        }
+
+        self.log(LogLevel::Info, vm_id, &format!("Deleted snapshot {}", name));
+        Ok(())
+    }
+
+    // Whether snapshots of the VM have to go through QEMU; refuses while
+    // it's between states or its format has no internal snapshots
+    fn snapshot_live(&self, vm_id: &str) -> Result<bool, AppError> {
+        let vms = self.vms.lock().unwrap();
+        let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+
+        if !matches!(instance.config.disk_format, DiskFormat::Qcow2) {
+            return Err(ValidationError::InvalidDiskOption(format!(
+                ".{} disks have no internal snapshots", instance.config.disk_format.extension()
+            )).into());
+        }
+
+        match &instance.status.state {
+            VMState::Running | VMState::Paused => Ok(true),
+            VMState::Stopped | VMState::Error(_) | VMState::Suspended => Ok(false),
+            state => Err(AppError::Conflict(format!("VM is {:?}; try again once it settles", state))),
+        }
+    }
+
+    // The disk manager's auto snapshot before a destructive operation, taken
+    // through the monitor while QEMU holds the image
+    async fn snapshot_before(&self, vm_id: &str, op: &str) -> Result<(), AppError> {
+        let Some(keep) = self.disk_manager.auto_snapshot_keep() else {
+            return Ok(());
+        };
+        let live = match self.snapshot_live(vm_id) {
+            Ok(live) => live,
+            // Not qcow2, so there's nothing to snapshot into
+            Err(AppError::Validation(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if !live {
+            let (disks, id, op) = (self.disk_manager.clone(), vm_id.to_string(), op.to_string());
+            return Ok(blocking(move || disks.snapshot_before(&id, &op, None)).await?);
+        }
+
+        self.hmp(vm_id, &format!("savevm {}", auto_snapshot_name(op)), SAVEVM_TIMEOUT).await?;
+        let listing = self.hmp(vm_id, "info snapshots", Duration::from_secs(10)).await?;
+        for name in excess_auto_snapshots(SnapshotInfo::from_qemu_output(&listing), keep, None) {
+            if let Err(e) = self.hmp(vm_id, &format!("delvm {}", name), SAVEVM_TIMEOUT).await {
+                self.log(LogLevel::Warn, vm_id, &format!("Failed to prune snapshot {}: {}", name, e));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn qmp_passthrough(&self, vm_id: &str, execute: &str, arguments: Value) -> Result<Value, AppError> {
+        if DENIED_QMP_COMMANDS.contains(&execute) {
+            return Err(AppError::Forbidden(format!("QMP command '{}' is not allowed", execute)));
+        }
+
+        match self.get_vm_status(vm_id).await.ok_or_else(|| not_found(vm_id))?.state {
+            VMState::Running | VMState::Paused => {}
+            state => return Err(AppError::Conflict(format!("VM is not running ({:?})", state))),
+        }
+
+        self.log(LogLevel::Info, vm_id, &format!("QMP passthrough: {}", execute));
+
+        let processes = self.processes.lock().await;
+        let process = processes.get(vm_id)
+            .ok_or_else(|| AppError::Conflict("VM is not running".to_string()))?;
+        Ok(process.qmp_command(execute, arguments).await?)
+    }
+
+    // Background task honoring each VM's snapshot_schedule. Holds only a
+    // weak reference, so it ends when the manager is dropped.
+    pub fn spawn_snapshot_scheduler(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let manager = Arc::downgrade(self);
+        let mut stop = self.stop_tasks.subscribe();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SNAPSHOT_SCHEDULER_TICK);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = stop.changed() => break,
+                }
+                match manager.upgrade() {
+                    Some(manager) => manager.run_due_snapshots().await,
+                    None => break,
+                }
+            }
+        })
+    }
+
+    // Background task suspending VMs whose idle_suspend window has passed
+    // without activity. Like the snapshot scheduler it holds only a weak
+    // reference.
+    pub fn spawn_idle_monitor(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let manager = Arc::downgrade(self);
+        let mut stop = self.stop_tasks.subscribe();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(IDLE_MONITOR_TICK);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = stop.changed() => break,
+                }
+                match manager.upgrade() {
+                    Some(manager) => manager.run_idle_checks().await,
+                    None => break,
+                }
+            }
+        })
+    }
+
+    // Background task filling each running VM's metrics history and the
+    // live fields of its status (cpu, memory, traffic, disk usage) every
+    // metrics interval; holds only a weak reference
+    pub fn spawn_metrics_sampler(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let manager = Arc::downgrade(self);
+        let mut stop = self.stop_tasks.subscribe();
+        let interval = self.metrics_interval;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // A slow qemu-img shouldn't be followed by a burst of catch-up ticks
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = stop.changed() => break,
+                }
+                match manager.upgrade() {
+                    Some(manager) => manager.poll_status().await,
+                    None => break,
+                }
+            }
+        })
+    }
+
+    // Recent samples, kept in memory only. A VM's history survives stops
+    // but not a backend restart.
+    pub fn metrics_history(&self, vm_id: &str) -> Result<MetricsHistory, AppError> {
+        if !self.vms.lock().unwrap().contains_key(vm_id) {
+            return Err(not_found(vm_id));
+        }
+
+        let samples = self.metrics.lock().unwrap()
+            .get(vm_id)
+            .map(|buffer| buffer.samples.iter().cloned().collect())
+            .unwrap_or_default();
+        Ok(MetricsHistory { interval_seconds: self.metrics_interval.as_secs(), samples })
+    }
+
+    // Runs shutdown on SIGTERM/SIGINT and then exits the process
+    pub fn spawn_shutdown_handler(self: &Arc<Self>, policy: StopPolicy) -> tokio::task::JoinHandle<()> {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let (mut term, mut int) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+                (Ok(term), Ok(int)) => (term, int),
+                (Err(e), _) | (_, Err(e)) => {
+                    log::error!("Cannot install shutdown signal handlers: {}", e);
+                    return;
+                }
+            };
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = int.recv() => {}
+            }
+
+            manager.shutdown(policy).await;
+            std::process::exit(0);
+        })
+    }
+
+    // Teardown in a fixed order: VMs, then their taps, then ports, then the
+    // log, and the bridge last once nothing is attached to it. Every step
+    // runs even if an earlier one failed; failures are only logged.
+    pub async fn shutdown(&self, policy: StopPolicy) {
+        // 0. Background tasks, so none of them acts on a VM mid-teardown
+        let _ = self.stop_tasks.send(true);
+
+        let running: Vec<String> = self.processes.lock().await.keys().cloned().collect();
+        self.logger.info("vm_manager", &format!(
+            "Shutting down with {} running VM(s), policy {:?}", running.len(), policy
+        ));
+
+        // 1. Running VMs. Detached ones keep their runtime state for reattach.
+        let mut stopped = Vec::new();
+        for vm_id in &running {
+            match policy {
+                StopPolicy::Detach => self.log(LogLevel::Info, vm_id, "Left running across backend shutdown"),
+                StopPolicy::Stop => match self.stop_vm(vm_id).await {
+                    Ok(()) => stopped.push(vm_id.clone()),
+                    Err(e) => self.log(LogLevel::Error, vm_id, &format!("Shutdown: stop failed: {}", e)),
+                },
+            }
+        }
+
+        // 2. Taps of stopped VMs; anything attached to a tap (qdiscs
+        // included) goes with it. Detached guests still use theirs.
+        for vm_id in &stopped {
+            let hotplug_nics: Vec<HotplugNic> = self.vms.lock().unwrap().get(vm_id)
+                .map(|instance| instance.config.hotplug_nics.clone())
+                .unwrap_or_default();
+            for nic in hotplug_nics.iter().filter(|nic| nic.tap.is_some()) {
+                if let Err(e) = self.remove_nic_tap(nic) {
+                    self.log(LogLevel::Warn, vm_id, &format!("Shutdown: failed to remove tap of {}: {}", nic.netdev_id, e));
+                }
+            }
+        }
+
+        // 3. Ports, except those of guests left running
+        let released: Vec<VMConfig> = self.vms.lock().unwrap().values()
+            .filter(|instance| policy == StopPolicy::Stop || !running.contains(&instance.config.id))
+            .map(|instance| instance.config.clone())
+            .collect();
+        for config in released {
+            self.vnc_ports.release_port(config.vnc_port);
+            self.release_forwards(&config);
+        }
+
+        // 4. The event log, so nothing above is lost on exit
+        self.logger.info("vm_manager", "Shutdown: VMs, taps and ports released");
+        if let Err(e) = self.logger.flush() {
+            log::error!("Shutdown: failed to flush logs: {}", e);
+        }
+
+        // 5. The bridge, only if no interface is left on it
+        if let Some(network) = &self.network {
+            match network.delete_bridge_if_unused() {
+                Ok(true) => log::info!("Shutdown: bridge deleted"),
+                Ok(false) => log::info!("Shutdown: bridge kept, still in use"),
+                Err(e) => log::error!("Shutdown: failed to delete bridge: {}", e),
+            }
+        }
+    }
+
+    async fn run_due_snapshots(&self) {
+        let now = chrono::Utc::now();
+
+        // A VM's first scheduled snapshot comes one interval after boot
+        let due: Vec<(String, SnapshotPolicy, DiskFormat)> = {
+            let vms = self.vms.lock().unwrap();
+            let last = self.last_scheduled_snapshot.lock().unwrap();
+            vms.values()
+                .filter(|instance| instance.status.state == VMState::Running)
+                .filter_map(|instance| {
+                    let policy = instance.config.snapshot_schedule.clone()?;
+                    let since = last.get(&instance.config.id).copied()
+                        .into_iter()
+                        .chain(instance.status.started_at)
+                        .max()?;
+                    let interval = chrono::Duration::minutes(policy.interval_minutes as i64);
+                    (now - since >= interval)
+                        .then(|| (instance.config.id.clone(), policy, instance.config.disk_format.clone()))
+                })
+                .collect()
+        };
+
+        for (vm_id, policy, format) in due {
+            self.last_scheduled_snapshot.lock().unwrap().insert(vm_id.clone(), now);
+
+            if !matches!(format, DiskFormat::Qcow2) {
+                self.log(LogLevel::Warn, &vm_id, &format!(
+                    "Skipping scheduled snapshot: .{} disks have no internal snapshots", format.extension()
+                ));
+                continue;
+            }
+
+            if let Err(e) = self.scheduled_snapshot(&vm_id, &policy).await {
+                self.log(LogLevel::Warn, &vm_id, &format!("Scheduled snapshot failed: {}", e));
+            }
+        }
+    }
+
+    // savevm rather than qemu-img: the image is locked by the running QEMU,
+    // and savevm also captures RAM so the snapshot is consistent
+    async fn scheduled_snapshot(&self, vm_id: &str, policy: &SnapshotPolicy) -> Result<(), AppError> {
+        let name = format!("{}{}", SCHEDULED_SNAPSHOT_PREFIX, chrono::Utc::now().format("%Y%m%dT%H%M%S"));
+        self.hmp(vm_id, &format!("savevm {}", name), SAVEVM_TIMEOUT).await?;
+        self.log(LogLevel::Info, vm_id, &format!("Took scheduled snapshot {}", name));
+
+        let listing = self.hmp(vm_id, "info snapshots", Duration::from_secs(10)).await?;
+        let mut scheduled: Vec<String> = SnapshotInfo::from_qemu_output(&listing)
+            .into_iter()
+            .map(|snapshot| snapshot.name)
+            .filter(|name| name.starts_with(SCHEDULED_SNAPSHOT_PREFIX))
+            .collect();
+        // Timestamped names sort oldest first
+        scheduled.sort();
+
+        let excess = scheduled.len().saturating_sub(policy.keep);
+        for name in &scheduled[..excess] {
+            match self.hmp(vm_id, &format!("delvm {}", name), SAVEVM_TIMEOUT).await {
+                Ok(_) => self.log(LogLevel::Debug, vm_id, &format!("Pruned scheduled snapshot {}", name)),
+                Err(e) => self.log(LogLevel::Warn, vm_id, &format!("Failed to prune snapshot {}: {}", name, e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // A VM is active for a tick if it used at least its policy's CPU share,
+    // moved any bytes over its taps, or had a console attached. CPU is read
+    // from /proc for the recorded pid; with an isolated PID namespace that
+    // is the supervisor, which mostly sleeps.
+    async fn run_idle_checks(&self) {
+        let candidates: Vec<(String, u32, IdleSuspendPolicy, Vec<String>)> = {
+            let vms = self.vms.lock().unwrap();
+            vms.values()
+                .filter(|instance| instance.status.state == VMState::Running)
+                .filter_map(|instance| {
+                    let policy = instance.config.idle_suspend.clone()?;
+                    let pid = instance.status.pid?;
+                    let config = instance.running_config.as_ref().unwrap_or(&instance.config);
+                    Some((instance.config.id.clone(), pid, policy, self.vm_taps(config)))
+                })
+                .collect()
+        };
+
+        let now = Instant::now();
+        let ticks_per_second = clock_ticks_per_second() as f32;
+        let mut due = Vec::new();
+        {
+            let sessions = self.console_sessions.lock().unwrap();
+            let mut samples = self.idle_samples.lock().unwrap();
+            samples.retain(|vm_id, _| candidates.iter().any(|(id, ..)| id == vm_id));
+
+            for (vm_id, pid, policy, taps) in &candidates {
+                let cpu_ticks = match process_cpu_ticks(*pid) {
+                    Some(ticks) => ticks,
+                    None => continue,
+                };
+                let traffic: u64 = taps.iter()
+                    .filter_map(|tap| interface_traffic(tap))
+                    .map(|(rx, tx)| rx + tx)
+                    .sum();
+                let attached = sessions.get(vm_id).is_some_and(|count| *count > 0);
+
+                // The first sample of a boot only sets the baseline
+                let idle_since = match samples.get(vm_id).filter(|prev| prev.pid == *pid) {
+                    Some(prev) => {
+                        let elapsed = now.duration_since(prev.at).as_secs_f32().max(1.0);
+                        let cpu_percent = cpu_ticks.saturating_sub(prev.cpu_ticks) as f32
+                            / ticks_per_second / elapsed * 100.0;
+                        let active = attached || traffic != prev.traffic || cpu_percent >= policy.cpu_percent;
+                        if active { now } else { prev.idle_since }
+                    }
+                    None => now,
+                };
+
+                if now.duration_since(idle_since) >= Duration::from_secs(policy.idle_minutes as u64 * 60) {
+                    due.push((vm_id.clone(), policy.idle_minutes));
+                }
+                samples.insert(vm_id.clone(), IdleSample { pid: *pid, cpu_ticks, traffic, at: now, idle_since });
+            }
+        }
+
+        for (vm_id, idle_minutes) in due {
+            self.log(LogLevel::Info, &vm_id, &format!("Idle for {} minutes; suspending", idle_minutes));
+            if let Err(e) = self.suspend_vm(&vm_id).await {
+                // Wait out another full window before retrying
+                self.idle_samples.lock().unwrap().remove(&vm_id);
+                self.log(LogLevel::Warn, &vm_id, &format!("Idle suspend failed: {}", e));
+            }
+        }
+    }
+
+    // One round of the metrics sampler. Disk usage goes through the cached
+    // summary, so qemu-img still only runs once per DISK_SUMMARY_TTL.
+    async fn poll_status(&self) {
+        for (vm_id, sample) in self.sample_metrics() {
+            self.update_status(&vm_id, |status| {
+                // Stopped while the sample was being taken
+                if status.state != VMState::Running {
+                    return;
+                }
+                status.cpu_usage = sample.cpu_usage;
+                status.memory_mb = sample.memory_mb;
+                status.network_rx_bytes = sample.rx_bytes;
+                status.network_tx_bytes = sample.tx_bytes;
+            });
+            self.refresh_disk_summary(&vm_id, false).await;
+        }
+    }
+
+    fn sample_metrics(&self) -> Vec<(String, MetricsSample)> {
+        let running: Vec<(String, u32, Vec<String>)> = self.vms.lock().unwrap().values()
+            .filter(|instance| instance.status.state == VMState::Running)
+            .filter_map(|instance| {
+                let config = instance.running_config.as_ref().unwrap_or(&instance.config);
+                Some((instance.config.id.clone(), instance.status.pid?, self.vm_taps(config)))
+            })
+            .collect();
+
+        let now = Instant::now();
+        let ticks_per_second = clock_ticks_per_second() as f32;
+        let mut metrics = self.metrics.lock().unwrap();
+        let mut taken = Vec::new();
+
+        for (vm_id, pid, taps) in running {
+            let cpu_ticks = match process_cpu_ticks(pid) {
+                Some(ticks) => ticks,
+                None => continue,
+            };
+            let (rx_bytes, tx_bytes) = taps.iter()
+                .filter_map(|tap| interface_traffic(tap))
+                .fold((0, 0), |(rx, tx), (tap_rx, tap_tx)| (rx + tap_rx, tx + tap_tx));
+
+            let buffer = metrics.entry(vm_id.clone()).or_default();
+            // Nothing to compare against on the first sample of a boot
+            let cpu_usage = match buffer.last_cpu {
+                Some((last_pid, last_ticks, at)) if last_pid == pid => {
+                    let elapsed = now.duration_since(at).as_secs_f32().max(0.001);
+                    cpu_ticks.saturating_sub(last_ticks) as f32 / ticks_per_second / elapsed * 100.0
+                }
+                _ => 0.0,
+            };
+            buffer.last_cpu = Some((pid, cpu_ticks, now));
+
+            if buffer.samples.len() >= self.metrics_capacity {
+                buffer.samples.pop_front();
+            }
+            let sample = MetricsSample {
+                timestamp: chrono::Utc::now(),
+                cpu_usage,
+                memory_mb: process_rss_mb(pid).unwrap_or(0),
+                rx_bytes,
+                tx_bytes,
+            };
+            buffer.samples.push_back(sample.clone());
+            taken.push((vm_id, sample));
+        }
+
+        taken
+    }
+
+    // The sandbox QEMU is launched in. The seccomp filter needs no
+    // privileges, so even an unprivileged manager loads it.
+    fn sandbox(&self, config: &VMConfig, disk_path: &Path) -> Result<VMSandbox, AppError> {
+        if !self.sandbox_vms {
+            let sandbox = VMSandbox::new();
+            return Ok(if self.privileged { sandbox.with_host_network() } else { sandbox.without_namespaces() });
+        }
+        let mut builder = VMSandboxBuilder::new()
+            .with_seccomp_mode(self.seccomp_mode)
+            .with_seccomp_action(self.seccomp_action);
+        for syscall in &self.denied_syscalls {
+            builder = builder.add_denied_syscall(syscall);
+        }
+        for (syscall, action) in &self.syscall_actions {
+            builder = builder.with_syscall_action(syscall, *action);
+        }
+        if let Some(mapping) = &self.user_namespace {
+            builder = builder.with_user_namespace(mapping.clone());
+        }
+        if self.privileged {
+            builder = builder.with_host_network();
+            if let Some((uid, gid)) = self.qemu_user {
+                builder = builder.with_user(uid.as_raw(), gid.as_raw());
+            }
+            // Attaching to a tap, or a bridge through qemu-bridge-helper,
+            // needs CAP_NET_ADMIN on the host's interfaces
+            let bridged = config.networks.iter().map(|nic| &nic.network_type)
+                .chain(config.hotplug_nics.iter().map(|nic| &nic.network_type))
+                .any(|network| matches!(network, NetworkType::Bridge(_)));
+            if bridged || !self.vm_taps(config).is_empty() {
+                builder = builder.with_capabilities(&[Capability::CAP_NET_ADMIN]);
+            }
+            if let Some(chroot_dir) = &self.chroot_dir {
+                builder = self.chroot(builder, config, chroot_dir)?;
+            }
+        } else {
+            builder = builder.without_namespaces();
+        }
+
+        if let Some(disk_limit_mb) = self.cgroup_disk_limit {
+            builder = builder.with_limits(ResourceLimits {
+                memory_limit_mb: config.memory_mb as u64 + QEMU_MEMORY_OVERHEAD_MB,
+                cpu_limit_percent: config.cpu_cores * 100,
+                disk_limit_mb,
+            });
+            builder.apply_resource_limits(&config.id, disk_path)?;
+        }
+        Ok(builder.build()?)
+    }
+
+    // Roots QEMU at <chroot_dir>/<id>/root: device nodes for what it opens
+    // under /dev, and binds of the host paths its command line names so
+    // they resolve the same inside. QemuProcess::start adds the binary, its
+    // libraries and firmware.
+    fn chroot(&self, builder: VMSandboxBuilder, config: &VMConfig, chroot_dir: &Path) -> Result<VMSandboxBuilder, AppError> {
+        let root = chroot_dir.join(&config.id).join("root");
+        let outside_data_dir = |path: &Path| !path.starts_with(&self.data_dir);
+
+        let mut builder = builder
+            .with_chroot(&root.to_string_lossy())
+            .add_allowed_device("/dev/kvm")
+            .add_allowed_device("/dev/net/tun")
+            // Disks, keys and suspend state, then the QMP and serial sockets
+            .add_writable_path(&self.data_dir)
+            .add_writable_path("/tmp");
+        let iso_dir = Path::new(&config.iso_path).parent().filter(|_| !config.iso_path.is_empty());
+        if let Some(dir) = iso_dir.filter(|dir| outside_data_dir(dir)) {
+            builder = builder.add_read_only_path(dir);
+        }
+        let disk_dirs = config.disks.iter().map(|disk| disk.path.as_path())
+            .chain(config.disk_key.as_deref())
+            .filter_map(Path::parent)
+            .filter(|dir| outside_data_dir(dir));
+        for dir in disk_dirs {
+            builder = builder.add_writable_path(dir);
+        }
+
+        builder.setup_vm_environment(&config.id, chroot_dir)?;
+        Ok(builder)
+    }
+
+    // A QEMU running as qemu_user opens the VM's files after switching, so
+    // they're handed to that user first. run/ is where suspend writes state.
+    fn grant_qemu_access(&self, config: &VMConfig, disk_path: &Path) -> Result<(), AppError> {
+        let (uid, gid) = match (self.privileged, self.qemu_user) {
+            (true, Some(user)) => user,
+            _ => return Ok(()),
+        };
+
+        let paths = std::iter::once(disk_path.to_path_buf())
+            .chain(config.disks.iter().map(|disk| disk.path.clone()))
+            .chain(config.disk_key.clone())
+            .chain(std::iter::once(self.data_dir.join("run")));
+        for path in paths {
+            nix::unistd::chown(&path, Some(uid), Some(gid)).map_err(|e| {
+                AppError::Internal(format!("Failed to hand {} to the QEMU user: {}", path.display(), e))
+            })?;
+        }
+        Ok(())
+    }
+
+    // Every tap the VM's traffic can show up on
+    fn vm_taps(&self, config: &VMConfig) -> Vec<String> {
+        let mut taps: Vec<String> = config.hotplug_nics.iter()
+            .filter_map(|nic| nic.tap_name().map(String::from))
+            .collect();
+        for nic in &config.networks {
+            if let NetworkType::Tap(tap) = &nic.network_type {
+                taps.push(tap.clone());
+            }
+        }
+        taps
+    }
+
+    // Applies the network's rate limit to taps QEMU has opened. Taps
+    // qemu-bridge-helper creates for bridge NICs aren't known by name, so
+    // those stay unshaped.
+    fn shape_taps(&self, vm_id: &str, taps: &[String]) {
+        let Some(mbps) = self.network.as_ref().and_then(|network| network.rate_limit()) else { return };
+        for tap in taps {
+            if let Err(e) = NetworkManager::set_rate_limit(tap, mbps) {
+                self.log(LogLevel::Warn, vm_id, &format!("Failed to limit {} to {} Mbit/s: {}", tap, mbps, e));
+            }
+        }
+    }
+
+    // Pauses the guest and streams its state to `path` with an outgoing
+    // migration; QEMU is left paused for the caller to stop or resume
+    async fn save_state(&self, vm_id: &str, path: &Path) -> Result<(), AppError> {
+        let socket = qmp_socket_path(vm_id);
+        qmp_command_at(&socket, "stop", Value::Null, QMP_TIMEOUT).await?;
+        qmp_command_at(&socket, "migrate-set-parameters", json!({ "max-bandwidth": SUSPEND_BANDWIDTH }), QMP_TIMEOUT).await?;
+        qmp_command_at(&socket, "migrate", json!({ "uri": suspend_uri(path)? }), QMP_TIMEOUT).await?;
+
+        let deadline = Instant::now() + SAVEVM_TIMEOUT;
+        loop {
+            let info = qmp_command_at(&socket, "query-migrate", Value::Null, QMP_TIMEOUT).await?;
+            match info.get("status").and_then(Value::as_str) {
+                Some("completed") => return Ok(()),
+                Some("failed") | Some("cancelled") => {
+                    let reason = info.get("error-desc").and_then(Value::as_str).unwrap_or("migration failed");
+                    return Err(QemuError::Qmp(reason.to_string()).into());
+                }
+                _ => {}
+            }
+            if Instant::now() >= deadline {
+                let _ = qmp_command_at(&socket, "migrate_cancel", Value::Null, QMP_TIMEOUT).await;
+                return Err(QemuError::Timeout.into());
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    // QEMU started with -incoming reports "inmigrate" until the saved state
+    // has loaded and then runs the guest; a bad state file makes it exit
+    async fn wait_for_restore(&self, vm_id: &str) -> Result<(), AppError> {
+        let socket = qmp_socket_path(vm_id);
+        let deadline = Instant::now() + SAVEVM_TIMEOUT;
+        loop {
+            let status = qmp_command_at(&socket, "query-status", Value::Null, QMP_TIMEOUT).await?;
+            match status.get("status").and_then(Value::as_str) {
+                Some("running") => return Ok(()),
+                Some("inmigrate") | Some("prelaunch") => {}
+                other => {
+                    return Err(QemuError::Qmp(format!("guest is {} after restore", other.unwrap_or("unknown"))).into());
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(QemuError::Timeout.into());
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    // HMP commands like savevm report failure as output text, not as a QMP error
+    async fn hmp(&self, vm_id: &str, command_line: &str, timeout: Duration) -> Result<String, AppError> {
+        if !self.processes.lock().await.contains_key(vm_id) {
+            return Err(AppError::Conflict("VM is not running".to_string()));
+        }
+
+        let reply = qmp_command_at(
+            &qmp_socket_path(vm_id),
+            "human-monitor-command",
+            json!({ "command-line": command_line }),
+            timeout,
+        ).await?;
+        let output = reply.as_str().unwrap_or_default().trim().to_string();
+
+        if !command_line.starts_with("info ") && !output.is_empty() {
+            return Err(QemuError::Qmp(output).into());
+        }
+        Ok(output)
+    }
+
+    // Bundles the config and disk into exports/<name>-<id>.tar. The VM must
+    // be stopped so the disk is consistent.
+    pub async fn export_vm(&self, vm_id: &str, compress: bool) -> Result<PathBuf, AppError> {
+        let (config, disk_path) = {
+            let vms = self.vms.lock().unwrap();
+            let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+            match &instance.status.state {
+                VMState::Stopped | VMState::Error(_) => {}
+                state => return Err(AppError::Conflict(format!("VM must be stopped to export ({:?})", state))),
+            }
+            // A bundle would need the key alongside the disk
+            if instance.config.disk_options.encrypted {
+                return Err(ValidationError::InvalidDiskOption("encrypted VMs can't be exported".to_string()).into());
+            }
+            (instance.config.clone(), instance.disk_path.clone())
+        };
+
+        let exports_dir = self.data_dir.join("exports");
+        let staging = exports_dir.join(format!(".export-{}", vm_id));
+        let bundle = self.export_bundle_path(&config);
+        let _ = fs::remove_dir_all(&staging);
+        fs::create_dir_all(&staging).map_err(internal)?;
+
+        let (export_staging, export_bundle) = (staging.clone(), bundle.clone());
+        let result = blocking(move || {
+            let (staging, bundle) = (export_staging, export_bundle);
+            let mut exported = config.clone();
+            if compress {
+                // Compressed qcow2 regardless of the source format
+                run_tool(Command::new("qemu-img")
+                    .args(["convert", "-c", "-O", "qcow2"])
+                    .arg(&disk_path)
+                    .arg(staging.join("disk.qcow2")))?;
+                exported.disk_format = DiskFormat::Qcow2;
+            } else {
+                fs::copy(&disk_path, staging.join(format!("disk.{}", config.disk_format.extension())))
+                    .map_err(internal)?;
+            }
+
+            exported.save_to_file(&staging.join("config.json")).map_err(internal)?;
+
+            run_tool(Command::new("tar")
+                .arg("-cf").arg(&bundle)
+                .arg("-C").arg(&staging)
+                .arg("."))
+        }).await;
+
+        let _ = fs::remove_dir_all(&staging);
+        result?;
+
+        self.log(LogLevel::Info, vm_id, &format!("Exported to {}", bundle.display()));
+        Ok(bundle)
+    }
+
+    // The bundle a previous export_vm left behind, for resuming its download
+    pub fn existing_export(&self, vm_id: &str) -> Result<Option<PathBuf>, AppError> {
+        let config = self.vms.lock().unwrap().get(vm_id)
+            .map(|instance| instance.config.clone())
+            .ok_or_else(|| not_found(vm_id))?;
+        let bundle = self.export_bundle_path(&config);
+        Ok(bundle.is_file().then_some(bundle))
+    }
+
+    fn export_bundle_path(&self, config: &VMConfig) -> PathBuf {
+        self.data_dir.join("exports").join(format!("{}-{}.tar", config.name, config.id))
+    }
+
+    // Entries that can't be read are left out and reported as warnings
+    pub async fn list_isos(&self) -> Result<IsoListing, AppError> {
+        let isos = IsoManager::new(&self.data_dir.join("isos"));
+        Ok(blocking(move || isos.list_isos()).await?)
+    }
+
+    pub async fn list_disks(&self) -> Result<DiskListing, AppError> {
+        let disks = self.disk_manager.clone();
+        Ok(blocking(move || disks.list_disks()).await?)
+    }
+
+    pub fn iso_path(&self, name: &str) -> Result<PathBuf, AppError> {
+        Ok(IsoManager::new(&self.data_dir.join("isos")).get_iso_path(name)?)
+    }
+
+    // Refused while a VM's config names the ISO, running or not, since its
+    // next start would fail on the missing file
+    pub async fn delete_iso(&self, name: &str) -> Result<(), AppError> {
+        let isos = IsoManager::new(&self.data_dir.join("isos"));
+        let path = isos.get_iso_path(name)?;
+        let canonical = path.canonicalize().map_err(internal)?;
+
+        let mut users: Vec<String> = self.vms.lock().unwrap().values()
+            .filter(|instance| {
+                std::iter::once(&instance.config).chain(instance.running_config.as_ref())
+                    .any(|config| !config.iso_path.is_empty()
+                        && Path::new(&config.iso_path).canonicalize().is_ok_and(|iso| iso == canonical))
+            })
+            .map(|instance| instance.config.id.clone())
+            .collect();
+        if !users.is_empty() {
+            users.sort();
+            return Err(AppError::Conflict(format!("ISO {} is in use by {}", name, users.join(", "))));
+        }
+
+        let name = name.to_string();
+        blocking(move || isos.delete_iso(&name)).await?;
+        log::info!("Deleted ISO {}", path.display());
+        Ok(())
+    }
+
+    // Chunked uploads, for ISOs too large to send as one request body
+    pub fn begin_upload(&self, name: &str, size: Option<u64>) -> Result<UploadSession, AppError> {
+        Ok(self.uploads.begin(name, size)?)
+    }
+
+    pub fn upload_status(&self, upload_id: &str) -> Result<UploadSession, AppError> {
+        Ok(self.uploads.status(upload_id)?)
+    }
+
+    pub async fn append_upload<S, B, E>(&self, upload_id: &str, offset: u64, body: S) -> Result<UploadSession, AppError>
+    where
+        S: futures::Stream<Item = Result<B, E>> + Unpin,
+        B: bytes::Buf,
+        E: std::fmt::Display,
+    {
+        Ok(self.uploads.append(upload_id, offset, body).await?)
+    }
+
+    pub async fn complete_upload(&self, upload_id: &str, expected_hash: Option<&str>) -> Result<IsoInfo, AppError> {
+        let (uploads, id, hash) = (self.uploads.clone(), upload_id.to_string(), expected_hash.map(str::to_string));
+        Ok(blocking(move || uploads.complete(&id, hash.as_deref())).await?)
+    }
+
+    pub fn abort_upload(&self, upload_id: &str) -> Result<(), AppError> {
+        Ok(self.uploads.abort(upload_id)?)
+    }
+
+    // A whole ISO in one request, taken as the single chunk of an upload
+    // session so it streams to disk like the chunked ones
+    pub async fn upload_iso<S, B, E>(&self, name: &str, size: Option<u64>, body: S) -> Result<IsoInfo, AppError>
+    where
+        S: futures::Stream<Item = Result<B, E>> + Unpin,
+        B: bytes::Buf,
+        E: std::fmt::Display,
+    {
+        let session = self.begin_upload(name, size)?;
+        if let Err(e) = self.append_upload(&session.id, 0, body).await {
+            let _ = self.abort_upload(&session.id);
+            return Err(e);
+        }
+        // A body short of its Content-Length leaves the session open
+        let completed = self.complete_upload(&session.id, None).await;
+        if completed.is_err() {
+            let _ = self.abort_upload(&session.id);
+        }
+        completed
+    }
+
+    pub async fn fetch_iso(&self, url: &str, name: Option<&str>, expected_hash: Option<&str>) -> Result<IsoInfo, AppError> {
+        const PROGRESS_STEP: u64 = 512 * 1024 * 1024;
+
+        log::info!("Downloading ISO from {}", url);
+        let isos = IsoManager::new(&self.data_dir.join("isos"));
+        let (url, name, expected_hash) = (url.to_string(), name.map(str::to_string), expected_hash.map(str::to_string));
+        let info = blocking(move || {
+            let mut reported = 0;
+            isos.download_iso(&url, name.as_deref(), expected_hash.as_deref(), |bytes| {
+                if bytes >= reported + PROGRESS_STEP {
+                    reported = bytes;
+                    log::debug!("{}: {} MB downloaded", url, bytes / (1024 * 1024));
+                }
+            })
+        }).await?;
+
+        log::info!("Downloaded ISO {} ({})", info.name, info.hash);
+        Ok(info)
+    }
+
+    // Registers a VM from an export bundle under a fresh id and VNC port.
+    // Bundles are only read from the exports directory.
+    pub async fn import_vm(&self, bundle_path: &Path) -> Result<VMConfig, AppError> {
+        let exports_dir = self.data_dir.join("exports").canonicalize().map_err(internal)?;
+        let bundle = bundle_path.canonicalize()
+            .map_err(|_| AppError::NotFound(format!("Bundle {} not found", bundle_path.display())))?;
+        if !bundle.starts_with(&exports_dir) {
+            return Err(AppError::BadRequest(format!("Bundles must be placed in {}", exports_dir.display())));
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let staging = exports_dir.join(format!(".import-{}", id));
+        fs::create_dir_all(&staging).map_err(internal)?;
+
+        let result = self.import_from_staging(&id, &bundle, &staging).await;
+        let _ = fs::remove_dir_all(&staging);
+        let config = result?;
+
+        self.log(LogLevel::Info, &config.id, &format!("Imported '{}' from {}", config.name, bundle.display()));
+        Ok(config)
+    }
+
+    async fn import_from_staging(&self, id: &str, bundle: &Path, staging: &Path) -> Result<VMConfig, AppError> {
+        let (bundle, staging) = (bundle.to_path_buf(), staging.to_path_buf());
+        let extract_to = staging.clone();
+        let mut config = blocking(move || -> Result<VMConfig, AppError> {
+            run_tool(Command::new("tar")
+                .arg("-xf").arg(&bundle)
+                .arg("-C").arg(&extract_to))?;
+
+            VMConfig::load_from_file(&extract_to.join("config.json"))
+                .map_err(|e| AppError::BadRequest(format!("Invalid bundle config: {}", e)))
+        }).await?;
+
+        if config.disk_options.encrypted {
+            return Err(AppError::BadRequest("Bundle holds an encrypted disk without its key".to_string()));
+        }
+
+        let staged_disk = staging.join(format!("disk.{}", config.disk_format.extension()));
+        match fs::symlink_metadata(&staged_disk) {
+            Ok(meta) if meta.is_file() => {}
+            _ => return Err(AppError::BadRequest("Bundle does not contain a disk image".to_string())),
+        }
+
+        // The disk may only lean on one of our base images, which makes the
+        // import a linked clone of it; any other backing file is refused
+        config.base_image = match image_backing_file(&staged_disk, &storage_format(&config.disk_format))? {
+            Some(backing) => match backing.to_str() {
+                Some(name) if backing.is_absolute() => {
+                    validate_base_image(name)?;
+                    Some(name.to_string())
+                }
+                _ => return Err(ValidationError::InvalidBaseImage(
+                    format!("{} is not an absolute path", backing.display())
+                ).into()),
+            },
+            None => None,
+        };
+
+        config.id = id.to_string();
+        // The exported VM may still exist here, so the copy gets its own MACs
+        for (index, nic) in config.networks.iter_mut().enumerate() {
+            nic.mac_address = Some(generated_mac(id, index));
+        }
+        // Taps are host-specific; hot-plugged NICs that relied on one are dropped
+        config.hotplug_nics.retain(|nic| nic.tap.is_none());
+        // Bundles carry the primary disk only
+        config.disks.clear();
+        // Whether a VM is protected or a template is up to this host
+        config.protected = false;
+        config.is_template = false;
+        // The disk holds the OS, so an ISO this host doesn't have is just dropped
+        if !config.iso_path.is_empty() && !Path::new(&config.iso_path).exists() {
+            config.iso_path.clear();
+        }
+        config.updated_at = chrono::Utc::now();
+
+        // Everything else is held to what create_vm would accept
+        validate_bundle_config(&config.to_create_request())?;
+        for nic in &config.networks {
+            if let NetworkType::Tap(name) | NetworkType::Bridge(name) = &nic.network_type {
+                NetworkManager::validate_interface_name(name)?;
+            }
+        }
+
+        let vnc_port = if self.deterministic_vnc_ports {
+            self.vnc_ports.allocate_stable_port(id)?
+        } else {
+            self.vnc_ports.allocate_port()?
+        };
+        if let Err(e) = self.reserve_forwards(&mut config.networks) {
+            self.vnc_ports.release_port(vnc_port);
+            return Err(e);
+        }
+        config.vnc_port = vnc_port;
+
+        let disk_path = self.data_dir.join("disks").join(format!("{}.{}", id, config.disk_format.extension()));
+        let (target, config_path, saved_config) = (disk_path.clone(), self.config_path(id), config.clone());
+        let saved = blocking(move || fs::rename(&staged_disk, &target)
+            .or_else(|_| fs::copy(&staged_disk, &target).map(|_| ()))
+            .and_then(|_| saved_config.save_to_file(&config_path))).await;
+        if let Err(e) = saved {
+            let _ = fs::remove_file(&disk_path);
+            self.vnc_ports.release_port(vnc_port);
+            self.release_forwards(&config);
+            return Err(internal(e));
+        }
+
+        let instance = VMInstance::stopped(config.clone(), disk_path);
+        self.vms.lock().unwrap().insert(config.id.clone(), instance);
+
+        Ok(config)
+    }
+
+    // None while the VM has no log file of its own
+    pub fn log_level(&self, vm_id: &str) -> Result<Option<LogLevel>, AppError> {
+        if !self.vms.lock().unwrap().contains_key(vm_id) {
+            return Err(not_found(vm_id));
+        }
+        Ok(self.logger.vm_log_level(vm_id))
+    }
+
+    // None turns the per-VM log file off again
+    pub async fn set_log_level(&self, vm_id: &str, level: Option<LogLevel>) -> Result<(), AppError> {
+        if !self.vms.lock().unwrap().contains_key(vm_id) {
+            return Err(not_found(vm_id));
+        }
+
+        match level {
+            Some(level) => self.logger.set_vm_log_level(vm_id, level).map_err(|e| {
+                AppError::Internal(format!("Failed to open log for VM {}: {}", vm_id, e))
+            })?,
+            None => self.logger.clear_vm_log_level(vm_id),
+        }
+
+        Ok(())
+    }
+
+    pub async fn list_vms(&self) -> Vec<VMStatus> {
+        let vms = self.vms.lock().unwrap();
+        vms.values().map(|i| i.current_status()).collect()
+    }
+
+    pub async fn get_vm(&self, vm_id: &str) -> Option<VMDetails> {
+        self.refresh_disk_summary(vm_id, false).await;
+
+        let (config, status) = {
+            let vms = self.vms.lock().unwrap();
+            vms.get(vm_id).map(|i| (i.config.clone(), i.current_status()))?
+        };
+        let id = vm_id.to_string();
+        let qemu_log_tail = blocking(move || qemu_log_tail(&id, QEMU_LOG_TAIL_LINES)).await;
+        Some(VMDetails { config, status, qemu_log_tail })
+    }
+
+    pub async fn get_vm_status(&self, vm_id: &str) -> Option<VMStatus> {
+        let vms = self.vms.lock().unwrap();
+        vms.get(vm_id).map(|i| i.current_status())
+    }
+
+    // Refreshes the cached disk summary if it's older than DISK_SUMMARY_TTL
+    // (or unconditionally with `force`). Failures keep the old value.
+    pub async fn refresh_disk_summary(&self, vm_id: &str, force: bool) {
+        let stale = match self.vms.lock().unwrap().get(vm_id) {
+            Some(instance) => force || instance.status.disk.as_ref().is_none_or(|disk| {
+                (chrono::Utc::now() - disk.refreshed_at).to_std().unwrap_or_default() >= DISK_SUMMARY_TTL
+            }),
+            None => return,
+        };
+        if !stale {
+            return;
+        }
+
+        let (disks, id) = (self.disk_manager.clone(), vm_id.to_string());
+        match blocking(move || disks.get_disk_info(&id)).await {
+            Ok(info) => self.update_status(vm_id, |status| {
+                status.disk_usage_gb = info.actual_size_gb;
+                status.disk = Some(DiskSummary::from(&info));
+            }),
+            Err(e) => self.log(LogLevel::Debug, vm_id, &format!("Disk info unavailable: {}", e)),
+        }
+    }
+
+    // Whether this host can actually create and run VMs right now
+    pub async fn readiness(&self) -> Vec<ReadinessCheck> {
+        let free_ports = self.vnc_ports.unallocated_count();
+        let data_dir = self.data_dir.clone();
+        blocking(move || {
+            vec![
+                ReadinessCheck::from_result("data_dir", probe_data_dir(&data_dir)),
+                ReadinessCheck::from_result("qemu", tool_version(&GuestArch::default().binary())),
+                ReadinessCheck::from_result("qemu_img", tool_version("qemu-img")),
+                // Not fatal: VMs fall back to TCG, unless they insist on KVM
+                ReadinessCheck::from_result(
+                    "kvm",
+                    Ok(match kvm_unavailable_reason() {
+                        None => "/dev/kvm accessible".to_string(),
+                        Some(reason) => format!("unavailable, VMs fall back to TCG: {}", reason),
+                    }),
+                ),
+                ReadinessCheck::from_result(
+                    "vnc_ports",
+                    if free_ports > 0 {
+                        Ok(format!("{} free", free_ports))
+                    } else {
+                        Err("VNC port range exhausted".to_string())
+                    },
+                ),
+            ]
+        }).await
+    }
+
+    // The managed network, its taps and the host's bridges
+    pub async fn network_overview(&self) -> Result<NetworkOverview, AppError> {
+        let network = self.network.clone();
+        blocking(move || -> Result<NetworkOverview, AppError> {
+            Ok(NetworkOverview {
+                managed: network.map(|network| network.status()).transpose()?,
+                bridges: NetworkManager::list_bridges()?,
+            })
+        }).await
+    }
+    
+    // What the host has and what VMs claim, for sizing new VMs
+    pub async fn capacity(&self) -> Result<HostCapacity, AppError> {
+        let (allocated, running) = self.allocated_resources();
+        let vnc_ports_free = self.vnc_ports.unallocated_count();
+        let disks = self.disk_manager.clone();
+        
+        blocking(move || -> Result<HostCapacity, AppError> {
+            let (cpu_cores, memory_total_mb, memory_available_mb) = host_resources();
+            let (disk_total, disk_available) = disks.filesystem_space()?;
+            // Nothing hands these out yet, so count what's actually bindable
+            let websocket_ports_free = PortManager::new(port_ranges::WEBSOCKET.0, port_ranges::WEBSOCKET.1)?
+                .scan_available_ports()?
+                .len();
+            
+            Ok(HostCapacity {
+                cpu_cores,
+                cpu_cores_available: cpu_cores.saturating_sub(running.cpu_cores),
+                memory_total_mb,
+                memory_available_mb,
+                allocated,
+                running,
+                vnc_ports_free,
+                websocket_ports_free,
+                disk_total_gb: disk_total as f64 / (1024.0 * 1024.0 * 1024.0),
+                disk_available_gb: disk_available as f64 / (1024.0 * 1024.0 * 1024.0),
+            })
+        }).await
+    }
+    
+    // (all defined VMs, running VMs)
+    fn allocated_resources(&self) -> (ResourceTotals, ResourceTotals) {
+        let vms = self.vms.lock().unwrap();
+        let mut allocated = ResourceTotals::default();
+        let mut running = ResourceTotals::default();
+        
+        for instance in vms.values() {
+            allocated.add(&instance.config);
+            if matches!(instance.status.state, VMState::Running | VMState::Paused) {
+                running.add(&instance.config);
+            }
+        }
+        
+        (allocated, running)
+    }
+
+    fn serial_console(&self, config: &VMConfig) -> SerialConsole {
+        match self.serial_tcp {
+            // VNC ports are unique per VM, so the same offset keeps these unique too
+            Some(ip) => {
+                let port = port_ranges::SERIAL.0 + config.vnc_port.saturating_sub(port_ranges::VNC.0);
+                SerialConsole::Tcp(SocketAddr::new(ip, port))
+            }
+            None => SerialConsole::Unix,
+        }
+    }
+
+    pub fn console_socket(&self, vm_id: &str) -> Result<ConsoleSocket, AppError> {
+        let vms = self.vms.lock().unwrap();
+        let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+        let running = matches!(instance.status.state, VMState::Running | VMState::Paused);
+
+        // Reported for the config the VM is running with, not any pending edit
+        let config = instance.running_config.as_ref().unwrap_or(&instance.config);
+        let (path, tcp) = match self.serial_console(config) {
+            SerialConsole::Unix => (Some(serial_socket_path(vm_id)), None),
+            SerialConsole::Tcp(addr) => (None, Some(addr)),
+        };
+
+        Ok(ConsoleSocket { path, tcp, running })
+    }
+
+    // Local VNC port of a running VM, for the console WebSocket proxy
+    pub fn vnc_port(&self, vm_id: &str) -> Result<u16, AppError> {
+        let vms = self.vms.lock().unwrap();
+        let instance = vms.get(vm_id).ok_or_else(|| not_found(vm_id))?;
+        match instance.status.state {
+            VMState::Running | VMState::Paused => Ok(instance.status.vnc_port),
+            _ => Err(AppError::Conflict(format!("VM {} is not running", vm_id))),
+        }
+    }
+
+    // Claims the host ports forwarded by a VM's user-mode NICs for as long
+    // as the VM exists, filling in any left at 0 from the SSH range
+    fn reserve_forwards(&self, networks: &mut [NetworkInterface]) -> Result<(), AppError> {
+        let forwards = networks.iter_mut().flat_map(|nic| match &mut nic.network_type {
+            NetworkType::User(forwards) => forwards.as_mut_slice(),
+            _ => &mut [],
+        });
+
+        let mut reserved: Vec<u16> = Vec::new();
+        for forward in forwards {
+            // tcp and udp forwards may share a host port
+            if reserved.contains(&forward.host_port) {
+                continue;
+            }
+            let result = if forward.host_port == 0 {
+                self.forward_ports.allocate_port_in(port_ranges::SSH.0, port_ranges::SSH.1)
+                    .map(|port| forward.host_port = port)
+            } else {
+                self.forward_ports.allocate_specific_port(forward.host_port)
+            };
+            if let Err(e) = result {
+                for port in reserved {
+                    self.forward_ports.release_port(port);
+                }
+                return Err(e.into());
+            }
+            reserved.push(forward.host_port);
+        }
+        Ok(())
+    }
+
+    fn release_forwards(&self, config: &VMConfig) {
+        for forward in config.port_forwards() {
+            self.forward_ports.release_port(forward.host_port);
+        }
+    }
+
+    // The one path for changing a VM's config: the new config is written to
+    // disk first, then swapped into memory and mirrored into the status, all
+    // under the map lock so the three can't drift apart
+    fn update_config<F: FnOnce(&mut VMConfig)>(&self, vm_id: &str, f: F) -> Result<VMConfig, AppError> {
+        let mut vms = self.vms.lock().unwrap();
+        let instance = vms.get_mut(vm_id).ok_or_else(|| not_found(vm_id))?;
+
+        let mut config = instance.config.clone();
+        f(&mut config);
+        config.updated_at = chrono::Utc::now();
+
+        let path = self.config_path(vm_id);
+        config.save_to_file(&path)
+            .map_err(|e| AppError::Internal(format!("Failed to save config: {}", e)))?;
+
+        instance.config = config;
+        instance.sync_status();
+
+        debug_assert!(
+            VMConfig::load_from_file(&path).ok().and_then(|c| c.to_json().ok()) == instance.config.to_json().ok(),
+            "on-disk config for {} diverged from memory", vm_id
+        );
+
+        Ok(instance.config.clone())
+    }
+
+    fn set_running_config(&self, vm_id: &str, running: Option<VMConfig>) {
+        let mut vms = self.vms.lock().unwrap();
+        if let Some(instance) = vms.get_mut(vm_id) {
+            instance.running_config = running;
+            instance.sync_status();
+        }
+    }
+
+    fn update_status<F: FnOnce(&mut VMStatus)>(&self, vm_id: &str, f: F) {
+        let mut vms = self.vms.lock().unwrap();
+        if let Some(instance) = vms.get_mut(vm_id) {
+            let before = instance.status.clone();
+            f(&mut instance.status);
+            if let VMState::Error(message) = &instance.status.state {
+                instance.status.last_error = Some(message.clone());
+            }
+            instance.status.last_updated = chrono::Utc::now();
+            if significant_change(&before, &instance.status) {
+                self.publish_status(instance.current_status());
+            }
+        }
+    }
+
+    // Fails only when nobody is subscribed, which is fine
+    fn publish_status(&self, status: VMStatus) {
+        let _ = self.status_events.send(StatusEvent::Changed(Box::new(status)));
+    }
+
+    fn log(&self, level: LogLevel, vm_id: &str, message: &str) {
+        self.logger.log_vm(level, "vm_manager", vm_id, message);
+    }
+
+    fn save_runtime_state(&self, vm_id: &str, state: &RuntimeState) {
+        let result = serde_json::to_vec(state)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(self.runtime_state_path(vm_id), json).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            self.log(LogLevel::Warn, vm_id, &format!("Failed to record runtime state: {}", e));
+        }
+    }
+
+    // Boot time for a VM found running, e.g. after a backend restart: the
+    // recorded value if it matches the live pid, else the process start time
+    fn recorded_started_at(&self, vm_id: &str, pid: u32) -> Option<chrono::DateTime<chrono::Utc>> {
+        fs::read(self.runtime_state_path(vm_id)).ok()
+            .and_then(|json| serde_json::from_slice::<RuntimeState>(&json).ok())
+            .filter(|state| state.pid == pid)
+            .map(|state| state.started_at)
+            .or_else(|| process_start_time(pid))
+    }
+
+    // The QEMU an earlier backend left running for this VM, if it's still up
+    fn reattach(&self, config: &VMConfig) -> Option<QemuProcess> {
+        let json = fs::read(self.runtime_state_path(&config.id)).ok()?;
+        let state: RuntimeState = serde_json::from_slice(&json).ok()?;
+        let started_at = self.recorded_started_at(&config.id, state.pid)?;
+        QemuProcess::adopt(config, state.pid, started_at)
+    }
+
+    fn runtime_state_path(&self, vm_id: &str) -> PathBuf {
+        self.data_dir.join("run").join(format!("{}.json", vm_id))
+    }
+
+    // Written by suspend_vm, consumed by the next start
+    fn suspend_state_path(&self, vm_id: &str) -> PathBuf {
+        self.data_dir.join("run").join(format!("{}.state", vm_id))
+    }
+
+    fn config_path(&self, vm_id: &str) -> PathBuf {
+        self.data_dir.join("configs").join(format!("{}.json", vm_id))
+    }
+}
+
+// Worth pushing to subscribers: a new state, or cpu/memory moving by more
+// than the thresholds. Smaller wobbles show up with the next big one.
+fn significant_change(before: &VMStatus, after: &VMStatus) -> bool {
+    if before.state != after.state {
+        return true;
+    }
+    let memory_delta = after.memory_mb.abs_diff(before.memory_mb) as f64;
+    (after.cpu_usage - before.cpu_usage).abs() > STATUS_CPU_DELTA
+        || memory_delta > before.memory_mb as f64 * STATUS_MEMORY_DELTA
+}
+
+fn not_found(vm_id: &str) -> AppError {
+    AppError::NotFound(format!("VM {} not found", vm_id))
+}
+
+// DiskManager and friends shell out synchronously, so that runs on the
+// blocking pool. Callers hand over clones (DiskManager is a few paths and
+// flags); a panic in `f` is re-raised here.
+async fn blocking<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+fn probe_data_dir(data_dir: &Path) -> Result<String, String> {
+    let probe = data_dir.join(".ready-probe");
+    fs::write(&probe, b"ok")
+        .and_then(|()| fs::remove_file(&probe))
+        .map(|()| format!("{} writable", data_dir.display()))
+        .map_err(|e| format!("{} not writable: {}", data_dir.display(), e))
+}
+
+// (logical cpus, total memory MB, available memory MB)
+fn host_resources() -> (u32, u64, u64) {
+    use sysinfo::{CpuRefreshKind, MemoryRefreshKind, RefreshKind, System};
+    
+    let system = System::new_with_specifics(
+        RefreshKind::new()
+            .with_cpu(CpuRefreshKind::new())
+            .with_memory(MemoryRefreshKind::new().with_ram()),
+    );
+    
+    (
+        system.cpus().len() as u32,
+        system.total_memory() / (1024 * 1024),
+        system.available_memory() / (1024 * 1024),
+    )
+}
+
+// First line of `<tool> --version`
+fn tool_version(tool: &str) -> Result<String, String> {
+    match Command::new(tool).arg("--version").output_within(CommandCategory::Service) {
+        Ok(output) if output.status.success() => Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .unwrap_or(tool)
+            .to_string()),
+        Ok(output) => Err(format!("{} --version failed: {}", tool, String::from_utf8_lossy(&output.stderr).trim())),
+        Err(e) => Err(format!("{} unavailable: {}", tool, e)),
+    }
+}
+
+fn internal(e: std::io::Error) -> AppError {
+    AppError::Internal(e.to_string())
+}
+
+fn run_tool(cmd: &mut Command) -> Result<(), AppError> {
+    let output = cmd.output_within(CommandCategory::DiskCopy)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(AppError::Internal(
+            String::from_utf8_lossy(&output.stderr).to_string()
+        ));
+    }
+
+    Ok(())
+}
+
+// 256 bits, hex so the key file stays printable
+fn random_disk_key() -> Vec<u8> {
+    rand::random::<[u8; 32]>().iter().map(|b| format!("{:02x}", b)).collect::<String>().into_bytes()
+}
+
+fn storage_format(format: &DiskFormat) -> StorageFormat {
+    match format {
+        DiskFormat::Qcow2 => StorageFormat::Qcow2,
+        DiskFormat::Raw => StorageFormat::Raw,
+        DiskFormat::Vdi => StorageFormat::Vdi,
+        DiskFormat::Vmdk => StorageFormat::Vmdk,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A manager over a fresh directory under the system temp dir
+    fn test_manager(name: &str) -> VMManager {
+        let data_dir = std::env::temp_dir().join(format!("aegis-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        let logger = Logger::new(&data_dir.join("logs").to_string_lossy(), LogLevel::Debug).unwrap();
+        VMManager::new(&data_dir, Arc::new(logger)).unwrap()
+    }
+
+    fn test_request(name: &str) -> CreateVMRequest {
+        serde_json::from_value(json!({
+            "name": name,
+            "iso_path": "",
+            "memory_mb": 512,
+            "cpu_cores": 1,
+            "disk_size_gb": 1,
+        }))
+        .unwrap()
+    }
+
+    // Registers a VM as create_vm would, minus the disk, in `state`
+    fn insert_vm(manager: &VMManager, name: &str, state: VMState) -> String {
+        let vnc_port = manager.vnc_ports.allocate_port().unwrap();
+        let config = VMConfig::new(test_request(name), vnc_port);
+        let id = config.id.clone();
+        let disk_path = manager.data_dir.join("disks").join(format!("{}.qcow2", id));
+        let mut instance = VMInstance::stopped(config, disk_path);
+        instance.status.state = state;
+        manager.vms.lock().unwrap().insert(id.clone(), instance);
+        id
+    }
+
+    // Stands in `sleep` for QEMU, or fails every start
+    #[derive(Default)]
+    struct FakeQemu {
+        fail: bool,
+        spawned: std::sync::atomic::AtomicUsize,
+    }
+
+    impl QemuSpawner for FakeQemu {
+        fn spawn<'a>(
+            &'a self,
+            config: &'a VMConfig,
+            _disk_path: &'a Path,
+            _sandbox: VMSandbox,
+            _serial: SerialConsole,
+            _incoming: Option<&'a Path>,
+            _startup_timeout: Duration,
+        ) -> futures::future::BoxFuture<'a, Result<QemuProcess, QemuError>> {
+            Box::pin(async move {
+                self.spawned.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                if self.fail {
+                    return Err(QemuError::StartFailed("fake QEMU refused to start".to_string()));
+                }
+                // Like QEMU, take a while to come up
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let child = tokio::process::Command::new("sleep").arg("60").spawn()?;
+                Ok(QemuProcess::from_child(config, child))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn start_records_the_process_and_runs() {
+        let manager = test_manager("start").with_spawner(Arc::new(FakeQemu::default()));
+        let id = insert_vm(&manager, "start", VMState::Stopped);
+
+        manager.start_vm(&id).await.unwrap();
+        let status = manager.get_vm_status(&id).await.unwrap();
+        assert_eq!(status.state, VMState::Running);
+        let pid = manager.processes.lock().await[&id].pid();
+        assert_eq!(status.pid, Some(pid));
+
+        manager.stop_vm(&id).await.unwrap();
+        assert_eq!(manager.get_vm_status(&id).await.unwrap().state, VMState::Stopped);
+        assert!(manager.processes.lock().await.get(&id).is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_start_leaves_the_vm_in_error() {
+        let manager = test_manager("start-fails").with_spawner(Arc::new(FakeQemu { fail: true, ..Default::default() }));
+        let id = insert_vm(&manager, "start-fails", VMState::Stopped);
+
+        assert!(manager.start_vm(&id).await.is_err());
+        let status = manager.get_vm_status(&id).await.unwrap();
+        assert!(matches!(status.state, VMState::Error(_)));
+        assert!(status.pid.is_none());
+    }
+
+    #[tokio::test]
+    async fn starting_a_vm_shapes_its_tap() {
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("skipping: creating a tap needs root");
+            return;
+        }
+        let network = NetworkManager::new("aegis-shape0", "192.168.151.1/24", "192.168.151.2", "192.168.151.254")
+            .unwrap()
+            .with_rate_limit(8);
+        let manager = test_manager("shape-tap")
+            .with_spawner(Arc::new(FakeQemu::default()))
+            .with_network(Arc::new(network));
+        let tap = format!("tapshape{}", std::process::id() % 10000);
+        let created = std::process::Command::new("ip").args(["tuntap", "add", "dev", &tap, "mode", "tap"]).status();
+        if !created.is_ok_and(|status| status.success()) {
+            eprintln!("skipping: no tun device to create a tap on");
+            return;
+        }
+        let id = insert_vm(&manager, "shape-tap", VMState::Stopped);
+        manager.vms.lock().unwrap().get_mut(&id).unwrap().config.networks = vec![NetworkInterface {
+            network_type: NetworkType::Tap(tap.clone()),
+            mac_address: None,
+            model: Default::default(),
+        }];
+
+        manager.start_vm(&id).await.unwrap();
+        let qdiscs = std::process::Command::new("tc").args(["qdisc", "show", "dev", &tap]).output().unwrap();
+        manager.stop_vm(&id).await.unwrap();
+        let _ = NetworkManager::remove_tap(&tap);
+
+        let qdiscs = String::from_utf8_lossy(&qdiscs.stdout);
+        // 8 Mbit/s is 1 MB/s
+        assert!(qdiscs.contains("tbf") && qdiscs.contains("rate 8Mbit"), "{}", qdiscs);
+        assert!(qdiscs.contains("ingress"), "{}", qdiscs);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_starts_launch_one_qemu() {
+        let qemu = Arc::new(FakeQemu::default());
+        let manager = Arc::new(test_manager("start-concurrent").with_spawner(qemu.clone()));
+        let id = insert_vm(&manager, "start-concurrent", VMState::Stopped);
+
+        let starts = (0..16).map(|_| {
+            let (manager, id) = (manager.clone(), id.clone());
+            tokio::spawn(async move { manager.start_vm(&id).await })
+        });
+        let results = futures::future::join_all(starts).await;
+
+        let started = results.into_iter().filter(|result| matches!(result, Ok(Ok(())))).count();
+        assert_eq!(started, 1);
+        assert_eq!(qemu.spawned.load(std::sync::atomic::Ordering::SeqCst), 1);
+        manager.stop_vm(&id).await.unwrap();
+    }
+
+    #[test]
+    fn qemu_runs_as_the_configured_user() {
+        use std::os::unix::process::CommandExt;
+
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("skipping: the namespaces and the user switch need root");
+            return;
+        }
+        let nobody = (Uid::from_raw(65534), Gid::from_raw(65534));
+        let manager = test_manager("qemu-user").with_privileged(true).with_qemu_user(Some(nobody));
+        let config = VMConfig::new(test_request("qemu-user"), 5900);
+        let prepared = manager.sandbox(&config, &manager.data_dir).unwrap().prepare().unwrap();
+
+        // The hook QemuProcess::start installs, around a stand-in for QEMU
+        let mut command = std::process::Command::new("sleep");
+        command.arg("30");
+        unsafe {
+            command.pre_exec(move || prepared.apply());
+        }
+        let mut child = command.spawn().unwrap();
+        let pid = super::super::qemu::namespaced_qemu_pid(child.id()).unwrap_or(child.id());
+
+        let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+        let ids = |field: &str| status.lines()
+            .find_map(|line| line.strip_prefix(field))
+            .map(|ids| ids.split_whitespace().map(|id| id.parse::<u32>().unwrap()).collect::<Vec<_>>())
+            .unwrap();
+        assert_eq!(ids("Uid:"), vec![65534; 4]);
+        assert_eq!(ids("Gid:"), vec![65534; 4]);
+        // Mode 2 is a seccomp-bpf filter
+        assert_eq!(ids("Seccomp:"), vec![2]);
+        // Still in the host's network namespace, where its VNC port is
+        let ns = |pid: &str| fs::read_link(format!("/proc/{}/ns/net", pid)).unwrap();
+        assert_eq!(ns(&pid.to_string()), ns("self"));
+
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    #[test]
+    fn chroot_gets_device_nodes_at_their_host_paths() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("skipping: mknod needs root");
+            return;
+        }
+        let chroot_dir = std::env::temp_dir().join(format!("aegis-chroot-{}", std::process::id()));
+        let manager = test_manager("chroot").with_privileged(true).with_chroot(Some(chroot_dir.clone()));
+        let config = VMConfig::new(test_request("chroot"), 5900);
+        let sandbox = manager.sandbox(&config, &manager.data_dir).unwrap();
+
+        let root = chroot_dir.join(&config.id).join("root");
+        assert_eq!(sandbox.chroot_path.as_deref(), Some(root.to_str().unwrap()));
+        for device in ["/dev/null", "/dev/urandom", "/dev/kvm", "/dev/net/tun"] {
+            let Ok(host) = fs::metadata(device) else { continue };
+            let node = fs::metadata(root.join(&device[1..])).unwrap();
+            assert!(node.file_type().is_char_device(), "{}", device);
+            assert_eq!(node.rdev(), host.rdev(), "{}", device);
+        }
+        // Mount points for the binds that keep host paths valid inside
+        assert!(root.join(manager.data_dir.strip_prefix("/").unwrap()).is_dir());
+        assert!(root.join("tmp").is_dir());
+
+        let _ = fs::remove_dir_all(&chroot_dir);
+    }
+
+    #[test]
+    fn qemu_joins_cgroups_sized_to_the_vm() {
+        use std::os::unix::process::CommandExt;
+
+        if !nix::unistd::geteuid().is_root() || !privileges().cgroups_writable {
+            eprintln!("skipping: creating cgroups needs root and a writable /sys/fs/cgroup");
+            return;
+        }
+        let manager = test_manager("cgroups").with_privileged(true).with_cgroup_limits(true, 0);
+        let config = VMConfig::new(test_request("cgroups"), 5900);
+        let prepared = manager.sandbox(&config, &manager.data_dir).unwrap().prepare().unwrap();
+
+        let mut command = std::process::Command::new("sleep");
+        command.arg("30");
+        unsafe {
+            command.pre_exec(move || prepared.apply());
+        }
+        let mut child = command.spawn().unwrap();
+        let pid = super::super::qemu::namespaced_qemu_pid(child.id()).unwrap_or(child.id());
+
+        let group = format!("/vm-manager/{}", config.id);
+        let joined = fs::read_to_string(format!("/proc/{}/cgroup", pid)).unwrap();
+        let memory_limit = match crate::security::sandbox::cgroup_version() {
+            crate::security::sandbox::CgroupVersion::V2 => {
+                assert!(joined.lines().any(|line| line == format!("0::{}", group)), "{}", joined);
+                fs::read_to_string(format!("/sys/fs/cgroup{}/memory.max", group)).unwrap()
+            }
+            crate::security::sandbox::CgroupVersion::V1 => {
+                assert!(joined.lines().any(|line| line.ends_with(&format!(":memory:{}", group))), "{}", joined);
+                fs::read_to_string(format!("/sys/fs/cgroup/memory{}/memory.limit_in_bytes", group)).unwrap()
+            }
+        };
+        let expected = (config.memory_mb as u64 + QEMU_MEMORY_OVERHEAD_MB) * 1024 * 1024;
+        assert_eq!(memory_limit.trim().parse::<u64>().unwrap(), expected);
+
+        let _ = child.kill();
+        let _ = child.wait();
+        // The sleep inside the PID namespace is reaped after its init
+        while Path::new(&format!("/proc/{}", pid)).exists() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        remove_vm_cgroups(&config.id).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saved_vms_are_reloaded_stopped() {
+        let manager = test_manager("reload");
+        let configs = manager.data_dir.join("configs");
+        let saved: Vec<VMConfig> = [("first", 5910), ("second", 5911)].iter()
+            .map(|(name, port)| VMConfig::new(test_request(name), *port))
+            .collect();
+        for config in &saved {
+            config.save_to_file(&configs.join(format!("{}.json", config.id))).unwrap();
+        }
+        fs::write(configs.join("broken.json"), "{").unwrap();
+        fs::write(configs.join("notes.txt"), "not a config").unwrap();
+
+        assert_eq!(manager.load_saved_vms().await, 2);
+        // Already loaded ones aren't added twice
+        assert_eq!(manager.load_saved_vms().await, 0);
+
+        for config in &saved {
+            let status = manager.get_vm_status(&config.id).await.unwrap();
+            assert_eq!(status.state, VMState::Stopped);
+            assert_eq!(status.name, config.name);
+            assert_eq!(status.vnc_port, config.vnc_port);
+        }
+        // Their VNC ports are taken
+        assert!(manager.vnc_ports.reserve_port(5910).is_err());
+        assert!(manager.vnc_ports.reserve_port(5911).is_err());
+    }
+
+    #[tokio::test]
+    async fn saved_vms_reattach_to_their_running_qemu() {
+        let manager = test_manager("reattach");
+        fs::create_dir_all(manager.data_dir.join("run")).unwrap();
+        let configs = manager.data_dir.join("configs");
+        let [live, gone] = [("live", 5912), ("gone", 5913)].map(|(name, port)| {
+            let config = VMConfig::new(test_request(name), port);
+            config.save_to_file(&configs.join(format!("{}.json", config.id))).unwrap();
+            config
+        });
+
+        // Stands in for QEMU: the same -qmp argument, and it outlives the
+        // backend that started it
+        let qmp_arg = format!("unix:{},server,nowait", qmp_socket_path(&live.id).display());
+        let mut qemu = std::process::Command::new("sh")
+            .args(["-c", "sleep 60 & wait", "qemu", "-qmp", &qmp_arg])
+            .spawn()
+            .unwrap();
+        // A pid that's since been reused by something else
+        let mut other = std::process::Command::new("sleep").arg("60").spawn().unwrap();
+        let started_at = chrono::Utc::now() - chrono::Duration::hours(3);
+        manager.save_runtime_state(&live.id, &RuntimeState { pid: qemu.id(), started_at });
+        manager.save_runtime_state(&gone.id, &RuntimeState { pid: other.id(), started_at });
+
+        assert_eq!(manager.load_saved_vms().await, 2);
+
+        let status = manager.get_vm_status(&live.id).await.unwrap();
+        assert_eq!(status.state, VMState::Running);
+        assert_eq!(status.pid, Some(qemu.id()));
+        assert_eq!(status.started_at, Some(started_at));
+        assert!(status.uptime_seconds >= 3 * 3600);
+
+        let status = manager.get_vm_status(&gone.id).await.unwrap();
+        assert_eq!(status.state, VMState::Stopped);
+        assert!(!manager.runtime_state_path(&gone.id).exists());
+
+        manager.stop_vm(&live.id).await.unwrap();
+        assert!(qemu.try_wait().unwrap().is_some());
+        assert_eq!(manager.get_vm_status(&live.id).await.unwrap().state, VMState::Stopped);
+        let _ = other.kill();
+        let _ = other.wait();
+    }
+
+    #[tokio::test]
+    async fn offline_disk_operations_are_refused_while_running() {
+        let manager = test_manager("convert-running");
+        let id = insert_vm(&manager, "convert-running", VMState::Running);
+
+        let converted = manager.convert_disk(&id, DiskFormat::Raw, false).await;
+        assert!(matches!(converted, Err(AppError::Conflict(_))), "{:?}", converted);
+        let compacted = manager.compact_disk(&id).await;
+        assert!(matches!(compacted, Err(AppError::Conflict(_))), "{:?}", compacted);
+        let resized = manager.resize_disk(&id, 20).await;
+        assert!(matches!(resized, Err(AppError::Conflict(_))), "{:?}", resized);
+    }
+
+    #[tokio::test]
+    async fn rename_reaches_status_config_and_disk() {
+        let manager = test_manager("rename");
+        let id = insert_vm(&manager, "before", VMState::Stopped);
+
+        let req: UpdateVMRequest = serde_json::from_value(json!({ "name": "after" })).unwrap();
+        manager.update_vm(&id, req, false).await.unwrap();
+
+        assert_eq!(manager.get_vm_status(&id).await.unwrap().name, "after");
+        assert_eq!(manager.vms.lock().unwrap()[&id].config.name, "after");
+        assert_eq!(VMConfig::load_from_file(&manager.config_path(&id)).unwrap().name, "after");
+    }
+
+    #[tokio::test]
+    async fn force_delete_of_a_running_vm_returns() {
+        let manager = test_manager("delete-running");
+        let id = insert_vm(&manager, "running", VMState::Running);
+
+        let deleted = tokio::time::timeout(Duration::from_secs(5), manager.delete_vm(&id, true)).await;
+        assert!(matches!(deleted, Ok(Ok(()))), "delete hung or failed: {:?}", deleted);
+        assert!(manager.get_vm_status(&id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn delete_of_a_running_vm_needs_force() {
+        let manager = test_manager("delete-unforced");
+        let id = insert_vm(&manager, "running", VMState::Running);
+
+        assert!(matches!(manager.delete_vm(&id, false).await, Err(AppError::Conflict(_))));
+        assert_eq!(manager.get_vm_status(&id).await.unwrap().state, VMState::Running);
+    }
+
+    // A do-nothing qemu-system-x86_64 first on PATH for the rest of the test
+    // binary, unless a real one is installed: validation only needs it to
+    // exist, and as it prints no version the capability checks are skipped
+    fn fake_qemu_binary() {
+        static INSTALLED: std::sync::Once = std::sync::Once::new();
+        INSTALLED.call_once(|| {
+            let binary_name = GuestArch::X86_64.binary();
+            if crate::vm::qemu::find_in_path(&binary_name).is_some() {
+                return;
+            }
+            let dir = std::env::temp_dir().join(format!("aegis-bin-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let binary = dir.join(binary_name);
+            fs::write(&binary, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&binary, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+            let path = std::env::var_os("PATH").unwrap_or_default();
+            let dirs = std::iter::once(dir).chain(std::env::split_paths(&path));
+            std::env::set_var("PATH", std::env::join_paths(dirs).unwrap());
+        });
+    }
+
+    // A qcow2 v2 header, with the backing file name (if any) right after it
+    fn qcow2_image(backing: &str) -> Vec<u8> {
+        let mut image = vec![0u8; 72];
+        image[..4].copy_from_slice(b"QFI\xfb");
+        image[4..8].copy_from_slice(&2u32.to_be_bytes());
+        if !backing.is_empty() {
+            image[8..16].copy_from_slice(&72u64.to_be_bytes());
+            image[16..20].copy_from_slice(&(backing.len() as u32).to_be_bytes());
+        }
+        image.extend_from_slice(backing.as_bytes());
+        image
+    }
+
+    // An export bundle of `config` and `disk` in the manager's exports dir
+    fn write_bundle(manager: &VMManager, config: &VMConfig, disk: &[u8]) -> PathBuf {
+        let staging = manager.data_dir.join("bundle");
+        fs::create_dir_all(&staging).unwrap();
+        config.save_to_file(&staging.join("config.json")).unwrap();
+        fs::write(staging.join("disk.qcow2"), disk).unwrap();
+        let bundle = manager.data_dir.join("exports").join("bundle.tar");
+        run_tool(Command::new("tar").arg("-cf").arg(&bundle).arg("-C").arg(&staging).arg(".")).unwrap();
+        fs::remove_dir_all(&staging).unwrap();
+        bundle
+    }
+
+    #[tokio::test]
+    async fn imports_are_validated_and_start_unprotected() {
+        fake_qemu_binary();
+        let manager = test_manager("import");
+        let mut config = VMConfig::new(test_request("imported"), 5900);
+        config.disk_size_gb = 10;
+        config.protected = true;
+        config.is_template = true;
+
+        // A backing file outside the base image directory
+        let bundle = write_bundle(&manager, &config, &qcow2_image("/etc/shadow"));
+        let err = manager.import_vm(&bundle).await.unwrap_err();
+        assert!(matches!(err, AppError::Validation(ValidationError::InvalidPath(_))), "{}", err);
+
+        let mut bad_args = config.clone();
+        bad_args.extra_args = vec!["-m".to_string(), "65536".to_string()];
+        let bundle = write_bundle(&manager, &bad_args, &qcow2_image(""));
+        let err = manager.import_vm(&bundle).await.unwrap_err();
+        assert!(matches!(err, AppError::Validation(ValidationError::ReservedQemuFlag { .. })), "{}", err);
+        assert!(manager.vms.lock().unwrap().is_empty());
+
+        let bundle = write_bundle(&manager, &config, &qcow2_image(""));
+        let imported = manager.import_vm(&bundle).await.unwrap();
+        assert!(!imported.protected && !imported.is_template);
+        assert_eq!(imported.base_image, None);
+        let saved = VMConfig::load_from_file(&manager.config_path(&imported.id)).unwrap();
+        assert!(!saved.protected && !saved.is_template);
+
+        let _ = fs::remove_dir_all(&manager.data_dir);
+    }
+
+    #[tokio::test]
+    async fn one_shot_uploads_stream_through_an_upload_session() {
+        let manager = test_manager("upload");
+        let body = |chunks: Vec<&'static [u8]>| futures::stream::iter(chunks.into_iter().map(Ok::<_, String>));
+
+        let info = manager.upload_iso("whole.iso", Some(8), body(vec![b"abcd", b"efgh"])).await.unwrap();
+        assert_eq!(fs::read(&info.path).unwrap(), b"abcdefgh");
+
+        // Short of the declared length: nothing is kept and the name is free again
+        assert!(manager.upload_iso("short.iso", Some(8), body(vec![b"abcd"])).await.is_err());
+        assert!(!manager.data_dir.join("isos").join("short.iso").exists());
+        assert!(manager.upload_iso("short.iso", None, body(vec![b"abcd"])).await.is_ok());
+
+        let _ = fs::remove_dir_all(&manager.data_dir);
+    }
+
+    // Through create_vm, so the 101st has to fail before anything of it is
+    // written. Needs qemu-img for the disks.
+    #[tokio::test]
+    async fn vnc_ports_run_out_after_100_vms_without_leaking_the_next() {
+        if crate::vm::qemu::find_in_path("qemu-img").is_none() {
+            eprintln!("skipping: qemu-img not installed");
+            return;
+        }
+        fake_qemu_binary();
+        let manager = test_manager("vnc-exhaustion");
+        let request = |n: usize| CreateVMRequest { disk_size_gb: 10, ..test_request(&format!("vm{}", n)) };
+
+        let mut created = Vec::new();
+        // Fewer than 100 when something else on the host listens in the range
+        let exhausted = loop {
+            match manager.create_vm(request(created.len())).await {
+                Ok(config) => created.push(config),
+                Err(e) => break e,
+            }
+            assert!(created.len() <= 100);
+        };
+        assert!(matches!(exhausted, AppError::Port(crate::utils::ports::PortError::NoPortsAvailable)), "{}", exhausted);
+        let ports: std::collections::HashSet<u16> = created.iter().map(|config| config.vnc_port).collect();
+        assert_eq!(ports.len(), created.len());
+        assert!(ports.iter().all(|port| (port_ranges::VNC.0..=port_ranges::VNC.1).contains(port)));
+
+        // Nothing of the failed create is left behind
+        let count = |dir: &str| fs::read_dir(manager.data_dir.join(dir)).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().is_file())
+            .count();
+        assert_eq!(manager.vms.lock().unwrap().len(), created.len());
+        assert_eq!(count("configs"), created.len());
+        assert_eq!(count("disks"), created.len());
+
+        // and a deleted VM's port goes to the next one
+        let freed = created.pop().unwrap();
+        manager.delete_vm(&freed.id, false).await.unwrap();
+        assert_eq!(manager.create_vm(request(100)).await.unwrap().vnc_port, freed.vnc_port);
+
+        let _ = fs::remove_dir_all(&manager.data_dir);
+    }
+}
\ No newline at end of file
@@ -1,9 +1,5 @@
-pub mod config;
-pub mod manager;
-pub mod qemu;
-pub mod networking;
-
-pub use config::*;
-pub use manager::*;
-pub use qemu::*;
-pub use networking::*;
\ No newline at end of file
+pub mod config;
+pub mod manager;
+pub mod qemu;
+pub mod networking;
+pub mod operations;
\ No newline at end of file
@@ -1,367 +1,1361 @@
-use std::net::{IpAddr, Ipv4Addr};
-use std::process::Command;
-use std::str::FromStr;
-
-#[derive(Debug, thiserror::Error)]
-pub enum NetworkError {
-    #[error("IO error: {0}")]
-    IoError(#[from] std::io::Error),
-    #[error("Command failed: {0}")]
-    CommandFailed(String),
-    #[error("Invalid IP address: {0}")]
-    InvalidIp(String),
-    #[error("Invalid subnet: {0}")]
-    InvalidSubnet(String),
-    #[error("Bridge already exists: {0}")]
-    BridgeExists(String),
-    #[error("Bridge not found: {0}")]
-    BridgeNotFound(String),
-    #[error("Tap interface already exists: {0}")]
-    TapExists(String),
-    #[error("Tap interface not found: {0}")]
-    TapNotFound(String),
-}
-
-pub struct NetworkManager {
-    bridge_name: String,
-    subnet: Ipv4Addr,
-    netmask: u8,
-    dhcp_start: Ipv4Addr,
-    dhcp_end: Ipv4Addr,
-}
-
-impl NetworkManager {
-    pub fn new(
-        bridge_name: &str,
-        subnet: &str,
-        netmask: u8,
-        dhcp_start: &str,
-        dhcp_end: &str,
-    ) -> Result<Self, NetworkError> {
-        let subnet_addr = Ipv4Addr::from_str(subnet)
-            .map_err(|_| NetworkError::InvalidSubnet(subnet.to_string()))?;
-        
-        let dhcp_start_addr = Ipv4Addr::from_str(dhcp_start)
-            .map_err(|_| NetworkError::InvalidIp(dhcp_start.to_string()))?;
-        
-        let dhcp_end_addr = Ipv4Addr::from_str(dhcp_end)
-            .map_err(|_| NetworkError::InvalidIp(dhcp_end.to_string()))?;
-        
-        // Validate addresses are in the same subnet
-        if !Self::is_in_subnet(&dhcp_start_addr, &subnet_addr, netmask) ||
-           !Self::is_in_subnet(&dhcp_end_addr, &subnet_addr, netmask) {
-            return Err(NetworkError::InvalidSubnet(
-                "DHCP range not in subnet".to_string()
-            ));
-        }
-        
-        Ok(Self {
-            bridge_name: bridge_name.to_string(),
-            subnet: subnet_addr,
-            netmask,
-            dhcp_start: dhcp_start_addr,
-            dhcp_end: dhcp_end_addr,
-        })
-    }
-    
-    fn is_in_subnet(ip: &Ipv4Addr, subnet: &Ipv4Addr, mask: u8) -> bool {
-        let ip_int = u32::from(*ip);
-        let subnet_int = u32::from(*subnet);
-        let mask_int = !((1 << (32 - mask)) - 1);
-        
-        (ip_int & mask_int) == (subnet_int & mask_int)
-    }
-    
-    pub fn create_bridge(&self) -> Result<(), NetworkError> {
-        // Check if bridge already exists
-        if self.bridge_exists()? {
-            return Err(NetworkError::BridgeExists(self.bridge_name.clone()));
-        }
-        
-        // Create bridge
-        let output = Command::new("ip")
-            .args(&["link", "add", &self.bridge_name, "type", "bridge"])
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(NetworkError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        // Set bridge up
-        let output = Command::new("ip")
-            .args(&["link", "set", &self.bridge_name, "up"])
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(NetworkError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        // Assign IP to bridge
-        let cidr = format!("{}/{}", self.subnet, self.netmask);
-        let output = Command::new("ip")
-            .args(&["addr", "add", &cidr, "dev", &self.bridge_name])
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(NetworkError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        // Setup NAT
-        self.setup_nat()?;
-        
-        // Setup DHCP server (dnsmasq)
-        self.setup_dhcp()?;
-        
-        Ok(())
-    }
-    
-    pub fn delete_bridge(&self) -> Result<(), NetworkError> {
-        if !self.bridge_exists()? {
-            return Err(NetworkError::BridgeNotFound(self.bridge_name.clone()));
-        }
-        
-        // Set bridge down
-        let _ = Command::new("ip")
-            .args(&["link", "set", &self.bridge_name, "down"])
-            .output();
-        
-        // Delete bridge
-        let output = Command::new("ip")
-            .args(&["link", "delete", &self.bridge_name])
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(NetworkError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        // Cleanup iptables rules
-        self.cleanup_nat()?;
-        
-        Ok(())
-    }
-    
-    pub fn create_tap(&self, tap_name: &str) -> Result<(), NetworkError> {
-        // Check if tap already exists
-        if self.tap_exists(tap_name)? {
-            return Err(NetworkError::TapExists(tap_name.to_string()));
-        }
-        
-        // Create tap interface
-        let output = Command::new("ip")
-            .args(&["tuntap", "add", tap_name, "mode", "tap"])
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(NetworkError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        // Set tap up
-        let output = Command::new("ip")
-            .args(&["link", "set", tap_name, "up"])
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(NetworkError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        // Add tap to bridge
-        let output = Command::new("ip")
-            .args(&["link", "set", tap_name, "master", &self.bridge_name])
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(NetworkError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        Ok(())
-    }
-    
-    pub fn delete_tap(&self, tap_name: &str) -> Result<(), NetworkError> {
-        if !self.tap_exists(tap_name)? {
-            return Err(NetworkError::TapNotFound(tap_name.to_string()));
-        }
-        
-        // Remove tap from bridge
-        let _ = Command::new("ip")
-            .args(&["link", "set", tap_name, "nomaster"])
-            .output();
-        
-        // Set tap down
-        let _ = Command::new("ip")
-            .args(&["link", "set", tap_name, "down"])
-            .output();
-        
-        // Delete tap
-        let output = Command::new("ip")
-            .args(&["tuntap", "delete", tap_name, "mode", "tap"])
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(NetworkError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        Ok(())
-    }
-    
-    fn bridge_exists(&self) -> Result<bool, NetworkError> {
-        let output = Command::new("ip")
-            .args(&["link", "show", &self.bridge_name])
-            .output()?;
-        
-        Ok(output.status.success())
-    }
-    
-    fn tap_exists(&self, tap_name: &str) -> Result<bool, NetworkError> {
-        let output = Command::new("ip")
-            .args(&["link", "show", tap_name])
-            .output()?;
-        
-        Ok(output.status.success())
-    }
-    
-    fn setup_nat(&self) -> Result<(), NetworkError> {
-        // Enable IP forwarding
-        let _ = std::fs::write("/proc/sys/net/ipv4/ip_forward", "1");
-        
-        // Setup iptables rules
-        let rules = vec![
-            // NAT rule
-            format!("-t nat -A POSTROUTING -s {}/{} -j MASQUERADE", 
-                self.subnet, self.netmask),
-            // Forwarding rules
-            format!("-A FORWARD -i {} -j ACCEPT", self.bridge_name),
-            format!("-A FORWARD -o {} -j ACCEPT", self.bridge_name),
-        ];
-        
-        for rule in rules {
-            let output = Command::new("iptables")
-                .args(rule.split_whitespace())
-                .output()?;
-            
-            if !output.status.success() {
-                return Err(NetworkError::CommandFailed(
-                    String::from_utf8_lossy(&output.stderr).to_string()
-                ));
-            }
-        }
-        
-        Ok(())
-    }
-    
-    fn cleanup_nat(&self) -> Result<(), NetworkError> {
-        // Remove iptables rules
-        let rules = vec![
-            // NAT rule
-            format!("-t nat -D POSTROUTING -s {}/{} -j MASQUERADE", 
-                self.subnet, self.netmask),
-            // Forwarding rules
-            format!("-D FORWARD -i {} -j ACCEPT", self.bridge_name),
-            format!("-D FORWARD -o {} -j ACCEPT", self.bridge_name),
-        ];
-        
-        for rule in rules {
-            let _ = Command::new("iptables")
-                .args(rule.split_whitespace())
-                .output();
-        }
-        
-        Ok(())
-    }
-    
-    fn setup_dhcp(&self) -> Result<(), NetworkError> {
-        // Create dnsmasq configuration
-        let config = format!(
-            "interface={}\n\
-             bind-interfaces\n\
-             dhcp-range={},{}\n\
-             dhcp-option=option:router,{}\n\
-             dhcp-option=option:dns-server,8.8.8.8,8.8.4.4\n\
-             server=8.8.8.8\n\
-             server=8.8.4.4\n\
-             log-dhcp\n\
-             quiet-dhcp\n",
-            self.bridge_name,
-            self.dhcp_start,
-            self.dhcp_end,
-            self.subnet
-        );
-        
-        let config_path = format!("/etc/dnsmasq.d/{}.conf", self.bridge_name);
-        std::fs::write(&config_path, config)?;
-        
-        // Start dnsmasq
-        let output = Command::new("systemctl")
-            .args(&["restart", "dnsmasq"])
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(NetworkError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ));
-        }
-        
-        Ok(())
-    }
-    
-    pub fn allocate_ip(&self) -> Result<Ipv4Addr, NetworkError> {
-        // Simple IP allocation: start from DHCP start + 1
-        // In production, you'd want to track allocated IPs
-        let next_ip = self.dhcp_start;
-        Ok(next_ip)
-    }
-    
-    pub fn list_bridges() -> Result<Vec<String>, NetworkError> {
-        let output = Command::new("ip")
-            .args(&["link", "show", "type", "bridge"])
-            .output()?;
-        
-        if !output.status.success() {
-            return Ok(Vec::new());
-        }
-        
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        let bridges: Vec<String> = output_str.lines()
-            .filter_map(|line| {
-                line.split(':').nth(1).map(|s| s.trim().to_string())
-            })
-            .collect();
-        
-        Ok(bridges)
-    }
-    
-    pub fn list_taps(&self) -> Result<Vec<String>, NetworkError> {
-        let output = Command::new("ip")
-            .args(&["link", "show", "type", "tuntap"])
-            .output()?;
-        
-        if !output.status.success() {
-            return Ok(Vec::new());
-        }
-        
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        let taps: Vec<String> = output_str.lines()
-            .filter_map(|line| {
-                line.split(':').nth(1).map(|s| s.trim().to_string())
-            })
-            .filter(|tap| tap.starts_with("tap"))
-            .collect();
-        
-        Ok(taps)
-    }
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::command::{CommandCategory, CommandError, CommandTimeoutExt};
+
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Command failed: {0}")]
+    CommandFailed(String),
+    #[error("Invalid IP address: {0}")]
+    InvalidIp(String),
+    #[error("Invalid subnet: {0}")]
+    InvalidSubnet(String),
+    #[error("Invalid DNS domain '{0}': {1}")]
+    InvalidDomain(String, String),
+    #[error("Bridge already exists: {0}")]
+    BridgeExists(String),
+    #[error("Bridge not found: {0}")]
+    BridgeNotFound(String),
+    #[error("Bridge {0} still has {1} interface(s) attached")]
+    BridgeInUse(String, usize),
+    #[error("Tap interface already exists: {0}")]
+    TapExists(String),
+    #[error("Tap interface not found: {0}")]
+    TapNotFound(String),
+    #[error("Invalid interface name '{0}': {1}")]
+    InvalidInterfaceName(String, String),
+    #[error("Command timed out: {0}")]
+    Timeout(String),
+}
+
+impl From<CommandError> for NetworkError {
+    fn from(err: CommandError) -> Self {
+        match err {
+            CommandError::IoError(e) => NetworkError::IoError(e),
+            timeout => NetworkError::Timeout(timeout.to_string()),
+        }
+    }
+}
+
+// Kernel limit on interface names (IFNAMSIZ minus the terminating NUL)
+pub const MAX_INTERFACE_NAME_LEN: usize = 15;
+
+// Collision suffixes tried after the plain tap<id> name
+const TAP_NAME_ATTEMPTS: u32 = 16;
+
+// Bound on copies of one iptables rule removed during cleanup
+const MAX_DUPLICATE_RULES: u32 = 64;
+
+// One entry of `ip -j -d link show`; only the fields we filter on
+#[derive(Debug, Deserialize)]
+struct IpLink {
+    ifname: String,
+    #[serde(default)]
+    master: Option<String>,
+    #[serde(default)]
+    linkinfo: Option<IpLinkInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IpLinkInfo {
+    #[serde(default)]
+    info_kind: Option<String>,
+    #[serde(default)]
+    info_data: Option<IpLinkInfoData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IpLinkInfoData {
+    // "tap" or "tun" for tuntap links
+    #[serde(default, rename = "type")]
+    kind: Option<String>,
+}
+
+// One interface of `ip -j addr show dev <name>`
+#[derive(Debug, Deserialize)]
+struct IpAddrInfo {
+    #[serde(default)]
+    addr_info: Vec<IpAddress>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IpAddress {
+    local: String,
+    prefixlen: u8,
+    #[serde(default)]
+    scope: String,
+}
+
+// One entry of `ip -j route show`
+#[derive(Debug, Deserialize)]
+struct IpRoute {
+    #[serde(default)]
+    gateway: Option<String>,
+}
+
+impl IpLink {
+    fn kind(&self) -> Option<&str> {
+        self.linkinfo.as_ref()?.info_kind.as_deref()
+    }
+
+    fn is_tap(&self) -> bool {
+        self.kind() == Some("tun")
+            && self.linkinfo.as_ref()
+                .and_then(|info| info.info_data.as_ref())
+                .and_then(|data| data.kind.as_deref())
+                == Some("tap")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DnsConfig {
+    // Handed to guests over DHCP. None: the host's resolv.conf nameservers
+    pub servers: Option<Vec<Ipv4Addr>>,
+    // What dnsmasq forwards to. None: dnsmasq follows the host's resolv.conf
+    pub upstream: Option<Vec<IpAddr>>,
+    // Point guests at dnsmasq on the bridge instead, so lookups go through
+    // the host's resolver setup
+    pub resolve_locally: bool,
+    // Search domain handed to guests; dnsmasq also answers for it locally
+    pub domain: Option<String>,
+}
+
+impl DnsConfig {
+    pub fn validate(&self) -> Result<(), NetworkError> {
+        // Guests can't reach the host's loopback, so a stub resolver there is no use to them
+        for server in self.servers.iter().flatten() {
+            if server.is_unspecified() || server.is_loopback() || server.is_multicast() || server.is_broadcast() {
+                return Err(NetworkError::InvalidIp(format!("{} can't serve DNS to guests", server)));
+            }
+        }
+        for server in self.upstream.iter().flatten() {
+            if server.is_unspecified() || server.is_multicast() {
+                return Err(NetworkError::InvalidIp(format!("{} can't be a DNS upstream", server)));
+            }
+        }
+        if let Some(domain) = &self.domain {
+            validate_domain(domain)?;
+        }
+        Ok(())
+    }
+}
+
+// Dot-separated labels of letters, digits and inner hyphens
+fn validate_domain(domain: &str) -> Result<(), NetworkError> {
+    let invalid = |reason: &str| NetworkError::InvalidDomain(domain.to_string(), reason.to_string());
+    
+    if domain.is_empty() || domain.len() > 253 {
+        return Err(invalid("must be 1-253 characters"));
+    }
+    for label in domain.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid("each label must be 1-63 characters"));
+        }
+        if !label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+            return Err(invalid("only letters, digits, '-' and '.' are allowed"));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(invalid("labels can't start or end with '-'"));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallBackend {
+    Iptables,
+    // Rules live in a per-bridge table, so cleanup is one table delete
+    Nftables,
+}
+
+impl FirewallBackend {
+    // Prefers nft when it's installed; on most current distros iptables is
+    // only a compatibility shim over it anyway
+    pub fn detect() -> Self {
+        let has_nft = Command::new("nft")
+            .arg("--version")
+            .output_within(CommandCategory::Service)
+            .map(|output| output.status.success())
+            .unwrap_or(false);
+        
+        if has_nft { FirewallBackend::Nftables } else { FirewallBackend::Iptables }
+    }
+}
+
+// What GET /api/network reports about the managed network
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkStatus {
+    pub bridge: String,
+    // In bridged mode the LAN's DHCP hands out addresses, so the subnet and
+    // range are omitted
+    pub uplink: Option<String>,
+    pub subnet: Option<String>,
+    pub dhcp_start: Option<Ipv4Addr>,
+    pub dhcp_end: Option<Ipv4Addr>,
+    pub dhcp_lease_count: Option<u32>,
+    pub rate_limit_mbps: Option<u32>,
+    pub taps: Vec<String>,
+}
+
+pub struct NetworkManager {
+    bridge_name: String,
+    subnet: Ipv4Addr,
+    netmask: u8,
+    dhcp_start: Ipv4Addr,
+    dhcp_end: Ipv4Addr,
+    dns: DnsConfig,
+    firewall: FirewallBackend,
+    // The iptables binary; tests point it at a stand-in
+    iptables: PathBuf,
+    // Every tap this manager created and hasn't deleted; list_taps reports
+    // from this rather than guessing from names
+    managed_taps: Mutex<BTreeSet<String>>,
+    // Applied to every tap create_tap makes; None leaves them unshaped
+    rate_limit_mbps: Option<u32>,
+    // Physical NIC enslaved to the bridge in bridged mode
+    uplink: Option<String>,
+}
+
+// "a.b.c.d/n" with every octet 0-255 and the prefix 0-32. The address is
+// returned as written (it doubles as the gateway), not masked.
+pub fn parse_cidr(cidr: &str) -> Result<(Ipv4Addr, u8), NetworkError> {
+    let invalid = || NetworkError::InvalidSubnet(format!("{} (expected CIDR like 192.168.122.0/24)", cidr));
+    
+    let (addr, prefix) = cidr.trim().split_once('/').ok_or_else(invalid)?;
+    
+    // Ipv4Addr's parser rejects out-of-range and leading-zero octets
+    let addr = Ipv4Addr::from_str(addr).map_err(|_| invalid())?;
+    
+    if prefix.is_empty() || !prefix.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+    if prefix > 32 {
+        return Err(invalid());
+    }
+    
+    Ok((addr, prefix))
+}
+
+// IPv4 nameservers from the host's resolv.conf that a guest can reach.
+// Loopback stubs like systemd-resolved's 127.0.0.53 only exist on the host.
+fn host_nameservers() -> Vec<Ipv4Addr> {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|addr| Ipv4Addr::from_str(addr.trim()).ok())
+        .filter(|addr| !addr.is_loopback())
+        .collect()
+}
+
+// (rx_bytes, tx_bytes) the kernel has counted on an interface; None if
+// it doesn't exist
+pub fn interface_traffic(name: &str) -> Option<(u64, u64)> {
+    let read = |counter: &str| -> Option<u64> {
+        std::fs::read_to_string(format!("/sys/class/net/{}/statistics/{}", name, counter))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some((read("rx_bytes")?, read("tx_bytes")?))
+}
+
+impl NetworkManager {
+    pub fn new(
+        bridge_name: &str,
+        subnet_cidr: &str,
+        dhcp_start: &str,
+        dhcp_end: &str,
+    ) -> Result<Self, NetworkError> {
+        let (subnet_addr, netmask) = parse_cidr(subnet_cidr)?;
+        // A /0 "subnet" would be the whole internet
+        if netmask == 0 {
+            return Err(NetworkError::InvalidSubnet(
+                format!("{} (prefix must be between 1 and 32)", subnet_cidr)
+            ));
+        }
+        
+        let dhcp_start_addr = Ipv4Addr::from_str(dhcp_start)
+            .map_err(|_| NetworkError::InvalidIp(dhcp_start.to_string()))?;
+        
+        let dhcp_end_addr = Ipv4Addr::from_str(dhcp_end)
+            .map_err(|_| NetworkError::InvalidIp(dhcp_end.to_string()))?;
+        
+        // Validate addresses are in the same subnet
+        if !Self::is_in_subnet(&dhcp_start_addr, &subnet_addr, netmask) ||
+           !Self::is_in_subnet(&dhcp_end_addr, &subnet_addr, netmask) {
+            return Err(NetworkError::InvalidSubnet(
+                "DHCP range not in subnet".to_string()
+            ));
+        }
+        
+        // Validate range ordering and that it leaves the reserved addresses alone
+        let start = u32::from(dhcp_start_addr);
+        let end = u32::from(dhcp_end_addr);
+        if start > end {
+            return Err(NetworkError::InvalidSubnet(
+                format!("DHCP range is empty or reversed ({} > {})", dhcp_start_addr, dhcp_end_addr)
+            ));
+        }
+        
+        let (network, broadcast) = Self::network_bounds(&subnet_addr, netmask);
+        let gateway = u32::from(subnet_addr);
+        for (reserved, what) in [(network, "network"), (broadcast, "broadcast"), (gateway, "gateway")] {
+            if (start..=end).contains(&reserved) {
+                return Err(NetworkError::InvalidSubnet(
+                    format!("DHCP range includes the {} address {}", what, Ipv4Addr::from(reserved))
+                ));
+            }
+        }
+        
+        Ok(Self {
+            bridge_name: bridge_name.to_string(),
+            subnet: subnet_addr,
+            netmask,
+            dhcp_start: dhcp_start_addr,
+            dhcp_end: dhcp_end_addr,
+            dns: DnsConfig::default(),
+            firewall: FirewallBackend::detect(),
+            iptables: PathBuf::from("iptables"),
+            managed_taps: Mutex::new(BTreeSet::new()),
+            rate_limit_mbps: None,
+            uplink: None,
+        })
+    }
+    
+    pub fn with_dns(mut self, dns: DnsConfig) -> Result<Self, NetworkError> {
+        dns.validate()?;
+        self.dns = dns;
+        Ok(self)
+    }
+    
+    // Switches from NAT to bridged mode. In NAT mode (the default) the
+    // bridge is a private network behind the host, with our dnsmasq handing
+    // out addresses and masquerading for it. In bridged mode `uplink`, a
+    // physical NIC, is enslaved to the bridge instead: guests sit directly on
+    // the LAN and get their addresses from its DHCP, and the subnet and DHCP
+    // range given to new() go unused.
+    pub fn with_uplink(mut self, uplink: &str) -> Result<Self, NetworkError> {
+        Self::validate_interface_name(uplink)?;
+        self.uplink = Some(uplink.to_string());
+        Ok(self)
+    }
+    
+    // 0 means unlimited. Covers the taps this manager creates; the VM
+    // manager applies it to every other tap a VM is on.
+    pub fn with_rate_limit(mut self, mbps: u32) -> Self {
+        self.rate_limit_mbps = if mbps == 0 { None } else { Some(mbps) };
+        self
+    }
+    
+    pub fn bridge_name(&self) -> &str {
+        &self.bridge_name
+    }
+    
+    pub fn rate_limit(&self) -> Option<u32> {
+        self.rate_limit_mbps
+    }
+    
+    pub fn with_firewall(mut self, firewall: FirewallBackend) -> Self {
+        self.firewall = firewall;
+        self
+    }
+    
+    // Number of addresses dnsmasq can hand out
+    pub fn dhcp_lease_count(&self) -> u32 {
+        u32::from(self.dhcp_end) - u32::from(self.dhcp_start) + 1
+    }
+    
+    pub fn status(&self) -> Result<NetworkStatus, NetworkError> {
+        let nat = self.uplink.is_none();
+        Ok(NetworkStatus {
+            bridge: self.bridge_name.clone(),
+            uplink: self.uplink.clone(),
+            subnet: nat.then(|| format!("{}/{}", self.subnet, self.netmask)),
+            dhcp_start: nat.then_some(self.dhcp_start),
+            dhcp_end: nat.then_some(self.dhcp_end),
+            dhcp_lease_count: nat.then(|| self.dhcp_lease_count()),
+            rate_limit_mbps: self.rate_limit_mbps,
+            taps: self.list_taps()?,
+        })
+    }
+    
+    // Prefixes past 32 are treated as /32
+    fn mask_bits(mask: u8) -> u32 {
+        u32::MAX.checked_shl(32u32.saturating_sub(mask as u32)).unwrap_or(0)
+    }
+    
+    fn network_bounds(subnet: &Ipv4Addr, mask: u8) -> (u32, u32) {
+        let mask_int = Self::mask_bits(mask);
+        let network = u32::from(*subnet) & mask_int;
+        (network, network | !mask_int)
+    }
+    
+    fn is_in_subnet(ip: &Ipv4Addr, subnet: &Ipv4Addr, mask: u8) -> bool {
+        let mask_int = Self::mask_bits(mask);
+        (u32::from(*ip) & mask_int) == (u32::from(*subnet) & mask_int)
+    }
+    
+    pub fn create_bridge(&self) -> Result<(), NetworkError> {
+        // An existing bridge is reused as long as it carries our subnet (or
+        // our uplink), so every VM on a shared bridge can call this when it starts
+        if self.bridge_exists()? {
+            let ours = match &self.uplink {
+                Some(uplink) => self.uplink_attached(uplink)?,
+                None => self.bridge_has_subnet()?,
+            };
+            if ours {
+                return Ok(());
+            }
+            return Err(NetworkError::BridgeExists(self.bridge_name.clone()));
+        }
+        
+        // Create bridge
+        let output = Command::new("ip")
+            .args(["link", "add", &self.bridge_name, "type", "bridge"])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        // Set bridge up
+        let output = Command::new("ip")
+            .args(["link", "set", &self.bridge_name, "up"])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        // The LAN does addressing and routing for a bridged network
+        if let Some(uplink) = &self.uplink {
+            if let Err(e) = self.attach_uplink(uplink) {
+                let _ = self.detach_uplink(uplink);
+                let _ = Command::new("ip")
+                    .args(["link", "delete", &self.bridge_name])
+                    .output_within(CommandCategory::Network);
+                return Err(e);
+            }
+            return Ok(());
+        }
+        
+        // Assign IP to bridge
+        let cidr = format!("{}/{}", self.subnet, self.netmask);
+        let output = Command::new("ip")
+            .args(["addr", "add", &cidr, "dev", &self.bridge_name])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        // Setup NAT
+        self.setup_nat()?;
+        
+        // Setup DHCP server (dnsmasq)
+        self.setup_dhcp()?;
+        
+        Ok(())
+    }
+    
+    pub fn delete_bridge(&self) -> Result<(), NetworkError> {
+        if !self.bridge_exists()? {
+            return Err(NetworkError::BridgeNotFound(self.bridge_name.clone()));
+        }
+        
+        // Never pull the bridge out from under other VMs' taps
+        let attached = self.attached_interfaces()?;
+        if !attached.is_empty() {
+            return Err(NetworkError::BridgeInUse(self.bridge_name.clone(), attached.len()));
+        }
+        
+        // Hand the host's LAN connection back to the uplink first
+        if let Some(uplink) = &self.uplink {
+            self.detach_uplink(uplink)?;
+        }
+        
+        // Set bridge down
+        let _ = Command::new("ip")
+            .args(["link", "set", &self.bridge_name, "down"])
+            .output_within(CommandCategory::Network);
+        
+        // Delete bridge
+        let output = Command::new("ip")
+            .args(["link", "delete", &self.bridge_name])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        if self.uplink.is_some() {
+            return Ok(());
+        }
+        
+        // Cleanup NAT/forward rules
+        self.cleanup_nat()?;
+        
+        // Cleanup DHCP config
+        self.cleanup_dhcp()?;
+        
+        Ok(())
+    }
+    
+    // Enslaves the uplink and moves its addresses and default routes onto
+    // the bridge, which also takes the uplink's MAC so DHCP reservations
+    // for the host still match. The addresses move as static ones: a DHCP
+    // client managing the uplink should be pointed at the bridge instead.
+    fn attach_uplink(&self, uplink: &str) -> Result<(), NetworkError> {
+        if !Self::interface_exists(uplink)? {
+            return Err(NetworkError::InvalidInterfaceName(uplink.to_string(), "no such interface".to_string()));
+        }
+        
+        // Deleting an address drops the routes through it, so note them first
+        let addresses = Self::global_addresses(uplink)?;
+        let gateways = Self::default_gateways(uplink)?;
+        let mac = std::fs::read_to_string(format!("/sys/class/net/{}/address", uplink))?;
+        
+        Self::ip(&["link", "set", &self.bridge_name, "address", mac.trim()])?;
+        Self::ip(&["link", "set", uplink, "master", &self.bridge_name])?;
+        Self::move_addresses(uplink, &self.bridge_name, &addresses, &gateways)
+    }
+    
+    // Undoes attach_uplink from what's on the bridge now, so it works
+    // after a restart too
+    fn detach_uplink(&self, uplink: &str) -> Result<(), NetworkError> {
+        let addresses = Self::global_addresses(&self.bridge_name)?;
+        let gateways = Self::default_gateways(&self.bridge_name)?;
+        
+        if self.uplink_attached(uplink)? {
+            Self::ip(&["link", "set", uplink, "nomaster"])?;
+        }
+        Self::move_addresses(&self.bridge_name, uplink, &addresses, &gateways)
+    }
+    
+    fn uplink_attached(&self, uplink: &str) -> Result<bool, NetworkError> {
+        Ok(Self::ip_links(&["master", &self.bridge_name])?
+            .iter()
+            .any(|link| link.ifname == uplink))
+    }
+    
+    fn move_addresses(from: &str, to: &str, addresses: &[String], gateways: &[String]) -> Result<(), NetworkError> {
+        for cidr in addresses {
+            Self::ip(&["addr", "del", cidr, "dev", from])?;
+            Self::ip(&["addr", "add", cidr, "dev", to])?;
+        }
+        for gateway in gateways {
+            Self::ip(&["route", "replace", "default", "via", gateway, "dev", to])?;
+        }
+        Ok(())
+    }
+    
+    // "a.b.c.d/n" (or IPv6) for each global address on `dev`; link-local
+    // ones belong to the interface itself
+    fn global_addresses(dev: &str) -> Result<Vec<String>, NetworkError> {
+        let interfaces: Vec<IpAddrInfo> = Self::ip_json(&["addr", "show", "dev", dev])?;
+        Ok(interfaces.into_iter()
+            .flat_map(|interface| interface.addr_info)
+            .filter(|addr| addr.scope == "global")
+            .map(|addr| format!("{}/{}", addr.local, addr.prefixlen))
+            .collect())
+    }
+    
+    fn default_gateways(dev: &str) -> Result<Vec<String>, NetworkError> {
+        let mut gateways = Vec::new();
+        for family in ["-4", "-6"] {
+            let routes: Vec<IpRoute> = Self::ip_json(&[family, "route", "show", "default", "dev", dev])?;
+            gateways.extend(routes.into_iter().filter_map(|route| route.gateway));
+        }
+        Ok(gateways)
+    }
+    
+    fn ip(args: &[&str]) -> Result<(), NetworkError> {
+        let output = Command::new("ip")
+            .args(args)
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(())
+    }
+    
+    // Removes a VM's tap and tears the bridge down with it once no other
+    // interfaces remain enslaved. Returns whether the bridge was deleted.
+    pub fn release_tap(&self, tap_name: &str) -> Result<bool, NetworkError> {
+        self.delete_tap(tap_name)?;
+        self.delete_bridge_if_unused()
+    }
+    
+    // Deletes the bridge only once nothing is enslaved to it. Returns
+    // whether it was deleted.
+    pub fn delete_bridge_if_unused(&self) -> Result<bool, NetworkError> {
+        if !self.bridge_exists()? || !self.attached_interfaces()?.is_empty() {
+            return Ok(false);
+        }
+        
+        self.delete_bridge()?;
+        Ok(true)
+    }
+    
+    // The kernel's list of bridge ports is the reference count, so it stays
+    // correct across restarts and for taps created outside this manager.
+    // The uplink of a bridged network doesn't count.
+    pub fn attached_interfaces(&self) -> Result<Vec<String>, NetworkError> {
+        let interfaces = Self::ip_links(&["master", &self.bridge_name])?
+            .into_iter()
+            .filter(|link| link.master.as_deref() == Some(self.bridge_name.as_str()))
+            .filter(|link| Some(link.ifname.as_str()) != self.uplink.as_deref())
+            .map(|link| link.ifname)
+            .collect();
+        
+        Ok(interfaces)
+    }
+    
+    pub fn create_tap(&self, tap_name: &str) -> Result<(), NetworkError> {
+        Self::create_tap_on_bridge(&self.bridge_name, tap_name)?;
+        if let Some(mbps) = self.rate_limit_mbps {
+            if let Err(e) = Self::set_rate_limit(tap_name, mbps) {
+                let _ = Self::remove_tap(tap_name);
+                return Err(e);
+            }
+        }
+        self.managed_taps.lock().unwrap().insert(tap_name.to_string());
+        Ok(())
+    }
+    
+    // Caps the tap at `mbps` both ways: a token bucket on its egress (what
+    // the guest receives) and a policer on its ingress (what the guest
+    // sends). Replaces any earlier limit; 0 removes it.
+    pub fn set_rate_limit(tap_name: &str, mbps: u32) -> Result<(), NetworkError> {
+        Self::clear_rate_limit(tap_name)?;
+        if mbps == 0 {
+            return Ok(());
+        }
+        
+        // tc's "bps" is bytes per second
+        let bytes_per_sec = mbps as u64 * 1_000_000 / 8;
+        let rate = format!("{}bps", bytes_per_sec);
+        // 100ms worth of traffic, but never less than one full frame
+        let burst = (bytes_per_sec / 10).max(1600).to_string();
+        
+        Self::tc(&[
+            "qdisc", "add", "dev", tap_name, "root", "tbf",
+            "rate", &rate, "burst", &burst, "latency", "50ms",
+        ])?;
+        Self::tc(&["qdisc", "add", "dev", tap_name, "handle", "ffff:", "ingress"])?;
+        Self::tc(&[
+            "filter", "add", "dev", tap_name, "parent", "ffff:", "protocol", "all",
+            "u32", "match", "u32", "0", "0",
+            "police", "rate", &rate, "burst", &burst, "drop", "flowid", ":1",
+        ])
+    }
+    
+    // Removes both qdiscs set_rate_limit adds; a tap without them is fine
+    pub fn clear_rate_limit(tap_name: &str) -> Result<(), NetworkError> {
+        if !Self::interface_exists(tap_name)? {
+            return Err(NetworkError::TapNotFound(tap_name.to_string()));
+        }
+        
+        for parent in ["root", "ingress"] {
+            let output = Command::new("tc")
+                .args(["qdisc", "del", "dev", tap_name, parent])
+                .output_within(CommandCategory::Network)?;
+            
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // What tc says when there's no such qdisc to delete
+            let absent = stderr.contains("handle of zero") || stderr.contains("No such file or directory")
+                || stderr.contains("Invalid handle") || stderr.contains("Cannot find specified qdisc");
+            if !output.status.success() && !absent {
+                return Err(NetworkError::CommandFailed(stderr.to_string()));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn tc(args: &[&str]) -> Result<(), NetworkError> {
+        let output = Command::new("tc")
+            .args(args)
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(())
+    }
+    
+    // First unused tap<id>[suffix] name for a VM, for callers that track the
+    // tap themselves (e.g. hot-plugged NICs)
+    pub fn unused_tap_name(vm_id: &str) -> Result<String, NetworkError> {
+        for attempt in 0..TAP_NAME_ATTEMPTS {
+            let tap_name = Self::tap_name_candidate(vm_id, attempt);
+            if !Self::interface_exists(&tap_name)? {
+                return Ok(tap_name);
+            }
+        }
+        
+        Err(NetworkError::TapExists(Self::tap_name_candidate(vm_id, 0)))
+    }
+    
+    // "tap" + first 8 alphanumerics of the VM id, then a numeric suffix on
+    // collision; always within MAX_INTERFACE_NAME_LEN
+    fn tap_name_candidate(vm_id: &str, attempt: u32) -> String {
+        let id: String = vm_id.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .take(8)
+            .collect::<String>()
+            .to_ascii_lowercase();
+        
+        let mut name = format!("tap{}", id);
+        if attempt > 0 {
+            name.push_str(&attempt.to_string());
+        }
+        name.truncate(MAX_INTERFACE_NAME_LEN);
+        name
+    }
+    
+    // The kernel rejects over-long names with a vague error, and ip(8) would
+    // treat some characters as syntax, so check up front
+    pub fn validate_interface_name(name: &str) -> Result<(), NetworkError> {
+        let invalid = |reason: &str| Err(NetworkError::InvalidInterfaceName(name.to_string(), reason.to_string()));
+        
+        if name.is_empty() {
+            return invalid("name is empty");
+        }
+        if name.len() > MAX_INTERFACE_NAME_LEN {
+            return invalid(&format!("longer than {} characters", MAX_INTERFACE_NAME_LEN));
+        }
+        if name == "." || name == ".." {
+            return invalid("reserved name");
+        }
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            return invalid("only letters, digits, '-', '_' and '.' are allowed");
+        }
+        
+        Ok(())
+    }
+    
+    // For bridges this manager doesn't own (e.g. NICs hot-plugged onto an
+    // existing host bridge)
+    pub fn create_tap_on_bridge(bridge_name: &str, tap_name: &str) -> Result<(), NetworkError> {
+        Self::validate_interface_name(bridge_name)?;
+        Self::validate_interface_name(tap_name)?;
+        
+        // Check if tap already exists
+        if Self::interface_exists(tap_name)? {
+            return Err(NetworkError::TapExists(tap_name.to_string()));
+        }
+        
+        // Create tap interface
+        let output = Command::new("ip")
+            .args(["tuntap", "add", tap_name, "mode", "tap"])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        // Set tap up
+        let output = Command::new("ip")
+            .args(["link", "set", tap_name, "up"])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        // Add tap to bridge
+        let output = Command::new("ip")
+            .args(["link", "set", tap_name, "master", bridge_name])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(())
+    }
+    
+    pub fn delete_tap(&self, tap_name: &str) -> Result<(), NetworkError> {
+        let result = Self::remove_tap(tap_name);
+        // Already gone is as good as deleted for tracking purposes
+        if matches!(result, Ok(()) | Err(NetworkError::TapNotFound(_))) {
+            self.managed_taps.lock().unwrap().remove(tap_name);
+        }
+        result
+    }
+    
+    pub fn remove_tap(tap_name: &str) -> Result<(), NetworkError> {
+        if !Self::interface_exists(tap_name)? {
+            return Err(NetworkError::TapNotFound(tap_name.to_string()));
+        }
+        
+        // Deleting the link would drop them too, but not if that fails below
+        let _ = Self::clear_rate_limit(tap_name);
+        
+        // Remove tap from bridge
+        let _ = Command::new("ip")
+            .args(["link", "set", tap_name, "nomaster"])
+            .output_within(CommandCategory::Network);
+        
+        // Set tap down
+        let _ = Command::new("ip")
+            .args(["link", "set", tap_name, "down"])
+            .output_within(CommandCategory::Network);
+        
+        // Delete tap
+        let output = Command::new("ip")
+            .args(["tuntap", "delete", tap_name, "mode", "tap"])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(())
+    }
+    
+    fn bridge_exists(&self) -> Result<bool, NetworkError> {
+        let output = Command::new("ip")
+            .args(["link", "show", &self.bridge_name])
+            .output_within(CommandCategory::Network)?;
+        
+        Ok(output.status.success())
+    }
+    
+    fn bridge_has_subnet(&self) -> Result<bool, NetworkError> {
+        let output = Command::new("ip")
+            .args(["-o", "-4", "addr", "show", "dev", &self.bridge_name])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        // "5: br0    inet 192.168.122.1/24 brd 192.168.122.255 scope global br0"
+        let output_str = String::from_utf8_lossy(&output.stdout);
+        let matches = output_str.lines()
+            .filter_map(|line| {
+                let mut tokens = line.split_whitespace();
+                tokens.find(|t| *t == "inet")?;
+                tokens.next()
+            })
+            .filter_map(|cidr| {
+                let (ip, prefix) = cidr.split_once('/')?;
+                Some((Ipv4Addr::from_str(ip).ok()?, prefix.parse::<u8>().ok()?))
+            })
+            .any(|(ip, prefix)| {
+                prefix == self.netmask && Self::is_in_subnet(&ip, &self.subnet, self.netmask)
+            });
+        
+        Ok(matches)
+    }
+    
+    fn interface_exists(name: &str) -> Result<bool, NetworkError> {
+        let output = Command::new("ip")
+            .args(["link", "show", name])
+            .output_within(CommandCategory::Network)?;
+        
+        Ok(output.status.success())
+    }
+    
+    fn setup_nat(&self) -> Result<(), NetworkError> {
+        // Enable IP forwarding
+        let _ = std::fs::write("/proc/sys/net/ipv4/ip_forward", "1");
+        
+        match self.firewall {
+            FirewallBackend::Iptables => self.setup_nat_iptables(),
+            FirewallBackend::Nftables => self.setup_nat_nftables(),
+        }
+    }
+    
+    fn cleanup_nat(&self) -> Result<(), NetworkError> {
+        match self.firewall {
+            FirewallBackend::Iptables => self.cleanup_nat_iptables(),
+            FirewallBackend::Nftables => self.cleanup_nat_nftables(),
+        }
+    }
+    
+    // (table, chain, rule spec) for every rule the bridge needs
+    fn iptables_rules(&self) -> Vec<(&'static str, &'static str, String)> {
+        vec![
+            // NAT rule
+            ("nat", "POSTROUTING", format!("-s {}/{} -j MASQUERADE", self.subnet, self.netmask)),
+            // Forwarding rules
+            ("filter", "FORWARD", format!("-i {} -j ACCEPT", self.bridge_name)),
+            ("filter", "FORWARD", format!("-o {} -j ACCEPT", self.bridge_name)),
+        ]
+    }
+    
+    fn iptables(&self, table: &str, op: &str, chain: &str, spec: &str) -> Result<std::process::Output, NetworkError> {
+        Ok(Command::new(&self.iptables)
+            .args(["-t", table, op, chain])
+            .args(spec.split_whitespace())
+            .output_within(CommandCategory::Network)?)
+    }
+    
+    fn setup_nat_iptables(&self) -> Result<(), NetworkError> {
+        for (table, chain, spec) in self.iptables_rules() {
+            // -C succeeds if the rule is already there, e.g. from before a restart
+            if self.iptables(table, "-C", chain, &spec)?.status.success() {
+                continue;
+            }
+            
+            let output = self.iptables(table, "-A", chain, &spec)?;
+            if !output.status.success() {
+                return Err(NetworkError::CommandFailed(
+                    String::from_utf8_lossy(&output.stderr).to_string()
+                ));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn cleanup_nat_iptables(&self) -> Result<(), NetworkError> {
+        // -D removes one copy per call, so keep going until -C stops matching;
+        // duplicates may be left over from versions that appended blindly
+        for (table, chain, spec) in self.iptables_rules() {
+            for _ in 0..MAX_DUPLICATE_RULES {
+                if !self.iptables(table, "-C", chain, &spec)?.status.success() {
+                    break;
+                }
+                
+                let output = self.iptables(table, "-D", chain, &spec)?;
+                if !output.status.success() {
+                    return Err(NetworkError::CommandFailed(
+                        String::from_utf8_lossy(&output.stderr).to_string()
+                    ));
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    // One table per bridge; nft identifiers don't allow '-'
+    fn nft_table(&self) -> String {
+        let name: String = self.bridge_name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("aegis_{}", name)
+    }
+    
+    fn setup_nat_nftables(&self) -> Result<(), NetworkError> {
+        let table = self.nft_table();
+        
+        // Declaring then deleting the table first makes the load replace any
+        // leftover copy; nft -f applies the whole file atomically. Note an
+        // accept here can't override a drop from another table's forward hook.
+        let ruleset = format!(
+            "table ip {table}\n\
+             delete table ip {table}\n\
+             table ip {table} {{\n\
+             \tchain postrouting {{\n\
+             \t\ttype nat hook postrouting priority 100; policy accept;\n\
+             \t\tip saddr {subnet}/{mask} masquerade\n\
+             \t}}\n\
+             \tchain forward {{\n\
+             \t\ttype filter hook forward priority 0; policy accept;\n\
+             \t\tiifname \"{bridge}\" accept\n\
+             \t\toifname \"{bridge}\" accept\n\
+             \t}}\n\
+             }}\n",
+            table = table,
+            subnet = self.subnet,
+            mask = self.netmask,
+            bridge = self.bridge_name,
+        );
+        
+        // output_within gives the child a null stdin, so load from a file
+        let path = std::env::temp_dir().join(format!("{}.nft", table));
+        std::fs::write(&path, ruleset)?;
+        let output = Command::new("nft")
+            .arg("-f")
+            .arg(&path)
+            .output_within(CommandCategory::Network);
+        let _ = std::fs::remove_file(&path);
+        let output = output?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(())
+    }
+    
+    fn cleanup_nat_nftables(&self) -> Result<(), NetworkError> {
+        let table = self.nft_table();
+        
+        let exists = Command::new("nft")
+            .args(["list", "table", "ip", &table])
+            .output_within(CommandCategory::Network)?;
+        if !exists.status.success() {
+            return Ok(());
+        }
+        
+        let output = Command::new("nft")
+            .args(["delete", "table", "ip", &table])
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(())
+    }
+    
+    fn setup_dhcp(&self) -> Result<(), NetworkError> {
+        let guest_dns: Vec<String> = self.guest_dns_servers().iter().map(|ip| ip.to_string()).collect();
+        
+        // Create dnsmasq configuration
+        let mut config = format!(
+            "interface={}\n\
+             bind-interfaces\n\
+             dhcp-range={},{}\n\
+             dhcp-option=option:router,{}\n\
+             dhcp-option=option:dns-server,{}\n",
+            self.bridge_name,
+            self.dhcp_start,
+            self.dhcp_end,
+            self.subnet,
+            guest_dns.join(",")
+        );
+        
+        if let Some(domain) = &self.dns.domain {
+            config.push_str(&format!(
+                "domain={}\nlocal=/{}/\ndhcp-option=option:domain-search,{}\n",
+                domain, domain, domain
+            ));
+        }
+        
+        // Without server= lines dnsmasq forwards per the host's resolv.conf
+        if let Some(upstream) = &self.dns.upstream {
+            config.push_str("no-resolv\n");
+            for server in upstream {
+                config.push_str(&format!("server={}\n", server));
+            }
+        }
+        
+        config.push_str("log-dhcp\nquiet-dhcp\n");
+        
+        let config_path = format!("/etc/dnsmasq.d/{}.conf", self.bridge_name);
+        std::fs::write(&config_path, config)?;
+        
+        // Start dnsmasq
+        let output = Command::new("systemctl")
+            .args(["restart", "dnsmasq"])
+            .output_within(CommandCategory::Service)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        Ok(())
+    }
+    
+    fn guest_dns_servers(&self) -> Vec<Ipv4Addr> {
+        // The bridge address is where dnsmasq listens
+        let local = vec![self.subnet];
+        
+        if self.dns.resolve_locally {
+            return local;
+        }
+        
+        match &self.dns.servers {
+            Some(servers) if !servers.is_empty() => servers.clone(),
+            Some(_) => local,
+            None => {
+                let host = host_nameservers();
+                if host.is_empty() { local } else { host }
+            }
+        }
+    }
+    
+    fn cleanup_dhcp(&self) -> Result<(), NetworkError> {
+        let config_path = format!("/etc/dnsmasq.d/{}.conf", self.bridge_name);
+        if std::path::Path::new(&config_path).exists() {
+            std::fs::remove_file(&config_path)?;
+            let _ = Command::new("systemctl")
+                .args(["restart", "dnsmasq"])
+                .output_within(CommandCategory::Service);
+        }
+        
+        Ok(())
+    }
+    
+    // All bridges on the host, sorted, for picking one to attach to
+    pub fn list_bridges() -> Result<Vec<String>, NetworkError> {
+        let bridges: BTreeSet<String> = Self::ip_links(&["type", "bridge"])?
+            .into_iter()
+            .filter(|link| link.kind() == Some("bridge"))
+            .map(|link| link.ifname)
+            .collect();
+        
+        Ok(bridges.into_iter().collect())
+    }
+    
+    // Taps this manager created that still exist, sorted. Taps that vanished
+    // underneath us are dropped from the managed set.
+    pub fn list_taps(&self) -> Result<Vec<String>, NetworkError> {
+        Ok(self.managed_taps_among(Self::ip_links(&[])?))
+    }
+    
+    fn managed_taps_among(&self, links: Vec<IpLink>) -> Vec<String> {
+        let present: BTreeSet<String> = links
+            .into_iter()
+            .filter(|link| link.is_tap())
+            .map(|link| link.ifname)
+            .collect();
+        
+        let mut managed = self.managed_taps.lock().unwrap();
+        managed.retain(|tap| present.contains(tap));
+        
+        managed.iter().cloned().collect()
+    }
+    
+    // `ip -j -d link show <filter>`. JSON keeps names like "tap0@if5" and
+    // odd bytes intact where the text format needs fragile splitting, and
+    // -d adds the linkinfo the callers filter on.
+    fn ip_links(filter: &[&str]) -> Result<Vec<IpLink>, NetworkError> {
+        let mut args = vec!["-d", "link", "show"];
+        args.extend_from_slice(filter);
+        Self::ip_json(&args)
+    }
+    
+    // `ip -j <args>`, parsed
+    fn ip_json<T: DeserializeOwned>(args: &[&str]) -> Result<Vec<T>, NetworkError> {
+        let output = Command::new("ip")
+            .arg("-j")
+            .args(args)
+            .output_within(CommandCategory::Network)?;
+        
+        if !output.status.success() {
+            return Err(NetworkError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).to_string()
+            ));
+        }
+        
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if stdout.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        
+        serde_json::from_str(&stdout)
+            .map_err(|e| NetworkError::CommandFailed(format!("Unexpected `ip -j {}` output: {}", args.join(" "), e)))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::Path;
+
+    fn invalid(start: &str, end: &str) -> bool {
+        matches!(
+            NetworkManager::new("aegis-test0", "192.168.150.1/24", start, end),
+            Err(NetworkError::InvalidSubnet(_))
+        )
+    }
+
+    #[test]
+    fn parse_cidr_accepts_valid_networks() {
+        assert_eq!(parse_cidr("192.168.122.1/24").unwrap(), (Ipv4Addr::new(192, 168, 122, 1), 24));
+        assert_eq!(parse_cidr(" 10.0.0.0/8 ").unwrap(), (Ipv4Addr::new(10, 0, 0, 0), 8));
+        assert_eq!(parse_cidr("0.0.0.0/0").unwrap().1, 0);
+        assert_eq!(parse_cidr("255.255.255.255/32").unwrap().1, 32);
+    }
+
+    #[test]
+    fn parse_cidr_rejects_out_of_range_octets() {
+        for cidr in ["999.0.0.0/24", "192.168.256.1/24", "192.168.1/24", "192.168.01.1/24", "1.2.3.4.5/24"] {
+            assert!(matches!(parse_cidr(cidr), Err(NetworkError::InvalidSubnet(_))), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn parse_cidr_rejects_bad_prefixes() {
+        for cidr in ["10.0.0.0/33", "10.0.0.0/99", "10.0.0.0/", "10.0.0.0/-1", "10.0.0.0/+8", "10.0.0.0"] {
+            assert!(matches!(parse_cidr(cidr), Err(NetworkError::InvalidSubnet(_))), "{}", cidr);
+        }
+    }
+
+    // iptables -t <table> <-C|-A|-D> <chain> <spec>, over a file holding one
+    // "<table> <chain> <spec>" line per installed rule
+    fn fake_iptables(dir: &Path) -> (PathBuf, PathBuf) {
+        let rules = dir.join("rules");
+        let script = dir.join("iptables");
+        std::fs::write(&rules, "").unwrap();
+        std::fs::write(&script, format!(
+            "#!/bin/sh\n\
+             rules='{}'\n\
+             table=$2; op=$3; chain=$4; shift 4\n\
+             rule=\"$table $chain $*\"\n\
+             case $op in\n\
+             -C) grep -qxF \"$rule\" \"$rules\" ;;\n\
+             -A) echo \"$rule\" >> \"$rules\" ;;\n\
+             -D) grep -qxF \"$rule\" \"$rules\" || exit 1\n\
+                 awk -v r=\"$rule\" '!d && $0 == r {{ d = 1; next }} {{ print }}' \"$rules\" > \"$rules.new\"\n\
+                 mv \"$rules.new\" \"$rules\" ;;\n\
+             esac\n",
+            rules.display()
+        )).unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        (script, rules)
+    }
+
+    fn rule_count(rules: &Path) -> usize {
+        std::fs::read_to_string(rules).unwrap().lines().count()
+    }
+
+    #[test]
+    fn iptables_rules_are_not_duplicated_across_recreates() {
+        let dir = std::env::temp_dir().join(format!("aegis-iptables-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (script, rules) = fake_iptables(&dir);
+        let mut network = NetworkManager::new("aegis-test0", "192.168.150.1/24", "192.168.150.2", "192.168.150.254").unwrap();
+        network.iptables = script;
+
+        for _ in 0..3 {
+            network.setup_nat_iptables().unwrap();
+            assert_eq!(rule_count(&rules), 3);
+        }
+        for _ in 0..3 {
+            network.setup_nat_iptables().unwrap();
+            network.cleanup_nat_iptables().unwrap();
+            assert_eq!(rule_count(&rules), 0);
+        }
+
+        // Leftovers from versions that appended blindly all go
+        let installed = std::fs::read_to_string(&rules).unwrap();
+        network.setup_nat_iptables().unwrap();
+        let once = std::fs::read_to_string(&rules).unwrap();
+        std::fs::write(&rules, format!("{}{}{}", installed, once, once)).unwrap();
+        network.cleanup_nat_iptables().unwrap();
+        assert_eq!(rule_count(&rules), 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn list_taps_reports_only_managed_taps() {
+        let network = NetworkManager::new("aegis-test0", "192.168.150.1/24", "192.168.150.2", "192.168.150.254").unwrap();
+        network.managed_taps.lock().unwrap()
+            .extend(["tapvm2", "tapvm1", "tapgone"].map(String::from));
+
+        let tap = |name: &str| json!({ "ifname": name, "linkinfo": { "info_kind": "tun", "info_data": { "type": "tap" } } });
+        let links: Vec<IpLink> = serde_json::from_value(json!([
+            tap("tapvm2"),
+            tap("tapvm1"),
+            tap("tapvm1"),
+            // Someone else's
+            tap("tap0"),
+            tap("vnet3"),
+            { "ifname": "tapveth", "linkinfo": { "info_kind": "veth" } },
+            { "ifname": "lo" },
+        ])).unwrap();
+
+        assert_eq!(network.managed_taps_among(links), vec!["tapvm1", "tapvm2"]);
+        // A managed tap that disappeared is forgotten
+        assert!(!network.managed_taps.lock().unwrap().contains("tapgone"));
+    }
+
+    #[test]
+    fn subnet_masks_at_the_edges_dont_overflow() {
+        assert_eq!(NetworkManager::mask_bits(0), 0);
+        assert_eq!(NetworkManager::mask_bits(24), 0xffff_ff00);
+        assert_eq!(NetworkManager::mask_bits(32), u32::MAX);
+
+        let gateway = Ipv4Addr::new(10, 1, 2, 3);
+        assert!(NetworkManager::is_in_subnet(&Ipv4Addr::new(172, 16, 0, 1), &gateway, 0));
+        assert!(NetworkManager::is_in_subnet(&gateway, &gateway, 32));
+        assert!(!NetworkManager::is_in_subnet(&Ipv4Addr::new(10, 1, 2, 4), &gateway, 32));
+        assert_eq!(NetworkManager::network_bounds(&gateway, 32), (u32::from(gateway), u32::from(gateway)));
+    }
+
+    #[test]
+    fn zero_and_out_of_range_prefixes_are_rejected() {
+        for cidr in ["10.0.0.1/0", "10.0.0.1/33", "10.0.0.1/255"] {
+            assert!(
+                matches!(NetworkManager::new("aegis-test0", cidr, "10.0.0.2", "10.0.0.9"), Err(NetworkError::InvalidSubnet(_))),
+                "{}", cidr
+            );
+        }
+        // A /32 has no room for a pool besides its one address
+        assert!(matches!(
+            NetworkManager::new("aegis-test0", "10.0.0.1/32", "10.0.0.1", "10.0.0.1"),
+            Err(NetworkError::InvalidSubnet(_))
+        ));
+    }
+
+    #[test]
+    fn dhcp_range_counts_its_leases() {
+        let network = NetworkManager::new("aegis-test0", "192.168.150.1/24", "192.168.150.2", "192.168.150.254").unwrap();
+        assert_eq!(network.dhcp_lease_count(), 253);
+        let single = NetworkManager::new("aegis-test0", "192.168.150.1/24", "192.168.150.9", "192.168.150.9").unwrap();
+        assert_eq!(single.dhcp_lease_count(), 1);
+    }
+
+    #[test]
+    fn reversed_dhcp_range_is_rejected() {
+        assert!(invalid("192.168.150.200", "192.168.150.100"));
+    }
+
+    #[test]
+    fn dhcp_range_overlapping_reserved_addresses_is_rejected() {
+        // gateway, network and broadcast
+        assert!(invalid("192.168.150.1", "192.168.150.100"));
+        assert!(invalid("192.168.150.0", "192.168.150.0"));
+        assert!(invalid("192.168.150.100", "192.168.150.255"));
+    }
+
+    #[test]
+    fn dhcp_range_outside_the_subnet_is_rejected() {
+        assert!(invalid("192.168.151.2", "192.168.151.100"));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::watch;
+
+// Finished operations stay queryable this long, so a client that subscribes
+// after the fact still sees the outcome
+const FINISHED_RETENTION: Duration = Duration::from_secs(600);
+// How often an operation with no real progress to report says it's alive
+pub const PROGRESS_TICK: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum OperationState {
+    Running,
+    Completed { result: Value },
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OperationProgress {
+    pub op_id: String,
+    pub kind: String,
+    // None while the underlying tool gives no percentage
+    pub percent: Option<f32>,
+    pub message: String,
+    #[serde(flatten)]
+    pub state: OperationState,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl OperationProgress {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.state, OperationState::Running)
+    }
+}
+
+// Progress of long-running work, one watch channel per operation: a
+// subscriber always starts from the latest state and never sees a backlog
+#[derive(Default)]
+pub struct Operations {
+    ops: Mutex<HashMap<String, Arc<watch::Sender<OperationProgress>>>>,
+}
+
+impl Operations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn begin(&self, kind: &str, message: &str) -> OperationHandle {
+        let op_id = uuid::Uuid::new_v4().to_string();
+        let (sender, _) = watch::channel(OperationProgress {
+            op_id: op_id.clone(),
+            kind: kind.to_string(),
+            percent: None,
+            message: message.to_string(),
+            state: OperationState::Running,
+            updated_at: chrono::Utc::now(),
+        });
+        let sender = Arc::new(sender);
+
+        let mut ops = self.ops.lock().unwrap();
+        Self::prune(&mut ops);
+        ops.insert(op_id.clone(), sender.clone());
+
+        OperationHandle { op_id, sender }
+    }
+
+    pub fn get(&self, op_id: &str) -> Option<OperationProgress> {
+        self.ops.lock().unwrap().get(op_id).map(|sender| sender.borrow().clone())
+    }
+
+    pub fn subscribe(&self, op_id: &str) -> Option<watch::Receiver<OperationProgress>> {
+        self.ops.lock().unwrap().get(op_id).map(|sender| sender.subscribe())
+    }
+
+    fn prune(ops: &mut HashMap<String, Arc<watch::Sender<OperationProgress>>>) {
+        let now = chrono::Utc::now();
+        ops.retain(|_, sender| {
+            let progress = sender.borrow();
+            !progress.is_finished()
+                || (now - progress.updated_at).to_std().unwrap_or_default() < FINISHED_RETENTION
+        });
+    }
+}
+
+#[derive(Clone)]
+pub struct OperationHandle {
+    op_id: String,
+    sender: Arc<watch::Sender<OperationProgress>>,
+}
+
+impl OperationHandle {
+    pub fn id(&self) -> &str {
+        &self.op_id
+    }
+
+    // Re-sends the current state so subscribers can tell the work isn't stuck
+    pub fn tick(&self) {
+        self.update(|_| {});
+    }
+
+    pub fn complete(&self, result: Value) {
+        self.update(|progress| {
+            progress.percent = Some(100.0);
+            progress.message = "Done".to_string();
+            progress.state = OperationState::Completed { result };
+        });
+    }
+
+    pub fn fail(&self, error: &str) {
+        self.update(|progress| {
+            progress.message = "Failed".to_string();
+            progress.state = OperationState::Failed { error: error.to_string() };
+        });
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.sender.borrow().is_finished()
+    }
+
+    // Ticks every PROGRESS_TICK until the operation finishes
+    pub fn spawn_ticker(&self) -> tokio::task::JoinHandle<()> {
+        let handle = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PROGRESS_TICK);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if handle.is_finished() {
+                    break;
+                }
+                handle.tick();
+            }
+        })
+    }
+
+    fn update<F: FnOnce(&mut OperationProgress)>(&self, f: F) {
+        self.sender.send_modify(|progress| {
+            // Nothing moves an operation out of a finished state
+            if progress.is_finished() {
+                return;
+            }
+            f(progress);
+            progress.updated_at = chrono::Utc::now();
+        });
+    }
+}
\ No newline at end of file
@@ -1,211 +1,1126 @@
-use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::time::{Duration, Instant};
-use tokio::process;
-use tokio::time;
-
-use crate::security::sandbox::VMSandbox;
-use super::config::VMConfig;
-
-#[derive(Debug, thiserror::Error)]
-pub enum QemuError {
-    #[error("Failed to start QEMU: {0}")]
-    StartFailed(String),
-    #[error("QEMU process not running")]
-    NotRunning,
-    #[error("IO error: {0}")]
-    IoError(#[from] std::io::Error),
-    #[error("Timeout waiting for QEMU")]
-    Timeout,
-}
-
-pub struct QemuProcess {
-    pid: u32,
-    start_time: Instant,
-    child: process::Child,
-    config: VMConfig,
-}
-
-impl QemuProcess {
-    pub async fn start(
-        config: &VMConfig,
-        disk_path: &Path,
-        sandbox: VMSandbox,
-    ) -> Result<Self, QemuError> {
-        // Build QEMU command
-        let mut cmd = Command::new("qemu-system-x86_64");
-        
-        // Apply sandbox if configured
-        // Note: In production, this would involve more sophisticated sandboxing
-        
-        // Basic QEMU arguments
-        cmd.arg("-enable-kvm")
-            .arg("-cpu").arg(&config.cpu_type)
-            .arg("-smp").arg(config.cpu_cores.to_string())
-            .arg("-m").arg(format!("{}M", config.memory_mb))
-            .arg("-drive").arg(format!("file={},format={}", 
-                disk_path.display(), 
-                match config.disk_format {
-                    super::config::DiskFormat::Qcow2 => "qcow2",
-                    super::config::DiskFormat::Raw => "raw",
-                    super::config::DiskFormat::Vdi => "vdi",
-                    super::config::DiskFormat::Vmdk => "vmdk",
-                }))
-            .arg("-cdrom").arg(&config.iso_path)
-            .arg("-boot").arg("d")
-            .arg("-vnc").arg(format!(":{}", config.vnc_port - 5900))
-            .arg("-daemonize")
-            .arg("-pidfile").arg(format!("/tmp/qemu-{}.pid", config.id));
-        
-        // Add VNC password if set
-        if let Some(password) = &config.vnc_password {
-            cmd.arg("-vnc").arg(format!(":{}", config.vnc_port - 5900));
-            // Note: Real password handling would use -password option
-        }
-        
-        // Add machine type
-        cmd.arg("-machine").arg(&config.machine_type);
-        
-        // Add network
-        match &config.network_type {
-            super::config::NetworkType::User => {
-                cmd.arg("-netdev").arg("user,id=net0")
-                    .arg("-device").arg("virtio-net-pci,netdev=net0");
-            }
-            super::config::NetworkType::Tap(tap) => {
-                cmd.arg("-netdev").arg(format!("tap,id=net0,ifname={}", tap))
-                    .arg("-device").arg("virtio-net-pci,netdev=net0");
-            }
-            super::config::NetworkType::Bridge(bridge) => {
-                cmd.arg("-netdev").arg(format!("bridge,id=net0,br={}", bridge))
-                    .arg("-device").arg("virtio-net-pci,netdev=net0");
-            }
-            super::config::NetworkType::None => {
-                // No network
-            }
-        }
-        
-        // Add BIOS
-        match &config.bios {
-            super::config::BiosType::SeaBios => {
-                // Default, nothing to add
-            }
-            super::config::BiosType::Ovmf => {
-                cmd.arg("-bios").arg("/usr/share/OVMF/OVMF_CODE.fd");
-            }
-            super::config::BiosType::Custom(path) => {
-                cmd.arg("-bios").arg(path);
-            }
-        }
-        
-        // Add extra arguments
-        for arg in &config.extra_args {
-            cmd.arg(arg);
-        }
-        
-        // Redirect output to log file
-        let log_path = format!("/var/lib/vm-manager/logs/qemu-{}.log", config.id);
-        let log_file = std::fs::File::create(&log_path)
-            .map_err(|e| QemuError::IoError(e))?;
-        
-        cmd.stdout(Stdio::from(log_file.try_clone()?))
-            .stderr(Stdio::from(log_file));
-        
-        // Start QEMU process
-        let mut child = process::Command::from(cmd)
-            .spawn()
-            .map_err(|e| QemuError::StartFailed(e.to_string()))?;
-        
-        let pid = child.id()
-            .ok_or_else(|| QemuError::StartFailed("Failed to get PID".to_string()))?;
-        
-        // Wait for process to start
-        time::sleep(Duration::from_secs(2)).await;
-        
-        // Check if process is still running
-        match child.try_wait() {
-            Ok(Some(status)) => {
-                return Err(QemuError::StartFailed(
-                    format!("QEMU exited with status: {}", status)
-                ));
-            }
-            Ok(None) => {
-                // Process is still running, good
-            }
-            Err(e) => {
-                return Err(QemuError::StartFailed(e.to_string()));
-            }
-        }
-        
-        Ok(Self {
-            pid,
-            start_time: Instant::now(),
-            child,
-            config: config.clone(),
-        })
-    }
-    
-    pub async fn stop(&mut self) -> Result<(), QemuError> {
-        // Send SIGTERM
-        self.child.start_kill()
-            .map_err(|e| QemuError::IoError(e))?;
-        
-        // Wait for process to terminate
-        match time::timeout(Duration::from_secs(10), self.child.wait()).await {
-            Ok(Ok(_)) => Ok(()),
-            Ok(Err(e)) => Err(QemuError::IoError(e)),
-            Err(_) => {
-                // Force kill if timeout
-                let _ = self.child.kill().await;
-                Err(QemuError::Timeout)
-            }
-        }
-    }
-    
-    pub async fn is_running(&mut self) -> bool {
-        match self.child.try_wait() {
-            Ok(Some(_)) => false,
-            Ok(None) => true,
-            Err(_) => false,
-        }
-    }
-    
-    pub async fn get_status(&mut self) -> Result<ProcessStatus, QemuError> {
-        if !self.is_running().await {
-            return Err(QemuError::NotRunning);
-        }
-        
-        // Get process info using sysinfo crate
-        use sysinfo::{ProcessRefreshKind, RefreshKind, System};
-        
-        let mut system = System::new_with_specifics(
-            RefreshKind::new().with_processes(ProcessRefreshKind::new()),
-        );
-        system.refresh_processes();
-        
-        if let Some(process) = system.process(sysinfo::Pid::from(self.pid as usize)) {
-            Ok(ProcessStatus {
-                cpu_usage: process.cpu_usage(),
-                memory_mb: process.memory() / 1024 / 1024,
-                uptime_seconds: self.start_time.elapsed().as_secs(),
-            })
-        } else {
-            Err(QemuError::NotRunning)
-        }
-    }
-    
-    pub fn pid(&self) -> u32 {
-        self.pid
-    }
-    
-    pub fn config(&self) -> &VMConfig {
-        &self.config
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct ProcessStatus {
-    pub cpu_usage: f32,
-    pub memory_mb: u64,
-    pub uptime_seconds: u64,
+use std::net::SocketAddr;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::process;
+use tokio::time;
+
+use crate::security::isolation::VMSandbox;
+use crate::storage::disks::{secret_object, KEY_SECRET_ID};
+use crate::utils::command::{CommandCategory, CommandTimeoutExt};
+use super::config::{Accelerator, DiskBus, GuestArch, VMConfig};
+
+#[derive(Debug, thiserror::Error)]
+pub enum QemuError {
+    #[error("Failed to start QEMU: {0}")]
+    StartFailed(String),
+    #[error("QEMU process not running")]
+    NotRunning,
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Timeout waiting for QEMU")]
+    Timeout,
+    #[error("QMP error: {0}")]
+    Qmp(String),
+    // QEMU understood the command and refused it
+    #[error("QMP command '{command}' failed ({class}): {desc}")]
+    QmpCommand { command: String, class: String, desc: String },
+    #[error("Cannot access /dev/kvm: {0}")]
+    KvmPermission(String),
+    // The VM asked for KVM explicitly, so there's no falling back to TCG
+    #[error("KVM was requested but is unavailable: {0}")]
+    KvmUnavailable(String),
+    #[error("{feature} requires QEMU >= {min_version} (installed: {found})")]
+    Unsupported { feature: String, min_version: &'static str, found: String },
+}
+
+pub const DEFAULT_STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Options start() always sets (or sets for some configs, or can't have, like
+// -daemonize), plus their aliases. Repeating one in extra_args makes QEMU
+// fail or quietly pick one.
+pub const MANAGED_FLAGS: &[&str] = &[
+    "-enable-kvm", "-accel", "-cpu", "-smp", "-m", "-vnc", "-daemonize", "-pidfile",
+    "-drive", "-hda", "-cdrom", "-boot", "-qmp", "-serial", "-machine", "-M", "-bios", "-incoming",
+];
+
+// First extra_args entry naming a managed option
+pub fn managed_flag(extra_args: &[String]) -> Option<&str> {
+    extra_args.iter()
+        .map(String::as_str)
+        .find(|arg| {
+            // QEMU takes --opt as well as -opt
+            let flag = arg.strip_prefix('-').filter(|rest| rest.starts_with('-')).unwrap_or(arg);
+            MANAGED_FLAGS.contains(&flag)
+        })
+}
+
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).map(|dir| dir.join(program)).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .find(|candidate| candidate.is_file())
+}
+
+// In a PID namespace the spawned process is the sandbox's supervisor, and
+// QEMU its grandchild: supervisor -> namespace init -> QEMU
+pub(crate) fn namespaced_qemu_pid(supervisor: u32) -> Option<u32> {
+    let first_child = |pid: u32| {
+        std::fs::read_to_string(format!("/proc/{0}/task/{0}/children", pid)).ok()?
+            .split_whitespace()
+            .next()?
+            .parse::<u32>()
+            .ok()
+    };
+    first_child(supervisor).and_then(first_child)
+}
+
+pub fn qemu_log_path(vm_id: &str) -> PathBuf {
+    PathBuf::from(format!("/var/lib/vm-manager/logs/qemu-{}.log", vm_id))
+}
+
+// Last `lines` lines of the VM's QEMU stdout/stderr; empty if there's no log
+pub fn qemu_log_tail(vm_id: &str, lines: usize) -> Vec<String> {
+    use std::io::{Read, Seek, SeekFrom};
+    
+    // Plenty for a tail; keeps a runaway log from being read whole
+    const MAX_TAIL_BYTES: u64 = 64 * 1024;
+    
+    let mut file = match std::fs::File::open(qemu_log_path(vm_id)) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let mut buf = Vec::new();
+    if file.seek(SeekFrom::Start(len.saturating_sub(MAX_TAIL_BYTES))).is_err()
+        || file.read_to_end(&mut buf).is_err()
+    {
+        return Vec::new();
+    }
+    
+    let text = String::from_utf8_lossy(&buf);
+    let all: Vec<&str> = text.lines().collect();
+    all[all.len().saturating_sub(lines)..].iter().map(|line| line.to_string()).collect()
+}
+
+pub fn qmp_socket_path(vm_id: &str) -> PathBuf {
+    PathBuf::from(format!("/tmp/qmp-{}.sock", vm_id))
+}
+
+// Created by QEMU when the VM starts and removed again on stop
+pub fn serial_socket_path(vm_id: &str) -> PathBuf {
+    PathBuf::from(format!("/tmp/console-{}.sock", vm_id))
+}
+
+// Where the guest's first serial port is exposed on the host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SerialConsole {
+    // serial_socket_path, for local clients such as socat or minicom
+    #[default]
+    Unix,
+    // A raw TCP listener for remote terminal clients. QEMU does no
+    // authentication on it, so bind it somewhere trusted.
+    Tcp(SocketAddr),
+}
+
+// How devices get wired up for a machine type. q35's PCIe root bus can't
+// hot-plug, so there everything hangs off pcie-root-ports instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineLayout {
+    // i440fx: legacy IDE, devices straight on pci.0
+    Pc,
+    Q35,
+    // aarch64/riscv64 `virt`: PCIe like q35, but no IDE at all, so the
+    // CD-ROM goes on virtio-scsi
+    Virt,
+}
+
+// Spare root ports on q35 and virt for NICs hot-plugged at runtime
+pub const Q35_HOTPLUG_PORTS: usize = 4;
+
+impl MachineLayout {
+    pub fn for_machine(machine: &str) -> Self {
+        // "q35" or a versioned "pc-q35-8.2"
+        if machine == "q35" || machine.contains("-q35") {
+            MachineLayout::Q35
+        } else if machine == "virt" || machine.starts_with("virt-") {
+            // "virt" or a versioned "virt-8.2"
+            MachineLayout::Virt
+        } else {
+            MachineLayout::Pc
+        }
+    }
+    
+    // Devices sit behind pcie-root-ports
+    pub fn is_pcie(&self) -> bool {
+        matches!(self, MachineLayout::Q35 | MachineLayout::Virt)
+    }
+    
+    pub fn hotplug_port(index: usize) -> String {
+        format!("hp{}", index)
+    }
+    
+    // One per NIC from the command line
+    pub fn nic_port(index: usize) -> String {
+        format!("rp-net{}", index)
+    }
+    
+    // Drive behind the CD-ROM: QEMU's name for -cdrom on pc, ours elsewhere
+    pub fn cdrom_drive(&self) -> &'static str {
+        match self {
+            MachineLayout::Pc => "ide1-cd0",
+            MachineLayout::Q35 | MachineLayout::Virt => "cd0",
+        }
+    }
+}
+
+// What the installed QEMU can do, so options can be gated on it instead of
+// assuming the newest release
+#[derive(Debug, Clone, Serialize)]
+pub struct QemuCaps {
+    // (major, minor, micro) from --version
+    pub version: (u32, u32, u32),
+    pub machines: Vec<String>,
+    pub cpus: Vec<String>,
+    pub accels: Vec<String>,
+    pub devices: Vec<String>,
+    // -display backends; empty on builds too old to list them
+    pub displays: Vec<String>,
+    // Command-line options from -help, with their leading dash
+    pub options: Vec<String>,
+    pub supports_qmp: bool,
+    pub supports_q35: bool,
+}
+
+impl QemuCaps {
+    pub fn at_least(&self, major: u32, minor: u32) -> bool {
+        (self.version.0, self.version.1) >= (major, minor)
+    }
+    
+    pub fn has_device(&self, name: &str) -> bool {
+        self.devices.iter().any(|device| device == name)
+    }
+    
+    pub fn has_option(&self, name: &str) -> bool {
+        self.options.iter().any(|option| option == name)
+    }
+    
+    pub fn version_string(&self) -> String {
+        format!("{}.{}.{}", self.version.0, self.version.1, self.version.2)
+    }
+    
+    // Turns a missing capability into an error naming the release that added it
+    pub fn require(&self, available: bool, feature: &str, min_version: &'static str) -> Result<(), QemuError> {
+        if available {
+            return Ok(());
+        }
+        Err(QemuError::Unsupported {
+            feature: feature.to_string(),
+            min_version,
+            found: self.version_string(),
+        })
+    }
+}
+
+// One per GuestArch, indexed by its discriminant
+static QEMU_CAPS: [OnceLock<Option<QemuCaps>>; 3] = [OnceLock::new(), OnceLock::new(), OnceLock::new()];
+
+// The default (x86_64) binary's
+pub fn qemu_caps() -> Option<&'static QemuCaps> {
+    qemu_caps_for(GuestArch::default())
+}
+
+// Probed once per arch and cached. None when the binary can't be run, in
+// which case callers skip their checks and pass options through as before.
+pub fn qemu_caps_for(arch: GuestArch) -> Option<&'static QemuCaps> {
+    QEMU_CAPS[arch as usize].get_or_init(|| probe_qemu_caps(arch)).as_ref()
+}
+
+fn probe_qemu_caps(arch: GuestArch) -> Option<QemuCaps> {
+    let version = parse_version(&run_qemu(arch, &["--version"])?)?;
+    let machines = parse_machine_help(&run_help(arch, "-machine")?);
+    let cpus = parse_cpu_help(&run_help(arch, "-cpu")?);
+    
+    // -accel help and -display help only exist on newer QEMU; treat them as optional
+    let accels = run_help(arch, "-accel").map(|out| parse_accel_help(&out)).unwrap_or_default();
+    let displays = run_help(arch, "-display").map(|out| parse_display_help(&out)).unwrap_or_default();
+    let devices = run_help(arch, "-device").map(|out| parse_device_help(&out)).unwrap_or_default();
+    let options = run_qemu(arch, &["-help"]).map(|out| parse_options(&out)).unwrap_or_default();
+    
+    let caps = QemuCaps {
+        version,
+        supports_qmp: options.iter().any(|option| option == "-qmp"),
+        supports_q35: machines.iter().any(|machine| MachineLayout::for_machine(machine) == MachineLayout::Q35),
+        machines,
+        cpus,
+        accels,
+        devices,
+        displays,
+        options,
+    };
+    
+    log::info!(
+        "QEMU {} ({}) supports {} machine types, {} CPU models and {} devices",
+        caps.version_string(), arch.as_str(), caps.machines.len(), caps.cpus.len(), caps.devices.len()
+    );
+    Some(caps)
+}
+
+fn run_help(arch: GuestArch, flag: &str) -> Option<String> {
+    run_qemu(arch, &[flag, "help"])
+}
+
+// stdout and stderr together: older releases print some listings to stderr
+fn run_qemu(arch: GuestArch, args: &[&str]) -> Option<String> {
+    let binary = arch.binary();
+    let command_line = args.join(" ");
+    match Command::new(&binary).args(args).output_within(CommandCategory::Service) {
+        Ok(output) if output.status.success() => Some(format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )),
+        Ok(output) => {
+            log::warn!("{} {} failed: {}", binary, command_line, String::from_utf8_lossy(&output.stderr));
+            None
+        }
+        Err(e) => {
+            log::warn!("Could not run {} {}: {}", binary, command_line, e);
+            None
+        }
+    }
+}
+
+fn parse_version(output: &str) -> Option<(u32, u32, u32)> {
+    // "QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)"
+    let version = output.split("version ").nth(1)?.split_whitespace().next()?;
+    let mut parts = version.split('.').map(|part| {
+        part.chars().take_while(|c| c.is_ascii_digit()).collect::<String>().parse::<u32>().ok()
+    });
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    let micro = parts.next().flatten().unwrap_or(0);
+    Some((major, minor, micro))
+}
+
+fn parse_device_help(output: &str) -> Vec<String> {
+    // Grouped listing of `name "virtio-net-pci", bus PCI, desc "..."` lines
+    output.lines()
+        .filter_map(|line| line.trim().strip_prefix("name \""))
+        .filter_map(|rest| rest.split('"').next())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn parse_display_help(output: &str) -> Vec<String> {
+    // "Available display backend types:" followed by one name per line
+    output.lines()
+        .skip_while(|line| !line.starts_with("Available display"))
+        .skip(1)
+        .map(|line| line.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+fn parse_options(output: &str) -> Vec<String> {
+    // Each option's usage line starts at column 0: "-m [size=]megs[,slots=n]"
+    output.lines()
+        .filter(|line| line.starts_with('-'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|option| option.to_string())
+        .collect()
+}
+
+fn parse_machine_help(output: &str) -> Vec<String> {
+    // "Supported machines are:" followed by "<name>   <description>" lines
+    output.lines()
+        .skip_while(|line| !line.starts_with("Supported machines"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn parse_accel_help(output: &str) -> Vec<String> {
+    // "Accelerators supported in QEMU binary:" followed by one name per line
+    output.lines()
+        .skip_while(|line| !line.starts_with("Accelerators"))
+        .skip(1)
+        .map(|line| line.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// Catches the common "user isn't in the kvm group" setup before QEMU buries
+// the same failure in its log file
+pub fn check_kvm_access() -> Result<(), QemuError> {
+    const KVM_DEVICE: &str = "/dev/kvm";
+    
+    let metadata = match std::fs::metadata(KVM_DEVICE) {
+        Ok(metadata) => metadata,
+        Err(e) => return Err(QemuError::KvmPermission(format!(
+            "{} ({}). Load the kvm_intel/kvm_amd module and enable virtualization in the BIOS. {}",
+            KVM_DEVICE, e, tcg_hint()
+        ))),
+    };
+    
+    match std::fs::OpenOptions::new().read(true).write(true).open(KVM_DEVICE) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            use std::os::unix::fs::MetadataExt;
+            
+            let group = group_name(metadata.gid()).unwrap_or_else(|| metadata.gid().to_string());
+            let uid = unsafe { libc::getuid() };
+            Err(QemuError::KvmPermission(format!(
+                "uid {} cannot open {} read/write (owned by group '{}'). \
+                 Add the service user to that group (`usermod -aG {} <user>`) and restart the service. {}",
+                uid, KVM_DEVICE, group, group, tcg_hint()
+            )))
+        }
+        Err(e) => Err(QemuError::KvmPermission(format!("{}: {}. {}", KVM_DEVICE, e, tcg_hint()))),
+    }
+}
+
+// "vmx" (VT-x) or "svm" (AMD-V). Only x86 lists these in /proc/cpuinfo;
+// elsewhere /dev/kvm is the only signal.
+fn cpu_virtualization() -> Option<&'static str> {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+    let flags = cpuinfo.lines().find(|line| line.starts_with("flags"))?;
+    flags.split_whitespace().find_map(|flag| match flag {
+        "vmx" => Some("vmx"),
+        "svm" => Some("svm"),
+        _ => None,
+    })
+}
+
+// Whether guests may run KVM themselves
+fn nested_virtualization() -> bool {
+    ["/sys/module/kvm_intel/parameters/nested", "/sys/module/kvm_amd/parameters/nested"].iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .any(|value| matches!(value.trim(), "Y" | "1"))
+}
+
+// Why native guests can't use KVM right now, if they can't. Not cached:
+// modules get loaded and group memberships fixed while the service runs.
+pub fn kvm_unavailable_reason() -> Option<String> {
+    if std::env::consts::ARCH == "x86_64" && cpu_virtualization().is_none() {
+        return Some("the CPU doesn't advertise VT-x/AMD-V; enable virtualization in the BIOS".to_string());
+    }
+    check_kvm_access().err().map(|e| e.to_string())
+}
+
+// KVM or TCG for one start. Auto falls back to TCG with a warning instead
+// of letting QEMU die on -enable-kvm; an explicit Kvm fails.
+pub fn resolve_accel(config: &VMConfig) -> Result<Accelerator, QemuError> {
+    if config.accel == Accelerator::Tcg {
+        return Ok(Accelerator::Tcg);
+    }
+    
+    let unavailable = if config.arch.is_native() {
+        kvm_unavailable_reason()
+    } else {
+        Some(format!("{} guests can't use KVM on a {} host", config.arch.as_str(), std::env::consts::ARCH))
+    };
+    match (unavailable, config.accel) {
+        (None, _) => Ok(Accelerator::Kvm),
+        (Some(reason), Accelerator::Kvm) => Err(QemuError::KvmUnavailable(reason)),
+        (Some(reason), _) => {
+            // Foreign guests are always emulated; nothing to warn about
+            if config.arch.is_native() {
+                log::warn!("VM {}: {}; falling back to TCG. {}", config.id, reason, tcg_hint());
+            }
+            Ok(Accelerator::Tcg)
+        }
+    }
+}
+
+// What this host offers guests, for clients choosing arch and accel
+#[derive(Debug, Clone, Serialize)]
+pub struct HostCapabilities {
+    pub arch: &'static str,
+    pub kvm: bool,
+    pub kvm_unavailable: Option<String>,
+    // "vmx" or "svm"; None if the CPU doesn't say (always, off x86)
+    pub virtualization: Option<&'static str>,
+    pub nested: bool,
+    // Whether QEMU has TCG to fall back on; None if unknown
+    pub tcg: Option<bool>,
+    // Guest archs with a QEMU binary installed
+    pub guest_archs: Vec<GuestArch>,
+}
+
+pub fn host_capabilities() -> HostCapabilities {
+    let kvm_unavailable = kvm_unavailable_reason();
+    HostCapabilities {
+        arch: std::env::consts::ARCH,
+        kvm: kvm_unavailable.is_none(),
+        kvm_unavailable,
+        virtualization: cpu_virtualization(),
+        nested: nested_virtualization(),
+        tcg: qemu_caps()
+            .filter(|caps| !caps.accels.is_empty())
+            .map(|caps| caps.accels.iter().any(|accel| accel == "tcg")),
+        guest_archs: GuestArch::ALL.iter()
+            .copied()
+            .filter(|arch| find_in_path(&arch.binary()).is_some())
+            .collect(),
+    }
+}
+
+fn tcg_hint() -> &'static str {
+    match qemu_caps() {
+        Some(help) if help.accels.iter().any(|a| a == "tcg") => {
+            "TCG software emulation is available as a (much slower) fallback."
+        }
+        Some(help) if !help.accels.is_empty() => "This QEMU build has no TCG fallback.",
+        _ => "TCG fallback availability is unknown.",
+    }
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    // /etc/group lines are "name:password:gid:members"
+    std::fs::read_to_string("/etc/group").ok()?
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse::<u32>().ok()?;
+            (id == gid).then(|| name.to_string())
+        })
+}
+
+fn parse_cpu_help(output: &str) -> Vec<String> {
+    // "Available CPUs:" followed by "x86 <name>   <description>" lines, then a
+    // blank line before the CPUID flag listing
+    output.lines()
+        .skip_while(|line| !line.starts_with("Available CPUs"))
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("x86") => tokens.next(),
+                other => other,
+            }
+        })
+        .map(|name| name.to_string())
+        .collect()
+}
+
+// How VMManager launches QEMU. The manager holds one of these instead of
+// calling QemuProcess::start itself so tests can stand in a fake process.
+pub trait QemuSpawner: Send + Sync {
+    fn spawn<'a>(
+        &'a self,
+        config: &'a VMConfig,
+        disk_path: &'a Path,
+        sandbox: VMSandbox,
+        serial: SerialConsole,
+        incoming: Option<&'a Path>,
+        startup_timeout: Duration,
+    ) -> BoxFuture<'a, Result<QemuProcess, QemuError>>;
+}
+
+// Runs the real QEMU through QemuProcess::start
+pub struct SystemQemu;
+
+impl QemuSpawner for SystemQemu {
+    fn spawn<'a>(
+        &'a self,
+        config: &'a VMConfig,
+        disk_path: &'a Path,
+        sandbox: VMSandbox,
+        serial: SerialConsole,
+        incoming: Option<&'a Path>,
+        startup_timeout: Duration,
+    ) -> BoxFuture<'a, Result<QemuProcess, QemuError>> {
+        Box::pin(QemuProcess::start(config, disk_path, sandbox, serial, incoming, startup_timeout))
+    }
+}
+
+pub struct QemuProcess {
+    pid: u32,
+    started_at: chrono::DateTime<chrono::Utc>,
+    // None for a QEMU adopted from an earlier backend, which can only be
+    // signalled and watched by pid
+    child: Option<process::Child>,
+    config: VMConfig,
+    qmp_socket: PathBuf,
+    // What Auto resolved to for this run
+    accel: Accelerator,
+}
+
+impl QemuProcess {
+    // Wraps an already running stand-in for QEMU, for a test QemuSpawner
+    #[cfg(test)]
+    pub(crate) fn from_child(config: &VMConfig, child: process::Child) -> Self {
+        Self {
+            pid: child.id().unwrap_or_default(),
+            started_at: chrono::Utc::now(),
+            child: Some(child),
+            config: config.clone(),
+            qmp_socket: qmp_socket_path(&config.id),
+            accel: Accelerator::Tcg,
+        }
+    }
+    
+    // Takes over a QEMU an earlier backend started, by the pid it recorded.
+    // Only a live process with this VM's QMP socket on its command line is
+    // taken, so a reused pid isn't mistaken for it.
+    pub fn adopt(config: &VMConfig, pid: u32, started_at: chrono::DateTime<chrono::Utc>) -> Option<Self> {
+        let qmp_socket = qmp_socket_path(&config.id);
+        let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+        let args: Vec<&[u8]> = cmdline.split(|byte| *byte == 0).collect();
+        let qmp_arg = format!("unix:{},server,nowait", qmp_socket.display());
+        if !args.windows(2).any(|pair| pair[0] == b"-qmp" && pair[1] == qmp_arg.as_bytes()) || !process_alive(pid) {
+            return None;
+        }
+        
+        let accel = if args.contains(&&b"-enable-kvm"[..]) { Accelerator::Kvm } else { Accelerator::Tcg };
+        Some(Self {
+            pid,
+            started_at,
+            child: None,
+            config: config.clone(),
+            qmp_socket,
+            accel,
+        })
+    }
+
+    pub async fn start(
+        config: &VMConfig,
+        disk_path: &Path,
+        sandbox: VMSandbox,
+        serial: SerialConsole,
+        // Saved state from suspend_uri to restore instead of booting
+        incoming: Option<&Path>,
+        startup_timeout: Duration,
+    ) -> Result<Self, QemuError> {
+        // Decide before QEMU runs, which would only leave a cryptic
+        // -enable-kvm failure in its log
+        let accel = resolve_accel(config)?;
+        
+        let binary = config.arch.binary();
+        
+        // A chroot has to already hold QEMU and what it loads by the time
+        // the sandbox switches into it
+        if let Some(root) = &sandbox.chroot_path {
+            let binary = find_in_path(&binary)
+                .ok_or_else(|| QemuError::StartFailed(format!("{} not found in PATH", binary)))?;
+            VMSandbox::populate_chroot(Path::new(root), &binary)
+                .map_err(|e| QemuError::StartFailed(e.to_string()))?;
+        }
+        
+        // Configs saved before extra_args were validated
+        if let Some(flag) = managed_flag(&config.extra_args) {
+            return Err(QemuError::StartFailed(format!("extra_args sets {}, which is managed", flag)));
+        }
+        
+        let layout = MachineLayout::for_machine(&config.machine_type);
+        let caps = qemu_caps_for(config.arch);
+        if let Some(caps) = caps {
+            caps.require(caps.supports_qmp, "QMP control", "0.13")?;
+            if layout.is_pcie() {
+                caps.require(caps.has_device("pcie-root-port"), "q35 with PCIe root ports", "2.9")?;
+            }
+        }
+        
+        // Build QEMU command
+        let mut cmd = Command::new(&binary);
+        
+        // Basic QEMU arguments. TCG is also QEMU's default without
+        // -enable-kvm, for releases too old for -accel.
+        match accel {
+            Accelerator::Kvm => {
+                cmd.arg("-enable-kvm");
+            }
+            _ => {
+                if caps.is_none_or(|caps| caps.has_option("-accel")) {
+                    cmd.arg("-accel").arg("tcg");
+                }
+            }
+        }
+        // TCG can't pass the host CPU through
+        let cpu = match (accel, config.cpu_type.split(',').next()) {
+            (Accelerator::Tcg, Some("host")) => "max",
+            _ => config.cpu_type.as_str(),
+        };
+        cmd.arg("-cpu").arg(cpu)
+            .arg("-smp").arg(config.cpu_cores.to_string())
+            .arg("-m").arg(format!("{}M", config.memory_mb))
+            .arg("-vnc").arg(vnc_arg(config));
+        
+        let mut drive = format!("file={},format={}{}", 
+            disk_path.display(), 
+            match config.disk_format {
+                super::config::DiskFormat::Qcow2 => "qcow2",
+                super::config::DiskFormat::Raw => "raw",
+                super::config::DiskFormat::Vdi => "vdi",
+                super::config::DiskFormat::Vmdk => "vmdk",
+            },
+            // Let guest TRIM reclaim host space on thin-provisioned images.
+            // Zero writes are only turned into discards from 2.1 on.
+            match (config.discard && config.disk_format.supports_discard(), caps.is_none_or(|caps| caps.at_least(2, 1))) {
+                (true, true) => ",discard=unmap,detect-zeroes=unmap",
+                (true, false) => ",discard=unmap",
+                (false, _) => "",
+            });
+        // The passphrase is read from the key file, so it never shows up in argv
+        if let Some(key) = &config.disk_key {
+            cmd.arg("-object").arg(secret_object(key));
+            drive.push_str(&format!(",encrypt.key-secret={}", KEY_SECRET_ID));
+        }
+        
+        // One virtio-scsi controller for SCSI volumes and virt's CD-ROM
+        let needs_scsi = config.disks.iter().any(|disk| disk.bus == DiskBus::Scsi)
+            || (layout == MachineLayout::Virt && !config.iso_path.is_empty());
+        
+        if layout.is_pcie() {
+            // One root port per device, plus spares for hot-plug
+            let volume_ports = config.disks.iter()
+                .filter(|disk| disk.bus == DiskBus::Virtio)
+                .map(|disk| format!("rp-{}", disk.drive_id()));
+            let scsi_port = needs_scsi.then(|| "rp-scsi".to_string());
+            let nic_ports = (0..config.networks.len()).map(MachineLayout::nic_port);
+            let ports = std::iter::once("rp-disk".to_string())
+                .chain(nic_ports)
+                .chain(volume_ports)
+                .chain(scsi_port)
+                .chain((0..Q35_HOTPLUG_PORTS).map(MachineLayout::hotplug_port));
+            for (chassis, id) in ports.enumerate() {
+                cmd.arg("-device").arg(format!("pcie-root-port,id={},bus=pcie.0,chassis={}", id, chassis + 1));
+            }
+        }
+        
+        // Before any device that sits on it
+        if needs_scsi {
+            cmd.arg("-device").arg(match layout {
+                MachineLayout::Pc => "virtio-scsi-pci,id=scsi0",
+                MachineLayout::Q35 | MachineLayout::Virt => "virtio-scsi-pci,id=scsi0,bus=rp-scsi",
+            });
+        }
+        
+        // Boot the installer when there is one; imported and cloned disks
+        // may come without an ISO and boot straight from disk
+        match layout {
+            MachineLayout::Pc => {
+                cmd.arg("-drive").arg(drive);
+                if config.iso_path.is_empty() {
+                    cmd.arg("-boot").arg("c");
+                } else {
+                    cmd.arg("-cdrom").arg(&config.iso_path).arg("-boot").arg("d");
+                }
+            }
+            MachineLayout::Q35 | MachineLayout::Virt => {
+                // bootindex replaces -boot, which only knows the legacy IDE devices
+                cmd.arg("-drive").arg(format!("{},if=none,id=disk0", drive))
+                    .arg("-device").arg("virtio-blk-pci,drive=disk0,bus=rp-disk,bootindex=1");
+                if !config.iso_path.is_empty() {
+                    // On q35's built-in AHCI controller; virt has no IDE
+                    let cdrom = match layout {
+                        MachineLayout::Virt => "scsi-cd,drive=cd0,bus=scsi0.0,bootindex=0",
+                        _ => "ide-cd,drive=cd0,bus=ide.0,bootindex=0",
+                    };
+                    cmd.arg("-drive").arg(format!("file={},media=cdrom,if=none,id=cd0,readonly=on", config.iso_path))
+                        .arg("-device").arg(cdrom);
+                }
+            }
+        }
+        
+        // Extra volumes, each with its own drive and device
+        for disk in &config.disks {
+            let port = layout.is_pcie().then(|| format!("rp-{}", disk.drive_id()));
+            cmd.arg("-drive").arg(disk.drive_arg(config.discard))
+                .arg("-device").arg(disk.device_arg(port.as_deref()));
+        }
+        
+        // QMP monitor for live control
+        let qmp_socket = qmp_socket_path(&config.id);
+        let _ = std::fs::remove_file(&qmp_socket);
+        cmd.arg("-qmp").arg(format!("unix:{},server,nowait", qmp_socket.display()));
+        
+        // Serial console for terminal clients; nowait so boot doesn't block
+        // until someone connects
+        let serial_socket = serial_socket_path(&config.id);
+        let _ = std::fs::remove_file(&serial_socket);
+        match serial {
+            SerialConsole::Unix => {
+                cmd.arg("-serial").arg(format!("unix:{},server,nowait", serial_socket.display()));
+            }
+            SerialConsole::Tcp(addr) => {
+                cmd.arg("-serial").arg(format!("tcp:{},server,nowait", addr));
+            }
+        }
+        
+        // Add machine type
+        cmd.arg("-machine").arg(&config.machine_type);
+        
+        // Add network: net0, net1, ... in the order they're configured
+        for (index, nic) in config.networks.iter().enumerate() {
+            let netdev = match nic.netdev_arg(index) {
+                Some(netdev) => netdev,
+                None => continue,
+            };
+            let port = layout.is_pcie().then(|| MachineLayout::nic_port(index));
+            cmd.arg("-netdev").arg(netdev)
+                .arg("-device").arg(nic.device_arg(index, &config.nic_mac(index), port.as_deref()));
+        }
+        
+        // Re-create NICs that were hot-plugged into an earlier run
+        for nic in &config.hotplug_nics {
+            cmd.arg("-netdev").arg(nic.netdev_arg())
+                .arg("-device").arg(nic.device_arg());
+        }
+        
+        // Add BIOS
+        match &config.bios {
+            super::config::BiosType::SeaBios => {
+                // Default, nothing to add
+            }
+            super::config::BiosType::Ovmf => {
+                let firmware = config.arch.uefi_firmware().ok_or_else(|| QemuError::StartFailed(format!(
+                    "No packaged UEFI firmware for {}; use a Custom BIOS", config.arch.as_str()
+                )))?;
+                cmd.arg("-bios").arg(firmware);
+            }
+            super::config::BiosType::Custom(path) => {
+                cmd.arg("-bios").arg(path);
+            }
+        }
+        
+        // Add extra arguments
+        for arg in &config.extra_args {
+            cmd.arg(arg);
+        }
+        
+        // The guest continues on its own once the state has loaded
+        if let Some(state) = incoming {
+            cmd.arg("-incoming").arg(format!("exec:{}", suspend_state_command("cat", state)?));
+        }
+        
+        // Redirect output to log file
+        let log_path = qemu_log_path(&config.id);
+        let log_file = std::fs::File::create(&log_path)
+            .map_err(QemuError::IoError)?;
+        
+        cmd.stdout(Stdio::from(log_file.try_clone()?))
+            .stderr(Stdio::from(log_file));
+        
+        // QEMU stays in the foreground: the sandbox's PID namespace lives
+        // exactly as long as its first process, and the child handle has
+        // to be QEMU (or its supervisor) rather than a parent that exits
+        let isolate_pid = sandbox.isolate_pid;
+        // apply() runs between fork and exec, where only raw syscalls are
+        // safe, so every path, map and mask is built here first
+        let prepared = sandbox.prepare()
+            .map_err(|e| QemuError::StartFailed(format!("Sandbox setup failed: {}", e)))?;
+        if !prepared.mounted().is_empty() {
+            log::debug!("Bind mounts for VM {}: {:?}", config.id, prepared.mounted());
+        }
+        unsafe {
+            cmd.pre_exec(move || prepared.apply());
+        }
+        
+        // Start QEMU process
+        let mut child = process::Command::from(cmd)
+            .spawn()
+            .map_err(|e| QemuError::StartFailed(e.to_string()))?;
+        
+        let spawned = child.id()
+            .ok_or_else(|| QemuError::StartFailed("Failed to get PID".to_string()))?;
+        // spawn() returns once QEMU has exec'd, so it's there to find
+        let pid = if isolate_pid { namespaced_qemu_pid(spawned).unwrap_or(spawned) } else { spawned };
+        
+        // Wait until the QMP socket accepts connections, bailing out early
+        // if QEMU exits
+        let deadline = Instant::now() + startup_timeout;
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    return Err(QemuError::StartFailed(
+                        format!("QEMU exited with status: {}", status)
+                    ));
+                }
+                Ok(None) => {
+                    // Process is still running, good
+                }
+                Err(e) => {
+                    return Err(QemuError::StartFailed(e.to_string()));
+                }
+            }
+            
+            if UnixStream::connect(&qmp_socket).await.is_ok() {
+                break;
+            }
+            
+            if Instant::now() >= deadline {
+                let _ = child.kill().await;
+                return Err(QemuError::Timeout);
+            }
+            
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        
+        // Until this is set, VNC with password=on turns every client away
+        if let Some(password) = &config.vnc_password {
+            let args = json!({ "protocol": "vnc", "password": password });
+            if let Err(e) = qmp_command_at(&qmp_socket, "set_password", args, Duration::from_secs(10)).await {
+                let _ = child.kill().await;
+                return Err(QemuError::StartFailed(format!("Failed to set the VNC password: {}", e)));
+            }
+        }
+        
+        Ok(Self {
+            pid,
+            started_at: chrono::Utc::now(),
+            child: Some(child),
+            config: config.clone(),
+            qmp_socket,
+            accel,
+        })
+    }
+    
+    pub async fn stop(&mut self) -> Result<(), QemuError> {
+        // Send SIGTERM; a sandbox supervisor passes it on to QEMU
+        let target = match &self.child {
+            Some(child) => child.id(),
+            None => Some(self.pid),
+        };
+        if let Some(pid) = target {
+            match nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), nix::sys::signal::Signal::SIGTERM) {
+                // Already exited
+                Ok(()) | Err(nix::errno::Errno::ESRCH) => {}
+                Err(e) => return Err(QemuError::IoError(e.into())),
+            }
+        }
+        
+        // Wait for process to terminate
+        let result = match &mut self.child {
+            Some(child) => match time::timeout(Duration::from_secs(10), child.wait()).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(e)) => Err(QemuError::IoError(e)),
+                Err(_) => {
+                    // Force kill if timeout
+                    let _ = child.kill().await;
+                    Err(QemuError::Timeout)
+                }
+            },
+            // Not our child to wait on; its new parent reaps it
+            None => {
+                let deadline = Instant::now() + Duration::from_secs(10);
+                while process_alive(self.pid) && Instant::now() < deadline {
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+                if process_alive(self.pid) {
+                    let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(self.pid as i32), nix::sys::signal::Signal::SIGKILL);
+                    let deadline = Instant::now() + Duration::from_secs(5);
+                    while process_alive(self.pid) && Instant::now() < deadline {
+                        time::sleep(Duration::from_millis(100)).await;
+                    }
+                    if process_alive(self.pid) {
+                        Err(QemuError::IoError(std::io::Error::other(
+                            format!("QEMU PID {} is still running after SIGKILL", self.pid)
+                        )))
+                    } else {
+                        Err(QemuError::Timeout)
+                    }
+                } else {
+                    Ok(())
+                }
+            }
+        };
+        
+        // QEMU leaves its listening socket behind; a stale one would look
+        // connectable to console clients
+        let _ = std::fs::remove_file(serial_socket_path(&self.config.id));
+        
+        result
+    }
+    
+    pub async fn is_running(&mut self) -> bool {
+        let child = match &mut self.child {
+            Some(child) => child,
+            None => return process_alive(self.pid),
+        };
+        match child.try_wait() {
+            Ok(Some(_)) => false,
+            Ok(None) => true,
+            Err(_) => false,
+        }
+    }
+    
+    // Opens a fresh QMP session per command: greeting, capability
+    // negotiation, then the command itself
+    pub async fn qmp_command(&self, cmd: &str, args: Value) -> Result<Value, QemuError> {
+        self.qmp_command_within(cmd, args, Duration::from_secs(10)).await
+    }
+    
+    pub async fn qmp_command_within(&self, cmd: &str, args: Value, timeout: Duration) -> Result<Value, QemuError> {
+        qmp_command_at(&self.qmp_socket, cmd, args, timeout).await
+    }
+    
+    pub fn started_at(&self) -> chrono::DateTime<chrono::Utc> {
+        self.started_at
+    }
+    
+    // With an isolated PID namespace this is the supervisor outside it,
+    // which relays signals down to QEMU and exits with its status
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+    
+    pub fn accel(&self) -> Accelerator {
+        self.accel
+    }
+}
+
+// The password itself goes over QMP once QEMU is up; on the command line
+// it would show in ps
+fn vnc_arg(config: &VMConfig) -> String {
+    let display = config.vnc_port - 5900;
+    if config.vnc_password.is_some() {
+        format!(":{},password=on", display)
+    } else {
+        format!(":{}", display)
+    }
+}
+
+// For callers that shouldn't hold on to the QemuProcess while a slow
+// command (e.g. savevm writing out RAM) runs
+pub async fn qmp_command_at(socket: &Path, cmd: &str, args: Value, timeout: Duration) -> Result<Value, QemuError> {
+    time::timeout(timeout, qmp_session(socket, cmd, args))
+        .await
+        .map_err(|_| QemuError::Timeout)?
+}
+
+async fn qmp_session(socket: &Path, cmd: &str, args: Value) -> Result<Value, QemuError> {
+    let stream = UnixStream::connect(socket).await
+        .map_err(|e| QemuError::Qmp(format!("Cannot connect to {}: {}", socket.display(), e)))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    
+    // Greeting
+    match lines.next_line().await? {
+        Some(line) if line.contains("\"QMP\"") => {}
+        _ => return Err(QemuError::Qmp("Missing QMP greeting".to_string())),
+    }
+    
+    writer.write_all(b"{\"execute\":\"qmp_capabilities\"}\n").await?;
+    read_qmp_reply(&mut lines, "qmp_capabilities").await?;
+    
+    let mut request = json!({ "execute": cmd });
+    if !args.is_null() {
+        request["arguments"] = args;
+    }
+    writer.write_all(format!("{}\n", request).as_bytes()).await?;
+    read_qmp_reply(&mut lines, cmd).await
+}
+
+async fn read_qmp_reply<R>(lines: &mut tokio::io::Lines<R>, cmd: &str) -> Result<Value, QemuError>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    loop {
+        let line = lines.next_line().await?
+            .ok_or_else(|| QemuError::Qmp("QMP connection closed".to_string()))?;
+        let message: Value = serde_json::from_str(&line)
+            .map_err(|e| QemuError::Qmp(format!("Invalid QMP message: {}", e)))?;
+        
+        if let Some(ret) = message.get("return") {
+            return Ok(ret.clone());
+        }
+        if let Some(error) = message.get("error") {
+            let field = |name| error.get(name).and_then(Value::as_str);
+            return Err(QemuError::QmpCommand {
+                command: cmd.to_string(),
+                class: field("class").unwrap_or("GenericError").to_string(),
+                desc: field("desc").unwrap_or("unknown error").to_string(),
+            });
+        }
+        // Asynchronous events can arrive in between; skip them
+    }
+}
+
+// Migration URI that writes the guest's state to `path`, for the matching
+// `incoming` on the next start
+pub fn suspend_uri(path: &Path) -> Result<String, QemuError> {
+    Ok(format!("exec:{}", suspend_state_command("cat >", path)?))
+}
+
+// exec: URIs go through /bin/sh, so the path is single-quoted
+fn suspend_state_command(prefix: &str, path: &Path) -> Result<String, QemuError> {
+    let path = path.to_str()
+        .filter(|path| !path.contains('\''))
+        .ok_or_else(|| QemuError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unusable state file path {}", path.display()),
+        )))?;
+    Ok(format!("{} '{}'", prefix, path))
+}
+
+// utime + stime of `pid` in clock ticks, for CPU use between two samples.
+// sysinfo needs two refreshes of the same System to report a usage.
+pub fn process_cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // comm may contain spaces; the fields after it are fixed
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+// Resident set size of `pid`, which for QEMU is mostly guest RAM touched
+pub fn process_rss_mb(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let kb: u64 = status.lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb / 1024)
+}
+
+pub fn clock_ticks_per_second() -> u64 {
+    use nix::unistd::{sysconf, SysconfVar};
+    
+    match sysconf(SysconfVar::CLK_TCK) {
+        Ok(Some(ticks)) if ticks > 0 => ticks as u64,
+        _ => 100,
+    }
+}
+
+pub fn uptime_since(started_at: chrono::DateTime<chrono::Utc>) -> u64 {
+    (chrono::Utc::now() - started_at).num_seconds().max(0) as u64
+}
+
+// Kernel's record of when `pid` started, for seeding started_at when
+// reattaching to a QEMU this backend didn't launch
+// Running, or at least not yet a zombie waiting to be reaped
+fn process_alive(pid: u32) -> bool {
+    // The state follows the command name, which may itself hold ") "
+    std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()
+        .and_then(|stat| stat.rsplit_once(") ").and_then(|(_, rest)| rest.chars().next()))
+        .is_some_and(|state| state != 'Z' && state != 'X')
+}
+
+pub fn process_start_time(pid: u32) -> Option<chrono::DateTime<chrono::Utc>> {
+    use sysinfo::{ProcessRefreshKind, RefreshKind, System};
+    
+    let mut system = System::new_with_specifics(
+        RefreshKind::new().with_processes(ProcessRefreshKind::new()),
+    );
+    system.refresh_processes();
+    
+    let secs = system.process(sysinfo::Pid::from(pid as usize))?.start_time();
+    chrono::DateTime::from_timestamp(secs as i64, 0)
 }
\ No newline at end of file
@@ -1,78 +1,158 @@
-class VMApi {
-    constructor(baseUrl = 'http://127.0.0.1:3030/api') {
-        this.baseUrl = baseUrl;
-    }
-
-    async request(endpoint, options = {}) {
-        const url = `${this.baseUrl}${endpoint}`;
-        const defaultOptions = {
-            headers: {
-                'Content-Type': 'application/json',
-            },
-        };
-
-        const response = await fetch(url, { ...defaultOptions, ...options });
-        
-        if (!response.ok) {
-            let errorMsg = `HTTP ${response.status}`;
-            try {
-                const errorData = await response.json();
-                errorMsg = errorData.error || errorMsg;
-            } catch (e) {
-                // Ignore JSON parsing errors
-            }
-            throw new Error(errorMsg);
-        }
-
-        return response.json();
-    }
-
-    async listVMs() {
-        return this.request('/vms');
-    }
-
-    async createVM(config) {
-        return this.request('/vms', {
-            method: 'POST',
-            body: JSON.stringify(config),
-        });
-    }
-
-    async startVM(vmId) {
-        return this.request(`/vms/${vmId}/start`, {
-            method: 'POST',
-        });
-    }
-
-    async stopVM(vmId) {
-        return this.request(`/vms/${vmId}/stop`, {
-            method: 'POST',
-        });
-    }
-
-    async deleteVM(vmId) {
-        return this.request(`/vms/${vmId}`, {
-            method: 'DELETE',
-        });
-    }
-
-    async getVNCUrl(vmId) {
-        return this.request(`/vms/${vmId}/vnc`);
-    }
-
-    async uploadISO(file) {
-        const formData = new FormData();
-        formData.append('iso', file);
-        
-        const response = await fetch(`${this.baseUrl}/isos/upload`, {
-            method: 'POST',
-            body: formData,
-        });
-        
-        if (!response.ok) {
-            throw new Error(`Upload failed: ${response.status}`);
-        }
-        
-        return response.json();
-    }
+class VMApi {
+    constructor(baseUrl = 'http://127.0.0.1:3030/api') {
+        this.baseUrl = baseUrl;
+    }
+
+    async request(endpoint, options = {}) {
+        const url = `${this.baseUrl}${endpoint}`;
+        const defaultOptions = {
+            headers: {
+                'Content-Type': 'application/json',
+            },
+        };
+
+        const response = await fetch(url, { ...defaultOptions, ...options });
+        
+        if (!response.ok) {
+            let errorMsg = `HTTP ${response.status}`;
+            try {
+                const errorData = await response.json();
+                errorMsg = errorData.message || errorData.error || errorMsg;
+            } catch (e) {
+                // Ignore JSON parsing errors
+            }
+            throw new Error(errorMsg);
+        }
+
+        return response.json();
+    }
+
+    async listVMs() {
+        return this.request('/vms');
+    }
+
+    async createVM(config) {
+        return this.request('/vms', {
+            method: 'POST',
+            body: JSON.stringify(config),
+        });
+    }
+
+    async startVM(vmId) {
+        return this.request(`/vms/${vmId}/start`, {
+            method: 'POST',
+        });
+    }
+
+    async stopVM(vmId) {
+        return this.request(`/vms/${vmId}/stop`, {
+            method: 'POST',
+        });
+    }
+
+    async pauseVM(vmId) {
+        return this.request(`/vms/${vmId}/pause`, {
+            method: 'POST',
+        });
+    }
+
+    // Soft asks the guest via Ctrl+Alt+Del; hard resets the machine
+    async rebootVM(vmId, hard = false) {
+        const query = hard ? '?hard=true' : '';
+        return this.request(`/vms/${vmId}/reboot${query}`, {
+            method: 'POST',
+        });
+    }
+
+    async resumeVM(vmId) {
+        return this.request(`/vms/${vmId}/resume`, {
+            method: 'POST',
+        });
+    }
+
+    async resetVMState(vmId) {
+        return this.request(`/vms/${vmId}/reset-state`, {
+            method: 'POST',
+        });
+    }
+
+    async updateVM(vmId, changes, deferRestart = false) {
+        const query = deferRestart ? '?defer_restart=true' : '';
+        return this.request(`/vms/${vmId}${query}`, {
+            method: 'PATCH',
+            body: JSON.stringify(changes),
+        });
+    }
+
+    // Live on a running VM, otherwise used from the next start
+    async changeISO(vmId, isoPath) {
+        return this.request(`/vms/${vmId}/iso`, {
+            method: 'POST',
+            body: JSON.stringify({ iso_path: isoPath }),
+        });
+    }
+
+    async deleteVM(vmId, force = false) {
+        const query = force ? '?force=true' : '';
+        return this.request(`/vms/${vmId}${query}`, {
+            method: 'DELETE',
+        });
+    }
+
+    // The returned URL holds a single-use token valid for about a minute,
+    // so fetch it right before connecting
+    async getVNCUrl(vmId) {
+        const result = await this.request(`/vms/${vmId}/vnc`);
+        const origin = new URL(this.baseUrl);
+        origin.protocol = origin.protocol === 'https:' ? 'wss:' : 'ws:';
+        return { ...result, url: new URL(result.url, origin).toString() };
+    }
+
+    async getMetricsHistory(vmId) {
+        return this.request(`/vms/${vmId}/metrics/history`);
+    }
+
+    async fetchISO(url, name = null, hash = null) {
+        return this.request('/isos/fetch', {
+            method: 'POST',
+            body: JSON.stringify({ url, name, hash }),
+        });
+    }
+
+    // Sent in chunks so multi-GB images never sit in memory on either end.
+    // A failed chunk is retried from wherever the server says it got to.
+    async uploadISO(file, onProgress = () => {}) {
+        const chunkSize = 64 * 1024 * 1024;
+        const upload = await this.request('/isos/upload/init', {
+            method: 'POST',
+            body: JSON.stringify({ name: file.name, size: file.size }),
+        });
+
+        let offset = 0;
+        let retries = 0;
+        while (offset < file.size) {
+            try {
+                const status = await this.request(`/isos/upload/${upload.id}?offset=${offset}`, {
+                    method: 'PUT',
+                    headers: {
+                        'Content-Type': 'application/octet-stream',
+                    },
+                    body: file.slice(offset, offset + chunkSize),
+                });
+                offset = status.received;
+                retries = 0;
+                onProgress(offset, file.size);
+            } catch (error) {
+                if (++retries > 3) {
+                    await this.request(`/isos/upload/${upload.id}`, { method: 'DELETE' }).catch(() => {});
+                    throw error;
+                }
+                offset = (await this.request(`/isos/upload/${upload.id}`)).received;
+            }
+        }
+
+        return this.request(`/isos/upload/${upload.id}/complete`, {
+            method: 'POST',
+        });
+    }
 }
\ No newline at end of file